@@ -0,0 +1,350 @@
+// Derives `Serialize` and `Deserialize` for wire types.
+//
+// Structs are encoded field by field in declaration order, C-like enums are
+// encoded as their discriminant using the integer type given by `#[repr]`
+// (u32 by default). Supported attributes:
+//
+//  - `#[bitcoin(serialize_path = "...")]` on the type, path of the module
+//    that exports the serialization traits. Defaults to `::serialize`, which
+//    only resolves inside the bitcoin-rust crate, other crates must set it.
+//  - `#[bitcoin(max_count = N)]` on a `Vec` field, refuses to decode more
+//    than N elements.
+//  - `#[bitcoin(big_endian)]` on an integer field, e.g. ports.
+//  - `#[bitcoin(optional)]` on an `Option` field, the field is omitted when
+//    `None` and decoded as `None` if the data ends before it. Only trailing
+//    fields can be optional. It can't be combined with `max_count`.
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{Data, DeriveInput, Fields, Ident, Index, Lit, Meta, NestedMeta, Path};
+
+#[proc_macro_derive(BitcoinEncode, attributes(bitcoin))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).unwrap();
+
+    match encode(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[proc_macro_derive(BitcoinDecode, attributes(bitcoin))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).unwrap();
+
+    match decode(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct FieldOptions {
+    max_count: Option<syn::Expr>,
+    big_endian: bool,
+    optional: bool,
+}
+
+struct Field {
+    // `self.name` or `self.0`
+    member: TokenStream2,
+    // Name used in error paths
+    name: String,
+    ty: syn::Type,
+    options: FieldOptions,
+}
+
+fn bitcoin_attributes(attrs: &[syn::Attribute]) -> syn::Result<Vec<NestedMeta>> {
+    let mut result = vec![];
+
+    for attr in attrs.iter().filter(|a| a.path.is_ident("bitcoin")) {
+        match try!(attr.parse_meta()) {
+            Meta::List(list) => result.extend(list.nested),
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[bitcoin(...)]")),
+        }
+    }
+
+    Ok(result)
+}
+
+fn serialize_path(input: &DeriveInput) -> syn::Result<Path> {
+    let mut path = None;
+
+    for meta in try!(bitcoin_attributes(&input.attrs)) {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident("serialize_path") => {
+                if path.is_some() {
+                    return Err(syn::Error::new_spanned(nv, "duplicate serialize_path"));
+                }
+
+                path = match nv.lit {
+                    Lit::Str(ref value) => Some(try!(value.parse())),
+                    _ => return Err(syn::Error::new_spanned(&nv.lit, "expected a string")),
+                };
+            },
+            meta => return Err(syn::Error::new_spanned(meta, "unknown attribute")),
+        }
+    }
+
+    Ok(path.unwrap_or_else(|| syn::parse_str("::serialize").unwrap()))
+}
+
+fn field_options(attrs: &[syn::Attribute]) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+
+    for meta in try!(bitcoin_attributes(attrs)) {
+        match meta {
+            NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("big_endian") => {
+                options.big_endian = true;
+            },
+            NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("optional") => {
+                options.optional = true;
+            },
+            NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident("max_count") => {
+                options.max_count = Some(match nv.lit {
+                    Lit::Int(ref value) => syn::parse_str(&value.to_string()).unwrap(),
+                    Lit::Str(ref value) => try!(value.parse()),
+                    _ => return Err(syn::Error::new_spanned(&nv.lit,
+                                                            "expected a number or a path")),
+                });
+            },
+            meta => return Err(syn::Error::new_spanned(meta, "unknown attribute")),
+        }
+    }
+
+    if options.big_endian && (options.optional || options.max_count.is_some()) {
+        return Err(syn::Error::new(proc_macro2::Span::call_site(),
+                                   "big_endian can't be combined with other attributes"));
+    }
+
+    // An optional field is decoded whole, the bound would be ignored
+    if options.optional && options.max_count.is_some() {
+        return Err(syn::Error::new(proc_macro2::Span::call_site(),
+                                   "optional can't be combined with max_count"));
+    }
+
+    Ok(options)
+}
+
+fn fields(fields: &Fields) -> syn::Result<Vec<Field>> {
+    let mut result = vec![];
+
+    for (i, field) in fields.iter().enumerate() {
+        let (member, name) = match field.ident {
+            Some(ref ident) => (quote!(#ident), ident.to_string()),
+            None => {
+                let index = Index::from(i);
+                (quote!(#index), i.to_string())
+            }
+        };
+
+        result.push(Field {
+            member: member,
+            name: name,
+            ty: field.ty.clone(),
+            options: try!(field_options(&field.attrs)),
+        });
+    }
+
+    // An optional field followed by a mandatory one could never be omitted
+    let mut seen_optional = false;
+    for field in result.iter() {
+        if seen_optional && !field.options.optional {
+            return Err(syn::Error::new_spanned(&field.ty,
+                                               "only trailing fields can be optional"));
+        }
+        seen_optional = field.options.optional;
+    }
+
+    Ok(result)
+}
+
+struct Variant {
+    ident: Ident,
+    discriminant: syn::Expr,
+}
+
+fn enum_repr(input: &DeriveInput) -> syn::Result<Ident> {
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("repr")) {
+        if let Meta::List(list) = try!(attr.parse_meta()) {
+            for nested in list.nested.iter() {
+                if let &NestedMeta::Meta(Meta::Path(ref path)) = nested {
+                    if let Some(ident) = path.get_ident() {
+                        return Ok(ident.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(Ident::new("u32", proc_macro2::Span::call_site()))
+}
+
+fn variants(data: &syn::DataEnum) -> syn::Result<Vec<Variant>> {
+    let mut result = vec![];
+
+    for variant in data.variants.iter() {
+        if let Fields::Unit = variant.fields {} else {
+            return Err(syn::Error::new_spanned(variant, "only C-like enums are supported"));
+        }
+
+        match variant.discriminant {
+            Some((_, ref discriminant)) => result.push(Variant {
+                ident: variant.ident.clone(),
+                discriminant: discriminant.clone(),
+            }),
+            None => return Err(syn::Error::new_spanned(variant,
+                                                       "missing explicit discriminant")),
+        }
+    }
+
+    Ok(result)
+}
+
+fn encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let path = try!(serialize_path(input));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (serialize, encoded_len) = match input.data {
+        Data::Struct(ref data) => {
+            let fields = try!(fields(&data.fields));
+
+            let serialize = fields.iter().map(|field| {
+                let member = &field.member;
+                if field.options.big_endian {
+                    quote!(#path::BigEndian::serialize_be(&self.#member, serializer);)
+                } else if field.options.optional {
+                    quote! {
+                        if let Some(ref value) = self.#member {
+                            #path::Serialize::serialize(value, serializer);
+                        }
+                    }
+                } else {
+                    quote!(#path::Serialize::serialize(&self.#member, serializer);)
+                }
+            }).collect::<Vec<_>>();
+
+            let encoded_len = fields.iter().map(|field| {
+                let member = &field.member;
+                if field.options.optional {
+                    quote!(self.#member.as_ref().map_or(0, |value| {
+                        #path::Serialize::encoded_len(value)
+                    }))
+                } else {
+                    quote!(#path::Serialize::encoded_len(&self.#member))
+                }
+            }).collect::<Vec<_>>();
+
+            (quote!(#(#serialize)*),
+             quote!(0usize #(+ #encoded_len)*))
+        },
+        Data::Enum(ref data) => {
+            let repr = try!(enum_repr(input));
+            let variants = try!(variants(data));
+
+            let arms = variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let discriminant = &variant.discriminant;
+                quote!(&#name::#ident => #discriminant)
+            }).collect::<Vec<_>>();
+
+            (quote! {
+                let value: #repr = match self { #(#arms),* };
+                #path::Serialize::serialize(&value, serializer);
+            },
+             quote! {
+                let value: #repr = match self { #(#arms),* };
+                #path::Serialize::encoded_len(&value)
+             })
+        },
+        Data::Union(_) => return Err(syn::Error::new_spanned(input, "unions are not supported")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics #path::Serialize for #name #ty_generics #where_clause {
+            fn serialize(&self, serializer: &mut #path::Serializer) {
+                #serialize
+            }
+
+            fn encoded_len(&self) -> usize {
+                #encoded_len
+            }
+        }
+    })
+}
+
+fn decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let path = try!(serialize_path(input));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let fields = try!(fields(&data.fields));
+
+            let values = fields.iter().map(|field| {
+                let member = &field.member;
+                let field_name = &field.name;
+
+                let value = if field.options.big_endian {
+                    quote!(#path::BigEndian::deserialize_be(deserializer))
+                } else if field.options.optional {
+                    quote!(#path::deserialize_optional(deserializer))
+                } else if let Some(ref max) = field.options.max_count {
+                    quote!(#path::deserialize_vec(deserializer, #max))
+                } else {
+                    quote!(#path::Deserialize::deserialize(deserializer))
+                };
+
+                quote! {
+                    #member: match #value {
+                        Ok(value) => value,
+                        Err(e) => return Err(e.in_struct(#name_str, #field_name)),
+                    }
+                }
+            }).collect::<Vec<_>>();
+
+            quote!(Ok(#name { #(#values),* }))
+        },
+        Data::Enum(ref data) => {
+            let repr = try!(enum_repr(input));
+            let variants = try!(variants(data));
+
+            let arms = variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let discriminant = &variant.discriminant;
+                quote!(x if x == #discriminant => Ok(#name::#ident))
+            }).collect::<Vec<_>>();
+
+            quote! {
+                let value: #repr = match #path::Deserialize::deserialize(deserializer) {
+                    Ok(value) => value,
+                    Err(e) => return Err(e),
+                };
+
+                match value {
+                    #(#arms,)*
+                    x => Err(deserializer.error(
+                            #path::DecodeErrorKind::InvalidEnumValue(x as u64))),
+                }
+            }
+        },
+        Data::Union(_) => return Err(syn::Error::new_spanned(input, "unions are not supported")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics #path::Deserialize for #name #ty_generics #where_clause {
+            fn deserialize(deserializer: &mut #path::Deserializer)
+                -> Result<Self, #path::DecodeError> {
+                #body
+            }
+        }
+    })
+}
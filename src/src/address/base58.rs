@@ -0,0 +1,118 @@
+use utils::CryptoUtils;
+
+use super::AddressError;
+
+const ALPHABET: &'static [u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let hash = CryptoUtils::sha256(&CryptoUtils::sha256(data));
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn encode(data: &[u8]) -> String {
+    // Every leading zero byte is encoded as a '1'
+    let zeros = data.iter().take_while(|&&x| x == 0).count();
+
+    // Base 58 digits, least significant first
+    let mut digits: Vec<u8> = vec![];
+    for &byte in data[zeros..].iter() {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let mut result = String::with_capacity(zeros + digits.len());
+    for _ in 0..zeros {
+        result.push('1');
+    }
+
+    for &digit in digits.iter().rev() {
+        result.push(ALPHABET[digit as usize] as char);
+    }
+
+    result
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, AddressError> {
+    let zeros = data.chars().take_while(|&c| c == '1').count();
+
+    // Bytes, least significant first
+    let mut bytes: Vec<u8> = vec![];
+    for c in data.chars().skip(zeros) {
+        let mut carry = match ALPHABET.iter().position(|&x| x as char == c) {
+            Some(value) => value as u32,
+            None => return Err(AddressError::InvalidCharacter(c)),
+        };
+
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut result = vec![0; zeros];
+    result.extend(bytes.iter().rev());
+
+    Ok(result)
+}
+
+pub fn encode_check(data: &[u8]) -> String {
+    let mut payload = data.to_vec();
+    payload.extend(checksum(data).iter());
+
+    encode(&payload)
+}
+
+pub fn decode_check(data: &str) -> Result<Vec<u8>, AddressError> {
+    let mut payload = try!(decode(data));
+    if payload.len() < 4 {
+        return Err(AddressError::InvalidLength(payload.len()));
+    }
+
+    let data_len = payload.len() - 4;
+    if checksum(&payload[..data_len]) != payload[data_len..] {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    payload.truncate(data_len);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    #[test]
+    fn test_base58() {
+        let vectors = [("", ""),
+                       ("61", "2g"),
+                       ("626262", "a3gV"),
+                       ("00000000000000000000", "1111111111"),
+                       ("516b6fcd0f", "ABnLTmg"),
+                       ("00eb15231dfceb60925886b67d065299925915aeb172c06647",
+                        "1NS17iag9jJgTHD1VXjvLCEnZuQ3rJDE9L")];
+
+        for &(hex, base58) in vectors.iter() {
+            let bytes = hex.from_hex().unwrap();
+            assert_eq!(encode(&bytes), base58);
+            assert_eq!(decode(base58).unwrap(), bytes);
+        }
+
+        assert_eq!(decode("0OIl"), Err(AddressError::InvalidCharacter('0')));
+    }
+}
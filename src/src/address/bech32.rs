@@ -0,0 +1,201 @@
+use super::AddressError;
+
+const CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+// BIP173 only allows strings of up to 90 characters
+const MAX_LENGTH: usize = 90;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Variant {
+    // BIP173, used by segwit v0
+    Bech32,
+    // BIP350, used by segwit v1 and later
+    Bech32m,
+}
+
+impl Variant {
+    fn constant(&self) -> u32 {
+        match self {
+            &Variant::Bech32  => 1,
+            &Variant::Bech32m => 0x2bc830a3,
+        }
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut checksum: u32 = 1;
+    for &value in values.iter() {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ value as u32;
+        for i in 0..5 {
+            if (top >> i) & 1 == 1 {
+                checksum ^= GENERATOR[i];
+            }
+        }
+    }
+
+    checksum
+}
+
+fn expand_hrp(hrp: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(hrp.len() * 2 + 1);
+    result.extend(hrp.bytes().map(|c| c >> 5));
+    result.push(0);
+    result.extend(hrp.bytes().map(|c| c & 0x1f));
+
+    result
+}
+
+fn create_checksum(hrp: &str, data: &[u8], variant: Variant) -> [u8; 6] {
+    let mut values = expand_hrp(hrp);
+    values.extend(data.iter());
+    values.extend([0; 6].iter());
+
+    let checksum = polymod(&values) ^ variant.constant();
+
+    let mut result = [0; 6];
+    for i in 0..6 {
+        result[i] = ((checksum >> (5 * (5 - i))) & 0x1f) as u8;
+    }
+
+    result
+}
+
+// Regroups `data` from `from` bits per element to `to` bits per element
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>, AddressError> {
+    let mut accumulator: u32 = 0;
+    let mut bits: u32 = 0;
+    let max = (1 << to) - 1;
+
+    let mut result = vec![];
+    for &value in data.iter() {
+        if (value as u32) >> from != 0 {
+            return Err(AddressError::InvalidPadding);
+        }
+
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((accumulator >> bits) & max) as u8);
+        }
+    }
+
+    if pad {
+        if bits > 0 {
+            result.push(((accumulator << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((accumulator << (to - bits)) & max) != 0 {
+        return Err(AddressError::InvalidPadding);
+    }
+
+    Ok(result)
+}
+
+// `data` holds 5 bit values
+pub fn encode(hrp: &str, data: &[u8], variant: Variant) -> String {
+    let checksum = create_checksum(hrp, data, variant);
+
+    let mut result = String::with_capacity(hrp.len() + 1 + data.len() + 6);
+    result.push_str(hrp);
+    result.push('1');
+    for &value in data.iter().chain(checksum.iter()) {
+        result.push(CHARSET[value as usize] as char);
+    }
+
+    result
+}
+
+// Returns the lowercase human readable part, the 5 bit values without the
+// checksum and the checksum variant
+pub fn decode(data: &str) -> Result<(String, Vec<u8>, Variant), AddressError> {
+    if data.len() > MAX_LENGTH {
+        return Err(AddressError::InvalidLength(data.len()));
+    }
+
+    let has_lower = data.chars().any(|c| c.is_lowercase());
+    let has_upper = data.chars().any(|c| c.is_uppercase());
+    if has_lower && has_upper {
+        return Err(AddressError::MixedCase);
+    }
+
+    let data = data.to_lowercase();
+
+    let separator = match data.rfind('1') {
+        Some(separator) if separator > 0 => separator,
+        _ => return Err(AddressError::MissingSeparator),
+    };
+
+    // The checksum alone takes 6 characters
+    if separator + 7 > data.len() {
+        return Err(AddressError::InvalidLength(data.len()));
+    }
+
+    let hrp = &data[..separator];
+    if let Some(c) = hrp.chars().find(|&c| c < '!' || c > '~') {
+        return Err(AddressError::InvalidCharacter(c));
+    }
+
+    let mut values = vec![];
+    for c in data[separator + 1..].chars() {
+        match CHARSET.iter().position(|&x| x as char == c) {
+            Some(value) => values.push(value as u8),
+            None => return Err(AddressError::InvalidCharacter(c)),
+        }
+    }
+
+    let mut checked = expand_hrp(hrp);
+    checked.extend(values.iter());
+
+    let variant = match polymod(&checked) {
+        x if x == Variant::Bech32.constant() => Variant::Bech32,
+        x if x == Variant::Bech32m.constant() => Variant::Bech32m,
+        _ => return Err(AddressError::InvalidChecksum),
+    };
+
+    let data_len = values.len() - 6;
+    values.truncate(data_len);
+
+    Ok((hrp.to_owned(), values, variant))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_checksums() {
+        let bech32 = ["A12UEL5L",
+                      "a12uel5l",
+                      "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+                      "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w"];
+
+        for s in bech32.iter() {
+            let (hrp, data, variant) = decode(s).unwrap();
+            assert_eq!(variant, Variant::Bech32);
+            assert_eq!(encode(&hrp, &data, variant), s.to_lowercase());
+        }
+
+        let bech32m = ["A1LQFN3A",
+                       "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+                       "split1checkupstagehandshakeupstreamerranterredcaperredlc445v"];
+
+        for s in bech32m.iter() {
+            let (hrp, data, variant) = decode(s).unwrap();
+            assert_eq!(variant, Variant::Bech32m);
+            assert_eq!(encode(&hrp, &data, variant), s.to_lowercase());
+        }
+    }
+
+    #[test]
+    fn test_invalid_checksums() {
+        assert_eq!(decode("pzry9x0s0muk"), Err(AddressError::MissingSeparator));
+        assert_eq!(decode("1pzry9x0s0muk"), Err(AddressError::MissingSeparator));
+        assert_eq!(decode("x1b4n0q5v"), Err(AddressError::InvalidCharacter('b')));
+        assert_eq!(decode("A1G7SGD8"), Err(AddressError::InvalidChecksum));
+        assert_eq!(decode("li1dgmt3"), Err(AddressError::InvalidLength(8)));
+        assert_eq!(decode("a12UEL5L"), Err(AddressError::MixedCase));
+    }
+}
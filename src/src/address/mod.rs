@@ -0,0 +1,327 @@
+// Bitcoin addresses: Base58Check for P2PKH and P2SH, Bech32 (BIP173) for
+// segwit v0 and Bech32m (BIP350) for segwit v1 and later.
+mod base58;
+mod bech32;
+
+use self::bech32::Variant;
+
+use net::messages::NetworkType;
+use script::{ScriptType, witness_program};
+
+use std::fmt;
+
+// Networks with distinct address prefixes, the other testnets share them with
+// TestNet3 and Regtest only differs in its human readable part
+const NETWORKS: [NetworkType; 4] = [NetworkType::Main, NetworkType::TestNet3,
+                                    NetworkType::Regtest, NetworkType::NameCoin];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AddressError {
+    InvalidCharacter(char),
+    InvalidLength(usize),
+    InvalidChecksum,
+    // Segwit v0 must use Bech32, later versions Bech32m
+    InvalidChecksumVariant,
+    MixedCase,
+    // No separator or empty human readable part
+    MissingSeparator,
+    InvalidPadding,
+    InvalidWitnessVersion(u8),
+    InvalidWitnessProgram(usize),
+    UnknownPrefix(String),
+    WrongNetwork(NetworkType),
+    UnsupportedNetwork(NetworkType),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AddressError::InvalidCharacter(c) => write!(f, "invalid character `{}`", c),
+            &AddressError::InvalidLength(length) => write!(f, "invalid length {}", length),
+            &AddressError::InvalidChecksum => write!(f, "invalid checksum"),
+            &AddressError::InvalidChecksumVariant =>
+                write!(f, "wrong checksum variant for the witness version"),
+            &AddressError::MixedCase => write!(f, "mixed upper and lower case"),
+            &AddressError::MissingSeparator => write!(f, "missing separator"),
+            &AddressError::InvalidPadding => write!(f, "invalid padding"),
+            &AddressError::InvalidWitnessVersion(version) =>
+                write!(f, "invalid witness version {}", version),
+            &AddressError::InvalidWitnessProgram(length) =>
+                write!(f, "invalid witness program length {}", length),
+            &AddressError::UnknownPrefix(ref prefix) => write!(f, "unknown prefix `{}`", prefix),
+            &AddressError::WrongNetwork(network) =>
+                write!(f, "address belongs to network {:?}", network),
+            &AddressError::UnsupportedNetwork(network) =>
+                write!(f, "network {:?} has no addresses", network),
+        }
+    }
+}
+
+impl From<AddressError> for String {
+    fn from(error: AddressError) -> String {
+        error.to_string()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Payload {
+    PubKeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+    WitnessProgram {
+        version: u8,
+        program: Vec<u8>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Address {
+    pub network: NetworkType,
+    pub payload: Payload,
+}
+
+fn to_hash(data: &[u8]) -> [u8; 20] {
+    let mut hash = [0; 20];
+    hash.copy_from_slice(data);
+    hash
+}
+
+fn check_witness_program(version: u8, program: &[u8]) -> Result<(), AddressError> {
+    if version > 16 {
+        return Err(AddressError::InvalidWitnessVersion(version));
+    }
+
+    match (version, program.len()) {
+        (0, 20) | (0, 32) => Ok(()),
+        (0, length) => Err(AddressError::InvalidWitnessProgram(length)),
+        (_, 2...40) => Ok(()),
+        (_, length) => Err(AddressError::InvalidWitnessProgram(length)),
+    }
+}
+
+impl Address {
+    pub fn new(network: NetworkType, payload: Payload) -> Address {
+        Address {
+            network: network,
+            payload: payload,
+        }
+    }
+
+    // Only standard output types have an address
+    pub fn from_script(script: &[u8], network: NetworkType) -> Option<Address> {
+        let payload = match ScriptType::classify(script) {
+            ScriptType::PubKeyHash => Payload::PubKeyHash(to_hash(&script[3..23])),
+            ScriptType::ScriptHash => Payload::ScriptHash(to_hash(&script[2..22])),
+            ScriptType::WitnessV0KeyHash | ScriptType::WitnessV0ScriptHash |
+            ScriptType::WitnessV1Taproot | ScriptType::WitnessUnknown => {
+                let (version, program) = witness_program(script).unwrap();
+                Payload::WitnessProgram {
+                    version: version,
+                    program: program.to_vec(),
+                }
+            },
+            _ => return None,
+        };
+
+        Some(Address::new(network, payload))
+    }
+
+    pub fn script_pub_key(&self) -> Vec<u8> {
+        let mut script = vec![];
+
+        match self.payload {
+            Payload::PubKeyHash(ref hash) => {
+                // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+                script.extend([0x76, 0xa9, 0x14].iter());
+                script.extend(hash.iter());
+                script.extend([0x88, 0xac].iter());
+            },
+            Payload::ScriptHash(ref hash) => {
+                // OP_HASH160 <hash> OP_EQUAL
+                script.extend([0xa9, 0x14].iter());
+                script.extend(hash.iter());
+                script.push(0x87);
+            },
+            Payload::WitnessProgram { version, ref program } => {
+                // OP_0 or OP_1 to OP_16 followed by a single push
+                script.push(if version == 0 { 0x00 } else { 0x50 + version });
+                script.push(program.len() as u8);
+                script.extend(program.iter());
+            },
+        }
+
+        script
+    }
+
+    // Parses an address and checks that it belongs to `network`
+    pub fn from_str(address: &str, network: NetworkType) -> Result<Address, AddressError> {
+        let hrp = match network.bech32_hrp() {
+            Some(hrp) => hrp,
+            None => return Err(AddressError::UnsupportedNetwork(network)),
+        };
+
+        // Base58 addresses can't start with a human readable part
+        let lowercase = address.to_lowercase();
+        let is_segwit = NETWORKS.iter().filter_map(|n| n.bech32_hrp())
+            .any(|hrp| lowercase.starts_with(&format!("{}1", hrp)));
+
+        let payload = if is_segwit {
+            try!(Self::decode_segwit(address, hrp))
+        } else {
+            try!(Self::decode_base58(address, network))
+        };
+
+        Ok(Address::new(network, payload))
+    }
+
+    fn decode_base58(address: &str, network: NetworkType) -> Result<Payload, AddressError> {
+        let data = try!(base58::decode_check(address));
+        if data.len() != 21 {
+            return Err(AddressError::InvalidLength(data.len()));
+        }
+
+        let prefix = data[0];
+        if Some(prefix) == network.pub_key_hash_prefix() {
+            return Ok(Payload::PubKeyHash(to_hash(&data[1..])));
+        }
+
+        if Some(prefix) == network.script_hash_prefix() {
+            return Ok(Payload::ScriptHash(to_hash(&data[1..])));
+        }
+
+        match NETWORKS.iter().find(|n| Some(prefix) == n.pub_key_hash_prefix() ||
+                                       Some(prefix) == n.script_hash_prefix()) {
+            Some(other) => Err(AddressError::WrongNetwork(*other)),
+            None => Err(AddressError::UnknownPrefix(format!("{:02x}", prefix))),
+        }
+    }
+
+    fn decode_segwit(address: &str, hrp: &str) -> Result<Payload, AddressError> {
+        let (found, data, variant) = try!(bech32::decode(address));
+
+        if found != hrp {
+            return match NETWORKS.iter().find(|n| n.bech32_hrp() == Some(&found)) {
+                Some(other) => Err(AddressError::WrongNetwork(*other)),
+                None => Err(AddressError::UnknownPrefix(found)),
+            };
+        }
+
+        if data.len() == 0 {
+            return Err(AddressError::InvalidWitnessProgram(0));
+        }
+
+        let version = data[0];
+        let program = try!(bech32::convert_bits(&data[1..], 5, 8, false));
+        try!(check_witness_program(version, &program));
+
+        let expected = if version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+        if variant != expected {
+            return Err(AddressError::InvalidChecksumVariant);
+        }
+
+        Ok(Payload::WitnessProgram {
+            version: version,
+            program: program,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (prefix, hash) = match self.payload {
+            Payload::PubKeyHash(ref hash) => (self.network.pub_key_hash_prefix(), hash),
+            Payload::ScriptHash(ref hash) => (self.network.script_hash_prefix(), hash),
+            Payload::WitnessProgram { version, ref program } => {
+                let hrp = match self.network.bech32_hrp() {
+                    Some(hrp) => hrp,
+                    None => return Err(fmt::Error),
+                };
+
+                let variant = if version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+
+                let mut data = vec![version];
+                data.extend(bech32::convert_bits(program, 8, 5, true).unwrap());
+
+                return write!(f, "{}", bech32::encode(hrp, &data, variant));
+            },
+        };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix,
+            None => return Err(fmt::Error),
+        };
+
+        let mut data = vec![prefix];
+        data.extend(hash.iter());
+
+        write!(f, "{}", base58::encode_check(&data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::NetworkType;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    fn assert_address(address: &str, network: NetworkType, script: &str) {
+        let parsed = Address::from_str(address, network).unwrap();
+        assert_eq!(parsed.script_pub_key().to_hex(), script);
+        // Uppercase Bech32 is valid but always encoded as lowercase
+        if address == address.to_uppercase() {
+            assert_eq!(parsed.to_string(), address.to_lowercase());
+        } else {
+            assert_eq!(parsed.to_string(), address);
+        }
+
+        let from_script = Address::from_script(&script.from_hex().unwrap(), network).unwrap();
+        assert_eq!(from_script, parsed);
+    }
+
+    #[test]
+    fn test_base58_addresses() {
+        assert_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", NetworkType::Main,
+                       "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        assert_address("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn", NetworkType::TestNet3,
+                       "76a914243f1394f44554f4ce3fd68649c19adc483ce92488ac");
+    }
+
+    #[test]
+    fn test_segwit_addresses() {
+        assert_address("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", NetworkType::Main,
+                       "0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        assert_address("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                       NetworkType::TestNet3,
+                       "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262");
+        assert_address("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+                       NetworkType::Main,
+                       "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6");
+        assert_address("BC1SW50QGDZ25J", NetworkType::Main, "6002751e");
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        assert_eq!(Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", NetworkType::TestNet3),
+                   Err(AddressError::WrongNetwork(NetworkType::Main)));
+        assert_eq!(Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb", NetworkType::Main),
+                   Err(AddressError::InvalidChecksum));
+        assert_eq!(Address::from_str("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                                     NetworkType::Main),
+                   Err(AddressError::WrongNetwork(NetworkType::TestNet3)));
+        assert_eq!(Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5", NetworkType::Main),
+                   Err(AddressError::InvalidChecksum));
+        // Segwit v1 with a Bech32 checksum
+        assert_eq!(Address::from_str("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+                                     NetworkType::Main),
+                   Err(AddressError::InvalidChecksumVariant));
+        assert_eq!(Address::from_str("BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P", NetworkType::Main),
+                   Err(AddressError::InvalidWitnessProgram(16)));
+        assert_eq!(Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", NetworkType::Unknown),
+                   Err(AddressError::UnsupportedNetwork(NetworkType::Unknown)));
+    }
+
+    #[test]
+    fn test_non_standard_script() {
+        assert_eq!(Address::from_script(&"6a0379657300".from_hex().unwrap(), NetworkType::Main),
+                   None);
+    }
+}
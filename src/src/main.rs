@@ -3,6 +3,8 @@ mod serialize;
 
 pub mod script;
 pub mod net;
+pub mod address;
+pub mod psbt;
 
 #[cfg(test)]
 mod test;
@@ -15,15 +17,99 @@ extern crate time;
 extern crate mio;
 extern crate bytes;
 extern crate rand;
+extern crate secp256k1;
+#[macro_use]
+extern crate bitcoin_rust_derive;
 
+use std::io::Cursor;
 use std::net::SocketAddr;
 
+use net::banlist::BanList;
+use net::chain_params::ChainParams;
+use net::messages::{BlockMessage, Timestamp32};
+use net::generate;
+use net::signet;
+use rustc_serialize::hex::ToHex;
+use secp256k1::SecretKey;
+use serialize::{Serialize, Deserialize};
 use utils::Config;
 
+// Signs a block template for a signet and redoes its proof of work
+fn sign_signet_block(chain: &ChainParams, block: &[u8],
+                     key: Option<&SecretKey>) -> Result<Vec<u8>, String> {
+    let challenge = try!(chain.signet_challenge.as_ref()
+                         .ok_or(format!("Not a signet: {}", chain.name)));
+    let key = try!(key.ok_or(format!("Missing signet key.")));
+
+    let mut block = try!(BlockMessage::deserialize(&mut Cursor::new(block))
+                         .map_err(|e| format!("Invalid block: {}", e)));
+    try!(signet::sign_block(&mut block, challenge, key).map_err(|e| e.to_string()));
+
+    if !generate::solve_proof_of_work(&mut block) {
+        return Err(format!("No nonce satisfies the proof of work"));
+    }
+
+    let mut data = vec![];
+    block.serialize(&mut data);
+    Ok(data)
+}
+
+// Applies the bans and unbans of the command line and prints the ban list
+fn manage_banlist(config: &Config) -> Result<(), String> {
+    let path = &config.banlist_file;
+    let mut banlist = if path.exists() { try!(BanList::load(path)) } else { BanList::new() };
+    let now = Timestamp32::now().secs();
+
+    for subnet in config.unban.iter() {
+        if !banlist.unban(subnet) {
+            println!("{} wasn't banned", subnet);
+        }
+    }
+
+    for subnet in config.ban.iter() {
+        banlist.ban(*subnet, now, now.saturating_add(config.ban_time));
+    }
+
+    banlist.sweep(now);
+    try!(banlist.save(path));
+
+    for (subnet, entry) in banlist.entries() {
+        println!("{} banned for {} more seconds", subnet, entry.until - now);
+    }
+
+    Ok(())
+}
+
 pub fn main() {
     let config = Config::from_command_line().unwrap_or_else(
         |e| { println!("Error: {}", e); panic!() });
 
+    if let Some(ref block) = config.sign_block {
+        match sign_signet_block(&config.chain, block, config.signet_key.as_ref()) {
+            Ok(block) => println!("{}", block.to_hex()),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+
+    if config.list_banned || !config.ban.is_empty() || !config.unban.is_empty() {
+        if let Err(e) = manage_banlist(&config) {
+            println!("Error: {}", e);
+        }
+        return;
+    }
+
+    if let Some(count) = config.generate {
+        match generate::generate_to_file(&config.chain, config.blocks_file, count,
+                                         &config.coinbase_script) {
+            Ok(hashes) => for hash in hashes { println!("{:?}", hash) },
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+
     let addr: SocketAddr = format!("0.0.0.0:{}", config.port).parse().unwrap();
-    net::p2pclient::start(addr, config.connect_to, config.blocks_file);
+    net::p2pclient::start(config.chain, addr, config.connect_to, config.blocks_file,
+                          config.peers_file, config.banlist_file, config.import_dir,
+                          config.peer_requirements);
 }
@@ -0,0 +1,598 @@
+// Addresses of peers we heard about, kept the way Bitcoin Core's addrman
+// does. Addresses we never connected to are in the "new" table, bucketed by
+// their group and the group of the peer that sent them, and move to the
+// "tried" table once we connect to them. However many addresses an attacker
+// sends, they only fill the few buckets its groups map to.
+use rand::{self, Rng};
+
+use serialize::{Serialize, Deserialize};
+use utils::CryptoUtils;
+
+use super::IPAddress;
+use super::messages::{Timestamp32, MAX_ADDR_COUNT};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::net::Ipv6Addr;
+use std::path::Path;
+
+const NEW_BUCKET_COUNT: usize = 1024;
+const TRIED_BUCKET_COUNT: usize = 256;
+const BUCKET_SIZE: usize = 64;
+// Addresses from one source group go to at most this many new buckets
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+// Addresses of one group go to at most this many tried buckets
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+const MINUTE: u32 = 60;
+const HOUR: u32 = 60 * MINUTE;
+const DAY: u32 = 24 * HOUR;
+// Addresses not seen for this long are terrible
+const HORIZON: u32 = 30 * DAY;
+// Attempts without a success after which an address is terrible
+const RETRIES: u32 = 3;
+// Failed attempts since the last success after which an address is terrible
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL: u32 = 7 * DAY;
+// Relayed addresses are assumed to have been seen this long before
+const RELAY_PENALTY: u32 = 2 * HOUR;
+// getaddr is answered with at most this percentage of the addresses
+const GETADDR_MAX_PCT: usize = 23;
+// Bumped when the format of peers.dat changes
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+struct AddrInfo {
+    address: IPAddress,
+    // Last time we heard it was online
+    time: Timestamp32,
+    // Who told us about it
+    source: Ipv6Addr,
+    last_try: u32,
+    last_success: u32,
+    // Since the last success
+    attempts: u32,
+    tried: bool,
+}
+
+impl AddrInfo {
+    // Not worth keeping or sharing
+    fn is_terrible(&self, now: u32) -> bool {
+        let time = self.time.secs();
+
+        // Never remove what we just tried
+        if self.last_try > 0 && now.saturating_sub(self.last_try) < MINUTE {
+            return false;
+        }
+
+        time > now + 10 * MINUTE ||
+            now.saturating_sub(time) > HORIZON ||
+            (self.last_success == 0 && self.attempts >= RETRIES) ||
+            (now.saturating_sub(self.last_success) > MIN_FAIL && self.attempts >= MAX_FAILURES)
+    }
+
+    // Relative chance of being selected
+    fn chance(&self, now: u32) -> f64 {
+        let mut chance = 1.0;
+
+        // Recently tried addresses are mostly skipped
+        if now.saturating_sub(self.last_try) < 10 * MINUTE {
+            chance *= 0.01;
+        }
+
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+#[derive(Debug, PartialEq, BitcoinEncode, BitcoinDecode)]
+struct PeersFile {
+    version: u8,
+    key: [u8; 32],
+    entries: Vec<AddrInfo>,
+}
+
+/// Addresses in one group are likely run by the same operator: the /16 of
+/// IPv4 addresses and the /32 of IPv6 ones.
+pub fn network_group(address: &Ipv6Addr) -> Vec<u8> {
+    let octets = address.octets();
+    match ipv4(address) {
+        Some(ipv4) => vec![4, ipv4[0], ipv4[1]],
+        None => vec![6, octets[0], octets[1], octets[2], octets[3]],
+    }
+}
+
+// Octets of IPv4 mapped addresses
+fn ipv4(address: &Ipv6Addr) -> Option<[u8; 4]> {
+    let octets = address.octets();
+    if octets[..10].iter().all(|b| *b == 0) && octets[10] == 0xff && octets[11] == 0xff {
+        Some([octets[12], octets[13], octets[14], octets[15]])
+    } else {
+        None
+    }
+}
+
+/// Whether peers on the internet can reach the address.
+pub fn is_routable(address: &Ipv6Addr) -> bool {
+    match ipv4(address) {
+        Some(ip) => match (ip[0], ip[1]) {
+            (0, _) | (10, _) | (127, _) | (169, 254) | (192, 168) => false,
+            (172, 16...31) | (100, 64...127) => false,
+            (a, _) if a >= 224 => false,
+            _ => true,
+        },
+        None => {
+            let segments = address.segments();
+            !(address.is_unspecified() || address.is_loopback() ||
+              // Unique local and link local
+              segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80 ||
+              // Documentation
+              (segments[0] == 0x2001 && segments[1] == 0x0db8))
+        },
+    }
+}
+
+/// Table of known peer addresses.
+pub struct AddrMan {
+    // Secret, so others can't tell which bucket an address goes to
+    key: [u8; 32],
+    entries: HashMap<u64, AddrInfo>,
+    ids: HashMap<(Ipv6Addr, u16), u64>,
+    next_id: u64,
+    new_table: Vec<Option<u64>>,
+    tried_table: Vec<Option<u64>>,
+}
+
+impl AddrMan {
+    pub fn new() -> AddrMan {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        AddrMan::with_key(key)
+    }
+
+    fn with_key(key: [u8; 32]) -> AddrMan {
+        AddrMan {
+            key: key,
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+            next_id: 0,
+            new_table: vec![None; NEW_BUCKET_COUNT * BUCKET_SIZE],
+            tried_table: vec![None; TRIED_BUCKET_COUNT * BUCKET_SIZE],
+        }
+    }
+
+    /// Reads the addresses saved in `path`, starts empty when there are none.
+    pub fn open(path: &Path) -> AddrMan {
+        if !path.exists() {
+            return AddrMan::new();
+        }
+
+        AddrMan::load(path).unwrap_or_else(|e| {
+            println!("Ignoring {}: {}", path.display(), e);
+            AddrMan::new()
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<AddrMan, String> {
+        let mut data = vec![];
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|e| e.to_string()));
+
+        let file = try!(PeersFile::deserialize(&mut Cursor::new(&data[..])));
+        if file.version != FORMAT_VERSION {
+            return Err(format!("unknown version {}", file.version));
+        }
+
+        // Buckets only depend on the key, tried addresses get their place first
+        let mut addrman = AddrMan::with_key(file.key);
+        let (tried, new): (Vec<_>, Vec<_>) = file.entries.into_iter().partition(|info| info.tried);
+
+        for info in tried {
+            let slot = addrman.tried_slot(&info.address);
+            if addrman.tried_table[slot].is_none() {
+                let id = addrman.insert(info);
+                addrman.tried_table[slot] = Some(id);
+            }
+        }
+
+        for info in new {
+            let slot = addrman.new_slot(&info.address, &info.source);
+            if addrman.new_table[slot].is_none() && !addrman.ids.contains_key(&key(&info.address)) {
+                let id = addrman.insert(info);
+                addrman.new_table[slot] = Some(id);
+            }
+        }
+
+        Ok(addrman)
+    }
+
+    /// Writes the addresses to `path`, through a temporary file so a crash
+    /// doesn't leave half of them.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut ids: Vec<_> = self.entries.keys().collect();
+        ids.sort();
+
+        let file = PeersFile {
+            version: FORMAT_VERSION,
+            key: self.key,
+            entries: ids.into_iter().map(|id| self.entries[id].clone()).collect(),
+        };
+
+        let mut data = vec![];
+        file.serialize(&mut data);
+
+        let tmp = path.with_extension("tmp");
+        try!(File::create(&tmp).and_then(|mut file| file.write_all(&data).and(file.sync_all()))
+             .and_then(|_| fs::rename(&tmp, path))
+             .map_err(|e| e.to_string()));
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn tried_len(&self) -> usize {
+        self.entries.values().filter(|info| info.tried).count()
+    }
+
+    /// Adds addresses `source` sent us, returns how many were new.
+    pub fn add(&mut self, addresses: &[(Timestamp32, IPAddress)], source: &Ipv6Addr,
+               now: u32) -> usize {
+        addresses.iter().filter(|&&(time, address)| self.add_one(address, time, source, now))
+            .count()
+    }
+
+    fn add_one(&mut self, address: IPAddress, time: Timestamp32, source: &Ipv6Addr,
+               now: u32) -> bool {
+        if !is_routable(&address.address) {
+            return false;
+        }
+
+        // Times that make no sense count as five days ago
+        let mut time = time.secs();
+        if time <= 100000000 || time > now + 10 * MINUTE {
+            time = now.saturating_sub(5 * DAY);
+        }
+        if *source != address.address {
+            time = time.saturating_sub(RELAY_PENALTY);
+        }
+
+        if let Some(id) = self.ids.get(&key(&address)) {
+            let info = self.entries.get_mut(id).unwrap();
+            if time > info.time.secs() {
+                info.time = Timestamp32::new(time);
+            }
+            info.address.services = info.address.services | address.services;
+            return false;
+        }
+
+        let slot = self.new_slot(&address, source);
+        if let Some(existing) = self.new_table[slot] {
+            // Only bad addresses make room for others
+            if !self.entries[&existing].is_terrible(now) {
+                return false;
+            }
+            self.remove(existing);
+        }
+
+        let id = self.insert(AddrInfo {
+            address: address,
+            time: Timestamp32::new(time),
+            source: *source,
+            last_try: 0,
+            last_success: 0,
+            attempts: 0,
+            tried: false,
+        });
+        self.new_table[slot] = Some(id);
+
+        true
+    }
+
+    /// We're about to connect to `address`.
+    pub fn attempt(&mut self, address: &IPAddress, now: u32) {
+        if let Some(info) = self.get_mut(address) {
+            info.last_try = now;
+            info.attempts += 1;
+        }
+    }
+
+    /// The handshake with `address` succeeded, it moves to the tried table.
+    pub fn good(&mut self, address: &IPAddress, now: u32) {
+        let id = match self.ids.get(&key(address)) {
+            Some(id) => *id,
+            None => return,
+        };
+
+        let (address, source) = {
+            let info = self.entries.get_mut(&id).unwrap();
+            info.last_success = now;
+            info.last_try = now;
+            info.attempts = 0;
+
+            if info.tried {
+                return;
+            }
+            (info.address, info.source)
+        };
+
+        let slot = self.new_slot(&address, &source);
+        if self.new_table[slot] == Some(id) {
+            self.new_table[slot] = None;
+        }
+
+        // Whoever had the place goes back to the new table
+        let slot = self.tried_slot(&address);
+        if let Some(evicted) = self.tried_table[slot] {
+            let (address, source) = {
+                let info = self.entries.get_mut(&evicted).unwrap();
+                info.tried = false;
+                (info.address, info.source)
+            };
+
+            let new_slot = self.new_slot(&address, &source);
+            if let Some(other) = self.new_table[new_slot] {
+                self.remove(other);
+            }
+            self.new_table[new_slot] = Some(evicted);
+        }
+
+        self.tried_table[slot] = Some(id);
+        self.entries.get_mut(&id).unwrap().tried = true;
+    }
+
+    /// We're connected to `address`, it's online.
+    pub fn connected(&mut self, address: &IPAddress, now: u32) {
+        if let Some(info) = self.get_mut(address) {
+            if now.saturating_sub(info.time.secs()) > 20 * MINUTE {
+                info.time = Timestamp32::new(now);
+            }
+        }
+    }
+
+    /// Picks an address to connect to, tried and new ones as often unless
+    /// `new_only`, and within them favouring those that didn't fail recently.
+    pub fn select(&self, now: u32, new_only: bool) -> Option<IPAddress> {
+        let mut rng = rand::thread_rng();
+        let tried = if new_only { 0 } else { self.tried_len() };
+        let new = self.len() - self.tried_len();
+
+        let use_tried = match (tried, new) {
+            (0, 0) => return None,
+            (0, _) => false,
+            (_, 0) => true,
+            _ => rng.gen(),
+        };
+
+        let table = if use_tried { &self.tried_table } else { &self.new_table };
+        let candidates: Vec<_> = table.iter().filter_map(|id| *id).collect();
+
+        let mut factor = 1.0;
+        loop {
+            let info = &self.entries[&candidates[rng.gen_range(0, candidates.len())]];
+            if rng.gen::<f64>() < factor * info.chance(now) {
+                return Some(info.address);
+            }
+            // Don't loop forever when everything is bad
+            factor *= 1.2;
+        }
+    }
+
+    /// Random addresses to answer a getaddr with.
+    pub fn get_addr(&self, now: u32) -> Vec<(Timestamp32, IPAddress)> {
+        let count = (self.len() * GETADDR_MAX_PCT / 100).min(MAX_ADDR_COUNT as usize);
+
+        let mut addresses: Vec<_> = self.entries.values()
+            .filter(|info| !info.is_terrible(now))
+            .map(|info| (info.time, info.address))
+            .collect();
+
+        rand::thread_rng().shuffle(&mut addresses);
+        addresses.truncate(count);
+        addresses
+    }
+
+    fn get_mut(&mut self, address: &IPAddress) -> Option<&mut AddrInfo> {
+        match self.ids.get(&key(address)) {
+            Some(id) => self.entries.get_mut(id),
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, info: AddrInfo) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.ids.insert(key(&info.address), id);
+        self.entries.insert(id, info);
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(info) = self.entries.remove(&id) {
+            self.ids.remove(&key(&info.address));
+        }
+    }
+
+    // First 8 bytes of the double SHA256 of the key and `data`
+    fn hash(&self, data: &[&[u8]]) -> u64 {
+        let mut bytes = self.key.to_vec();
+        for part in data {
+            bytes.extend(part.iter());
+        }
+
+        let hash = CryptoUtils::sha256(&CryptoUtils::sha256(&bytes));
+        hash[..8].iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+    }
+
+    fn new_slot(&self, address: &IPAddress, source: &Ipv6Addr) -> usize {
+        let group = network_group(&address.address);
+        let source_group = network_group(source);
+
+        let bucket = self.hash(&[&group, &source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        let bucket = self.hash(&[&source_group, &u64_bytes(bucket)]) as usize % NEW_BUCKET_COUNT;
+        bucket * BUCKET_SIZE + self.position(b'N', bucket, address)
+    }
+
+    fn tried_slot(&self, address: &IPAddress) -> usize {
+        let group = network_group(&address.address);
+
+        let bucket = self.hash(&[&address_bytes(address)]) % TRIED_BUCKETS_PER_GROUP;
+        let bucket = self.hash(&[&group, &u64_bytes(bucket)]) as usize % TRIED_BUCKET_COUNT;
+        bucket * BUCKET_SIZE + self.position(b'T', bucket, address)
+    }
+
+    fn position(&self, table: u8, bucket: usize, address: &IPAddress) -> usize {
+        self.hash(&[&[table], &u64_bytes(bucket as u64), &address_bytes(address)]) as usize %
+            BUCKET_SIZE
+    }
+}
+
+fn key(address: &IPAddress) -> (Ipv6Addr, u16) { (address.address, address.port) }
+
+fn address_bytes(address: &IPAddress) -> Vec<u8> {
+    let mut bytes = address.address.octets().to_vec();
+    bytes.extend(&[(address.port >> 8) as u8, address.port as u8]);
+    bytes
+}
+
+fn u64_bytes(value: u64) -> Vec<u8> {
+    (0..8).map(|i| (value >> (8 * i)) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::{IPAddress, NODE_NETWORK};
+    use net::messages::Timestamp32;
+
+    use std::env;
+    use std::fs;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const NOW: u32 = 1700000000;
+
+    fn ip(a: u8, b: u8, c: u8, d: u8) -> Ipv6Addr { Ipv4Addr::new(a, b, c, d).to_ipv6_mapped() }
+
+    fn addr(a: u8, b: u8, c: u8, d: u8) -> IPAddress { IPAddress::new(NODE_NETWORK, ip(a, b, c, d), 8333) }
+
+    #[test]
+    fn test_network_group() {
+        assert_eq!(network_group(&ip(1, 2, 3, 4)), vec![4, 1, 2]);
+        assert_eq!(network_group(&ip(1, 2, 200, 1)), network_group(&ip(1, 2, 3, 4)));
+        assert!(network_group(&ip(1, 3, 3, 4)) != network_group(&ip(1, 2, 3, 4)));
+
+        let ipv6: Ipv6Addr = "2a01:4f8:1:2::1".parse().unwrap();
+        assert_eq!(network_group(&ipv6), vec![6, 0x2a, 0x01, 0x04, 0xf8]);
+
+        assert!(is_routable(&ip(1, 2, 3, 4)));
+        assert!(is_routable(&ipv6));
+        for unroutable in [ip(127, 0, 0, 1), ip(10, 1, 2, 3), ip(192, 168, 1, 1), ip(172, 20, 0, 1),
+                           Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+                           "fe80::1".parse().unwrap(), "2001:db8::1".parse().unwrap()].iter() {
+            assert!(!is_routable(unroutable), "{}", unroutable);
+        }
+    }
+
+    #[test]
+    fn test_add_and_good() {
+        let mut addrman = AddrMan::new();
+        let source = ip(5, 6, 7, 8);
+        let addresses = vec![(Timestamp32::new(NOW), addr(1, 2, 3, 4)),
+                             (Timestamp32::new(NOW), addr(2, 3, 4, 5)),
+                             (Timestamp32::new(NOW), addr(127, 0, 0, 1))];
+
+        assert_eq!(addrman.add(&addresses, &source, NOW), 2);
+        assert_eq!(addrman.add(&addresses, &source, NOW), 0);
+        assert_eq!((addrman.len(), addrman.tried_len()), (2, 0));
+
+        // Relayed addresses are older than their source says
+        let info = &addrman.entries[&addrman.ids[&key(&addr(1, 2, 3, 4))]];
+        assert_eq!(info.time.secs(), NOW - RELAY_PENALTY);
+
+        addrman.attempt(&addr(1, 2, 3, 4), NOW);
+        addrman.good(&addr(1, 2, 3, 4), NOW);
+        assert_eq!((addrman.len(), addrman.tried_len()), (2, 1));
+        assert!(addrman.new_table.iter().filter(|id| id.is_some()).count() == 1);
+        assert!(addrman.tried_table.iter().filter(|id| id.is_some()).count() == 1);
+
+        let info = &addrman.entries[&addrman.ids[&key(&addr(1, 2, 3, 4))]];
+        assert_eq!((info.attempts, info.last_success), (0, NOW));
+
+        // Now selects from both tables
+        let selected = addrman.select(NOW + DAY, false).unwrap();
+        assert!(selected == addr(1, 2, 3, 4) || selected == addr(2, 3, 4, 5));
+        assert_eq!(addrman.select(NOW + DAY, true), Some(addr(2, 3, 4, 5)));
+    }
+
+    #[test]
+    fn test_source_buckets() {
+        // One source can only fill a few buckets, whatever it sends. With a
+        // random key the other source's buckets could be full already.
+        let mut addrman = AddrMan::with_key([1; 32]);
+        let source = ip(5, 6, 7, 8);
+        let addresses: Vec<_> = (0..10000u32)
+            .map(|i| (Timestamp32::new(NOW), addr(1 + (i >> 8) as u8, i as u8, 1, 1)))
+            .collect();
+
+        for chunk in addresses.chunks(1000) {
+            addrman.add(chunk, &source, NOW);
+        }
+        assert!(addrman.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize * BUCKET_SIZE);
+
+        let buckets: Vec<_> = (0..NEW_BUCKET_COUNT)
+            .filter(|bucket| addrman.new_table[bucket * BUCKET_SIZE..(bucket + 1) * BUCKET_SIZE]
+                             .iter().any(|id| id.is_some()))
+            .collect();
+        assert!(buckets.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize);
+
+        // Another source group gets its own buckets
+        let before = addrman.len();
+        addrman.add(&addresses[..100], &ip(9, 9, 9, 9), NOW);
+        assert!(addrman.len() > before);
+    }
+
+    #[test]
+    fn test_terrible() {
+        let mut addrman = AddrMan::new();
+        let source = ip(5, 6, 7, 8);
+        addrman.add(&[(Timestamp32::new(NOW), addr(1, 2, 3, 4))], &source, NOW);
+
+        for _ in 0..RETRIES {
+            addrman.attempt(&addr(1, 2, 3, 4), NOW);
+        }
+
+        let info = &addrman.entries[&addrman.ids[&key(&addr(1, 2, 3, 4))]];
+        assert!(!info.is_terrible(NOW));
+        assert!(info.is_terrible(NOW + MINUTE));
+        assert!(info.chance(NOW + DAY) < 0.3);
+        assert_eq!(addrman.get_addr(NOW + MINUTE), vec![]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut addrman = AddrMan::new();
+        let source = ip(5, 6, 7, 8);
+        let addresses: Vec<_> = (0..50)
+            .map(|i| (Timestamp32::new(NOW), addr(1 + i, 2, 3, 4)))
+            .collect();
+
+        addrman.add(&addresses, &source, NOW);
+        addrman.good(&addr(1, 2, 3, 4), NOW);
+
+        let path = env::temp_dir().join("addrman_test_peers.dat");
+        addrman.save(&path).unwrap();
+        let loaded = AddrMan::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.key, addrman.key);
+        assert_eq!((loaded.len(), loaded.tried_len()), (addrman.len(), 1));
+        assert_eq!(loaded.new_table, addrman.new_table.iter()
+                   .map(|id| id.map(|id| loaded.ids[&key(&addrman.entries[&id].address)]))
+                   .collect::<Vec<_>>());
+        assert_eq!(loaded.get_addr(NOW).len(), addrman.len() * GETADDR_MAX_PCT / 100);
+
+        let missing = env::temp_dir().join("addrman_test_missing.dat");
+        assert_eq!(AddrMan::open(&missing).len(), 0);
+    }
+}
@@ -0,0 +1,494 @@
+// Merged mining as done by Namecoin. A block can be mined as part of a
+// parent chain block, its header is then followed by an AuxPoW proving that
+// the parent coinbase commits to it and that the parent header has enough
+// work.
+use serialize::{Serialize, Serializer, Deserialize, Deserializer, DecodeError, VarInt};
+use utils::CryptoUtils;
+
+use super::import;
+use super::messages::{BitcoinHash, BlockMetadata, SerializeHash, TxMessage, MAX_HEADERS_COUNT};
+
+use std::fmt;
+
+/// Version bit of headers that are followed by an AuxPoW.
+pub const VERSION_AUXPOW: i32 = 1 << 8;
+
+/// Marks the chain merkle root in the parent coinbase.
+pub const MERGED_MINING_HEADER: [u8; 4] = [0xfa, 0xbe, 0x6d, 0x6d];
+
+// A tree of up to 2^30 merge mined chains
+const MAX_CHAIN_MERKLE_BRANCH: usize = 30;
+
+/// Merged mining rules of a chain.
+#[derive(PartialEq, Clone, Debug)]
+pub struct AuxPowParams {
+    // Identifies the chain in the version of its headers
+    pub chain_id: i32,
+    // First height where AuxPoW blocks are accepted
+    pub start_height: usize,
+    // Refuse blocks with other chain IDs, and parents with ours
+    pub strict_chain_id: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct AuxPow {
+    // Parent coinbase with its position in the parent block
+    pub coinbase_tx: TxMessage,
+    pub parent_hash: BitcoinHash,
+    pub merkle_branch: Vec<BitcoinHash>,
+    pub index: i32,
+    // Position of this chain in the merged mining tree
+    pub chain_merkle_branch: Vec<BitcoinHash>,
+    pub chain_index: i32,
+    pub parent_block: BlockMetadata,
+}
+
+/// A header as merge mined chains send it, followed by its AuxPoW when the
+/// version has the flag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxPowHeader {
+    pub header: BlockMetadata,
+    pub auxpow: Option<AuxPow>,
+}
+
+impl Serialize for AuxPowHeader {
+    fn serialize(&self, serializer: &mut Serializer) {
+        self.header.serialize(serializer);
+        if let Some(ref auxpow) = self.auxpow {
+            auxpow.serialize(serializer);
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.header.encoded_len() + self.auxpow.as_ref().map_or(0, |auxpow| auxpow.encoded_len())
+    }
+}
+
+impl Deserialize for AuxPowHeader {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let header = try!(BlockMetadata::deserialize(deserializer)
+                          .map_err(|e| e.in_struct("AuxPowHeader", "header")));
+        let auxpow = if is_auxpow(header.version) {
+            Some(try!(AuxPow::deserialize(deserializer)
+                      .map_err(|e| e.in_struct("AuxPowHeader", "auxpow"))))
+        } else {
+            None
+        };
+
+        Ok(AuxPowHeader {
+            header: header,
+            auxpow: auxpow,
+        })
+    }
+}
+
+/// `headers` of merge mined chains.
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct AuxPowHeadersMessage {
+    #[bitcoin(max_count = "MAX_HEADERS_COUNT")]
+    pub headers: Vec<(AuxPowHeader, VarInt)>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AuxPowError {
+    WrongChainId(i32),
+    MissingAuxPow,
+    UnexpectedAuxPow,
+    AuxPowBeforeStart,
+    LateLegacyBlock,
+    InvalidProofOfWork,
+    NotACoinbase,
+    ParentHasOurChainId,
+    ChainMerkleBranchTooLong,
+    WrongParentMerkleRoot,
+    MissingChainMerkleRoot,
+    MultipleMergedMiningHeaders,
+    MisplacedChainMerkleRoot,
+    MissingMerkleSizeAndNonce,
+    WrongMerkleSize,
+    WrongChainIndex,
+}
+
+impl fmt::Display for AuxPowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AuxPowError::WrongChainId(id) => write!(f, "block has chain ID {}", id),
+            &AuxPowError::MissingAuxPow => write!(f, "no auxpow on block with auxpow version"),
+            &AuxPowError::UnexpectedAuxPow => write!(f, "auxpow on block with non-auxpow version"),
+            &AuxPowError::AuxPowBeforeStart => write!(f, "auxpow before merged mining started"),
+            &AuxPowError::LateLegacyBlock => write!(f, "legacy block after merged mining started"),
+            &AuxPowError::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            &AuxPowError::NotACoinbase => write!(f, "auxpow is not a generate"),
+            &AuxPowError::ParentHasOurChainId => write!(f, "auxpow parent has our chain ID"),
+            &AuxPowError::ChainMerkleBranchTooLong =>
+                write!(f, "auxpow chain merkle branch too long"),
+            &AuxPowError::WrongParentMerkleRoot => write!(f, "auxpow merkle root incorrect"),
+            &AuxPowError::MissingChainMerkleRoot =>
+                write!(f, "auxpow missing chain merkle root in parent coinbase"),
+            &AuxPowError::MultipleMergedMiningHeaders =>
+                write!(f, "multiple merged mining headers in coinbase"),
+            &AuxPowError::MisplacedChainMerkleRoot =>
+                write!(f, "auxpow chain merkle root is not where it should be"),
+            &AuxPowError::MissingMerkleSizeAndNonce =>
+                write!(f, "auxpow missing chain merkle tree size and nonce in parent coinbase"),
+            &AuxPowError::WrongMerkleSize =>
+                write!(f, "auxpow merkle branch size does not match parent coinbase"),
+            &AuxPowError::WrongChainIndex => write!(f, "auxpow wrong index"),
+        }
+    }
+}
+
+pub fn is_auxpow(version: i32) -> bool { version & VERSION_AUXPOW != 0 }
+
+// Blocks from before merged mining have the full version 1
+pub fn is_legacy(version: i32) -> bool { version == 1 }
+
+pub fn chain_id(version: i32) -> i32 { version >> 16 }
+
+/// Root of the merkle tree containing `hash` at `index` given its branch.
+pub fn check_merkle_branch(hash: &BitcoinHash, branch: &[BitcoinHash],
+                           index: i32) -> BitcoinHash {
+    if index == -1 {
+        return BitcoinHash::new([0; 32]);
+    }
+
+    let mut hash = *hash;
+    let mut index = index;
+    for other in branch {
+        let mut data = vec![];
+        if index & 1 == 1 {
+            data.extend_from_slice(other.inner());
+            data.extend_from_slice(hash.inner());
+        } else {
+            data.extend_from_slice(hash.inner());
+            data.extend_from_slice(other.inner());
+        }
+        hash = BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(&data)));
+        index >>= 1;
+    }
+
+    hash
+}
+
+// Slot of the chain in the merged mining tree, picked by a nonce so that a
+// chain can't be in two slots at once
+fn expected_index(nonce: u32, chain_id: i32, height: usize) -> u32 {
+    let mut rand = nonce;
+    rand = rand.wrapping_mul(1103515245).wrapping_add(12345);
+    rand = rand.wrapping_add(chain_id as u32);
+    rand = rand.wrapping_mul(1103515245).wrapping_add(12345);
+
+    rand % (1 << height)
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|window| window == needle)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    data.iter().take(4).enumerate().fold(0, |value, (i, byte)| value | (*byte as u32) << (8 * i))
+}
+
+impl AuxPow {
+    /// Checks that the parent block commits to `aux_block_hash`, the proof
+    /// of work of the parent is checked separately.
+    pub fn check(&self, aux_block_hash: &BitcoinHash, chain_id: i32,
+                 params: &AuxPowParams) -> Result<(), AuxPowError> {
+        if self.index != 0 {
+            return Err(AuxPowError::NotACoinbase);
+        }
+
+        if params.strict_chain_id && self::chain_id(self.parent_block.version) == chain_id {
+            return Err(AuxPowError::ParentHasOurChainId);
+        }
+
+        if self.chain_merkle_branch.len() > MAX_CHAIN_MERKLE_BRANCH {
+            return Err(AuxPowError::ChainMerkleBranchTooLong);
+        }
+
+        let root = check_merkle_branch(aux_block_hash, &self.chain_merkle_branch,
+                                       self.chain_index);
+        // The coinbase holds the root big endian
+        let root: Vec<u8> = root.iter().rev().cloned().collect();
+
+        if check_merkle_branch(&self.coinbase_tx.hash(), &self.merkle_branch, self.index) !=
+           self.parent_block.merkle_root {
+            return Err(AuxPowError::WrongParentMerkleRoot);
+        }
+
+        let script = match self.coinbase_tx.tx_in.first() {
+            Some(input) => &input.script[..],
+            None => return Err(AuxPowError::MissingChainMerkleRoot),
+        };
+
+        let position = try!(find(script, &root).ok_or(AuxPowError::MissingChainMerkleRoot));
+
+        match find(script, &MERGED_MINING_HEADER) {
+            Some(header) => {
+                // Only one chain merkle root, right after the only header
+                if find(&script[header + 1..], &MERGED_MINING_HEADER).is_some() {
+                    return Err(AuxPowError::MultipleMergedMiningHeaders);
+                }
+                if header + MERGED_MINING_HEADER.len() != position {
+                    return Err(AuxPowError::MisplacedChainMerkleRoot);
+                }
+            },
+            None => {
+                // Older parents have no header, the root has to come early
+                // enough that there is room for nothing else before it
+                if position > 20 {
+                    return Err(AuxPowError::MisplacedChainMerkleRoot);
+                }
+            }
+        }
+
+        let rest = &script[position + root.len()..];
+        if rest.len() < 8 {
+            return Err(AuxPowError::MissingMerkleSizeAndNonce);
+        }
+
+        let height = self.chain_merkle_branch.len();
+        if read_u32(&rest[0..4]) != 1 << height {
+            return Err(AuxPowError::WrongMerkleSize);
+        }
+
+        if self.chain_index as u32 != expected_index(read_u32(&rest[4..8]), chain_id, height) {
+            return Err(AuxPowError::WrongChainIndex);
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the proof of work of `header`, either its own or the one of the
+/// parent block in `auxpow`.
+pub fn check_proof_of_work(header: &BlockMetadata, auxpow: Option<&AuxPow>,
+                           params: &AuxPowParams) -> Result<(), AuxPowError> {
+    let chain_id = self::chain_id(header.version);
+    if !is_legacy(header.version) && params.strict_chain_id && chain_id != params.chain_id {
+        return Err(AuxPowError::WrongChainId(chain_id));
+    }
+
+    match auxpow {
+        None => {
+            if is_auxpow(header.version) {
+                return Err(AuxPowError::MissingAuxPow);
+            }
+            if !import::check_proof_of_work(&header.hash(), header.bits) {
+                return Err(AuxPowError::InvalidProofOfWork);
+            }
+        },
+        Some(auxpow) => {
+            if !is_auxpow(header.version) {
+                return Err(AuxPowError::UnexpectedAuxPow);
+            }
+            if !import::check_proof_of_work(&auxpow.parent_block.hash(), header.bits) {
+                return Err(AuxPowError::InvalidProofOfWork);
+            }
+            try!(auxpow.check(&header.hash(), chain_id, params));
+        },
+    }
+
+    Ok(())
+}
+
+/// Rules that depend on the height of the block.
+pub fn check_height(header: &BlockMetadata, height: usize,
+                    params: &AuxPowParams) -> Result<(), AuxPowError> {
+    if height < params.start_height && is_auxpow(header.version) {
+        return Err(AuxPowError::AuxPowBeforeStart);
+    }
+
+    if height >= params.start_height && is_legacy(header.version) {
+        return Err(AuxPowError::LateLegacyBlock);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::import;
+    use net::messages::{OutPoint, Timestamp32, TxIn, TxOut};
+    use serialize::VarInt;
+
+    use std::io::Cursor;
+
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn params() -> AuxPowParams {
+        AuxPowParams { chain_id: 1, start_height: 100, strict_chain_id: true }
+    }
+
+    fn header(version: i32) -> BlockMetadata {
+        BlockMetadata {
+            version: version,
+            prev_block: BitcoinHash::new([1; 32]),
+            merkle_root: BitcoinHash::new([2; 32]),
+            timestamp: Timestamp32::new(1400000000),
+            bits: EASY_BITS,
+            nonce: 0,
+        }
+    }
+
+    // Parent coinbase committing to `root` of a tree with `size` leaves
+    fn coinbase(prefix: &[u8], root: &BitcoinHash, size: u32, nonce: u32) -> TxMessage {
+        let mut script = prefix.to_vec();
+        script.extend(root.iter().rev());
+        script.extend(&[size as u8, (size >> 8) as u8, 0, 0]);
+        script.extend(&[nonce as u8, (nonce >> 8) as u8, (nonce >> 16) as u8, (nonce >> 24) as u8]);
+
+        TxMessage {
+            version: 1,
+            tx_in: vec![TxIn {
+                previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                script: script,
+                sequence: 0xffffffff,
+            }],
+            tx_out: vec![TxOut { value: 5000000000, pk_script: vec![0x51] }],
+            lock_time: 0,
+        }
+    }
+
+    fn auxpow(aux_hash: &BitcoinHash, chain_branch: Vec<BitcoinHash>, chain_index: i32,
+              prefix: &[u8], nonce: u32) -> AuxPow {
+        let root = check_merkle_branch(aux_hash, &chain_branch, chain_index);
+        let coinbase_tx = coinbase(prefix, &root, 1 << chain_branch.len(), nonce);
+
+        // The parent block has a second transaction
+        let other = BitcoinHash::new([3; 32]);
+        let mut parent_block = header(0x20000000);
+        parent_block.merkle_root = check_merkle_branch(&coinbase_tx.hash(), &[other], 0);
+        while !import::check_proof_of_work(&parent_block.hash(), EASY_BITS) {
+            parent_block.nonce += 1;
+        }
+
+        AuxPow {
+            coinbase_tx: coinbase_tx,
+            parent_hash: parent_block.hash(),
+            merkle_branch: vec![other],
+            index: 0,
+            chain_merkle_branch: chain_branch,
+            chain_index: chain_index,
+            parent_block: parent_block,
+        }
+    }
+
+    #[test]
+    fn test_expected_index() {
+        assert_eq!(expected_index(0, 1, 0), 0);
+        assert_eq!(expected_index(0, 1, 1), 1);
+        assert_eq!(expected_index(7, 1, 3), 2);
+        assert_eq!(expected_index(7, 2, 3), 7);
+    }
+
+    #[test]
+    fn test_check_auxpow() {
+        let header = header(VERSION_AUXPOW | 1 << 16);
+        let hash = header.hash();
+        let mut prefix = vec![0x03, 0x01, 0x02, 0x03];
+        prefix.extend(MERGED_MINING_HEADER.iter());
+
+        let valid = auxpow(&hash, vec![], 0, &prefix, 0);
+        assert_eq!(valid.check(&hash, 1, &params()), Ok(()));
+        assert_eq!(check_proof_of_work(&header, Some(&valid), &params()), Ok(()));
+
+        // Slot picked by the nonce in a tree of eight chains
+        let branch = vec![BitcoinHash::new([4; 32]), BitcoinHash::new([5; 32]),
+                          BitcoinHash::new([6; 32])];
+        assert_eq!(auxpow(&hash, branch.clone(), 2, &prefix, 7).check(&hash, 1, &params()),
+                   Ok(()));
+        assert_eq!(auxpow(&hash, branch, 5, &prefix, 7).check(&hash, 1, &params()),
+                   Err(AuxPowError::WrongChainIndex));
+
+        // Without the merged mining header the root must come early
+        assert_eq!(auxpow(&hash, vec![], 0, &[0x01; 20], 0).check(&hash, 1, &params()),
+                   Ok(()));
+        assert_eq!(auxpow(&hash, vec![], 0, &[0x01; 21], 0).check(&hash, 1, &params()),
+                   Err(AuxPowError::MisplacedChainMerkleRoot));
+
+        let mut twice = prefix.clone();
+        twice.extend(MERGED_MINING_HEADER.iter());
+        assert_eq!(auxpow(&hash, vec![], 0, &twice, 0).check(&hash, 1, &params()),
+                   Err(AuxPowError::MultipleMergedMiningHeaders));
+
+        assert_eq!(valid.check(&BitcoinHash::new([9; 32]), 1, &params()),
+                   Err(AuxPowError::MissingChainMerkleRoot));
+
+        let mut wrong = valid.clone();
+        wrong.index = 1;
+        assert_eq!(wrong.check(&hash, 1, &params()), Err(AuxPowError::NotACoinbase));
+
+        let mut wrong = valid.clone();
+        wrong.merkle_branch = vec![];
+        assert_eq!(wrong.check(&hash, 1, &params()), Err(AuxPowError::WrongParentMerkleRoot));
+
+        let mut wrong = valid.clone();
+        wrong.parent_block.version = 1 << 16;
+        assert_eq!(wrong.check(&hash, 1, &params()), Err(AuxPowError::ParentHasOurChainId));
+
+        let mut wrong = valid.clone();
+        wrong.coinbase_tx.tx_in[0].script.truncate(prefix.len() + 32 + 4);
+        wrong.parent_block.merkle_root =
+            check_merkle_branch(&wrong.coinbase_tx.hash(), &wrong.merkle_branch, 0);
+        assert_eq!(wrong.check(&hash, 1, &params()),
+                   Err(AuxPowError::MissingMerkleSizeAndNonce));
+    }
+
+    #[test]
+    fn test_check_proof_of_work() {
+        let mut legacy = header(1);
+        while !import::check_proof_of_work(&legacy.hash(), EASY_BITS) {
+            legacy.nonce += 1;
+        }
+        assert_eq!(check_proof_of_work(&legacy, None, &params()), Ok(()));
+        assert_eq!(check_height(&legacy, 99, &params()), Ok(()));
+        assert_eq!(check_height(&legacy, 100, &params()), Err(AuxPowError::LateLegacyBlock));
+
+        let merged = header(VERSION_AUXPOW | 1 << 16);
+        let proof = auxpow(&merged.hash(), vec![], 0, &MERGED_MINING_HEADER, 0);
+        assert_eq!(check_proof_of_work(&merged, None, &params()),
+                   Err(AuxPowError::MissingAuxPow));
+        assert_eq!(check_proof_of_work(&legacy, Some(&proof), &params()),
+                   Err(AuxPowError::UnexpectedAuxPow));
+        assert_eq!(check_height(&merged, 99, &params()), Err(AuxPowError::AuxPowBeforeStart));
+        assert_eq!(check_height(&merged, 100, &params()), Ok(()));
+
+        let other_chain = header(VERSION_AUXPOW | 2 << 16);
+        assert_eq!(check_proof_of_work(&other_chain, Some(&proof), &params()),
+                   Err(AuxPowError::WrongChainId(2)));
+
+        // The parent has to meet the target of the child
+        let mut hard = merged.clone();
+        hard.bits = 0x1d00ffff;
+        let proof = auxpow(&hard.hash(), vec![], 0, &MERGED_MINING_HEADER, 0);
+        assert_eq!(check_proof_of_work(&hard, Some(&proof), &params()),
+                   Err(AuxPowError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn test_headers_message() {
+        let legacy = header(1);
+        let merged = header(VERSION_AUXPOW | 1 << 16);
+        let proof = auxpow(&merged.hash(), vec![], 0, &MERGED_MINING_HEADER, 0);
+
+        let message = AuxPowHeadersMessage {
+            headers: vec![
+                (AuxPowHeader { header: legacy, auxpow: None }, VarInt::new(0)),
+                (AuxPowHeader { header: merged, auxpow: Some(proof) }, VarInt::new(0)),
+            ],
+        };
+
+        let mut serialized = vec![];
+        message.serialize(&mut serialized);
+        assert_eq!(serialized.len(), message.encoded_len());
+        assert_eq!(AuxPowHeadersMessage::deserialize(&mut Cursor::new(&serialized[..])),
+                   Ok(message));
+
+        // The flag says an AuxPoW follows
+        let err = AuxPowHeadersMessage::deserialize(&mut Cursor::new(&serialized[..83 + 80])).err()
+            .unwrap();
+        assert!(format!("{}", err).contains("auxpow"));
+    }
+}
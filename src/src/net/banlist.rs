@@ -0,0 +1,276 @@
+// Addresses and subnets we don't talk to, each until its ban expires. IPv4
+// subnets are kept as IPv4 mapped IPv6 ones so both families share a table.
+use serialize::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+// Seconds a ban lasts when no time is given
+pub const DEFAULT_BAN_TIME: u32 = 24 * 60 * 60;
+// Bumped when the format of the ban list file changes
+const FORMAT_VERSION: u8 = 1;
+// The prefix of IPv4 mapped addresses is 96 bits long
+const IPV4_PREFIX: u8 = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BitcoinEncode, BitcoinDecode)]
+pub struct SubNet {
+    network: Ipv6Addr,
+    prefix: u8,
+}
+
+impl SubNet {
+    /// The subnet of the first `prefix` bits of `address`.
+    pub fn new(address: Ipv6Addr, prefix: u8) -> SubNet {
+        let prefix = if prefix > 128 { 128 } else { prefix };
+        SubNet {
+            network: mask(&address, prefix),
+            prefix: prefix,
+        }
+    }
+
+    /// Just `address`.
+    pub fn single(address: Ipv6Addr) -> SubNet { SubNet::new(address, 128) }
+
+    /// Parses an address, optionally followed by `/` and the length of the
+    /// prefix, like `1.2.3.0/24` or `2001:db8::/32`.
+    pub fn parse(subnet: &str) -> Result<SubNet, String> {
+        let mut parts = subnet.splitn(2, '/');
+        let address = parts.next().unwrap();
+
+        let (address, max_prefix, offset) = match address.parse::<Ipv4Addr>() {
+            Ok(ipv4) => (ipv4.to_ipv6_mapped(), 32, IPV4_PREFIX),
+            Err(_) => match address.parse::<Ipv6Addr>() {
+                Ok(ipv6) => (ipv6, 128, 0),
+                Err(_) => return Err(format!("Invalid address `{}`", address)),
+            },
+        };
+
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("Invalid prefix length `{}`", prefix)),
+            },
+            None => max_prefix,
+        };
+
+        Ok(SubNet::new(address, prefix + offset))
+    }
+
+    pub fn contains(&self, address: &Ipv6Addr) -> bool {
+        mask(address, self.prefix) == self.network
+    }
+
+    fn ipv4(&self) -> Option<Ipv4Addr> {
+        let segments = self.network.segments();
+        if self.prefix >= IPV4_PREFIX && segments[..5].iter().all(|s| *s == 0) &&
+           segments[5] == 0xffff {
+            self.network.to_ipv4()
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for SubNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ipv4() {
+            Some(ipv4) if self.prefix == 128 => write!(f, "{}", ipv4),
+            Some(ipv4) => write!(f, "{}/{}", ipv4, self.prefix - IPV4_PREFIX),
+            None if self.prefix == 128 => write!(f, "{}", self.network),
+            None => write!(f, "{}/{}", self.network, self.prefix),
+        }
+    }
+}
+
+fn mask(address: &Ipv6Addr, prefix: u8) -> Ipv6Addr {
+    let mut octets = address.octets();
+    for (i, octet) in octets.iter_mut().enumerate() {
+        let bits = (prefix as usize).saturating_sub(i * 8);
+        if bits < 8 {
+            *octet &= !(0xffu8 >> bits);
+        }
+    }
+
+    Ipv6Addr::from(octets)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct BanEntry {
+    pub created: u32,
+    pub until: u32,
+}
+
+#[derive(Debug, PartialEq, BitcoinEncode, BitcoinDecode)]
+struct BanFile {
+    version: u8,
+    entries: Vec<(SubNet, BanEntry)>,
+}
+
+pub struct BanList {
+    entries: HashMap<SubNet, BanEntry>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Bans `subnet` from `now` until `until`, replacing an earlier ban.
+    pub fn ban(&mut self, subnet: SubNet, now: u32, until: u32) {
+        self.entries.insert(subnet, BanEntry { created: now, until: until });
+    }
+
+    /// Returns false if `subnet` wasn't banned.
+    pub fn unban(&mut self, subnet: &SubNet) -> bool {
+        self.entries.remove(subnet).is_some()
+    }
+
+    pub fn is_banned(&self, address: &Ipv6Addr, now: u32) -> bool {
+        self.entries.iter().any(|(subnet, entry)| now < entry.until && subnet.contains(address))
+    }
+
+    /// The bans sorted by when they expire.
+    pub fn entries(&self) -> Vec<(SubNet, BanEntry)> {
+        let mut entries: Vec<_> = self.entries.iter().map(|(s, e)| (*s, *e)).collect();
+        entries.sort_by_key(|&(subnet, entry)| (entry.until, subnet.prefix));
+        entries
+    }
+
+    /// Forgets the bans that expired, returns whether there were any.
+    pub fn sweep(&mut self, now: u32) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|_, entry| now < entry.until);
+        self.entries.len() != len
+    }
+
+    /// Loads the ban list at `path`, an empty one if it can't be read.
+    pub fn open(path: &Path) -> BanList {
+        if !path.exists() {
+            return BanList::new();
+        }
+
+        BanList::load(path).unwrap_or_else(|e| {
+            println!("Ignoring {}: {}", path.display(), e);
+            BanList::new()
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<BanList, String> {
+        let mut data = vec![];
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+             .map_err(|e| e.to_string()));
+
+        let file = try!(BanFile::deserialize(&mut Cursor::new(&data[..])));
+        if file.version != FORMAT_VERSION {
+            return Err(format!("unknown version {}", file.version));
+        }
+
+        Ok(BanList {
+            entries: file.entries.into_iter().collect(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let file = BanFile {
+            version: FORMAT_VERSION,
+            entries: self.entries(),
+        };
+
+        let mut data = vec![];
+        file.serialize(&mut data);
+
+        let tmp = path.with_extension("tmp");
+        try!(File::create(&tmp).and_then(|mut file| file.write_all(&data).and(file.sync_all()))
+             .and_then(|_| fs::rename(&tmp, path))
+             .map_err(|e| e.to_string()));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const NOW: u32 = 1500000000;
+
+    fn ip(address: &str) -> Ipv6Addr {
+        match address.parse::<Ipv4Addr>() {
+            Ok(ipv4) => ipv4.to_ipv6_mapped(),
+            Err(_) => address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet = SubNet::parse("1.2.3.4/24").unwrap();
+        assert_eq!(subnet.to_string(), "1.2.3.0/24");
+        assert!(subnet.contains(&ip("1.2.3.200")));
+        assert!(!subnet.contains(&ip("1.2.4.1")));
+
+        let subnet = SubNet::parse("10.0.0.0/9").unwrap();
+        assert!(subnet.contains(&ip("10.127.255.255")));
+        assert!(!subnet.contains(&ip("10.128.0.0")));
+
+        assert_eq!(SubNet::parse("1.2.3.4").unwrap(), SubNet::single(ip("1.2.3.4")));
+        assert_eq!(SubNet::parse("1.2.3.4").unwrap().to_string(), "1.2.3.4");
+        assert_eq!(SubNet::parse("2001:db8:1::/32").unwrap().to_string(), "2001:db8::/32");
+        assert!(SubNet::parse("2001:db8::/32").unwrap().contains(&ip("2001:db8:ffff::1")));
+        assert!(SubNet::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(!SubNet::parse("0.0.0.0/0").unwrap().contains(&ip("2001:db8::1")));
+
+        assert!(SubNet::parse("1.2.3.4/33").is_err());
+        assert!(SubNet::parse("2001:db8::/129").is_err());
+        assert!(SubNet::parse("1.2.3/8").is_err());
+    }
+
+    #[test]
+    fn test_ban_and_expire() {
+        let mut banlist = BanList::new();
+        banlist.ban(SubNet::parse("1.2.3.0/24").unwrap(), NOW, NOW + 100);
+        banlist.ban(SubNet::parse("5.6.7.8").unwrap(), NOW, NOW + DEFAULT_BAN_TIME);
+
+        assert!(banlist.is_banned(&ip("1.2.3.4"), NOW));
+        assert!(banlist.is_banned(&ip("5.6.7.8"), NOW));
+        assert!(!banlist.is_banned(&ip("5.6.7.9"), NOW));
+
+        // Expired bans don't count, even before they're swept
+        assert!(!banlist.is_banned(&ip("1.2.3.4"), NOW + 100));
+        assert!(!banlist.sweep(NOW + 99));
+        assert!(banlist.sweep(NOW + 100));
+        assert_eq!(banlist.entries().len(), 1);
+
+        assert!(banlist.unban(&SubNet::parse("5.6.7.8").unwrap()));
+        assert!(!banlist.unban(&SubNet::parse("5.6.7.8").unwrap()));
+        assert!(!banlist.is_banned(&ip("5.6.7.8"), NOW));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut banlist = BanList::new();
+        banlist.ban(SubNet::parse("1.2.3.0/24").unwrap(), NOW, NOW + 200);
+        banlist.ban(SubNet::parse("2001:db8::/32").unwrap(), NOW, NOW + 100);
+
+        let path = env::temp_dir().join("banlist_test.dat");
+        banlist.save(&path).unwrap();
+        let loaded = BanList::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.entries(), banlist.entries());
+        assert_eq!(loaded.entries()[0].0.to_string(), "2001:db8::/32");
+        assert_eq!(loaded.entries()[1].1, BanEntry { created: NOW, until: NOW + 200 });
+
+        let missing = env::temp_dir().join("banlist_test_missing.dat");
+        assert_eq!(BanList::open(&missing).entries().len(), 0);
+    }
+}
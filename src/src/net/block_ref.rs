@@ -0,0 +1,579 @@
+use std::io::Cursor;
+
+use serialize::{Deserialize, DecodeError, DecodeErrorKind, MAX_SIZE};
+use utils::CryptoUtils;
+
+use super::auxpow::{self, AuxPow};
+use super::messages::{merkle_root_mutated, BitcoinHash, BlockMessage, BlockMetadata, OutPoint,
+                      TxMessage};
+
+const HEADER_SIZE: usize = 80;
+
+// Reads from a borrowed slice, every error offset is relative to the
+// outermost buffer so that it matches what the owned deserializer reports.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader {
+            data: data,
+            position: position,
+        }
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind, self.position as u64)
+    }
+
+    fn remaining(&self) -> usize { self.data.len() - self.position }
+
+    fn take(&mut self, bytes: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < bytes {
+            return Err(self.error(DecodeErrorKind::UnexpectedEof));
+        }
+
+        let result = &self.data[self.position..self.position + bytes];
+        self.position += bytes;
+
+        Ok(result)
+    }
+
+    fn read_u(&mut self, bytes: usize) -> Result<u64, DecodeError> {
+        let data = try!(self.take(bytes));
+
+        let mut result = 0;
+        for i in 0..bytes {
+            result |= (data[i] as u64) << (8 * i);
+        }
+
+        Ok(result)
+    }
+
+    fn read_var_int(&mut self) -> Result<u64, DecodeError> {
+        let start = *self;
+        let first = try!(self.read_u(1));
+
+        let (value, min) = match first {
+            0xfd => (try!(self.read_u(2)), 0xfd),
+            0xfe => (try!(self.read_u(4)), 0x10000),
+            0xff => (try!(self.read_u(8)), 0x100000000),
+            _    => return Ok(first),
+        };
+
+        if value < min {
+            return Err(start.error(DecodeErrorKind::NonCanonicalVarInt));
+        }
+
+        Ok(value)
+    }
+
+    fn read_length(&mut self) -> Result<u64, DecodeError> {
+        let start = *self;
+        let length = try!(self.read_var_int());
+
+        if length > MAX_SIZE {
+            return Err(start.error(DecodeErrorKind::OversizedLength(length)));
+        }
+
+        Ok(length)
+    }
+
+    fn read_script(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = try!(self.read_length());
+
+        if length > self.remaining() as u64 {
+            return Err(self.error(DecodeErrorKind::UnexpectedEof));
+        }
+
+        self.take(length as usize)
+    }
+
+    fn skip_hashes(&mut self) -> Result<(), DecodeError> {
+        let count = try!(self.read_length());
+
+        if count > (self.remaining() / 32) as u64 {
+            return Err(self.error(DecodeErrorKind::UnexpectedEof));
+        }
+
+        try!(self.take(count as usize * 32));
+        Ok(())
+    }
+
+    fn read_out_point(&mut self) -> Result<OutPoint, DecodeError> {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(try!(self.take(32)));
+        let index = try!(self.read_u(4)) as u32;
+
+        Ok(OutPoint::new(BitcoinHash::new(hash), index))
+    }
+}
+
+/// A serialized block that is parsed lazily, nothing is copied out of the
+/// original buffer until it's asked for.
+pub struct BlockRef<'a> {
+    data: &'a [u8],
+    // Where the AuxPoW after the header ends, if there is one
+    auxpow_end: usize,
+    tx_count: u64,
+    txns_start: usize,
+}
+
+impl<'a> BlockRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<BlockRef<'a>, DecodeError> {
+        Self::parse_from(data, false)
+    }
+
+    /// Like `parse` for merge mined chains, the header is followed by an
+    /// AuxPoW when its version says so.
+    pub fn parse_auxpow(data: &'a [u8]) -> Result<BlockRef<'a>, DecodeError> {
+        Self::parse_from(data, true)
+    }
+
+    fn parse_from(data: &'a [u8], merge_mined: bool) -> Result<BlockRef<'a>, DecodeError> {
+        let mut reader = Reader::new(data, 0);
+
+        try!(reader.take(HEADER_SIZE)
+             .map_err(|e| e.in_struct("BlockMessage", "metadata")));
+
+        let version = Reader::new(data, 0).read_u(4).unwrap() as u32 as i32;
+        if merge_mined && auxpow::is_auxpow(version) {
+            try!(Self::skip_auxpow(&mut reader)
+                 .map_err(|e| e.in_struct("BlockMessage", "auxpow")));
+        }
+        let auxpow_end = reader.position;
+
+        let tx_count = try!(reader.read_length()
+             .map_err(|e| e.in_struct("BlockMessage", "txns")));
+
+        Ok(BlockRef {
+            data: data,
+            auxpow_end: auxpow_end,
+            tx_count: tx_count,
+            txns_start: reader.position,
+        })
+    }
+
+    fn skip_auxpow(reader: &mut Reader<'a>) -> Result<(), DecodeError> {
+        try!(TxRef::parse_from(reader).map_err(|e| e.in_struct("AuxPow", "coinbase_tx")));
+        try!(reader.take(32).map_err(|e| e.in_struct("AuxPow", "parent_hash")));
+        try!(reader.skip_hashes().map_err(|e| e.in_struct("AuxPow", "merkle_branch")));
+        try!(reader.take(4).map_err(|e| e.in_struct("AuxPow", "index")));
+        try!(reader.skip_hashes().map_err(|e| e.in_struct("AuxPow", "chain_merkle_branch")));
+        try!(reader.take(4).map_err(|e| e.in_struct("AuxPow", "chain_index")));
+        try!(reader.take(HEADER_SIZE).map_err(|e| e.in_struct("AuxPow", "parent_block")));
+        Ok(())
+    }
+
+    pub fn data(&self) -> &'a [u8] { self.data }
+
+    pub fn auxpow(&self) -> Option<AuxPow> {
+        if self.auxpow_end == HEADER_SIZE {
+            return None;
+        }
+
+        // Its layout was checked in parse_auxpow()
+        let data = &self.data[HEADER_SIZE..self.auxpow_end];
+        Some(AuxPow::deserialize(&mut Cursor::new(data)).unwrap())
+    }
+
+    pub fn header_bytes(&self) -> &'a [u8] { &self.data[0..HEADER_SIZE] }
+
+    pub fn hash(&self) -> BitcoinHash {
+        BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(self.header_bytes())))
+    }
+
+    pub fn metadata(&self) -> BlockMetadata {
+        // The header is fixed size and its length was checked in parse()
+        BlockMetadata::deserialize(&mut Cursor::new(self.header_bytes())).unwrap()
+    }
+
+    pub fn prev_block(&self) -> BitcoinHash {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&self.data[4..36]);
+        BitcoinHash::new(hash)
+    }
+
+    pub fn tx_count(&self) -> u64 { self.tx_count }
+
+    pub fn transactions(&self) -> Transactions<'a> {
+        Transactions {
+            reader: Reader::new(self.data, self.txns_start),
+            remaining: self.tx_count,
+            index: 0,
+        }
+    }
+
+    /// Walks every transaction without allocating and checks that they
+    /// cover the whole buffer.
+    pub fn check_length(&self) -> Result<(), DecodeError> {
+        let mut transactions = self.transactions();
+
+        while let Some(tx) = transactions.next() {
+            try!(tx);
+        }
+
+        if transactions.reader.remaining() > 0 {
+            return Err(transactions.reader.error(
+                    DecodeErrorKind::OversizedLength(self.data.len() as u64)));
+        }
+
+        Ok(())
+    }
+
+    /// Merkle root of the transactions and whether their tree is mutated,
+    /// see `merkle_root_mutated`.
+    pub fn compute_merkle_root(&self) -> Result<(BitcoinHash, bool), DecodeError> {
+        let mut txids = vec![];
+        for tx in self.transactions() {
+            txids.push(try!(tx).txid());
+        }
+
+        Ok(merkle_root_mutated(&txids))
+    }
+
+    /// The block without its AuxPoW, if it has one.
+    pub fn to_owned(&self) -> Result<BlockMessage, DecodeError> {
+        if self.auxpow_end == HEADER_SIZE {
+            return BlockMessage::deserialize(&mut Cursor::new(self.data));
+        }
+
+        let mut txns = Cursor::new(&self.data[self.auxpow_end..]);
+        Ok(BlockMessage {
+            metadata: self.metadata(),
+            txns: try!(Deserialize::deserialize(&mut txns)
+                       .map_err(|e: DecodeError| e.in_struct("BlockMessage", "txns"))),
+        })
+    }
+}
+
+pub struct Transactions<'a> {
+    reader: Reader<'a>,
+    remaining: u64,
+    index: usize,
+}
+
+impl<'a> Iterator for Transactions<'a> {
+    type Item = Result<TxRef<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Result<TxRef<'a>, DecodeError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        match TxRef::parse_from(&mut self.reader) {
+            Ok(tx) => {
+                self.remaining -= 1;
+                self.index += 1;
+                Some(Ok(tx))
+            },
+            Err(e) => {
+                // Nothing after a broken transaction can be trusted
+                self.remaining = 0;
+                Some(Err(e.at_index(self.index).in_struct("BlockMessage", "txns")))
+            }
+        }
+    }
+}
+
+/// A serialized transaction, its txid is computed from the original bytes.
+pub struct TxRef<'a> {
+    data: &'a [u8],
+    start: usize,
+    end: usize,
+    input_count: u64,
+    inputs_start: usize,
+    output_count: u64,
+    outputs_start: usize,
+}
+
+impl<'a> TxRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<TxRef<'a>, DecodeError> {
+        Self::parse_from(&mut Reader::new(data, 0))
+    }
+
+    fn parse_from(reader: &mut Reader<'a>) -> Result<TxRef<'a>, DecodeError> {
+        let start = reader.position;
+
+        try!(reader.take(4).map_err(|e| e.in_struct("TxMessage", "version")));
+
+        let input_count = try!(reader.read_length()
+                               .map_err(|e| e.in_struct("TxMessage", "tx_in")));
+        let inputs_start = reader.position;
+        for i in 0..input_count {
+            try!(Self::skip_input(reader)
+                 .map_err(|e| e.at_index(i as usize).in_struct("TxMessage", "tx_in")));
+        }
+
+        let output_count = try!(reader.read_length()
+                                .map_err(|e| e.in_struct("TxMessage", "tx_out")));
+        let outputs_start = reader.position;
+        for i in 0..output_count {
+            try!(Self::skip_output(reader)
+                 .map_err(|e| e.at_index(i as usize).in_struct("TxMessage", "tx_out")));
+        }
+
+        try!(reader.take(4).map_err(|e| e.in_struct("TxMessage", "lock_time")));
+
+        Ok(TxRef {
+            data: reader.data,
+            start: start,
+            end: reader.position,
+            input_count: input_count,
+            inputs_start: inputs_start,
+            output_count: output_count,
+            outputs_start: outputs_start,
+        })
+    }
+
+    fn skip_input(reader: &mut Reader<'a>) -> Result<(), DecodeError> {
+        try!(reader.take(36).map_err(|e| e.in_struct("TxIn", "previous_output")));
+        try!(reader.read_script().map_err(|e| e.in_struct("TxIn", "script")));
+        try!(reader.take(4).map_err(|e| e.in_struct("TxIn", "sequence")));
+        Ok(())
+    }
+
+    fn skip_output(reader: &mut Reader<'a>) -> Result<(), DecodeError> {
+        try!(reader.take(8).map_err(|e| e.in_struct("TxOut", "value")));
+        try!(reader.read_script().map_err(|e| e.in_struct("TxOut", "pk_script")));
+        Ok(())
+    }
+
+    pub fn data(&self) -> &'a [u8] { &self.data[self.start..self.end] }
+
+    pub fn txid(&self) -> BitcoinHash {
+        BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(self.data())))
+    }
+
+    pub fn version(&self) -> u32 {
+        Reader::new(self.data, self.start).read_u(4).unwrap() as u32
+    }
+
+    pub fn lock_time(&self) -> u32 {
+        Reader::new(self.data, self.end - 4).read_u(4).unwrap() as u32
+    }
+
+    pub fn input_count(&self) -> u64 { self.input_count }
+
+    pub fn output_count(&self) -> u64 { self.output_count }
+
+    pub fn inputs(&self) -> Inputs<'a> {
+        Inputs {
+            reader: Reader::new(self.data, self.inputs_start),
+            remaining: self.input_count,
+        }
+    }
+
+    pub fn outputs(&self) -> Outputs<'a> {
+        Outputs {
+            reader: Reader::new(self.data, self.outputs_start),
+            remaining: self.output_count,
+        }
+    }
+
+    pub fn to_owned(&self) -> Result<TxMessage, DecodeError> {
+        TxMessage::deserialize(&mut Cursor::new(self.data()))
+    }
+}
+
+pub struct TxInRef<'a> {
+    pub previous_output: OutPoint,
+    pub script: &'a [u8],
+    pub sequence: u32,
+}
+
+pub struct TxOutRef<'a> {
+    pub value: i64,
+    pub pk_script: &'a [u8],
+}
+
+// The layout of inputs and outputs was already validated when the TxRef was
+// created so the iterators below can't fail.
+pub struct Inputs<'a> {
+    reader: Reader<'a>,
+    remaining: u64,
+}
+
+impl<'a> Iterator for Inputs<'a> {
+    type Item = TxInRef<'a>;
+
+    fn next(&mut self) -> Option<TxInRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        Some(TxInRef {
+            previous_output: self.reader.read_out_point().unwrap(),
+            script: self.reader.read_script().unwrap(),
+            sequence: self.reader.read_u(4).unwrap() as u32,
+        })
+    }
+}
+
+pub struct Outputs<'a> {
+    reader: Reader<'a>,
+    remaining: u64,
+}
+
+impl<'a> Iterator for Outputs<'a> {
+    type Item = TxOutRef<'a>;
+
+    fn next(&mut self) -> Option<TxOutRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+
+        Some(TxOutRef {
+            value: self.reader.read_u(8).unwrap() as i64,
+            pk_script: self.reader.read_script().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+
+    use super::*;
+    use net::auxpow::{self, AuxPow};
+    use net::messages::{BlockMessage, SerializeHash};
+    use serialize::{Deserialize, Serialize, DecodeErrorKind};
+
+    fn read_block() -> (Vec<u8>, BlockMessage) {
+        let mut data = vec![];
+        File::open("src/test/block.dat").unwrap().read_to_end(&mut data).unwrap();
+
+        let block = BlockMessage::deserialize(&mut Cursor::new(&data[..])).unwrap();
+
+        let mut serialized = vec![];
+        block.serialize(&mut serialized);
+
+        (serialized, block)
+    }
+
+    #[test]
+    fn test_block_ref_matches_owned() {
+        let (data, block) = read_block();
+        let block_ref = BlockRef::parse(&data).unwrap();
+
+        block_ref.check_length().unwrap();
+
+        assert_eq!(block_ref.hash(), block.hash());
+        assert_eq!(block_ref.metadata(), block.metadata);
+        assert_eq!(block_ref.prev_block(), *block.prev_block());
+        assert_eq!(block_ref.tx_count() as usize, block.txns.len());
+
+        for (tx_ref, tx) in block_ref.transactions().zip(block.txns.iter()) {
+            let tx_ref = tx_ref.unwrap();
+
+            assert_eq!(tx_ref.txid(), tx.hash());
+            assert_eq!(tx_ref.version(), tx.version);
+            assert_eq!(tx_ref.lock_time(), tx.lock_time);
+            assert_eq!(&tx_ref.to_owned().unwrap(), tx);
+
+            for (input, tx_in) in tx_ref.inputs().zip(tx.tx_in.iter()) {
+                assert_eq!(input.previous_output, tx_in.previous_output);
+                assert_eq!(input.script, &tx_in.script[..]);
+                assert_eq!(input.sequence, tx_in.sequence);
+            }
+
+            for (output, tx_out) in tx_ref.outputs().zip(tx.tx_out.iter()) {
+                assert_eq!(output.value, tx_out.value);
+                assert_eq!(output.pk_script, &tx_out.pk_script[..]);
+            }
+        }
+
+        assert_eq!(block_ref.to_owned().unwrap(), block);
+    }
+
+    #[test]
+    fn test_block_ref_trailing_data() {
+        let (mut data, _) = read_block();
+        data.push(0);
+
+        let block_ref = BlockRef::parse(&data).unwrap();
+        let error = block_ref.check_length().unwrap_err();
+
+        assert_eq!(error.offset() as usize, data.len() - 1);
+    }
+
+    #[test]
+    fn test_block_ref_truncated() {
+        let (data, _) = read_block();
+        let truncated = &data[..data.len() - 10];
+
+        let block_ref = BlockRef::parse(truncated).unwrap();
+        let error = block_ref.check_length().unwrap_err();
+
+        assert_eq!(error.kind(), &DecodeErrorKind::UnexpectedEof);
+        assert!(error.path().starts_with("BlockMessage.txns["));
+    }
+
+    #[test]
+    fn test_block_ref_merkle_root() {
+        let (mut data, mut block) = read_block();
+        let root = BlockRef::parse(&data).unwrap().compute_merkle_root().unwrap();
+        assert_eq!(root, (block.metadata.merkle_root, false));
+
+        // The last byte is in the lock time of the last transaction
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let (root, _) = BlockRef::parse(&data).unwrap().compute_merkle_root().unwrap();
+        assert!(root != block.metadata.merkle_root);
+
+        // Repeating the last transaction of an odd count keeps the root
+        block.txns.truncate(3);
+        block.metadata.merkle_root = block.compute_merkle_root();
+        let last = block.txns[2].clone();
+        block.txns.push(last);
+
+        let mut mutated = vec![];
+        block.serialize(&mut mutated);
+        let root = BlockRef::parse(&mutated).unwrap().compute_merkle_root().unwrap();
+        assert_eq!(root, (block.metadata.merkle_root, true));
+    }
+
+    #[test]
+    fn test_block_ref_auxpow() {
+        let (data, mut block) = read_block();
+        let coinbase_tx = block.txns[0].clone();
+        let auxpow = AuxPow {
+            coinbase_tx: coinbase_tx,
+            parent_hash: BitcoinHash::new([1; 32]),
+            merkle_branch: vec![BitcoinHash::new([2; 32])],
+            index: 0,
+            chain_merkle_branch: vec![],
+            chain_index: 0,
+            parent_block: block.metadata.clone(),
+        };
+
+        // A merge mined version has no meaning without auxpow support
+        assert!(BlockRef::parse_auxpow(&data).unwrap().auxpow().is_none());
+
+        block.metadata.version |= auxpow::VERSION_AUXPOW;
+        let mut merged = vec![];
+        block.metadata.serialize(&mut merged);
+        auxpow.serialize(&mut merged);
+        block.txns.serialize(&mut merged);
+
+        let block_ref = BlockRef::parse_auxpow(&merged).unwrap();
+        block_ref.check_length().unwrap();
+
+        assert_eq!(block_ref.auxpow(), Some(auxpow));
+        assert_eq!(block_ref.hash(), block.hash());
+        assert_eq!(block_ref.tx_count() as usize, block.txns.len());
+        assert_eq!(block_ref.to_owned().unwrap(), block);
+
+        let error = BlockRef::parse_auxpow(&merged[..100]).err().unwrap();
+        assert_eq!(error.kind(), &DecodeErrorKind::UnexpectedEof);
+        assert!(error.path().starts_with("BlockMessage.auxpow.coinbase_tx"));
+    }
+}
@@ -0,0 +1,461 @@
+use super::messages::{BlockMessage, BlockMetadata, BitcoinHash, NetworkType, OutPoint,
+                      SerializeHash, Timestamp32, TxIn, TxMessage, TxOut};
+
+use super::auxpow::AuxPowParams;
+use super::signet;
+use script::push_data;
+
+use rustc_serialize::hex::FromHex;
+
+const MAINNET_GENESIS_MESSAGE: &'static [u8] =
+    b"The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+const MAINNET_GENESIS_KEY: &'static str =
+    "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f355\
+     04e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+const TESTNET4_GENESIS_MESSAGE: &'static [u8] =
+    b"03/May/2024 000000000000000000001ebd58c244970b3aa9d783bb001011fbe8ea8e98e00e";
+const TESTNET4_GENESIS_KEY: &'static str =
+    "000000000000000000000000000000000000000000000000000000000000000000";
+
+const NAMECOIN_GENESIS_MESSAGE: &'static [u8] =
+    b"... choose what comes next.  Lives of your own, or a return to chains. -- V";
+const NAMECOIN_GENESIS_KEY: &'static str =
+    "04b620369050cd899ffbbc4e8ee51e8c4534a855bb463439d63d235d4779685d8b6f4870a238cf365ac94f\
+     a13ef9a2a22cd99d0d5ee86dcabcafce36c7acf43ce5";
+
+// The bits of the first Bitcoin block and the number 4 precede the message
+const BITCOIN_GENESIS_PREFIX: &'static [u8] = &[0x04, 0xff, 0xff, 0x00, 0x1d, 0x01, 0x04];
+// Namecoin pushes its own bits and 522
+const NAMECOIN_GENESIS_PREFIX: &'static [u8] = &[0x04, 0xff, 0x7f, 0x00, 0x1c, 0x02, 0x0a, 0x02];
+
+const SIGNET_CHALLENGE: &'static str =
+    "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef50219\
+     64fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+/// Heights at which soft forks are enforced.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Deployments {
+    pub bip34: i64,
+    pub bip65: i64,
+    pub bip66: i64,
+    pub csv: i64,
+    pub segwit: i64,
+}
+
+/// Everything that differs between the chains we can follow.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ChainParams {
+    pub network_type: NetworkType,
+    // Name used by Bitcoin Core's -chain option
+    pub name: &'static str,
+    pub default_port: u16,
+    pub dns_seeds: Vec<&'static str>,
+    pub genesis_hash: BitcoinHash,
+    genesis_block: BlockMessage,
+    // Blocks between halvings of the coinbase reward
+    pub subsidy_halving_interval: u32,
+    // Compact form of the easiest allowed target
+    pub pow_limit_bits: u32,
+    pub pow_target_timespan: i64,
+    pub pow_target_spacing: i64,
+    // Testnets accept a minimum difficulty block after 20 minutes
+    pub allow_min_difficulty_blocks: bool,
+    pub no_retargeting: bool,
+    // BIP94, retarget from the first block of the period and forbid time warps
+    pub enforce_bip94: bool,
+    pub deployments: Deployments,
+    // Script that signs signet blocks
+    pub signet_challenge: Option<Vec<u8>>,
+    // Merged mining, blocks may be mined as part of another chain
+    pub auxpow: Option<AuxPowParams>,
+}
+
+// Hashes are written big endian but stored little endian
+fn hash(hex: &str) -> BitcoinHash {
+    let mut data = [0; 32];
+    for (i, byte) in hex.from_hex().unwrap().into_iter().rev().enumerate() {
+        data[i] = byte;
+    }
+    BitcoinHash::new(data)
+}
+
+fn genesis_block(prefix: &[u8], message: &[u8], pub_key: &str, time: u32, nonce: u32,
+                 bits: u32) -> BlockMessage {
+    let mut script = prefix.to_vec();
+    push_data(&mut script, message);
+
+    let mut pk_script = vec![];
+    push_data(&mut pk_script, &pub_key.from_hex().unwrap());
+    // OP_CHECKSIG
+    pk_script.push(0xac);
+
+    let tx = TxMessage {
+        version: 1,
+        tx_in: vec![TxIn {
+            previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+            script: script,
+            sequence: 0xffffffff,
+        }],
+        tx_out: vec![TxOut {
+            value: 5000000000,
+            pk_script: pk_script,
+        }],
+        lock_time: 0,
+    };
+
+    BlockMessage {
+        metadata: BlockMetadata {
+            version: 1,
+            prev_block: BitcoinHash::new([0; 32]),
+            // A single transaction is its own merkle root
+            merkle_root: tx.hash(),
+            timestamp: Timestamp32::new(time),
+            bits: bits,
+            nonce: nonce,
+        },
+        txns: vec![tx],
+    }
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::Main,
+            name: "main",
+            default_port: 8333,
+            dns_seeds: vec![
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            genesis_hash:
+                hash("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            genesis_block: genesis_block(BITCOIN_GENESIS_PREFIX, MAINNET_GENESIS_MESSAGE,
+                                         MAINNET_GENESIS_KEY, 1231006505, 2083236893, 0x1d00ffff),
+            subsidy_halving_interval: 210000,
+            pow_limit_bits: 0x1d00ffff,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            pow_target_spacing: 10 * 60,
+            allow_min_difficulty_blocks: false,
+            no_retargeting: false,
+            enforce_bip94: false,
+            deployments: Deployments {
+                bip34: 227931,
+                bip65: 388381,
+                bip66: 363725,
+                csv: 419328,
+                segwit: 481824,
+            },
+            signet_challenge: None,
+            auxpow: None,
+        }
+    }
+
+    pub fn testnet3() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::TestNet3,
+            name: "test",
+            default_port: 18333,
+            dns_seeds: vec![
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            genesis_hash:
+                hash("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            genesis_block: genesis_block(BITCOIN_GENESIS_PREFIX, MAINNET_GENESIS_MESSAGE,
+                                         MAINNET_GENESIS_KEY, 1296688602, 414098458, 0x1d00ffff),
+            allow_min_difficulty_blocks: true,
+            deployments: Deployments {
+                bip34: 21111,
+                bip65: 581885,
+                bip66: 330776,
+                csv: 770112,
+                segwit: 834624,
+            },
+            .. ChainParams::main()
+        }
+    }
+
+    pub fn testnet4() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::TestNet4,
+            name: "testnet4",
+            default_port: 48333,
+            dns_seeds: vec![
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            genesis_hash:
+                hash("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+            genesis_block: genesis_block(BITCOIN_GENESIS_PREFIX, TESTNET4_GENESIS_MESSAGE,
+                                         TESTNET4_GENESIS_KEY, 1714777860, 393743547, 0x1d00ffff),
+            allow_min_difficulty_blocks: true,
+            enforce_bip94: true,
+            deployments: Deployments {
+                bip34: 1,
+                bip65: 1,
+                bip66: 1,
+                csv: 1,
+                segwit: 1,
+            },
+            .. ChainParams::main()
+        }
+    }
+
+    pub fn signet() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::Signet,
+            name: "signet",
+            default_port: 38333,
+            dns_seeds: vec![
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achownodes.xyz",
+            ],
+            genesis_hash:
+                hash("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            genesis_block: genesis_block(BITCOIN_GENESIS_PREFIX, MAINNET_GENESIS_MESSAGE,
+                                         MAINNET_GENESIS_KEY, 1598918400, 52613770, 0x1e0377ae),
+            pow_limit_bits: 0x1e0377ae,
+            deployments: Deployments {
+                bip34: 1,
+                bip65: 1,
+                bip66: 1,
+                csv: 1,
+                segwit: 1,
+            },
+            signet_challenge: Some(SIGNET_CHALLENGE.from_hex().unwrap()),
+            .. ChainParams::main()
+        }
+    }
+
+    /// BIP325 signet with its own challenge. All signets share the genesis
+    /// block, they're told apart by the magic derived from the challenge.
+    pub fn custom_signet(challenge: Vec<u8>) -> ChainParams {
+        let signet = ChainParams::signet();
+        if Some(&challenge) == signet.signet_challenge.as_ref() {
+            return signet;
+        }
+
+        ChainParams {
+            network_type: NetworkType::CustomSignet(signet::network_magic(&challenge)),
+            dns_seeds: vec![],
+            signet_challenge: Some(challenge),
+            .. signet
+        }
+    }
+
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::Regtest,
+            name: "regtest",
+            default_port: 18444,
+            dns_seeds: vec![],
+            genesis_hash:
+                hash("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+            genesis_block: genesis_block(BITCOIN_GENESIS_PREFIX, MAINNET_GENESIS_MESSAGE,
+                                         MAINNET_GENESIS_KEY, 1296688602, 2, 0x207fffff),
+            subsidy_halving_interval: 150,
+            pow_limit_bits: 0x207fffff,
+            allow_min_difficulty_blocks: true,
+            no_retargeting: true,
+            deployments: Deployments {
+                bip34: 1,
+                bip65: 1,
+                bip66: 1,
+                csv: 1,
+                segwit: 0,
+            },
+            .. ChainParams::main()
+        }
+    }
+
+    pub fn namecoin() -> ChainParams {
+        ChainParams {
+            network_type: NetworkType::NameCoin,
+            name: "namecoin",
+            default_port: 8334,
+            dns_seeds: vec![
+                "nmc.seed.quisquis.de",
+                "seed.nmc.markasoftware.com",
+                "dnsseed1.nmc.dotbit.zone",
+                "dnsseed2.nmc.dotbit.zone",
+                "dnsseed.nmc.testls.space",
+            ],
+            genesis_hash:
+                hash("000000000062b72c5e2ceb45fbc8587e807c155b0da735e6483dfba2f0a9c770"),
+            genesis_block: genesis_block(NAMECOIN_GENESIS_PREFIX, NAMECOIN_GENESIS_MESSAGE,
+                                         NAMECOIN_GENESIS_KEY, 1303000001, 0xa21ea192,
+                                         0x1c007fff),
+            deployments: Deployments {
+                bip34: 250000,
+                bip65: 335000,
+                bip66: 250000,
+                csv: 482000,
+                segwit: 482000,
+            },
+            auxpow: Some(AuxPowParams {
+                chain_id: 0x0001,
+                start_height: 19200,
+                strict_chain_id: true,
+            }),
+            .. ChainParams::main()
+        }
+    }
+
+    /// Parameters by Bitcoin Core chain name, as given to -chain.
+    pub fn from_name(name: &str) -> Result<ChainParams, String> {
+        match name {
+            "main" => Ok(ChainParams::main()),
+            "test" => Ok(ChainParams::testnet3()),
+            "testnet4" => Ok(ChainParams::testnet4()),
+            "signet" => Ok(ChainParams::signet()),
+            "regtest" => Ok(ChainParams::regtest()),
+            "namecoin" => Ok(ChainParams::namecoin()),
+            _ => Err(format!("Unknown chain `{}`", name)),
+        }
+    }
+
+    pub fn for_network(network_type: NetworkType) -> Option<ChainParams> {
+        match network_type {
+            NetworkType::Main => Some(ChainParams::main()),
+            NetworkType::TestNet3 => Some(ChainParams::testnet3()),
+            NetworkType::TestNet4 => Some(ChainParams::testnet4()),
+            NetworkType::Signet => Some(ChainParams::signet()),
+            NetworkType::Regtest => Some(ChainParams::regtest()),
+            NetworkType::NameCoin => Some(ChainParams::namecoin()),
+            // The challenge can't be recovered from the magic
+            NetworkType::CustomSignet(_) |
+            NetworkType::TestNet |
+            NetworkType::Unknown => None,
+        }
+    }
+
+    pub fn genesis_block(&self) -> BlockMessage {
+        self.genesis_block.clone()
+    }
+
+    pub fn pub_key_hash_prefix(&self) -> u8 {
+        self.network_type.pub_key_hash_prefix().unwrap()
+    }
+
+    pub fn script_hash_prefix(&self) -> u8 {
+        self.network_type.script_hash_prefix().unwrap()
+    }
+
+    pub fn bech32_hrp(&self) -> &'static str {
+        self.network_type.bech32_hrp().unwrap()
+    }
+
+    // Blocks between difficulty adjustments
+    pub fn difficulty_adjustment_interval(&self) -> i64 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+
+    // Coinbase reward in satoshis at `height`, halved every interval
+    pub fn block_subsidy(&self, height: u32) -> i64 {
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+
+        5000000000 >> halvings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::{NetworkType, SerializeHash};
+    use rustc_serialize::hex::FromHex;
+
+    #[test]
+    fn test_genesis_blocks() {
+        let merkle_root = hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+
+        for name in ["main", "test", "testnet4", "signet", "regtest"].iter() {
+            let params = ChainParams::from_name(name).unwrap();
+            let genesis = params.genesis_block();
+
+            assert_eq!(genesis.hash(), params.genesis_hash);
+            assert_eq!(genesis.metadata.bits, params.pow_limit_bits);
+            assert_eq!(params.difficulty_adjustment_interval(), 2016);
+            assert_eq!(ChainParams::for_network(params.network_type), Some(params.clone()));
+
+            if params.network_type == NetworkType::TestNet4 {
+                assert_eq!(genesis.metadata.merkle_root,
+                    hash("7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e"));
+            } else {
+                assert_eq!(genesis.metadata.merkle_root, merkle_root);
+            }
+        }
+
+        assert!(ChainParams::from_name("testnet").is_err());
+    }
+
+    #[test]
+    fn test_namecoin() {
+        let params = ChainParams::from_name("namecoin").unwrap();
+        let genesis = params.genesis_block();
+
+        assert_eq!(genesis.hash(), params.genesis_hash);
+        assert_eq!(genesis.metadata.merkle_root,
+            hash("41c62dbd9068c89a449525e3cd5ac61b20ece28c3c38b3f35b2161f0e6d3cb0d"));
+        assert_eq!(ChainParams::for_network(NetworkType::NameCoin), Some(params.clone()));
+        assert_eq!(params.pub_key_hash_prefix(), 0x34);
+        assert_eq!(params.auxpow.unwrap().start_height, 19200);
+        assert_eq!(ChainParams::main().auxpow, None);
+    }
+
+    #[test]
+    fn test_address_prefixes() {
+        assert_eq!(ChainParams::main().bech32_hrp(), "bc");
+        assert_eq!(ChainParams::testnet4().bech32_hrp(), "tb");
+        assert_eq!(ChainParams::signet().pub_key_hash_prefix(), 0x6f);
+        assert_eq!(ChainParams::regtest().script_hash_prefix(), 0xc4);
+        assert_eq!(ChainParams::regtest().bech32_hrp(), "bcrt");
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let main = ChainParams::main();
+        assert_eq!(main.block_subsidy(0), 5000000000);
+        assert_eq!(main.block_subsidy(209999), 5000000000);
+        assert_eq!(main.block_subsidy(210000), 2500000000);
+        assert_eq!(main.block_subsidy(840000), 312500000);
+        assert_eq!(main.block_subsidy(64 * 210000), 0);
+
+        let regtest = ChainParams::regtest();
+        assert_eq!(regtest.block_subsidy(149), 5000000000);
+        assert_eq!(regtest.block_subsidy(150), 2500000000);
+    }
+
+    #[test]
+    fn test_custom_signet() {
+        let signet = ChainParams::signet();
+        let default_challenge = signet.signet_challenge.clone().unwrap();
+        assert_eq!(ChainParams::custom_signet(default_challenge), signet);
+
+        // 1-of-1 multisig with the generator point as key
+        let challenge = "51210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179851ae"
+            .from_hex().unwrap();
+        let custom = ChainParams::custom_signet(challenge.clone());
+
+        assert_eq!(custom.network_type, NetworkType::CustomSignet(signet::network_magic(&challenge)));
+        assert!(custom.network_type != NetworkType::Signet);
+        assert_eq!(custom.genesis_hash, signet.genesis_hash);
+        assert_eq!(custom.signet_challenge, Some(challenge));
+        assert!(custom.dns_seeds.is_empty());
+        assert_eq!(ChainParams::for_network(custom.network_type), None);
+    }
+}
@@ -0,0 +1,245 @@
+// Decides which connections we open and accept. Outbound connections fill
+// slots: full relay peers, block relay only peers, which never learn about
+// our transactions or addresses and so are harder to map, and short lived
+// feelers that check addresses before they go to the tried table. No two
+// outbound peers share a network group, so one operator can't be all of them.
+use mio;
+
+use super::addrman::network_group;
+
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+
+pub const MAX_CONNECTIONS: usize = 125;
+pub const MAX_FULL_RELAY: usize = 8;
+pub const MAX_BLOCK_RELAY: usize = 2;
+// Seconds between feelers
+const FEELER_INTERVAL: u32 = 2 * 60;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ConnectionType {
+    Inbound,
+    // Peers we were told to connect to, they don't take a slot
+    Manual,
+    FullRelay,
+    BlockRelay,
+    Feeler,
+}
+
+impl ConnectionType {
+    pub fn is_outbound(&self) -> bool { *self != ConnectionType::Inbound }
+
+    // Whether we exchange transactions and addresses
+    pub fn is_relay(&self) -> bool {
+        *self != ConnectionType::BlockRelay && *self != ConnectionType::Feeler
+    }
+}
+
+pub fn to_ipv6(addr: &SocketAddr) -> Ipv6Addr {
+    match *addr {
+        SocketAddr::V4(ipv4) => ipv4.ip().to_ipv6_mapped(),
+        SocketAddr::V6(ipv6) => *ipv6.ip(),
+    }
+}
+
+struct Connection {
+    type_: ConnectionType,
+    group: Vec<u8>,
+}
+
+pub struct ConnectionManager {
+    max_inbound: usize,
+    connections: HashMap<mio::Token, Connection>,
+    // Outbound connections we asked for that aren't open yet
+    pending: HashMap<SocketAddr, ConnectionType>,
+    next_feeler: u32,
+    // One more full relay peer while our tip looks stale
+    extra_full_relay: bool,
+}
+
+impl ConnectionManager {
+    pub fn new(max_inbound: usize) -> ConnectionManager {
+        ConnectionManager {
+            max_inbound: max_inbound,
+            connections: HashMap::new(),
+            pending: HashMap::new(),
+            next_feeler: 0,
+            extra_full_relay: false,
+        }
+    }
+
+    pub fn count(&self, type_: ConnectionType) -> usize {
+        self.connections.values().filter(|connection| connection.type_ == type_).count() +
+            self.pending.values().filter(|pending| **pending == type_).count()
+    }
+
+    pub fn set_extra_full_relay(&mut self, extra: bool) {
+        self.extra_full_relay = extra;
+    }
+
+    pub fn connection_type(&self, token: mio::Token) -> Option<ConnectionType> {
+        self.connections.get(&token).map(|connection| connection.type_)
+    }
+
+    pub fn is_inbound_full(&self) -> bool {
+        self.count(ConnectionType::Inbound) >= self.max_inbound
+    }
+
+    /// Whether we take an inbound connection from `addr`.
+    pub fn accept_inbound(&mut self, token: mio::Token, addr: &SocketAddr) -> bool {
+        if self.is_inbound_full() {
+            return false;
+        }
+
+        self.connections.insert(token, Connection {
+            type_: ConnectionType::Inbound,
+            group: network_group(&to_ipv6(addr)),
+        });
+        true
+    }
+
+    /// Which outbound slot to fill next, if any. Feelers only go out when
+    /// the other slots are full.
+    pub fn next_outbound(&self, now: u32) -> Option<ConnectionType> {
+        let max_full_relay = MAX_FULL_RELAY + if self.extra_full_relay { 1 } else { 0 };
+
+        if self.count(ConnectionType::FullRelay) < max_full_relay {
+            Some(ConnectionType::FullRelay)
+        } else if self.count(ConnectionType::BlockRelay) < MAX_BLOCK_RELAY {
+            Some(ConnectionType::BlockRelay)
+        } else if now >= self.next_feeler && self.count(ConnectionType::Feeler) == 0 {
+            Some(ConnectionType::Feeler)
+        } else {
+            None
+        }
+    }
+
+    /// Whether an outbound peer at `address` keeps the network groups apart.
+    pub fn is_group_free(&self, address: &Ipv6Addr) -> bool {
+        let group = network_group(address);
+
+        !self.connections.values().any(|connection| {
+            connection.type_ != ConnectionType::Inbound &&
+                connection.type_ != ConnectionType::Manual && connection.group == group
+        }) && !self.pending.iter().any(|(addr, type_)| {
+            *type_ != ConnectionType::Manual && network_group(&to_ipv6(addr)) == group
+        })
+    }
+
+    /// We're about to connect to `addr` for `type_`.
+    pub fn connecting(&mut self, addr: SocketAddr, type_: ConnectionType, now: u32) {
+        if type_ == ConnectionType::Feeler {
+            self.next_feeler = now + FEELER_INTERVAL;
+        }
+        self.pending.insert(addr, type_);
+    }
+
+    /// The connection to `addr` is open, returns what it's for. Connections
+    /// we didn't ask for are manual ones.
+    pub fn connected(&mut self, token: mio::Token, addr: &SocketAddr) -> ConnectionType {
+        let type_ = self.pending.remove(addr).unwrap_or(ConnectionType::Manual);
+        self.connections.insert(token, Connection {
+            type_: type_,
+            group: network_group(&to_ipv6(addr)),
+        });
+        type_
+    }
+
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        self.pending.remove(addr);
+    }
+
+    pub fn closed(&mut self, token: mio::Token) {
+        self.connections.remove(&token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio;
+
+    use std::net::SocketAddr;
+
+    fn addr(address: &str) -> SocketAddr { address.parse().unwrap() }
+
+    #[test]
+    fn test_outbound_slots() {
+        let mut connman = ConnectionManager::new(4);
+        let now = 1000;
+
+        for i in 0..MAX_FULL_RELAY {
+            assert_eq!(connman.next_outbound(now), Some(ConnectionType::FullRelay));
+            connman.connecting(addr(&format!("1.{}.0.1:8333", i)), ConnectionType::FullRelay, now);
+        }
+
+        // Pending connections take the slot too
+        assert_eq!(connman.next_outbound(now), Some(ConnectionType::BlockRelay));
+        connman.connecting(addr("2.0.0.1:8333"), ConnectionType::BlockRelay, now);
+        connman.connecting(addr("2.1.0.1:8333"), ConnectionType::BlockRelay, now);
+
+        assert_eq!(connman.next_outbound(now), Some(ConnectionType::Feeler));
+        connman.connecting(addr("3.0.0.1:8333"), ConnectionType::Feeler, now);
+        assert_eq!(connman.next_outbound(now), None);
+
+        assert_eq!(connman.connected(mio::Token(1), &addr("3.0.0.1:8333")), ConnectionType::Feeler);
+        connman.closed(mio::Token(1));
+        assert_eq!(connman.next_outbound(now + 1), None);
+        assert_eq!(connman.next_outbound(now + FEELER_INTERVAL), Some(ConnectionType::Feeler));
+
+        // A failed connection frees its slot
+        connman.failed(&addr("1.0.0.1:8333"));
+        assert_eq!(connman.next_outbound(now), Some(ConnectionType::FullRelay));
+
+        assert_eq!(connman.connected(mio::Token(2), &addr("1.1.0.1:8333")),
+                   ConnectionType::FullRelay);
+        assert_eq!(connman.connected(mio::Token(3), &addr("9.9.9.9:8333")),
+                   ConnectionType::Manual);
+        assert_eq!(connman.connection_type(mio::Token(3)), Some(ConnectionType::Manual));
+        assert_eq!(connman.count(ConnectionType::FullRelay), MAX_FULL_RELAY - 1);
+
+        // A stale tip makes room for one more
+        connman.connecting(addr("1.0.0.1:8333"), ConnectionType::FullRelay, now);
+        assert_eq!(connman.next_outbound(now), None);
+        connman.set_extra_full_relay(true);
+        assert_eq!(connman.next_outbound(now), Some(ConnectionType::FullRelay));
+        connman.connecting(addr("1.9.0.1:8333"), ConnectionType::FullRelay, now);
+        assert_eq!(connman.next_outbound(now), None);
+    }
+
+    #[test]
+    fn test_network_groups() {
+        let mut connman = ConnectionManager::new(4);
+        let ip = |address: &str| to_ipv6(&addr(address));
+
+        connman.connecting(addr("1.2.3.4:8333"), ConnectionType::FullRelay, 0);
+        assert!(!connman.is_group_free(&ip("1.2.200.1:8333")));
+        assert!(connman.is_group_free(&ip("1.3.3.4:8333")));
+
+        connman.connected(mio::Token(1), &addr("1.2.3.4:8333"));
+        assert!(!connman.is_group_free(&ip("1.2.200.1:8333")));
+        connman.closed(mio::Token(1));
+        assert!(connman.is_group_free(&ip("1.2.200.1:8333")));
+
+        // Inbound and manual peers don't count
+        connman.accept_inbound(mio::Token(2), &addr("5.6.7.8:1234"));
+        connman.connected(mio::Token(3), &addr("6.7.8.9:8333"));
+        assert!(connman.is_group_free(&ip("5.6.1.1:8333")));
+        assert!(connman.is_group_free(&ip("6.7.1.1:8333")));
+    }
+
+    #[test]
+    fn test_max_inbound() {
+        let mut connman = ConnectionManager::new(2);
+
+        assert!(connman.accept_inbound(mio::Token(1), &addr("1.2.3.4:1000")));
+        assert!(connman.accept_inbound(mio::Token(2), &addr("1.2.3.4:1001")));
+        assert!(connman.is_inbound_full());
+        assert!(!connman.accept_inbound(mio::Token(3), &addr("1.2.3.4:1002")));
+        assert_eq!(connman.connection_type(mio::Token(3)), None);
+
+        connman.closed(mio::Token(1));
+        assert!(connman.accept_inbound(mio::Token(3), &addr("1.2.3.4:1002")));
+        assert_eq!(connman.connection_type(mio::Token(3)), Some(ConnectionType::Inbound));
+    }
+}
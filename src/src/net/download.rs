@@ -0,0 +1,346 @@
+// Spreads the download of block bodies over the connected peers. Blocks come
+// from a window after the last connected block, every peer gets a few of them
+// at a time and the blocks we need first go to the fastest peers.
+use mio;
+use time::{Duration, SteadyTime};
+
+use super::messages::BitcoinHash;
+
+use std::collections::{HashMap, HashSet};
+
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+// How far past the last connected block we download
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 1024;
+// Blocks that didn't arrive after this many seconds are asked from others
+const BLOCK_STALL_TIMEOUT: i64 = 30;
+// Weight in percent of a new response time in the moving average
+const RESPONSE_TIME_WEIGHT: i32 = 20;
+
+struct PeerDownloads {
+    in_flight: usize,
+    // None until the first block arrives
+    response_time: Option<Duration>,
+}
+
+pub struct BlockDownloader {
+    peers: HashMap<mio::Token, PeerDownloads>,
+    // Who we asked for each block and when
+    in_flight: HashMap<BitcoinHash, (mio::Token, SteadyTime)>,
+    // Blocks peers stalled on or sent broken, they aren't asked for them again
+    failed: HashSet<(mio::Token, BitcoinHash)>,
+}
+
+impl BlockDownloader {
+    pub fn new() -> BlockDownloader {
+        BlockDownloader {
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+            failed: HashSet::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, token: mio::Token) {
+        self.peers.entry(token).or_insert(PeerDownloads {
+            in_flight: 0,
+            response_time: None,
+        });
+    }
+
+    /// Forgets a peer, the blocks it didn't send are returned so they can be
+    /// asked from others.
+    pub fn remove_peer(&mut self, token: mio::Token) -> Vec<BitcoinHash> {
+        self.peers.remove(&token);
+        self.failed.retain(|&(peer, _)| peer != token);
+
+        let released: Vec<_> = self.in_flight.iter()
+            .filter(|&(_, &(peer, _))| peer == token)
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        for hash in released.iter() {
+            self.in_flight.remove(hash);
+        }
+
+        released
+    }
+
+    pub fn is_in_flight(&self, hash: &BitcoinHash) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    // Blocks we're waiting for from the peer
+    pub fn peer_in_flight(&self, token: mio::Token) -> usize {
+        self.peers.get(&token).map_or(0, |peer| peer.in_flight)
+    }
+
+    pub fn is_idle(&self) -> bool { self.in_flight.is_empty() }
+
+    /// A block arrived from `token`, returns whether we're waiting for it
+    /// from that peer. Blocks it stalled on and sent late don't count.
+    pub fn received(&mut self, token: mio::Token, hash: &BitcoinHash, now: SteadyTime) -> bool {
+        self.failed.retain(|&(_, failed)| failed != *hash);
+
+        let requested = match self.in_flight.get(hash) {
+            Some(&(peer, requested)) if peer == token => requested,
+            _ => return false,
+        };
+        self.in_flight.remove(hash);
+
+        if let Some(peer) = self.peers.get_mut(&token) {
+            peer.in_flight -= 1;
+
+            let sample = now - requested;
+            peer.response_time = Some(match peer.response_time {
+                Some(average) => (average * (100 - RESPONSE_TIME_WEIGHT) +
+                                  sample * RESPONSE_TIME_WEIGHT) / 100,
+                None => sample,
+            });
+        }
+
+        true
+    }
+
+    /// The peer doesn't have a block we asked it for, it can go to others.
+    pub fn not_found(&mut self, token: mio::Token, hash: &BitcoinHash) {
+        match self.in_flight.get(hash) {
+            Some(&(peer, _)) if peer == token => {},
+            _ => return,
+        }
+
+        self.in_flight.remove(hash);
+        if let Some(peer) = self.peers.get_mut(&token) {
+            peer.in_flight -= 1;
+        }
+    }
+
+    /// The peer sent a block that doesn't match its header, it's asked from
+    /// another one.
+    pub fn invalid(&mut self, token: mio::Token, hash: &BitcoinHash) {
+        self.not_found(token, hash);
+        self.failed.insert((token, *hash));
+    }
+
+    /// Takes back the blocks of peers that didn't send them in time, returns
+    /// those peers. They won't be asked for these blocks again.
+    pub fn release_stalled(&mut self, now: SteadyTime) -> Vec<mio::Token> {
+        let timeout = Duration::seconds(BLOCK_STALL_TIMEOUT);
+        let stalled: Vec<_> = self.in_flight.iter()
+            .filter(|&(_, &(_, requested))| now - requested > timeout)
+            .map(|(hash, &(token, _))| (*hash, token))
+            .collect();
+
+        let mut peers = vec![];
+        for (hash, token) in stalled {
+            self.in_flight.remove(&hash);
+            if let Some(peer) = self.peers.get_mut(&token) {
+                peer.in_flight -= 1;
+            }
+            self.failed.insert((token, hash));
+
+            if !peers.contains(&token) {
+                peers.push(token);
+            }
+        }
+
+        peers.sort();
+        peers
+    }
+
+    /// Assigns `missing` blocks, given with their height in the order we
+    /// need them, to the peers that have room and `can_provide` them and
+    /// didn't stall on them. The fastest peers are served first, peers we
+    /// haven't measured last.
+    pub fn schedule<F>(&mut self, missing: &[(usize, BitcoinHash)], can_provide: F,
+                       now: SteadyTime) -> Vec<(mio::Token, Vec<BitcoinHash>)>
+        where F: Fn(mio::Token, usize) -> bool {
+        let mut peers: Vec<_> = self.peers.iter()
+            .filter(|&(_, peer)| peer.in_flight < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+            .map(|(token, peer)| (peer.response_time.is_none(), peer.response_time, *token))
+            .collect();
+        peers.sort();
+
+        let mut missing: Vec<_> = missing.iter()
+            .filter(|&&(_, hash)| !self.in_flight.contains_key(&hash))
+            .cloned()
+            .collect();
+
+        let mut requests = vec![];
+        for (_, _, token) in peers {
+            let room = MAX_BLOCKS_IN_FLIGHT_PER_PEER - self.peers[&token].in_flight;
+            let failed = &self.failed;
+
+            let mut blocks = vec![];
+            missing.retain(|&(height, hash)| {
+                if blocks.len() < room && can_provide(token, height) &&
+                   !failed.contains(&(token, hash)) {
+                    blocks.push(hash);
+                    false
+                } else {
+                    true
+                }
+            });
+
+            if blocks.len() > 0 {
+                for hash in blocks.iter() {
+                    self.in_flight.insert(*hash, (token, now));
+                }
+                self.peers.get_mut(&token).unwrap().in_flight += blocks.len();
+                requests.push((token, blocks));
+            }
+        }
+
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio;
+    use net::messages::BitcoinHash;
+    use time::{Duration, SteadyTime};
+
+    fn blocks(count: usize) -> Vec<(usize, BitcoinHash)> {
+        (0..count).map(|i| (i + 1, BitcoinHash::new([i as u8; 32]))).collect()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut downloader = BlockDownloader::new();
+        let (slow, fast, new) = (mio::Token(1), mio::Token(2), mio::Token(3));
+        let missing = blocks(40);
+        let now = SteadyTime::now();
+
+        for token in [slow, fast, new].iter() {
+            downloader.add_peer(*token);
+        }
+
+        // Without measurements the lowest token goes first
+        let requests = downloader.schedule(&missing[..2], |_, _| true, now);
+        assert_eq!(requests, vec![(slow, vec![missing[0].1, missing[1].1])]);
+        downloader.received(slow, &missing[0].1, now + Duration::seconds(5));
+        downloader.received(slow, &missing[1].1, now + Duration::seconds(5));
+
+        downloader.schedule(&missing[2..3], |token, _| token == fast, now);
+        assert!(downloader.received(fast, &missing[2].1, now + Duration::seconds(1)));
+
+        assert_eq!(downloader.peers[&fast].response_time, Some(Duration::seconds(1)));
+        assert_eq!(downloader.peers[&slow].response_time, Some(Duration::seconds(5)));
+        assert_eq!(downloader.peers[&new].response_time, None);
+
+        let requests = downloader.schedule(&missing[3..], |_, _| true, now);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], (fast, missing[3..19].iter().map(|b| b.1).collect()));
+        assert_eq!(requests[1], (slow, missing[19..35].iter().map(|b| b.1).collect()));
+        assert_eq!(requests[2], (new, missing[35..].iter().map(|b| b.1).collect()));
+
+        // Only the new peer has room left
+        assert_eq!(downloader.peers[&fast].in_flight, MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        let requests = downloader.schedule(&blocks(50), |_, _| true, now);
+        assert_eq!(requests.len(), 1);
+        assert_eq!((requests[0].0, requests[0].1.len()), (new, 11));
+        assert_eq!(downloader.schedule(&blocks(60), |_, _| true, now), vec![]);
+    }
+
+    #[test]
+    fn test_can_provide() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add_peer(mio::Token(1));
+        let missing = blocks(10);
+
+        let requests = downloader.schedule(&missing, |_, height| height <= 3, SteadyTime::now());
+        assert_eq!(requests, vec![(mio::Token(1), missing[..3].iter().map(|b| b.1).collect())]);
+        assert!(!downloader.is_in_flight(&missing[3].1));
+    }
+
+    #[test]
+    fn test_reassign() {
+        let mut downloader = BlockDownloader::new();
+        let (stalling, leaving, other) = (mio::Token(1), mio::Token(2), mio::Token(3));
+        let missing = blocks(4);
+        let now = SteadyTime::now();
+
+        downloader.add_peer(stalling);
+        downloader.add_peer(leaving);
+        downloader.schedule(&missing[..2], |token, _| token == stalling, now);
+        downloader.schedule(&missing[2..], |token, _| token == leaving, now);
+        downloader.add_peer(other);
+
+        assert_eq!(downloader.release_stalled(now + Duration::seconds(10)), vec![]);
+        assert_eq!(downloader.release_stalled(now + Duration::seconds(31)),
+                   vec![stalling, leaving]);
+        assert_eq!(downloader.peers[&stalling].in_flight, 0);
+
+        // They aren't asked again for the blocks they stalled on
+        assert_eq!(downloader.schedule(&missing[..2], |token, _| token == stalling, now), vec![]);
+        assert_eq!(downloader.schedule(&missing[2..], |token, _| token == leaving, now), vec![]);
+
+        let more = &blocks(6)[4..];
+        downloader.schedule(more, |token, _| token == leaving, now);
+        let released = downloader.remove_peer(leaving);
+        assert_eq!(released.len(), 2);
+        assert!(released.contains(&more[0].1) && released.contains(&more[1].1));
+
+        let requests = downloader.schedule(&missing, |token, _| token == other, now);
+        assert_eq!(requests, vec![(other, missing.iter().map(|b| b.1).collect())]);
+
+        // Only the peer we asked can say it doesn't have the block
+        downloader.not_found(leaving, &missing[1].1);
+        assert!(downloader.is_in_flight(&missing[1].1));
+        downloader.not_found(other, &missing[1].1);
+        assert!(!downloader.is_in_flight(&missing[1].1));
+        assert_eq!(downloader.peer_in_flight(other), 3);
+        assert!(!downloader.is_idle());
+
+        // Late blocks are still fine
+        assert!(!downloader.received(other, &BitcoinHash::new([9; 32]), now));
+        assert!(downloader.received(other, &missing[0].1, now));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut downloader = BlockDownloader::new();
+        let (sender, other) = (mio::Token(1), mio::Token(2));
+        let missing = blocks(1);
+        let now = SteadyTime::now();
+
+        downloader.add_peer(sender);
+        downloader.add_peer(other);
+        downloader.schedule(&missing, |_, _| true, now);
+        downloader.invalid(sender, &missing[0].1);
+
+        assert_eq!(downloader.peer_in_flight(sender), 0);
+        assert_eq!(downloader.schedule(&missing, |_, _| true, now),
+                   vec![(other, vec![missing[0].1])]);
+    }
+
+    #[test]
+    fn test_late_reply() {
+        let mut downloader = BlockDownloader::new();
+        let (stalling, other) = (mio::Token(1), mio::Token(2));
+        let missing = blocks(2);
+        let hashes: Vec<_> = missing.iter().map(|b| b.1).collect();
+        let now = SteadyTime::now();
+        let later = now + Duration::seconds(31);
+
+        downloader.add_peer(stalling);
+        downloader.add_peer(other);
+        assert_eq!(downloader.schedule(&missing, |_, _| true, now),
+                   vec![(stalling, hashes.clone())]);
+        assert_eq!(downloader.release_stalled(later), vec![stalling]);
+
+        // The stalling peer still comes first but the blocks go to the other
+        assert_eq!(downloader.schedule(&missing, |_, _| true, later),
+                   vec![(other, hashes.clone())]);
+
+        // Its late reply doesn't take the block from the peer we asked now
+        assert!(!downloader.received(stalling, &hashes[0], later));
+        assert!(downloader.is_in_flight(&hashes[0]));
+        assert_eq!(downloader.peer_in_flight(stalling), 0);
+        assert_eq!(downloader.peer_in_flight(other), 2);
+        assert_eq!(downloader.peers[&stalling].response_time, None);
+
+        assert!(downloader.received(other, &hashes[0], later + Duration::seconds(1)));
+        assert_eq!(downloader.peer_in_flight(other), 1);
+    }
+}
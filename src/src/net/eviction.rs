@@ -0,0 +1,142 @@
+// Picks the inbound peer to drop when a new one doesn't fit, the way Bitcoin
+// Core does. Peers that are hard for an attacker to imitate are protected:
+// those in groups the attacker can't predict, the fastest ones, those that
+// sent us new transactions or blocks lately and those connected the longest.
+// The rest are evicted from the network group with the most connections.
+use mio;
+use time::SteadyTime;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+const PROTECTED_BY_GROUP: usize = 4;
+const PROTECTED_BY_PING: usize = 8;
+const PROTECTED_BY_TX: usize = 4;
+const PROTECTED_BY_BLOCK: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub token: mio::Token,
+    pub connected_time: SteadyTime,
+    // In milliseconds, None until the first pong
+    pub min_ping: Option<i64>,
+    pub last_block_time: Option<SteadyTime>,
+    pub last_tx_time: Option<SteadyTime>,
+    // The network group hashed with a key of ours, so others can't tell
+    // which groups are protected
+    pub keyed_group: u64,
+}
+
+// Drops the `count` candidates with the highest keys
+fn protect<K, F>(candidates: &mut Vec<EvictionCandidate>, count: usize, key: F)
+    where K: Ord, F: Fn(&EvictionCandidate) -> K {
+    candidates.sort_by_key(|candidate| key(candidate));
+    let unprotected = candidates.len().saturating_sub(count);
+    candidates.truncate(unprotected);
+}
+
+/// The peer to evict, None if all of them are protected.
+pub fn select_to_evict(mut candidates: Vec<EvictionCandidate>) -> Option<mio::Token> {
+    protect(&mut candidates, PROTECTED_BY_GROUP, |c| c.keyed_group);
+    protect(&mut candidates, PROTECTED_BY_PING,
+            |c| Reverse(c.min_ping.unwrap_or(i64::max_value())));
+    // On a tie the older connection stays
+    protect(&mut candidates, PROTECTED_BY_TX, |c| (c.last_tx_time, Reverse(c.connected_time)));
+    protect(&mut candidates, PROTECTED_BY_BLOCK,
+            |c| (c.last_block_time, Reverse(c.connected_time)));
+    let half = candidates.len() / 2;
+    protect(&mut candidates, half, |c| Reverse(c.connected_time));
+
+    let mut groups: HashMap<u64, Vec<&EvictionCandidate>> = HashMap::new();
+    for candidate in candidates.iter() {
+        groups.entry(candidate.keyed_group).or_insert(vec![]).push(candidate);
+    }
+
+    // The biggest group, on a tie the one with the youngest member, loses
+    // its youngest member
+    groups.values()
+        .max_by_key(|members| {
+            (members.len(), members.iter().map(|c| c.connected_time).max())
+        })
+        .and_then(|members| members.iter().max_by_key(|c| c.connected_time))
+        .map(|candidate| candidate.token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio;
+    use time::{Duration, SteadyTime};
+
+    // Connected `age` seconds ago, in group 0 and without stats
+    fn candidate(token: usize, now: SteadyTime, age: i64) -> EvictionCandidate {
+        EvictionCandidate {
+            token: mio::Token(token),
+            connected_time: now - Duration::seconds(age),
+            min_ping: None,
+            last_block_time: None,
+            last_tx_time: None,
+            keyed_group: 0,
+        }
+    }
+
+    #[test]
+    fn test_protected_classes() {
+        let now = SteadyTime::now();
+        let mut candidates: Vec<_> = (1..41)
+            .map(|i| candidate(i, now, 1000 - i as i64))
+            .collect();
+
+        // Tokens 1-4 are in groups with high keys, 5-12 are fast, 13-16
+        // sent transactions and 17-20 blocks
+        for i in 0..4 {
+            candidates[i].keyed_group = 100 + i as u64;
+        }
+        for i in 4..12 {
+            candidates[i].min_ping = Some(10);
+        }
+        for i in 12..16 {
+            candidates[i].last_tx_time = Some(now);
+        }
+        for i in 16..20 {
+            candidates[i].last_block_time = Some(now);
+        }
+        // Slow peers aren't protected
+        for i in 20..40 {
+            candidates[i].min_ping = Some(1000);
+        }
+
+        // 20 are left, the 10 oldest of them are protected too. The youngest
+        // of the rest goes.
+        assert_eq!(select_to_evict(candidates.clone()), Some(mio::Token(40)));
+
+        // Otherwise the youngest of the biggest group
+        for i in 30..33 {
+            candidates[i].keyed_group = 1;
+        }
+        for i in 33..35 {
+            candidates[i].keyed_group = 2;
+        }
+        for i in 35..40 {
+            candidates[i].keyed_group = 3;
+        }
+        assert_eq!(select_to_evict(candidates.clone()), Some(mio::Token(40)));
+
+        // Even when others are younger
+        candidates[39].keyed_group = 2;
+        assert_eq!(select_to_evict(candidates.clone()), Some(mio::Token(39)));
+
+        // On a tie the group with the youngest member loses
+        candidates[39].keyed_group = 1;
+        assert_eq!(select_to_evict(candidates), Some(mio::Token(40)));
+    }
+
+    #[test]
+    fn test_all_protected() {
+        let now = SteadyTime::now();
+        let candidates: Vec<_> = (1..10).map(|i| candidate(i, now, i as i64)).collect();
+        assert_eq!(select_to_evict(candidates), None);
+
+        assert_eq!(select_to_evict(vec![]), None);
+    }
+}
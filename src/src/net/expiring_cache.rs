@@ -82,6 +82,4 @@ impl<V: Eq + Hash + Clone> ExpiringCache<V> {
         self.check_expiration();
         self.store.remove(key);
     }
-
-    pub fn len(&self) -> usize { self.store.len() }
 }
@@ -0,0 +1,221 @@
+// Local block generation for regtest, so that chains can be built offline.
+use script::push_data;
+use serialize::Serialize;
+
+use super::block_ref::BlockRef;
+use super::chain_params::ChainParams;
+use super::import::check_proof_of_work;
+use super::messages::{BitcoinHash, BlockMessage, BlockMetadata, NetworkType, OutPoint,
+                      SerializeHash, Timestamp32, TxIn, TxMessage, TxOut, merkle_root};
+use super::store::BlockStore;
+
+use std::cmp;
+use std::fs::File;
+
+// Top bits set as in BIP9, without signalling any deployment
+const BLOCK_VERSION: i32 = 0x20000000;
+
+const OP_0: u8 = 0x00;
+const OP_1: u8 = 0x51;
+
+/// Grinds the nonce until the proof of work is valid, false when none is.
+pub fn solve_proof_of_work(block: &mut BlockMessage) -> bool {
+    for nonce in 0..u32::max_value() {
+        block.metadata.nonce = nonce;
+        if check_proof_of_work(&block.metadata.hash(), block.metadata.bits) {
+            return true;
+        }
+    }
+
+    false
+}
+
+// BIP34 height followed by OP_0 like Bitcoin Core, scriptSigs of coinbases
+// must be at least two bytes long
+fn coinbase_script(height: u32) -> Vec<u8> {
+    let mut script = vec![];
+    match height {
+        0 => script.push(OP_0),
+        1...16 => script.push(OP_1 + height as u8 - 1),
+        _ => {
+            let mut number = vec![];
+            let mut value = height;
+            while value > 0 {
+                number.push(value as u8);
+                value >>= 8;
+            }
+            // Script numbers are signed, keep the top bit clear
+            if number[number.len() - 1] & 0x80 != 0 {
+                number.push(0);
+            }
+            push_data(&mut script, &number);
+        }
+    }
+    script.push(OP_0);
+    script
+}
+
+/// Coinbase paying the whole subsidy at `height` to `script`.
+pub fn coinbase(params: &ChainParams, height: u32, script: &[u8]) -> TxMessage {
+    TxMessage {
+        version: 1,
+        tx_in: vec![TxIn {
+            previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+            script: coinbase_script(height),
+            sequence: 0xffffffff,
+        }],
+        tx_out: vec![TxOut {
+            value: params.block_subsidy(height),
+            pk_script: script.to_vec(),
+        }],
+        lock_time: 0,
+    }
+}
+
+/// Builds and mines the block at `height` on top of `prev_block`.
+pub fn mine_block(params: &ChainParams, prev_block: BitcoinHash, prev_time: u32, height: u32,
+                  script: &[u8]) -> Result<BlockMessage, String> {
+    let txns = vec![coinbase(params, height, script)];
+    let hashes: Vec<_> = txns.iter().map(|tx| tx.hash()).collect();
+
+    let mut block = BlockMessage {
+        metadata: BlockMetadata {
+            version: BLOCK_VERSION,
+            prev_block: prev_block,
+            merkle_root: merkle_root(&hashes),
+            // Blocks generated within the same second still move forward
+            timestamp: Timestamp32::new(cmp::max(prev_time + 1, Timestamp32::now().secs())),
+            bits: params.pow_limit_bits,
+            nonce: 0,
+        },
+        txns: txns,
+    };
+
+    if !solve_proof_of_work(&mut block) {
+        return Err(format!("No nonce satisfies the proof of work at height {}", height));
+    }
+
+    Ok(block)
+}
+
+/// Mines `count` blocks on top of the best chain of `store`, paying to
+/// `script`. Returns the hashes of the new blocks.
+pub fn generate_to_script(store: &mut BlockStore, params: &ChainParams, count: usize,
+                          script: &[u8]) -> Result<Vec<BitcoinHash>, String> {
+    // Other chains are far too hard to mine on the CPU
+    if params.network_type != NetworkType::Regtest {
+        return Err(format!("Cannot generate blocks on {}", params.name));
+    }
+
+    let mut hashes = vec![];
+    for _ in 0..count {
+        let height = store.height();
+        let prev_block = *store.get_hash_at_height(height).unwrap();
+        let prev_time = try!(store.get(&prev_block)
+                             .ok_or(format!("Missing block {:?}", prev_block)))
+            .metadata.timestamp.secs();
+
+        let block = try!(mine_block(params, prev_block, prev_time, height as u32 + 1, script));
+
+        let mut data = vec![];
+        block.serialize(&mut data);
+        let block = try!(BlockRef::parse(&data).map_err(|e| e.to_string()));
+
+        store.insert(&block);
+        hashes.push(block.hash());
+    }
+
+    Ok(hashes)
+}
+
+/// Like `generate_to_script`, on the store kept in `blocks_file`.
+pub fn generate_to_file(params: &ChainParams, blocks_file: File, count: usize,
+                        script: &[u8]) -> Result<Vec<BitcoinHash>, String> {
+    let mut store = BlockStore::new(blocks_file, params);
+    generate_to_script(&mut store, params, count, script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::block_ref::BlockRef;
+    use serialize::Serialize;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+
+    fn temp_file(name: &str) -> (::std::path::PathBuf, File) {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let file = OpenOptions::new().read(true).write(true).append(true).create(true)
+            .open(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_coinbase_script() {
+        assert_eq!(coinbase_script(0), vec![0x00, 0x00]);
+        assert_eq!(coinbase_script(1), vec![0x51, 0x00]);
+        assert_eq!(coinbase_script(16), vec![0x60, 0x00]);
+        assert_eq!(coinbase_script(17), vec![0x01, 0x11, 0x00]);
+        assert_eq!(coinbase_script(128), vec![0x02, 0x80, 0x00, 0x00]);
+        assert_eq!(coinbase_script(227931), vec![0x03, 0x5b, 0x7a, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_generate_to_script() {
+        let (store_path, store_file) = temp_file("bitcoin-rust-generate-store.dat");
+        let params = ChainParams::regtest();
+        let script = vec![0x51];
+
+        let hashes = {
+            let mut store = BlockStore::new(store_file, &params);
+            let hashes = generate_to_script(&mut store, &params, 151, &script).unwrap();
+
+            assert_eq!(store.height(), 151);
+            assert_eq!(store.get_hash_at_height(151), Some(&hashes[150]));
+
+            let block = store.get(&hashes[0]).unwrap();
+            assert_eq!(block.metadata.prev_block, params.genesis_hash);
+            assert_eq!(block.metadata.merkle_root, block.txns[0].hash());
+            assert_eq!(block.txns[0].tx_in[0].script, vec![0x51, 0x00]);
+            assert_eq!(block.txns[0].tx_out[0].pk_script, script);
+            assert_eq!(block.txns[0].tx_out[0].value, 5000000000);
+            assert!(check_proof_of_work(&block.metadata.hash(), params.pow_limit_bits));
+
+            let mut data = vec![];
+            block.serialize(&mut data);
+            assert_eq!(BlockRef::parse(&data).unwrap().hash(), hashes[0]);
+
+            // The first halving on regtest
+            let block = store.get(&hashes[149]).unwrap();
+            assert_eq!(block.txns[0].tx_out[0].value, 2500000000);
+            assert!(block.metadata.timestamp >
+                    store.get(&hashes[148]).unwrap().metadata.timestamp);
+
+            hashes
+        };
+
+        // The chain is still there when the store is opened again
+        let store_file = OpenOptions::new().read(true).write(true).append(true)
+            .open(&store_path).unwrap();
+        let mut store = BlockStore::new(store_file, &params);
+        assert_eq!(store.height(), 151);
+        assert_eq!(generate_to_script(&mut store, &params, 1, &script).unwrap()[0],
+                   *store.get_hash_at_height(152).unwrap());
+        assert_eq!(store.get(&hashes[150]).unwrap().metadata.prev_block, hashes[149]);
+
+        fs::remove_file(store_path).unwrap();
+    }
+
+    #[test]
+    fn test_generate_only_on_regtest() {
+        let (store_path, store_file) = temp_file("bitcoin-rust-generate-testnet.dat");
+        let params = ChainParams::testnet3();
+        let mut store = BlockStore::new(store_file, &params);
+
+        assert!(generate_to_script(&mut store, &params, 1, &[0x51]).is_err());
+        assert_eq!(store.height(), 0);
+        fs::remove_file(store_path).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+// Headers of every chain we've heard of. They are validated before any block
+// is downloaded, and blocks are only fetched for the chain with most work.
+use super::auxpow::{self, AuxPow, AuxPowError};
+use super::chain_params::ChainParams;
+use super::import::check_proof_of_work;
+use super::messages::{BitcoinHash, BlockMetadata, SerializeHash};
+use super::pow::{self, Uint256, MAX_TIMEWARP};
+
+use std::collections::HashMap;
+use std::fmt;
+
+// How far ahead of our clock a header can be
+const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+// Headers must be later than the median time of this many ancestors
+const MEDIAN_TIME_SPAN: usize = 11;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeaderError {
+    // The parent isn't known
+    Unconnected(BitcoinHash),
+    InvalidProofOfWork,
+    // Holds the bits that were expected
+    BadDifficulty(u32),
+    TimeTooOld,
+    TimeTooNew,
+    TimeWarp,
+    AuxPow(AuxPowError),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &HeaderError::Unconnected(ref prev) => write!(f, "unknown parent {:?}", prev),
+            &HeaderError::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            &HeaderError::BadDifficulty(bits) => write!(f, "expected bits {:08x}", bits),
+            &HeaderError::TimeTooOld => write!(f, "time is not after the median time past"),
+            &HeaderError::TimeTooNew => write!(f, "time is too far in the future"),
+            &HeaderError::TimeWarp => write!(f, "time is too far before the previous block"),
+            &HeaderError::AuxPow(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+struct HeaderEntry {
+    header: BlockMetadata,
+    height: usize,
+    chain_work: Uint256,
+}
+
+/// Tree of validated headers with the chain with most work as best chain.
+pub struct HeaderChain {
+    params: ChainParams,
+    entries: HashMap<BitcoinHash, HeaderEntry>,
+    // Hashes of the best chain by height
+    best_chain: Vec<BitcoinHash>,
+}
+
+impl HeaderChain {
+    pub fn new(params: &ChainParams) -> HeaderChain {
+        let genesis = params.genesis_block().into_metadata();
+
+        let mut entries = HashMap::new();
+        entries.insert(params.genesis_hash, HeaderEntry {
+            chain_work: pow::block_work(genesis.bits),
+            header: genesis,
+            height: 0,
+        });
+
+        HeaderChain {
+            params: params.clone(),
+            entries: entries,
+            best_chain: vec![params.genesis_hash],
+        }
+    }
+
+    pub fn height(&self) -> usize { self.best_chain.len() - 1 }
+
+    pub fn tip(&self) -> &BitcoinHash { &self.best_chain[self.height()] }
+
+    pub fn contains(&self, hash: &BitcoinHash) -> bool { self.entries.contains_key(hash) }
+
+    // Height of any known header, not only those in the best chain
+    pub fn get_height(&self, hash: &BitcoinHash) -> Option<usize> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    pub fn get_hash_at_height(&self, height: usize) -> Option<&BitcoinHash> {
+        self.best_chain.get(height)
+    }
+
+    pub fn is_in_best_chain(&self, hash: &BitcoinHash) -> bool {
+        self.get_height(hash).and_then(|height| self.get_hash_at_height(height)) == Some(hash)
+    }
+
+    /// Whether `hash` has more chain work than `other`, unknown headers have
+    /// none.
+    pub fn has_more_work(&self, hash: &BitcoinHash, other: &BitcoinHash) -> bool {
+        match (self.entries.get(hash), self.entries.get(other)) {
+            (Some(entry), Some(other)) => entry.chain_work > other.chain_work,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    pub fn block_locators(&self) -> Vec<BitcoinHash> {
+        let height = self.height();
+        let mut step = 1;
+        let mut locator = vec![];
+
+        let mut index = height;
+        while index > 0 {
+            locator.push(self.best_chain[index]);
+            // The last ten blocks, then exponentially further back
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+
+        locator.push(self.best_chain[0]);
+        locator
+    }
+
+    /// Validates `header` against its ancestors and adds it, `now` is the
+    /// time according to our clock. Known headers are accepted again.
+    pub fn accept(&mut self, header: &BlockMetadata, auxpow: Option<&AuxPow>,
+                  now: u32) -> Result<BitcoinHash, HeaderError> {
+        let hash = header.hash();
+        if self.contains(&hash) {
+            return Ok(hash);
+        }
+
+        let (height, prev_time) = match self.entries.get(&header.prev_block) {
+            Some(prev) => (prev.height + 1, prev.header.timestamp.secs()),
+            None => return Err(HeaderError::Unconnected(header.prev_block)),
+        };
+
+        match self.params.auxpow {
+            Some(ref params) => {
+                try!(auxpow::check_proof_of_work(header, auxpow, params)
+                     .map_err(HeaderError::AuxPow));
+                try!(auxpow::check_height(header, height, params)
+                     .map_err(HeaderError::AuxPow));
+            },
+            None => if !check_proof_of_work(&hash, header.bits) {
+                return Err(HeaderError::InvalidProofOfWork);
+            },
+        }
+
+        let time = header.timestamp.secs();
+        let expected_bits = self.next_work_required(&header.prev_block, time);
+        if header.bits != expected_bits {
+            return Err(HeaderError::BadDifficulty(expected_bits));
+        }
+
+        if time <= self.median_time_past(&header.prev_block) {
+            return Err(HeaderError::TimeTooOld);
+        }
+
+        if time > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(HeaderError::TimeTooNew);
+        }
+
+        let interval = self.params.difficulty_adjustment_interval() as usize;
+        if self.params.enforce_bip94 && height % interval == 0 &&
+           time < prev_time.saturating_sub(MAX_TIMEWARP) {
+            return Err(HeaderError::TimeWarp);
+        }
+
+        self.insert(header.clone(), hash);
+        Ok(hash)
+    }
+
+    /// Adds a header that was validated before, e.g. one of a block we
+    /// stored. False if its parent isn't known.
+    pub fn insert_trusted(&mut self, header: BlockMetadata) -> bool {
+        if !self.contains(&header.prev_block) {
+            return false;
+        }
+
+        let hash = header.hash();
+        if !self.contains(&hash) {
+            self.insert(header, hash);
+        }
+
+        true
+    }
+
+    fn insert(&mut self, header: BlockMetadata, hash: BitcoinHash) {
+        let (height, chain_work) = {
+            let prev = &self.entries[&header.prev_block];
+            (prev.height + 1, prev.chain_work.add(&pow::block_work(header.bits)))
+        };
+
+        self.entries.insert(hash, HeaderEntry {
+            header: header,
+            height: height,
+            chain_work: chain_work,
+        });
+
+        // On a tie the chain we saw first stays
+        if chain_work > self.entries[self.tip()].chain_work {
+            self.set_tip(hash);
+        }
+    }
+
+    fn set_tip(&mut self, hash: BitcoinHash) {
+        let mut chain = vec![];
+        let mut current = hash;
+
+        while !self.is_in_best_chain(&current) {
+            chain.push(current);
+            current = self.entries[&current].header.prev_block;
+        }
+
+        let fork_height = self.entries[&current].height;
+        self.best_chain.truncate(fork_height + 1);
+        self.best_chain.extend(chain.into_iter().rev());
+    }
+
+    fn ancestor(&self, hash: &BitcoinHash, height: usize) -> &HeaderEntry {
+        let mut current = hash;
+        loop {
+            // The best chain can be indexed directly
+            if self.is_in_best_chain(current) {
+                return &self.entries[&self.best_chain[height]];
+            }
+
+            let entry = &self.entries[current];
+            if entry.height == height {
+                return entry;
+            }
+            current = &entry.header.prev_block;
+        }
+    }
+
+    fn median_time_past(&self, hash: &BitcoinHash) -> u32 {
+        let mut times = vec![];
+        let mut current = self.entries.get(hash);
+
+        while let Some(entry) = current {
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            times.push(entry.header.timestamp.secs());
+            current = self.entries.get(&entry.header.prev_block);
+        }
+
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    // Bits the child of `prev_hash` with `time` must have
+    fn next_work_required(&self, prev_hash: &BitcoinHash, time: u32) -> u32 {
+        let params = &self.params;
+        let prev = &self.entries[prev_hash];
+        let interval = params.difficulty_adjustment_interval() as usize;
+
+        if (prev.height + 1) % interval != 0 {
+            if !params.allow_min_difficulty_blocks {
+                return prev.header.bits;
+            }
+
+            // After twenty minutes without a block anyone can mine one
+            if time as i64 > prev.header.timestamp.secs() as i64 + params.pow_target_spacing * 2 {
+                return params.pow_limit_bits;
+            }
+
+            // Otherwise it's the last difficulty that wasn't the minimum
+            let mut entry = prev;
+            while entry.height > 0 && entry.height % interval != 0 &&
+                  entry.header.bits == params.pow_limit_bits {
+                entry = &self.entries[&entry.header.prev_block];
+            }
+            return entry.header.bits;
+        }
+
+        if params.no_retargeting {
+            return prev.header.bits;
+        }
+
+        let first = self.ancestor(prev_hash, prev.height + 1 - interval);
+        let timespan = prev.header.timestamp.secs() as i64 - first.header.timestamp.secs() as i64;
+
+        // BIP94 starts from the first block, which can't have the minimum
+        // difficulty exception
+        let bits = if params.enforce_bip94 { first.header.bits } else { prev.header.bits };
+        pow::retarget(bits, timespan, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::generate::{mine_block, solve_proof_of_work};
+    use net::messages::Timestamp32;
+
+    fn extend(chain: &mut HeaderChain, params: &ChainParams, from: BitcoinHash, count: usize,
+              script: &[u8]) -> Vec<BlockMetadata> {
+        let mut headers = vec![];
+        let mut prev = from;
+        for _ in 0..count {
+            let height = chain.get_height(&prev).unwrap() as u32 + 1;
+            let time = chain.entries[&prev].header.timestamp.secs();
+            let header = mine_block(params, prev, time, height, script).unwrap().into_metadata();
+
+            prev = chain.accept(&header, None, Timestamp32::now().secs()).unwrap();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_best_chain() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(&params);
+        let genesis = params.genesis_hash;
+
+        let first = extend(&mut chain, &params, genesis, 5, &[0x51]);
+        assert_eq!(chain.height(), 5);
+        assert_eq!(*chain.tip(), first[4].hash());
+
+        // Same work, the first chain stays
+        let second = extend(&mut chain, &params, first[1].hash(), 3, &[0x52]);
+        assert_eq!(*chain.tip(), first[4].hash());
+        assert!(!chain.is_in_best_chain(&second[0].hash()));
+
+        // More work wins
+        let second = extend(&mut chain, &params, second[2].hash(), 1, &[0x52]);
+        assert_eq!(chain.height(), 6);
+        assert_eq!(*chain.tip(), second[0].hash());
+        assert_eq!(chain.get_hash_at_height(2), Some(&first[1].hash()));
+        assert!(!chain.is_in_best_chain(&first[2].hash()));
+        assert_eq!(chain.entries[chain.tip()].chain_work, Uint256::from_u64(14));
+        assert!(chain.has_more_work(&second[0].hash(), &first[4].hash()));
+        assert!(!chain.has_more_work(&first[4].hash(), &first[4].hash()));
+        assert!(chain.has_more_work(&genesis, &BitcoinHash::new([1; 32])));
+        assert!(!chain.has_more_work(&BitcoinHash::new([1; 32]), &genesis));
+
+        // Known headers are fine, unknown parents aren't
+        assert_eq!(chain.accept(&first[4], None, 0), Ok(first[4].hash()));
+        let mut orphan = first[0].clone();
+        orphan.prev_block = BitcoinHash::new([1; 32]);
+        assert_eq!(chain.accept(&orphan, None, 0),
+                   Err(HeaderError::Unconnected(BitcoinHash::new([1; 32]))));
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(&params);
+        let genesis = chain.entries[&params.genesis_hash].header.clone();
+        let now = Timestamp32::now().secs();
+
+        let valid = mine_block(&params, params.genesis_hash, genesis.timestamp.secs(), 1, &[])
+            .unwrap();
+
+        let mut block = valid.clone();
+        while check_proof_of_work(&block.metadata.hash(), block.metadata.bits) {
+            block.metadata.nonce += 1;
+        }
+        assert_eq!(chain.accept(&block.metadata, None, now),
+                   Err(HeaderError::InvalidProofOfWork));
+
+        let mut block = valid.clone();
+        block.metadata.bits = 0x1f7fffff;
+        solve_proof_of_work(&mut block);
+        assert_eq!(chain.accept(&block.metadata, None, now),
+                   Err(HeaderError::BadDifficulty(0x207fffff)));
+
+        let mut block = valid.clone();
+        block.metadata.timestamp = genesis.timestamp;
+        solve_proof_of_work(&mut block);
+        assert_eq!(chain.accept(&block.metadata, None, now), Err(HeaderError::TimeTooOld));
+
+        let mut block = valid.clone();
+        block.metadata.timestamp = Timestamp32::new(now + MAX_FUTURE_BLOCK_TIME + 1);
+        solve_proof_of_work(&mut block);
+        assert_eq!(chain.accept(&block.metadata, None, now), Err(HeaderError::TimeTooNew));
+
+        assert_eq!(chain.height(), 0);
+        assert!(chain.accept(&valid.metadata, None, now).is_ok());
+    }
+
+    #[test]
+    fn test_min_difficulty_blocks() {
+        let params = ChainParams::testnet3();
+        let chain = HeaderChain::new(&params);
+        let genesis = params.genesis_hash;
+        let time = chain.entries[&genesis].header.timestamp.secs();
+
+        assert_eq!(chain.next_work_required(&genesis, time + 1200), 0x1d00ffff);
+        assert_eq!(chain.next_work_required(&genesis, time + 1201), params.pow_limit_bits);
+    }
+
+    #[test]
+    fn test_block_locators() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(&params);
+        let genesis = params.genesis_hash;
+        extend(&mut chain, &params, genesis, 30, &[0x51]);
+
+        let locators = chain.block_locators();
+        let heights: Vec<_> = locators.iter().map(|hash| chain.get_height(hash).unwrap())
+            .collect();
+        assert_eq!(heights, vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]);
+    }
+}
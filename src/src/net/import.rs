@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use serialize::{Serialize, DecodeError};
+use super::auxpow::{self, AuxPowParams};
+use super::block_ref::BlockRef;
+use super::chain_params::ChainParams;
+use super::messages::{BitcoinHash, NetworkType};
+use super::store::BlockStore;
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct ImportStats {
+    pub imported: usize,
+    // Blocks the store already had
+    pub skipped: usize,
+    // Blocks whose parent never showed up
+    pub unconnected: usize,
+}
+
+/// Reads the blocks of a Bitcoin Core `blk?????.dat` file, each one stored
+/// as network magic, little endian length and the serialized block.
+pub struct BlockFileReader<R: Read> {
+    reader: R,
+    magic: [u8; 4],
+    done: bool,
+}
+
+impl<R: Read> BlockFileReader<R> {
+    pub fn new(reader: R, network_type: NetworkType) -> BlockFileReader<R> {
+        let mut magic = vec![];
+        network_type.serialize(&mut magic);
+
+        BlockFileReader {
+            reader: reader,
+            magic: [magic[0], magic[1], magic[2], magic[3]],
+            done: false,
+        }
+    }
+
+    // Like read_exact but distinguishes a clean end of file
+    fn read_all(&mut self, out: &mut [u8]) -> Result<bool, String> {
+        let mut read = 0;
+        while read < out.len() {
+            match self.reader.read(&mut out[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(format!("Unexpected end of file")),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn next_block(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let mut magic = [0; 4];
+        if !try!(self.read_all(&mut magic)) {
+            return Ok(None);
+        }
+
+        // Core preallocates the files, zeros mean there are no more blocks
+        if magic == [0; 4] {
+            return Ok(None);
+        }
+
+        if magic != self.magic {
+            return Err(format!("Unexpected network magic {:?}", magic));
+        }
+
+        let mut length = [0; 4];
+        if !try!(self.read_all(&mut length)) {
+            return Err(format!("Unexpected end of file"));
+        }
+
+        let length = length.iter().rev().fold(0, |acc, &b| acc << 8 | b as usize);
+        let mut block = vec![0; length];
+        if !try!(self.read_all(&mut block)) {
+            return Err(format!("Unexpected end of file"));
+        }
+
+        Ok(Some(block))
+    }
+}
+
+impl<R: Read> Iterator for BlockFileReader<R> {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, String>> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.next_block();
+        match result {
+            Ok(Some(block)) => Some(Ok(block)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+// Expands the compact `bits` representation to a little endian 256 bit
+// target, None when it's negative, zero or overflows.
+pub fn target(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007fffff;
+
+    if bits & 0x00800000 != 0 || mantissa == 0 {
+        return None;
+    }
+
+    let mut result = [0; 32];
+    for i in 0..3 {
+        let byte = (mantissa >> (8 * i)) as u8;
+        if byte == 0 {
+            continue;
+        }
+
+        match (exponent + i).checked_sub(3) {
+            Some(position) if position < 32 => result[position] = byte,
+            Some(_) => return None,
+            // Shifted out to the right
+            None => {},
+        }
+    }
+
+    Some(result)
+}
+
+pub fn check_proof_of_work(hash: &BitcoinHash, bits: u32) -> bool {
+    let target = match target(bits) {
+        Some(target) => target,
+        None => return false,
+    };
+
+    // Both are little endian, compare from the most significant byte
+    hash.inner().iter().rev().cmp(target.iter().rev()) != ::std::cmp::Ordering::Greater
+}
+
+/// Inserts blocks from Bitcoin Core's block files into a `BlockStore`.
+/// Blocks are written in the order they were downloaded, so children can
+/// come before their parent and are kept until it shows up.
+pub struct BlockImporter<'a> {
+    store: &'a mut BlockStore,
+    network_type: NetworkType,
+    auxpow: Option<AuxPowParams>,
+    // Blocks waiting for their parent, by parent hash
+    pending: HashMap<BitcoinHash, Vec<Vec<u8>>>,
+    stats: ImportStats,
+}
+
+impl<'a> BlockImporter<'a> {
+    pub fn new(store: &'a mut BlockStore, params: &ChainParams) -> BlockImporter<'a> {
+        BlockImporter {
+            store: store,
+            network_type: params.network_type,
+            auxpow: params.auxpow.clone(),
+            pending: HashMap::new(),
+            stats: ImportStats::default(),
+        }
+    }
+
+    fn parse<'b>(&self, data: &'b [u8]) -> Result<BlockRef<'b>, DecodeError> {
+        match self.auxpow {
+            Some(_) => BlockRef::parse_auxpow(data),
+            None => BlockRef::parse(data),
+        }
+    }
+
+    pub fn add_block(&mut self, data: Vec<u8>) -> Result<(), String> {
+        let (hash, prev_block) = {
+            let block = try!(self.parse(&data));
+            try!(block.check_length());
+
+            let hash = block.hash();
+            match self.auxpow {
+                Some(ref params) => {
+                    try!(auxpow::check_proof_of_work(&block.metadata(), block.auxpow().as_ref(),
+                                                     params)
+                         .map_err(|e| format!("Block {:?}: {}", hash, e)));
+                },
+                None => if !check_proof_of_work(&hash, block.metadata().bits) {
+                    return Err(format!("Block {:?} has an invalid proof of work", hash));
+                },
+            }
+
+            (hash, block.prev_block())
+        };
+
+        if self.store.has(&hash) {
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        if !self.store.has(&prev_block) {
+            self.pending.entry(prev_block).or_insert(vec![]).push(data);
+            return Ok(());
+        }
+
+        self.insert(data);
+
+        // Children may have been waiting for this block, and so on
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.pending.remove(&parent).unwrap_or(vec![]) {
+                parents.push(self.insert(child));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Only called on blocks that were already parsed
+    fn insert(&mut self, data: Vec<u8>) -> BitcoinHash {
+        let block = self.parse(&data).unwrap();
+        self.store.insert(&block);
+        self.stats.imported += 1;
+
+        block.hash()
+    }
+
+    pub fn import_file(&mut self, path: &Path) -> Result<(), String> {
+        let file = try!(File::open(path).map_err(|e| format!("{}: {}", path.display(), e)));
+
+        for block in BlockFileReader::new(BufReader::new(file), self.network_type) {
+            let block = try!(block.map_err(|e| format!("{}: {}", path.display(), e)));
+            try!(self.add_block(block).map_err(|e| format!("{}: {}", path.display(), e)));
+        }
+
+        Ok(())
+    }
+
+    /// Imports every `blk?????.dat` of a Core `blocks` directory in order.
+    pub fn import_dir(&mut self, dir: &Path) -> Result<(), String> {
+        let entries = try!(fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e)));
+
+        let mut files = vec![];
+        for entry in entries {
+            let path = try!(entry.map_err(|e| format!("{}: {}", dir.display(), e))).path();
+            let is_block_file = path.file_name().and_then(|name| name.to_str())
+                .map_or(false, |name| name.starts_with("blk") && name.ends_with(".dat"));
+
+            if is_block_file {
+                files.push(path);
+            }
+        }
+
+        // File numbers are zero padded, so this is numeric order
+        files.sort();
+
+        for path in files.iter() {
+            println!("Importing {}", path.display());
+            try!(self.import_file(path));
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> ImportStats {
+        let mut stats = self.stats;
+        stats.unconnected = self.pending.values().map(|blocks| blocks.len()).sum();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+
+    use super::*;
+    use net::messages::{BlockMessage, BlockMetadata, Timestamp32, SerializeHash,
+                        TxIn, TxOut, OutPoint, TxMessage};
+    use serialize::Serialize;
+    use net::chain_params::ChainParams;
+
+    // Easiest possible target, about one in two hashes is below it
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn mine_block(prev_block: BitcoinHash, height: u8) -> Vec<u8> {
+        let coinbase = TxMessage {
+            version: 1,
+            tx_in: vec![TxIn {
+                previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                script: vec![0x01, height],
+                sequence: 0xffffffff,
+            }],
+            tx_out: vec![TxOut { value: 5000000000, pk_script: vec![0x51] }],
+            lock_time: 0,
+        };
+
+        let mut block = BlockMessage {
+            metadata: BlockMetadata {
+                version: 1,
+                prev_block: prev_block,
+                merkle_root: coinbase.hash(),
+                timestamp: Timestamp32::new(1296688602 + height as u32 * 600),
+                bits: EASY_BITS,
+                nonce: 0,
+            },
+            txns: vec![coinbase],
+        };
+
+        while !check_proof_of_work(&block.metadata.hash(), EASY_BITS) {
+            block.metadata.nonce += 1;
+        }
+
+        let mut data = vec![];
+        block.serialize(&mut data);
+        data
+    }
+
+    fn write_block(file: &mut Vec<u8>, block: &[u8]) {
+        NetworkType::TestNet3.serialize(file);
+        (block.len() as u32).serialize(file);
+        file.extend_from_slice(block);
+    }
+
+    fn temp_file(name: &str) -> (::std::path::PathBuf, File) {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let file = OpenOptions::new().read(true).write(true).append(true).create(true)
+            .open(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(target(0), None);
+        assert_eq!(target(0x04923456), None);
+
+        let mut expected = [0; 32];
+        expected[26] = 0xff;
+        expected[27] = 0xff;
+        assert_eq!(target(0x1d00ffff), Some(expected));
+
+        // The exponent pushes some of the mantissa out
+        let mut expected = [0; 32];
+        expected[0] = 0x12;
+        assert_eq!(target(0x01120000), Some(expected));
+        assert_eq!(target(0x22010000), None);
+    }
+
+    #[test]
+    fn test_import_out_of_order() {
+        let (store_path, store_file) = temp_file("bitcoin-rust-import-store.dat");
+        let (blk_path, mut blk_file) = temp_file("bitcoin-rust-import-blk00000.dat");
+
+        let mut store = BlockStore::new(store_file, &ChainParams::testnet3());
+        let genesis = *store.get_hash_at_height(0).unwrap();
+
+        let first = mine_block(genesis, 1);
+        let second = mine_block(BlockRef::parse(&first).unwrap().hash(), 2);
+        let third = mine_block(BlockRef::parse(&second).unwrap().hash(), 3);
+        let orphan = mine_block(BitcoinHash::new([1; 32]), 4);
+
+        let mut data = vec![];
+        for block in [&third, &orphan, &second, &first, &first].iter() {
+            write_block(&mut data, block);
+        }
+        // Preallocated space at the end of the file
+        data.extend(vec![0; 100]);
+        blk_file.write_all(&data).unwrap();
+
+        let stats = {
+            let mut importer = BlockImporter::new(&mut store, &ChainParams::testnet3());
+            importer.import_file(&blk_path).unwrap();
+            importer.finish()
+        };
+
+        assert_eq!(stats, ImportStats { imported: 3, skipped: 1, unconnected: 1 });
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.get_hash_at_height(3), Some(&BlockRef::parse(&third).unwrap().hash()));
+
+        fs::remove_file(store_path).unwrap();
+        fs::remove_file(blk_path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_block_files() {
+        let mut data = vec![];
+        write_block(&mut data, &[0; 10]);
+        data.truncate(data.len() - 1);
+
+        let mut reader = BlockFileReader::new(&data[..], NetworkType::TestNet3);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        let mut reader = BlockFileReader::new(&data[..], NetworkType::Main);
+        assert!(reader.next().unwrap().is_err());
+
+        // A header that doesn't meet its own target
+        let mut block = mine_block(BitcoinHash::new([0; 32]), 1);
+        while check_proof_of_work(&BlockRef::parse(&block).unwrap().hash(), EASY_BITS) {
+            block[76] = block[76].wrapping_add(1);
+        }
+
+        let (store_path, store_file) = temp_file("bitcoin-rust-import-invalid.dat");
+        let mut store = BlockStore::new(store_file, &ChainParams::testnet3());
+        assert!(BlockImporter::new(&mut store, &ChainParams::testnet3()).add_block(block).is_err());
+        fs::remove_file(store_path).unwrap();
+    }
+}
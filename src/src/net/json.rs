@@ -0,0 +1,731 @@
+// JSON representation of protocol messages.
+//
+// Transactions and blocks follow the layout of Bitcoin Core's
+// `decoderawtransaction` and `getblock` (verbosity 2): hashes are hex encoded
+// in reverse byte order, amounts are in BTC and scripts have both `asm` and
+// `hex`. Fields that depend on the state of the chain (e.g. `confirmations`
+// or `height`) are left out. Other messages use their field names.
+use rustc_serialize::json::{Json, ToJson};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use script::{to_asm, ScriptType};
+use serialize::{Serialize, VarInt};
+
+use super::{IPAddress, Services};
+use super::messages::*;
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const COIN: i64 = 100000000;
+const MAX_MONEY: i64 = 21000000 * COIN;
+
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Result<Self, String>;
+}
+
+macro_rules! object {
+    ($($key: expr => $value: expr),*) => {{
+        let mut object = BTreeMap::new();
+        $(object.insert($key.to_owned(), $value.to_json());)*
+        Json::Object(object)
+    }}
+}
+
+macro_rules! from_json_unsigned {
+    ($($t: ty),*) => {$(
+        impl FromJson for $t {
+            fn from_json(json: &Json) -> Result<$t, String> {
+                match json.as_u64() {
+                    Some(x) if x <= <$t>::max_value() as u64 => Ok(x as $t),
+                    _ => Err(format!("Expected {}, found `{}`", stringify!($t), json)),
+                }
+            }
+        }
+    )*}
+}
+
+macro_rules! from_json_signed {
+    ($($t: ty),*) => {$(
+        impl FromJson for $t {
+            fn from_json(json: &Json) -> Result<$t, String> {
+                let value = match json {
+                    &Json::I64(x) => Some(x),
+                    &Json::U64(x) if x <= i64::max_value() as u64 => Some(x as i64),
+                    _ => None,
+                };
+
+                match value {
+                    Some(x) if x >= <$t>::min_value() as i64 &&
+                               x <= <$t>::max_value() as i64 => Ok(x as $t),
+                    _ => Err(format!("Expected {}, found `{}`", stringify!($t), json)),
+                }
+            }
+        }
+    )*}
+}
+
+from_json_unsigned!(u8, u16, u32, u64);
+from_json_signed!(i32, i64);
+
+impl FromJson for String {
+    fn from_json(json: &Json) -> Result<String, String> {
+        match json.as_string() {
+            Some(x) => Ok(x.to_owned()),
+            None => Err(format!("Expected a string, found `{}`", json)),
+        }
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(json: &Json) -> Result<bool, String> {
+        match json.as_boolean() {
+            Some(x) => Ok(x),
+            None => Err(format!("Expected a boolean, found `{}`", json)),
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(json: &Json) -> Result<Vec<T>, String> {
+        let array = match json.as_array() {
+            Some(x) => x,
+            None => return Err(format!("Expected an array, found `{}`", json)),
+        };
+
+        let mut result = Vec::with_capacity(array.len());
+        for (i, element) in array.iter().enumerate() {
+            result.push(try!(T::from_json(element).map_err(|e| format!("[{}]: {}", i, e))));
+        }
+
+        Ok(result)
+    }
+}
+
+fn get<T: FromJson>(json: &Json, name: &str) -> Result<T, String> {
+    match json.find(name) {
+        Some(value) => T::from_json(value).map_err(|e| format!("{}: {}", name, e)),
+        None => Err(format!("Missing field `{}`", name)),
+    }
+}
+
+fn get_optional<T: FromJson>(json: &Json, name: &str) -> Result<Option<T>, String> {
+    match json.find(name) {
+        Some(&Json::Null) | None => Ok(None),
+        Some(_) => get(json, name).map(Some),
+    }
+}
+
+fn get_hex(json: &Json, name: &str) -> Result<Vec<u8>, String> {
+    let hex: String = try!(get(json, name));
+    hex.from_hex().map_err(|e| format!("{}: {}", name, e))
+}
+
+fn insert(mut json: Json, key: &str, value: Json) -> Json {
+    if let Json::Object(ref mut object) = json {
+        object.insert(key.to_owned(), value);
+    }
+
+    json
+}
+
+fn serialized<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.encoded_len());
+    value.serialize(&mut result);
+    result
+}
+
+fn amount_to_json(value: i64) -> Json {
+    Json::F64(value as f64 / COIN as f64)
+}
+
+fn amount_from_json(json: &Json, name: &str) -> Result<i64, String> {
+    let value = match json.find(name) {
+        Some(value) => value,
+        None => return Err(format!("Missing field `{}`", name)),
+    };
+
+    let satoshis = match value.as_f64() {
+        Some(x) => (x * COIN as f64).round(),
+        None => return Err(format!("{}: expected an amount, found `{}`", name, value)),
+    };
+
+    if satoshis < 0.0 || satoshis > MAX_MONEY as f64 {
+        return Err(format!("{}: amount out of range", name));
+    }
+
+    Ok(satoshis as i64)
+}
+
+fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
+
+impl ToJson for BitcoinHash {
+    fn to_json(&self) -> Json {
+        let mut bytes = self.inner().to_vec();
+        bytes.reverse();
+        Json::String(bytes.to_hex())
+    }
+}
+
+impl FromJson for BitcoinHash {
+    fn from_json(json: &Json) -> Result<BitcoinHash, String> {
+        let hex = try!(String::from_json(json));
+        let bytes = try!(hex.from_hex().map_err(|e| e.to_string()));
+        if bytes.len() != 32 {
+            return Err(format!("Invalid hash `{}`", hex));
+        }
+
+        let mut data = [0; 32];
+        for i in 0..32 {
+            data[i] = bytes[31 - i];
+        }
+
+        Ok(BitcoinHash::new(data))
+    }
+}
+
+impl ToJson for NetworkType {
+    fn to_json(&self) -> Json {
+        let name = match self {
+            &NetworkType::Main     => "main",
+            &NetworkType::TestNet  => "testnet",
+            &NetworkType::TestNet3 => "testnet3",
+            &NetworkType::TestNet4 => "testnet4",
+            &NetworkType::Signet   => "signet",
+            // Custom signets by their message start
+            &NetworkType::CustomSignet(magic) => {
+                let mut bytes = vec![];
+                magic.serialize(&mut bytes);
+                return format!("signet-{}", bytes.to_hex()).to_json();
+            },
+            &NetworkType::Regtest  => "regtest",
+            &NetworkType::NameCoin => "namecoin",
+            &NetworkType::Unknown  => "unknown",
+        };
+
+        name.to_json()
+    }
+}
+
+impl FromJson for NetworkType {
+    fn from_json(json: &Json) -> Result<NetworkType, String> {
+        match try!(String::from_json(json)).as_ref() {
+            "main"     => Ok(NetworkType::Main),
+            "testnet"  => Ok(NetworkType::TestNet),
+            "testnet3" => Ok(NetworkType::TestNet3),
+            "testnet4" => Ok(NetworkType::TestNet4),
+            "signet"   => Ok(NetworkType::Signet),
+            "regtest"  => Ok(NetworkType::Regtest),
+            "namecoin" => Ok(NetworkType::NameCoin),
+            name if name.starts_with("signet-") => match name[7..].from_hex() {
+                Ok(ref bytes) if bytes.len() == 4 => Ok(NetworkType::CustomSignet(
+                    bytes.iter().rev().fold(0, |magic, &b| magic << 8 | b as u32))),
+                _ => Err(format!("Unknown network `{}`", name)),
+            },
+            name       => Err(format!("Unknown network `{}`", name)),
+        }
+    }
+}
+
+impl ToJson for Command {
+    fn to_json(&self) -> Json {
+        self.name().to_json()
+    }
+}
+
+impl FromJson for Command {
+    fn from_json(json: &Json) -> Result<Command, String> {
+        let name = try!(String::from_json(json));
+        Command::from_name(&name).ok_or(format!("Invalid command `{}`", name))
+    }
+}
+
+impl ToJson for MessageHeader {
+    fn to_json(&self) -> Json {
+        object! {
+            "network" => self.network_type,
+            "command" => self.command,
+            "length" => self.length,
+            "checksum" => self.checksum.to_hex()
+        }
+    }
+}
+
+impl FromJson for MessageHeader {
+    fn from_json(json: &Json) -> Result<MessageHeader, String> {
+        let checksum = try!(get_hex(json, "checksum"));
+        if checksum.len() != 4 {
+            return Err(format!("checksum: expected 4 bytes"));
+        }
+
+        Ok(MessageHeader {
+            network_type: try!(get(json, "network")),
+            command: try!(get(json, "command")),
+            length: try!(get(json, "length")),
+            checksum: [checksum[0], checksum[1], checksum[2], checksum[3]],
+        })
+    }
+}
+
+impl ToJson for Services {
+    fn to_json(&self) -> Json {
+        Json::U64(self.bits())
+    }
+}
+
+impl FromJson for Services {
+    fn from_json(json: &Json) -> Result<Services, String> {
+        u64::from_json(json).map(Services::new)
+    }
+}
+
+impl ToJson for IPAddress {
+    fn to_json(&self) -> Json {
+        object! {
+            "services" => self.services,
+            "address" => self.address.to_string(),
+            "port" => self.port
+        }
+    }
+}
+
+impl FromJson for IPAddress {
+    fn from_json(json: &Json) -> Result<IPAddress, String> {
+        let address: String = try!(get(json, "address"));
+        let address = match address.parse::<Ipv4Addr>() {
+            Ok(ipv4) => ipv4.to_ipv6_mapped(),
+            Err(_) => try!(address.parse::<Ipv6Addr>()
+                               .map_err(|e| format!("address: {}", e))),
+        };
+
+        Ok(IPAddress::new(try!(get(json, "services")), address, try!(get(json, "port"))))
+    }
+}
+
+impl ToJson for Timestamp32 {
+    fn to_json(&self) -> Json {
+        Json::U64(self.secs() as u64)
+    }
+}
+
+impl FromJson for Timestamp32 {
+    fn from_json(json: &Json) -> Result<Timestamp32, String> {
+        u32::from_json(json).map(Timestamp32::new)
+    }
+}
+
+impl ToJson for Timestamp64 {
+    fn to_json(&self) -> Json {
+        Json::I64(self.secs())
+    }
+}
+
+impl FromJson for Timestamp64 {
+    fn from_json(json: &Json) -> Result<Timestamp64, String> {
+        i64::from_json(json).map(Timestamp64::new)
+    }
+}
+
+impl ToJson for VersionMessage {
+    fn to_json(&self) -> Json {
+        let json = object! {
+            "version" => self.version,
+            "services" => self.services,
+            "timestamp" => self.timestamp,
+            "addr_recv" => self.addr_recv,
+            "addr_from" => self.addr_from,
+            "nonce" => self.nonce,
+            "user_agent" => self.user_agent,
+            "start_height" => self.start_height
+        };
+
+        match self.relay {
+            Some(relay) => insert(json, "relay", relay.to_json()),
+            None => json,
+        }
+    }
+}
+
+impl FromJson for VersionMessage {
+    fn from_json(json: &Json) -> Result<VersionMessage, String> {
+        Ok(VersionMessage {
+            version: try!(get(json, "version")),
+            services: try!(get(json, "services")),
+            timestamp: try!(get(json, "timestamp")),
+            addr_recv: try!(get(json, "addr_recv")),
+            addr_from: try!(get(json, "addr_from")),
+            nonce: try!(get(json, "nonce")),
+            user_agent: try!(get(json, "user_agent")),
+            start_height: try!(get(json, "start_height")),
+            relay: try!(get_optional(json, "relay")),
+        })
+    }
+}
+
+impl ToJson for PingMessage {
+    fn to_json(&self) -> Json {
+        object!("nonce" => self.nonce)
+    }
+}
+
+impl FromJson for PingMessage {
+    fn from_json(json: &Json) -> Result<PingMessage, String> {
+        Ok(PingMessage::new(try!(get(json, "nonce"))))
+    }
+}
+
+impl ToJson for AddrMessage {
+    fn to_json(&self) -> Json {
+        let addr_list = self.addr_list.iter().map(|&(ref time, ref address)| {
+            insert(address.to_json(), "time", time.to_json())
+        }).collect::<Vec<_>>();
+
+        object!("addr_list" => addr_list)
+    }
+}
+
+impl FromJson for AddrMessage {
+    fn from_json(json: &Json) -> Result<AddrMessage, String> {
+        let entries = match json.find("addr_list").and_then(|x| x.as_array()) {
+            Some(entries) => entries,
+            None => return Err(format!("Missing field `addr_list`")),
+        };
+
+        let mut addr_list = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            let time = try!(get(entry, "time").map_err(|e| format!("addr_list[{}]: {}", i, e)));
+            let address = try!(IPAddress::from_json(entry)
+                                   .map_err(|e| format!("addr_list[{}]: {}", i, e)));
+            addr_list.push((time, address));
+        }
+
+        Ok(AddrMessage::new(addr_list))
+    }
+}
+
+impl ToJson for RejectMessage {
+    fn to_json(&self) -> Json {
+        object! {
+            "message" => self.message,
+            "ccode" => self.ccode,
+            "reason" => self.reason
+        }
+    }
+}
+
+impl FromJson for RejectMessage {
+    fn from_json(json: &Json) -> Result<RejectMessage, String> {
+        Ok(RejectMessage {
+            message: try!(get(json, "message")),
+            ccode: try!(get(json, "ccode")),
+            reason: try!(get(json, "reason")),
+        })
+    }
+}
+
+impl ToJson for BlockMetadata {
+    fn to_json(&self) -> Json {
+        let json = object! {
+            "hash" => self.hash(),
+            "version" => self.version,
+            "versionHex" => format!("{:08x}", self.version as u32),
+            "merkleroot" => self.merkle_root,
+            "time" => self.timestamp,
+            "nonce" => self.nonce,
+            "bits" => format!("{:08x}", self.bits),
+            "difficulty" => difficulty(self.bits)
+        };
+
+        // The genesis block has no previous block
+        if self.prev_block == BitcoinHash::new([0; 32]) {
+            json
+        } else {
+            insert(json, "previousblockhash", self.prev_block.to_json())
+        }
+    }
+}
+
+impl FromJson for BlockMetadata {
+    fn from_json(json: &Json) -> Result<BlockMetadata, String> {
+        let bits: String = try!(get(json, "bits"));
+        let bits = try!(u32::from_str_radix(&bits, 16).map_err(|e| format!("bits: {}", e)));
+
+        Ok(BlockMetadata {
+            version: try!(get(json, "version")),
+            prev_block: try!(get_optional(json, "previousblockhash"))
+                .unwrap_or(BitcoinHash::new([0; 32])),
+            merkle_root: try!(get(json, "merkleroot")),
+            timestamp: try!(get(json, "time")),
+            bits: bits,
+            nonce: try!(get(json, "nonce")),
+        })
+    }
+}
+
+impl ToJson for HeadersMessage {
+    fn to_json(&self) -> Json {
+        let headers = self.headers.iter().map(|&(ref header, _)| header.to_json());
+        object!("headers" => headers.collect::<Vec<_>>())
+    }
+}
+
+impl FromJson for HeadersMessage {
+    fn from_json(json: &Json) -> Result<HeadersMessage, String> {
+        let headers: Vec<BlockMetadata> = try!(get(json, "headers"));
+        Ok(HeadersMessage::new(headers.into_iter().map(|h| (h, VarInt::new(0))).collect()))
+    }
+}
+
+impl ToJson for GetHeadersMessage {
+    fn to_json(&self) -> Json {
+        object! {
+            "version" => self.version,
+            "block_locators" => self.block_locators,
+            "hash_stop" => self.hash_stop
+        }
+    }
+}
+
+impl FromJson for GetHeadersMessage {
+    fn from_json(json: &Json) -> Result<GetHeadersMessage, String> {
+        Ok(GetHeadersMessage {
+            version: try!(get(json, "version")),
+            block_locators: try!(get(json, "block_locators")),
+            hash_stop: try!(get(json, "hash_stop")),
+        })
+    }
+}
+
+impl ToJson for InventoryVectorType {
+    fn to_json(&self) -> Json {
+        let name = match self {
+            &InventoryVectorType::ERROR              => "ERROR",
+            &InventoryVectorType::MSG_TX             => "MSG_TX",
+            &InventoryVectorType::MSG_BLOCK          => "MSG_BLOCK",
+            &InventoryVectorType::MSG_FILTERED_BLOCK => "MSG_FILTERED_BLOCK",
+        };
+
+        name.to_json()
+    }
+}
+
+impl FromJson for InventoryVectorType {
+    fn from_json(json: &Json) -> Result<InventoryVectorType, String> {
+        match try!(String::from_json(json)).as_ref() {
+            "ERROR"              => Ok(InventoryVectorType::ERROR),
+            "MSG_TX"             => Ok(InventoryVectorType::MSG_TX),
+            "MSG_BLOCK"          => Ok(InventoryVectorType::MSG_BLOCK),
+            "MSG_FILTERED_BLOCK" => Ok(InventoryVectorType::MSG_FILTERED_BLOCK),
+            name                 => Err(format!("Unknown inventory type `{}`", name)),
+        }
+    }
+}
+
+impl ToJson for InventoryVector {
+    fn to_json(&self) -> Json {
+        object! {
+            "type" => self.type_,
+            "hash" => self.hash
+        }
+    }
+}
+
+impl FromJson for InventoryVector {
+    fn from_json(json: &Json) -> Result<InventoryVector, String> {
+        Ok(InventoryVector::new(try!(get(json, "type")), try!(get(json, "hash"))))
+    }
+}
+
+impl ToJson for InvMessage {
+    fn to_json(&self) -> Json {
+        object!("inventory" => self.inventory)
+    }
+}
+
+impl FromJson for InvMessage {
+    fn from_json(json: &Json) -> Result<InvMessage, String> {
+        Ok(InvMessage::new(try!(get(json, "inventory"))))
+    }
+}
+
+impl ToJson for FilterLoadMessage {
+    fn to_json(&self) -> Json {
+        object! {
+            "filter" => self.filter.to_hex(),
+            "n_hash_funcs" => self.n_hash_funcs,
+            "n_tweak" => self.n_tweak,
+            "n_flags" => self.n_flags
+        }
+    }
+}
+
+impl FromJson for FilterLoadMessage {
+    fn from_json(json: &Json) -> Result<FilterLoadMessage, String> {
+        Ok(FilterLoadMessage {
+            filter: try!(get_hex(json, "filter")),
+            n_hash_funcs: try!(get(json, "n_hash_funcs")),
+            n_tweak: try!(get(json, "n_tweak")),
+            n_flags: try!(get(json, "n_flags")),
+        })
+    }
+}
+
+impl ToJson for OutPoint {
+    fn to_json(&self) -> Json {
+        object! {
+            "txid" => self.hash,
+            "vout" => self.index
+        }
+    }
+}
+
+impl FromJson for OutPoint {
+    fn from_json(json: &Json) -> Result<OutPoint, String> {
+        Ok(OutPoint::new(try!(get(json, "txid")), try!(get(json, "vout"))))
+    }
+}
+
+impl ToJson for TxIn {
+    fn to_json(&self) -> Json {
+        // Only coinbase inputs spend the null outpoint
+        if self.previous_output == OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff) {
+            return object! {
+                "coinbase" => self.script.to_hex(),
+                "sequence" => self.sequence
+            };
+        }
+
+        let script_sig = object! {
+            "asm" => to_asm(&self.script, true),
+            "hex" => self.script.to_hex()
+        };
+
+        let json = insert(self.previous_output.to_json(), "scriptSig", script_sig);
+        insert(json, "sequence", self.sequence.to_json())
+    }
+}
+
+impl FromJson for TxIn {
+    fn from_json(json: &Json) -> Result<TxIn, String> {
+        if json.find("coinbase").is_some() {
+            return Ok(TxIn {
+                previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                script: try!(get_hex(json, "coinbase")),
+                sequence: try!(get(json, "sequence")),
+            });
+        }
+
+        let script_sig = match json.find("scriptSig") {
+            Some(script_sig) => script_sig,
+            None => return Err(format!("Missing field `scriptSig`")),
+        };
+
+        Ok(TxIn {
+            previous_output: try!(OutPoint::from_json(json)),
+            script: try!(get_hex(script_sig, "hex").map_err(|e| format!("scriptSig.{}", e))),
+            sequence: try!(get(json, "sequence")),
+        })
+    }
+}
+
+impl ToJson for TxOut {
+    fn to_json(&self) -> Json {
+        let script_pub_key = object! {
+            "asm" => to_asm(&self.pk_script, false),
+            "hex" => self.pk_script.to_hex(),
+            "type" => ScriptType::classify(&self.pk_script).name()
+        };
+
+        object! {
+            "value" => amount_to_json(self.value),
+            "scriptPubKey" => script_pub_key
+        }
+    }
+}
+
+impl FromJson for TxOut {
+    fn from_json(json: &Json) -> Result<TxOut, String> {
+        let script_pub_key = match json.find("scriptPubKey") {
+            Some(script_pub_key) => script_pub_key,
+            None => return Err(format!("Missing field `scriptPubKey`")),
+        };
+
+        Ok(TxOut {
+            value: try!(amount_from_json(json, "value")),
+            pk_script: try!(get_hex(script_pub_key, "hex")
+                                .map_err(|e| format!("scriptPubKey.{}", e))),
+        })
+    }
+}
+
+impl ToJson for TxMessage {
+    fn to_json(&self) -> Json {
+        let txid = self.hash();
+
+        let vout = self.tx_out.iter().enumerate().map(|(n, out)| {
+            insert(out.to_json(), "n", n.to_json())
+        }).collect::<Vec<_>>();
+
+        object! {
+            "txid" => txid,
+            "hash" => txid,
+            "version" => self.version,
+            "size" => self.encoded_len(),
+            "vsize" => self.vsize(),
+            "weight" => self.weight(),
+            "locktime" => self.lock_time,
+            "vin" => self.tx_in,
+            "vout" => vout
+        }
+    }
+}
+
+impl FromJson for TxMessage {
+    fn from_json(json: &Json) -> Result<TxMessage, String> {
+        Ok(TxMessage {
+            version: try!(get(json, "version")),
+            tx_in: try!(get(json, "vin")),
+            tx_out: try!(get(json, "vout")),
+            lock_time: try!(get(json, "locktime")),
+        })
+    }
+}
+
+impl ToJson for BlockMessage {
+    fn to_json(&self) -> Json {
+        let tx = self.txns.iter().map(|tx| {
+            insert(tx.to_json(), "hex", serialized(tx).to_hex().to_json())
+        }).collect::<Vec<_>>();
+
+        let mut json = self.metadata.to_json();
+        json = insert(json, "size", self.encoded_len().to_json());
+        json = insert(json, "strippedsize", self.encoded_len().to_json());
+        json = insert(json, "weight", self.weight().to_json());
+        json = insert(json, "nTx", self.txns.len().to_json());
+        insert(json, "tx", tx.to_json())
+    }
+}
+
+impl FromJson for BlockMessage {
+    fn from_json(json: &Json) -> Result<BlockMessage, String> {
+        Ok(BlockMessage {
+            metadata: try!(BlockMetadata::from_json(json)),
+            txns: try!(get(json, "tx")),
+        })
+    }
+}
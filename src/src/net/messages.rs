@@ -2,33 +2,42 @@ extern crate rand;
 
 use super::IPAddress;
 use super::Services;
+pub use super::timestamp::{Timestamp32, Timestamp64};
 
 use utils::CryptoUtils;
-use serialize::{Serialize, Serializer, Deserialize, Deserializer};
+use serialize::{Serialize, Serializer, Deserialize, Deserializer, VarInt, DecodeError,
+                DecodeErrorKind, MAX_SIZE};
 
 use std::ops::Deref;
 
-use std::io::{Cursor, SeekFrom, Seek, Write};
+use std::io::{Cursor, SeekFrom, Seek};
 use std::net::Ipv6Addr;
 
 use std::hash::{Hash, Hasher};
 
 use std::fmt;
 use std::str;
-use std::usize;
 
-use time;
+pub const MAX_INV_COUNT: u64 = 50000;
+pub const MAX_HEADERS_COUNT: u64 = 2000;
+pub const MAX_ADDR_COUNT: u64 = 1000;
+pub const MAX_LOCATOR_COUNT: u64 = 101;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum NetworkType {
     Main,
     TestNet,
     TestNet3,
+    TestNet4,
+    Signet,
+    // BIP325 signet with its own challenge, holds the magic derived from it
+    CustomSignet(u32),
+    Regtest,
     NameCoin,
     Unknown,
 }
 
-#[derive(PartialEq, Copy, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub enum Command {
     Addr,
     GetAddr,
@@ -46,7 +55,8 @@ pub enum Command {
     Headers,
     Block,
     FilterLoad,
-    Unknown,
+    // Any other command, with its raw NUL padded name
+    Unknown([u8; 12]),
 }
 
 type Bytes = Vec<u8>;
@@ -62,49 +72,19 @@ impl Serialize for Ipv6Addr {
         }
     }
 
-    fn size() -> usize { 16 }
+    fn encoded_len(&self) -> usize { 16 }
 }
 
 impl Serialize for Services {
     fn serialize(&self, serializer: &mut Serializer) {
-        let data = if self.node_network { 1 } else { 0 };
-        serializer.serialize_u(data, 8);
-    }
-
-    fn size() -> usize { 8 }
-}
-
-impl Deserialize for IPAddress {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let services: Services = try!(Deserialize::deserialize(deserializer));
-        let address: Ipv6Addr  = try!(Deserialize::deserialize(deserializer));
-
-        // The port is encoded in big endian
-        let mut data = [0; 2];
-        try!(deserializer.read_ex(&mut data));
-
-        let port = deserializer.to_u_slice(&[data[1], data[0]]) as u16;
-
-        Ok(IPAddress::new(services, address, port))
-    }
-}
-
-impl Serialize for IPAddress {
-    fn serialize(&self, serializer: &mut Serializer) {
-        self.services.serialize(serializer);
-        self. address.serialize(serializer);
-
-        // The port is encoded in big endian
-        let data = serializer.to_bytes(self.port as u64);
-        serializer.push(data[1]);
-        serializer.push(data[0]);
+        self.bits().serialize(serializer);
     }
 
-    fn size() -> usize { Services::size() + Ipv6Addr::size() + u16::size() }
+    fn encoded_len(&self) -> usize { 8 }
 }
 
 impl Deserialize for Ipv6Addr {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         let mut data = [0; 16];
         try!(deserializer.read_ex(&mut data));
 
@@ -119,49 +99,8 @@ impl Deserialize for Ipv6Addr {
 }
 
 impl Deserialize for Services {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let data = try!(u64::deserialize(deserializer));
-        Ok(Services::new(data == 1))
-    }
-}
-
-#[derive(PartialEq, Debug, Clone, Copy)]
-pub struct ShortFormatTm {
-    data: time::Tm,
-}
-
-impl ShortFormatTm {
-    pub fn new(data: time::Tm) -> ShortFormatTm {
-        ShortFormatTm {
-            data: data,
-        }
-    }
-
-    pub fn as_tm(&self) -> time::Tm {
-        self.data
-    }
-}
-
-impl Deref for ShortFormatTm {
-    type Target = time::Tm;
-
-    fn deref(&self) -> &time::Tm {
-        &self.data
-    }
-}
-
-impl Serialize for ShortFormatTm {
-    fn serialize(&self, serializer: &mut Serializer) {
-        serializer.serialize_u(self.data.to_timespec().sec as u64, 4);
-    }
-
-    fn size() -> usize { 4 }
-}
-
-impl Deserialize for ShortFormatTm {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let sec = try!(deserializer.to_u_fixed(4));
-        Ok(ShortFormatTm::new(time::at_utc(time::Timespec::new(sec as i64, 0))))
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        u64::deserialize(deserializer).map(Services::new)
     }
 }
 
@@ -212,294 +151,474 @@ impl Serialize for BitcoinHash {
         self.data.serialize(serializer);
     }
 
-    fn size() -> usize { <[u8; 32]>::size() }
+    fn encoded_len(&self) -> usize { self.data.encoded_len() }
 }
 
 impl Deserialize for BitcoinHash {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         Ok(BitcoinHash {
             data: try!(Deserialize::deserialize(deserializer)),
         })
     }
 }
 
-impl Deserialize for Command {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+const KNOWN_COMMANDS: [Command; 16] = [
+    Command::Addr, Command::GetAddr, Command::Version, Command::Verack, Command::Tx,
+    Command::Inv, Command::Ping, Command::Pong, Command::Reject, Command::NotFound,
+    Command::GetData, Command::GetHeaders, Command::Block, Command::GetBlocks,
+    Command::Headers, Command::FilterLoad];
+
+impl Command {
+    pub fn name(&self) -> &str {
+        match self {
+            &Command::Addr        => "addr",
+            &Command::GetAddr     => "getaddr",
+            &Command::Version     => "version",
+            &Command::Verack      => "verack",
+            &Command::Tx          => "tx",
+            &Command::Inv         => "inv",
+            &Command::Ping        => "ping",
+            &Command::Pong        => "pong",
+            &Command::Reject      => "reject",
+            &Command::NotFound    => "notfound",
+            &Command::GetData     => "getdata",
+            &Command::GetHeaders  => "getheaders",
+            &Command::Block       => "block",
+            &Command::GetBlocks   => "getblocks",
+            &Command::Headers     => "headers",
+            &Command::FilterLoad  => "filterload",
+            &Command::Unknown(ref bytes) => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                str::from_utf8(&bytes[..end]).unwrap_or("")
+            },
+        }
+    }
+
+    /// Known commands by name, anything else that fits in the header as
+    /// printable ASCII becomes `Command::Unknown`.
+    pub fn from_name(name: &str) -> Option<Command> {
+        if let Some(command) = KNOWN_COMMANDS.iter().find(|c| c.name() == name) {
+            return Some(*command);
+        }
+
+        if name.is_empty() || name.len() > 12 || name.bytes().any(|b| b < 0x20 || b > 0x7e) {
+            return None;
+        }
+
         let mut bytes = [0; 12];
-        try!(deserializer.read_ex(&mut bytes));
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Command::Unknown(bytes))
+    }
 
-        match &bytes {
-            b"version\0\0\0\0\0"      => Ok(Command::Version),
-            b"verack\0\0\0\0\0\0"     => Ok(Command::Verack),
-            b"tx\0\0\0\0\0\0\0\0\0\0" => Ok(Command::Tx),
-            b"inv\0\0\0\0\0\0\0\0\0"  => Ok(Command::Inv),
-            b"ping\0\0\0\0\0\0\0\0"   => Ok(Command::Ping),
-            b"pong\0\0\0\0\0\0\0\0"   => Ok(Command::Pong),
-            b"getaddr\0\0\0\0\0"      => Ok(Command::GetAddr),
-            b"notfound\0\0\0\0"       => Ok(Command::NotFound),
-            b"addr\0\0\0\0\0\0\0\0"   => Ok(Command::Addr),
-            b"reject\0\0\0\0\0\0"     => Ok(Command::Reject),
-            b"getblocks\0\0\0"        => Ok(Command::GetBlocks),
-            b"getheaders\0\0"         => Ok(Command::GetHeaders),
-            b"getdata\0\0\0\0\0"      => Ok(Command::GetData),
-            b"headers\0\0\0\0\0"      => Ok(Command::Headers),
-            b"block\0\0\0\0\0\0\0"    => Ok(Command::Block),
-            b"filterload\0\0"         => Ok(Command::Block),
-            command                   => {
-                println!("Warning: unknown command `{:?}`", str::from_utf8(command));
-                Ok(Command::Unknown)
+    pub fn is_known(&self) -> bool {
+        match self {
+            &Command::Unknown(_) => false,
+            _ => true,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 12] {
+        match self {
+            &Command::Unknown(bytes) => bytes,
+            command => {
+                let mut bytes = [0; 12];
+                bytes[..command.name().len()].copy_from_slice(command.name().as_bytes());
+                bytes
             },
         }
     }
 }
 
+impl Deserialize for Command {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let mut bytes = [0; 12];
+        try!(deserializer.read_ex(&mut bytes));
+
+        // Only exact, NUL padded names are known commands
+        let command = KNOWN_COMMANDS.iter().find(|c| c.to_bytes() == bytes);
+        Ok(command.cloned().unwrap_or(Command::Unknown(bytes)))
+    }
+}
+
 impl Serialize for Command {
     fn serialize(&self, serializer: &mut Serializer) {
-        let bytes = match self {
-            &Command::Addr        => b"addr\0\0\0\0\0\0\0\0",
-            &Command::GetAddr     => b"getaddr\0\0\0\0\0",
-            &Command::Version     => b"version\0\0\0\0\0",
-            &Command::Verack      => b"verack\0\0\0\0\0\0",
-            &Command::Tx          => b"tx\0\0\0\0\0\0\0\0\0\0",
-            &Command::Inv         => b"inv\0\0\0\0\0\0\0\0\0",
-            &Command::Ping        => b"ping\0\0\0\0\0\0\0\0",
-            &Command::Pong        => b"pong\0\0\0\0\0\0\0\0",
-            &Command::Reject      => b"reject\0\0\0\0\0\0",
-            &Command::NotFound    => b"notfound\0\0\0\0",
-            &Command::GetData     => b"getdata\0\0\0\0\0",
-            &Command::GetHeaders  => b"getheaders\0\0",
-            &Command::Block       => b"block\0\0\0\0\0\0\0",
-            &Command::GetBlocks   => b"getblocks\0\0\0",
-            &Command::Headers     => b"headers\0\0\0\0\0",
-            &Command::FilterLoad  => b"filterload\0\0",
-            &Command::Unknown     => unimplemented!(),
-        };
-
-        assert_eq!(bytes.len(), 12);
-        serializer.push_bytes(bytes);
-    }
-
-    fn size() -> usize { 12 }
+        serializer.push_bytes(&self.to_bytes());
+    }
+
+    fn encoded_len(&self) -> usize { 12 }
 }
 
 impl Deserialize for NetworkType {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         let data = try!(u32::deserialize(deserializer));
         match data {
             0xD9B4BEF9 => Ok(NetworkType::Main),
-            0xDAB5BFFA => Ok(NetworkType::TestNet),
+            // The original testnet shares its magic with regtest, which is
+            // the only one still in use. Headers received from peers are
+            // decoded with MessageHeader::deserialize_for, which takes the
+            // network from the configuration instead of guessing.
+            0xDAB5BFFA => Ok(NetworkType::Regtest),
             0x0709110B => Ok(NetworkType::TestNet3),
+            0x283F161C => Ok(NetworkType::TestNet4),
+            0x40CF030A => Ok(NetworkType::Signet),
             0xFEB4BEF9 => Ok(NetworkType::NameCoin),
-            _          => Err(format!("Unrecognized magic number {}", data)),
+            // Custom signets can't be told apart by their magic, their
+            // headers are decoded with MessageHeader::deserialize_for
+            _          => Err(deserializer.error(DecodeErrorKind::InvalidEnumValue(data as u64))),
         }
     }
 }
 
 impl Serialize for NetworkType {
     fn serialize(&self, serializer: &mut Serializer) {
-        let magic = match self {
+        serializer.serialize_u(self.magic() as u64, 4);
+    }
+
+    fn encoded_len(&self) -> usize { 4 }
+}
+
+impl NetworkType {
+    pub fn magic(&self) -> u32 {
+        match self {
             &NetworkType::Main      => 0xD9B4BEF9,
             &NetworkType::TestNet   => 0xDAB5BFFA,
             &NetworkType::TestNet3  => 0x0709110B,
+            &NetworkType::TestNet4  => 0x283F161C,
+            &NetworkType::Signet    => 0x40CF030A,
+            &NetworkType::CustomSignet(magic) => magic,
+            &NetworkType::Regtest   => 0xDAB5BFFA,
             &NetworkType::NameCoin  => 0xFEB4BEF9,
             // Uknown is only used internally and should
             // never be sent accross the network
             &NetworkType::Unknown   => unimplemented!(),
-        };
+        }
+    }
 
-        serializer.serialize_u(magic, 4);
+    // Version byte of Base58Check P2PKH addresses
+    pub fn pub_key_hash_prefix(&self) -> Option<u8> {
+        match self {
+            &NetworkType::Main      => Some(0x00),
+            &NetworkType::TestNet   => Some(0x6f),
+            &NetworkType::TestNet3  => Some(0x6f),
+            &NetworkType::TestNet4  => Some(0x6f),
+            &NetworkType::Signet    => Some(0x6f),
+            &NetworkType::CustomSignet(_) => Some(0x6f),
+            &NetworkType::Regtest   => Some(0x6f),
+            &NetworkType::NameCoin  => Some(0x34),
+            &NetworkType::Unknown   => None,
+        }
+    }
+
+    // Version byte of Base58Check P2SH addresses
+    pub fn script_hash_prefix(&self) -> Option<u8> {
+        match self {
+            &NetworkType::Main      => Some(0x05),
+            &NetworkType::TestNet   => Some(0xc4),
+            &NetworkType::TestNet3  => Some(0xc4),
+            &NetworkType::TestNet4  => Some(0xc4),
+            &NetworkType::Signet    => Some(0xc4),
+            &NetworkType::CustomSignet(_) => Some(0xc4),
+            &NetworkType::Regtest   => Some(0xc4),
+            &NetworkType::NameCoin  => Some(0x0d),
+            &NetworkType::Unknown   => None,
+        }
     }
 
-    fn size() -> usize { 4 }
+    // Human readable part of segwit addresses
+    pub fn bech32_hrp(&self) -> Option<&'static str> {
+        match self {
+            &NetworkType::Main      => Some("bc"),
+            &NetworkType::TestNet   => Some("tb"),
+            &NetworkType::TestNet3  => Some("tb"),
+            &NetworkType::TestNet4  => Some("tb"),
+            &NetworkType::Signet    => Some("tb"),
+            &NetworkType::CustomSignet(_) => Some("tb"),
+            &NetworkType::Regtest   => Some("bcrt"),
+            &NetworkType::NameCoin  => Some("nc"),
+            &NetworkType::Unknown   => None,
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+#[repr(u32)]
 pub enum InventoryVectorType {
-    ERROR,
-    MSG_TX,
-    MSG_BLOCK,
-    MSG_FILTERED_BLOCK,
+    ERROR = 0,
+    MSG_TX = 1,
+    MSG_BLOCK = 2,
+    MSG_FILTERED_BLOCK = 3,
 }
 
-impl Serialize for InventoryVectorType {
-    fn serialize(&self, serializer: &mut Serializer) {
-        let index: u32 = match self {
-            &InventoryVectorType::ERROR              => 0,
-            &InventoryVectorType::MSG_TX             => 1,
-            &InventoryVectorType::MSG_BLOCK          => 2,
-            &InventoryVectorType::MSG_FILTERED_BLOCK => 3,
-        };
+pub trait SerializeHash: Serialize {
+    fn serialize_hash(&self) -> (Vec<u8>, BitcoinHash) {
+        let mut buffer = Cursor::new(vec![]);
+        self.serialize(&mut buffer);
 
-        index.serialize(serializer);
+        let hash = CryptoUtils::sha256(&CryptoUtils::sha256(buffer.get_ref()));
+        (buffer.into_inner(), BitcoinHash::new(hash))
     }
 
-    fn size() -> usize { u32::size() }
+    fn hash(&self) -> BitcoinHash {
+        self.serialize_hash().1
+    }
 }
 
-impl Deserialize for InventoryVectorType {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let index = try!(u32::deserialize(deserializer));
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct FilterLoadMessage {
+    pub filter: Vec<u8>,
+    pub n_hash_funcs: u32,
+    pub n_tweak: u32,
+    pub n_flags: u8,
+}
 
-        match index {
-            0 => Ok(InventoryVectorType::ERROR),
-            1 => Ok(InventoryVectorType::MSG_TX),
-            2 => Ok(InventoryVectorType::MSG_BLOCK),
-            3 => Ok(InventoryVectorType::MSG_FILTERED_BLOCK),
-            vector_type => Err(format!("Unexpected inventory vector type = {}", vector_type)),
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct MessageHeader {
+    pub network_type: NetworkType,
+    pub command: Command,
+    pub length: u32,
+    pub checksum: [u8; 4],
+}
+
+impl MessageHeader {
+    /// Decodes a header sent on `network_type`, any other magic is an error.
+    /// Unlike `deserialize` it also accepts the magic of a custom signet.
+    pub fn deserialize_for(deserializer: &mut Deserializer, network_type: NetworkType)
+        -> Result<MessageHeader, DecodeError> {
+        let magic = try!(u32::deserialize(deserializer)
+                         .map_err(|e| e.in_struct("MessageHeader", "network_type")));
+        if magic != network_type.magic() {
+            return Err(deserializer.error(DecodeErrorKind::InvalidEnumValue(magic as u64))
+                       .in_struct("MessageHeader", "network_type"));
         }
+
+        Ok(MessageHeader {
+            network_type: network_type,
+            command: try!(Command::deserialize(deserializer)
+                          .map_err(|e| e.in_struct("MessageHeader", "command"))),
+            length: try!(u32::deserialize(deserializer)
+                         .map_err(|e| e.in_struct("MessageHeader", "length"))),
+            checksum: try!(Deserialize::deserialize(deserializer)
+                           .map_err(|e| e.in_struct("MessageHeader", "checksum"))),
+        })
     }
 }
 
-pub trait SerializeHash: Serialize {
-    fn serialize_hash(&self) -> (Vec<u8>, BitcoinHash) {
-        let mut buffer = Cursor::new(vec![]);
-        self.serialize(&mut buffer);
+/// Message with a command we don't implement, the payload is kept as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownMessage {
+    pub command: Command,
+    pub payload: Vec<u8>,
+}
 
-        let hash = CryptoUtils::sha256(&CryptoUtils::sha256(buffer.get_ref()));
-        (buffer.into_inner(), BitcoinHash::new(hash))
+impl Serialize for UnknownMessage {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.push_bytes(&self.payload);
     }
 
-    fn hash(&self) -> BitcoinHash {
-        self.serialize_hash().1
+    fn encoded_len(&self) -> usize { self.payload.len() }
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct VersionMessage {
+    pub version: i32,
+    pub services: Services,
+    pub timestamp: Timestamp64,
+    pub addr_recv: IPAddress,
+    pub addr_from: IPAddress,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    // Added by BIP37, older peers don't send it
+    #[bitcoin(optional)]
+    pub relay: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct PingMessage {
+    pub nonce: u64,
+}
+
+impl PingMessage {
+    pub fn new(nonce: u64) -> PingMessage {
+        PingMessage {
+            nonce: nonce,
+        }
     }
 }
 
-macro_rules! message {
-    ($name:ident ; $($element: ident: $ty: ty),*) => {
-        #[derive(Debug, Clone, PartialEq)]
-        pub struct $name { $(pub $element: $ty),* }
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct AddrMessage {
+    #[bitcoin(max_count = "MAX_ADDR_COUNT")]
+    pub addr_list: Vec<(Timestamp32, IPAddress)>,
+}
 
-        impl $name {
-            pub fn new($($element: $ty),*) -> $name {
-                $name {
-                    $($element: $element),*
-                }
-            }
+impl AddrMessage {
+    pub fn new(addr_list: Vec<(Timestamp32, IPAddress)>) -> AddrMessage {
+        AddrMessage {
+            addr_list: addr_list,
         }
+    }
+}
 
-        impl SerializeHash for $name {}
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct RejectMessage {
+    pub message: Command,
+    pub ccode: u8,
+    pub reason: String,
+}
 
-        impl Serialize for $name {
-            fn serialize(&self, serializer: &mut Serializer) {
-                $(self.$element.serialize(serializer));*
-            }
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct HeadersMessage {
+    // Every header is followed by a transaction count, which is always 0
+    #[bitcoin(max_count = "MAX_HEADERS_COUNT")]
+    pub headers: Vec<(BlockMetadata, VarInt)>,
+}
 
-            fn size() -> usize {
-                $(<$ty as Serialize>::size() +)* 0
-            }
+impl HeadersMessage {
+    pub fn new(headers: Vec<(BlockMetadata, VarInt)>) -> HeadersMessage {
+        HeadersMessage {
+            headers: headers,
         }
+    }
+}
 
-        impl Deserialize for $name {
-            fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-                Ok($name {
-                    $($element: try!(Deserialize::deserialize(deserializer))),*
-                })
-            }
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    #[bitcoin(max_count = "MAX_LOCATOR_COUNT")]
+    pub block_locators: Vec<BitcoinHash>,
+    pub hash_stop: BitcoinHash,
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct InventoryVector {
+    pub type_: InventoryVectorType,
+    pub hash: BitcoinHash,
+}
+
+impl InventoryVector {
+    pub fn new(type_: InventoryVectorType, hash: BitcoinHash) -> InventoryVector {
+        InventoryVector {
+            type_: type_,
+            hash: hash,
         }
     }
 }
 
-message!(FilterLoadMessage;
-    filter: Vec<u8>,
-    n_hash_funcs: u32,
-    n_tweak: u32,
-    n_flags: u8
-);
-
-message!(MessageHeader;
-    network_type: NetworkType,
-    command: Command,
-    length: u32,
-    checksum: [u8; 4]
-);
-
-message!(VersionMessage;
-    version: i32,
-    services: Services,
-    timestamp: time::Tm,
-    addr_recv: IPAddress,
-    addr_from: IPAddress,
-    nonce: u64,
-    user_agent: String,
-    start_height: i32,
-    relay: bool
-);
-
-message!(PingMessage;
-    nonce: u64
-);
-
-message!(AddrMessage;
-    addr_list: Vec<(ShortFormatTm, IPAddress)>
-);
-
-message!(RejectMessage;
-    message: Command,
-    ccode: u8,
-    reason: String
-);
-
-message!(HeadersMessage;
-    headers: Vec<(BlockMetadata, u64)>
-);
-
-message!(GetHeadersMessage;
-    version: u32,
-    block_locators: Vec<BitcoinHash>,
-    hash_stop: BitcoinHash
-);
-
-message!(InventoryVector;
-    type_: InventoryVectorType,
-    hash: BitcoinHash
-);
-
-message!(InvMessage;
-    inventory: Vec<InventoryVector>
-);
-
-message!(OutPoint;
-    hash: BitcoinHash,
-    index: u32
-);
-
-message!(TxIn;
-    previous_output: OutPoint,
-    script: Vec<u8>,
-    sequence: u32
-);
-
-message!(TxOut;
-    value: i64,
-    pk_script: Vec<u8>
-);
-
-message!(TxMessage;
-    version: u32,
-    tx_in: Vec<TxIn>,
-    tx_out: Vec<TxOut>,
-    lock_time: u32
-);
-
-message!(BlockMetadata;
-    version: i32,
-    prev_block: BitcoinHash,
-    merkle_root: BitcoinHash,
-    timestamp: ShortFormatTm,
-    bits: u32,
-    nonce: u32
-);
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct InvMessage {
+    #[bitcoin(max_count = "MAX_INV_COUNT")]
+    pub inventory: Vec<InventoryVector>,
+}
 
-#[derive(Debug, Clone, PartialEq)]
+impl InvMessage {
+    pub fn new(inventory: Vec<InventoryVector>) -> InvMessage {
+        InvMessage {
+            inventory: inventory,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct OutPoint {
+    pub hash: BitcoinHash,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(hash: BitcoinHash, index: u32) -> OutPoint {
+        OutPoint {
+            hash: hash,
+            index: index,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script: Vec<u8>,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct TxOut {
+    pub value: i64,
+    pub pk_script: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct TxMessage {
+    pub version: u32,
+    pub tx_in: Vec<TxIn>,
+    pub tx_out: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl TxMessage {
+    // Without witness data every byte counts four times
+    pub fn weight(&self) -> usize { self.encoded_len() * 4 }
+    pub fn vsize(&self) -> usize { (self.weight() + 3) / 4 }
+}
+
+impl SerializeHash for TxMessage {}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
+pub struct BlockMetadata {
+    pub version: i32,
+    pub prev_block: BitcoinHash,
+    pub merkle_root: BitcoinHash,
+    pub timestamp: Timestamp32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl SerializeHash for BlockMetadata {}
+
+#[derive(Debug, Clone, PartialEq, BitcoinEncode, BitcoinDecode)]
 pub struct BlockMessage {
     pub metadata: BlockMetadata,
     pub txns: Vec<TxMessage>,
 }
 
+/// Root of the merkle tree over `hashes`, the last one is paired with
+/// itself on levels with an odd count.
+pub fn merkle_root(hashes: &[BitcoinHash]) -> BitcoinHash {
+    merkle_root_mutated(hashes).0
+}
+
+/// Like `merkle_root`, also tells whether two equal hashes are paired on
+/// some level. Such a tree has the root of a list with fewer hashes, blocks
+/// with one are invalid even though their header is fine (CVE-2012-2459).
+pub fn merkle_root_mutated(hashes: &[BitcoinHash]) -> (BitcoinHash, bool) {
+    let mut level = hashes.to_vec();
+    if level.is_empty() {
+        return (BitcoinHash::new([0; 32]), false);
+    }
+
+    let mut mutated = false;
+    while level.len() > 1 {
+        mutated |= level.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        level = level.chunks(2).map(|pair| {
+            let mut data = pair[0].to_vec();
+            data.extend(pair[pair.len() - 1].iter());
+            BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(&data)))
+        }).collect();
+    }
+
+    (level[0], mutated)
+}
+
 impl BlockMessage {
+    pub fn compute_merkle_root(&self) -> BitcoinHash {
+        merkle_root(&self.txns.iter().map(|tx| tx.hash()).collect::<Vec<_>>())
+    }
+
     pub fn prev_block(&self) -> &BitcoinHash { &self.metadata.prev_block }
     pub fn into_metadata(self) -> BlockMetadata { self.metadata }
+    pub fn weight(&self) -> usize { self.encoded_len() * 4 }
+    pub fn vsize(&self) -> usize { (self.weight() + 3) / 4 }
 }
 
 impl SerializeHash for BlockMessage {
@@ -513,46 +632,31 @@ impl SerializeHash for BlockMessage {
     }
 }
 
-impl Serialize for BlockMessage {
-    fn serialize(&self, serializer: &mut Serializer) {
-        self.metadata.serialize(serializer);
-        self.txns    .serialize(serializer);
-    }
-
-    fn size() -> usize { usize::MAX }
-}
-
-impl Deserialize for BlockMessage {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        Ok(BlockMessage {
-            metadata: try!(Deserialize::deserialize(deserializer)),
-            txns:     try!(Deserialize::deserialize(deserializer)),
-        })
-    }
-}
-
 pub fn get_serialized_message(network_type: NetworkType,
                               command: Command,
-                              message: Option<Box<Serialize>>) -> Vec<u8> {
-    let mut buffer = Cursor::new(vec![]);
-    message.map(|m| m.serialize(&mut buffer));
+                              message: Option<Box<Serialize>>) -> Result<Vec<u8>, String> {
+    let length = message.as_ref().map_or(0, |m| m.encoded_len());
+    if length as u64 > MAX_SIZE {
+        return Err(format!("Message of {} bytes exceeds the maximum size", length));
+    }
+
+    let mut payload = Vec::with_capacity(length);
+    message.map(|m| m.serialize(&mut payload));
 
-    let checksum = CryptoUtils::sha256(&CryptoUtils::sha256(buffer.get_ref()));
+    let checksum = CryptoUtils::sha256(&CryptoUtils::sha256(&payload));
 
     let header = MessageHeader {
         network_type: network_type,
         command: command,
-        length: buffer.get_ref().len() as u32,
+        length: length as u32,
         checksum: [checksum[0], checksum[1], checksum[2], checksum[3]],
     };
 
-    let mut header_buffer = Cursor::new(vec![]);
-    header.serialize(&mut header_buffer);
+    let mut result = Vec::with_capacity(header.encoded_len() + length);
+    header.serialize(&mut result);
+    result.extend(payload);
 
-    let mut result = header_buffer.into_inner();
-    result.extend(buffer.into_inner());
-
-    result
+    Ok(result)
 }
 
 #[cfg(test)]
@@ -561,7 +665,7 @@ mod tests {
     use std::io::Cursor;
     use super::*;
     use utils::Debug;
-    use serialize::{Deserialize, Serialize};
+    use serialize::{Deserialize, Serialize, DecodeErrorKind};
 
     #[test]
     fn test_real_tx_fd_length_script() {
@@ -588,4 +692,87 @@ mod tests {
 
         println!("{:?}", tx_obj);
     }
+
+    #[test]
+    fn test_decode_error_path() {
+        let tx = "01000000\
+                  02\
+                  1111111111111111111111111111111111111111111111111111111111111111\
+                  00000000\
+                  00\
+                  ffffffff\
+                  2222222222222222222222222222222222222222222222222222222222222222\
+                  01000000\
+                  05aabb".from_hex().unwrap();
+
+        let mut cursor = Cursor::new(tx);
+        let error = TxMessage::deserialize(&mut cursor).unwrap_err();
+
+        assert_eq!(error.kind(), &DecodeErrorKind::UnexpectedEof);
+        assert_eq!(error.offset(), 85);
+        assert_eq!(error.path(), "TxMessage.tx_in[1].script[2]");
+    }
+
+    #[test]
+    fn test_decode_error_invalid_enum() {
+        let inv = "0109000000\
+                   1111111111111111111111111111111111111111111111111111111111111111"
+            .from_hex().unwrap();
+
+        let mut cursor = Cursor::new(inv);
+        let error = InvMessage::deserialize(&mut cursor).unwrap_err();
+
+        assert_eq!(error.kind(), &DecodeErrorKind::InvalidEnumValue(9));
+        assert_eq!(error.offset(), 5);
+        assert_eq!(error.path(), "InvMessage.inventory[0].type_");
+        assert_eq!(format!("{}", error),
+                   "invalid enum value 9 at offset 5 in `InvMessage.inventory[0].type_`");
+    }
+
+    #[test]
+    fn test_header_magic() {
+        let custom = NetworkType::CustomSignet(0x12345678);
+        let header = get_serialized_message(custom, Command::Verack, None).unwrap();
+
+        // Only the network it's expected on accepts a custom signet magic
+        let error = MessageHeader::deserialize(&mut Cursor::new(&header)).unwrap_err();
+        assert_eq!(error.kind(), &DecodeErrorKind::InvalidEnumValue(0x12345678));
+        let decoded = MessageHeader::deserialize_for(&mut Cursor::new(&header), custom).unwrap();
+        assert_eq!((decoded.network_type, decoded.command), (custom, Command::Verack));
+
+        let error = MessageHeader::deserialize_for(&mut Cursor::new(&header), NetworkType::Signet)
+            .unwrap_err();
+        assert_eq!(error.kind(), &DecodeErrorKind::InvalidEnumValue(0x12345678));
+        assert_eq!(error.path(), "MessageHeader.network_type");
+
+        let other = NetworkType::CustomSignet(0x12345679);
+        assert!(MessageHeader::deserialize_for(&mut Cursor::new(&header), other).is_err());
+    }
+
+    #[test]
+    fn test_header_network_round_trip() {
+        let networks = [NetworkType::Main, NetworkType::TestNet, NetworkType::TestNet3,
+                        NetworkType::TestNet4, NetworkType::Signet,
+                        NetworkType::CustomSignet(0x12345678), NetworkType::Regtest,
+                        NetworkType::NameCoin];
+
+        for &network in networks.iter() {
+            let header = get_serialized_message(network, Command::Verack, None).unwrap();
+            let decoded = MessageHeader::deserialize_for(&mut Cursor::new(&header), network)
+                .unwrap();
+            assert_eq!(decoded.network_type, network);
+
+            // Without a configured network the magic can be ambiguous, but
+            // whatever it decodes to must encode back to the same magic
+            if let Ok(decoded) = MessageHeader::deserialize(&mut Cursor::new(&header)) {
+                assert_eq!(decoded.network_type.magic(), network.magic());
+            }
+        }
+
+        // Testnet and regtest share a magic, only the configuration tells them apart
+        let header = get_serialized_message(NetworkType::TestNet, Command::Verack, None).unwrap();
+        let decoded = MessageHeader::deserialize_for(&mut Cursor::new(&header),
+                                                     NetworkType::Regtest).unwrap();
+        assert_eq!(decoded.network_type, NetworkType::Regtest);
+    }
 }
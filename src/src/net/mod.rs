@@ -1,29 +1,37 @@
 mod rpcengine;
 mod store;
+mod import;
+mod timestamp;
+mod services;
 mod expiring_cache;
+mod pow;
+mod header_chain;
+mod download;
+mod addrman;
+mod connman;
+mod eviction;
 
 pub mod messages;
+pub mod json;
+pub mod block_ref;
+pub mod chain_params;
 pub mod p2pclient;
+pub mod signet;
+pub mod generate;
+pub mod auxpow;
+pub mod banlist;
 
-use std::net;
-
-#[derive(PartialEq, Copy, Clone, Debug)]
-pub struct Services {
-    node_network: bool
-}
+pub use self::services::{Services, Fetch, NETWORK_LIMITED_BLOCKS, NODE_NONE, NODE_NETWORK,
+                         NODE_BLOOM, NODE_WITNESS, NODE_COMPACT_FILTERS, NODE_NETWORK_LIMITED,
+                         NODE_P2P_V2};
 
-impl Services {
-    pub fn new(node_network: bool) -> Services {
-        Services {
-            node_network: node_network,
-        }
-    }
-}
+use std::net;
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, BitcoinEncode, BitcoinDecode)]
 pub struct IPAddress {
     services: Services,
     pub address: net::Ipv6Addr,
+    #[bitcoin(big_endian)]
     pub port: u16,
 }
 
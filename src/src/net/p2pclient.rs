@@ -1,33 +1,107 @@
 extern crate mio;
 extern crate rand;
 
-use time;
-use time::Duration;
+use rand::Rng;
+use time::{Duration, SteadyTime};
 
+use std::cmp;
+use std::fmt;
 use std::io::Cursor;
 use std::fs::File;
-use std::net::ToSocketAddrs;
-use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::sync::{Mutex, MutexGuard, Arc};
 use std::thread;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use mio::Sender;
 use mio::tcp;
 
-use utils::Debug;
-use serialize::{Serialize, Deserialize};
+use serialize::{Serialize, Deserialize, DecodeError, DecodeErrorKind, VarInt};
 
 use super::IPAddress;
-use super::Services;
+use super::{Services, Fetch, NODE_NETWORK, NODE_NONE};
+use super::addrman::{self, AddrMan};
+use super::banlist::{BanEntry, BanList, SubNet};
+use super::connman::{self, ConnectionManager, ConnectionType, MAX_BLOCK_RELAY, MAX_CONNECTIONS,
+                     MAX_FULL_RELAY};
+use super::auxpow::{self, AuxPow, AuxPowHeader, AuxPowHeadersMessage, AuxPowParams};
+use super::block_ref::BlockRef;
+use super::chain_params::ChainParams;
 use super::expiring_cache::ExpiringCache;
+use super::download::{BlockDownloader, BLOCK_DOWNLOAD_WINDOW};
+use super::eviction::{self, EvictionCandidate};
 use super::expiring_cache::Timeout;
+use super::header_chain::{HeaderChain, HeaderError};
+use super::import::BlockImporter;
 use super::messages::*;
-use super::rpcengine::Message;
+use super::rpcengine::{FrameError, Message};
 use super::rpcengine::RPCEngine;
 use super::rpcengine;
+use super::signet;
 use super::store::BlockStore;
 
+/// Handles a command the client doesn't implement, returns the messages to
+/// send back to the peer.
+pub trait CommandHandler: Send + Sync {
+    fn handle(&self, message: &UnknownMessage) -> Vec<UnknownMessage>;
+}
+
+impl<F> CommandHandler for F where F: Fn(&UnknownMessage) -> Vec<UnknownMessage> + Send + Sync {
+    fn handle(&self, message: &UnknownMessage) -> Vec<UnknownMessage> {
+        self(message)
+    }
+}
+
+pub struct CommandHandlers {
+    handlers: HashMap<Command, Box<CommandHandler>>,
+}
+
+impl CommandHandlers {
+    pub fn new() -> CommandHandlers {
+        CommandHandlers {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Built-in commands can't be overridden.
+    pub fn register(&mut self, name: &str, handler: Box<CommandHandler>) -> Result<(), String> {
+        match Command::from_name(name) {
+            Some(command @ Command::Unknown(_)) => {
+                self.handlers.insert(command, handler);
+                Ok(())
+            },
+            Some(_) => Err(format!("`{}` is handled by the client", name)),
+            None => Err(format!("Invalid command `{}`", name)),
+        }
+    }
+
+    pub fn get(&self, command: &Command) -> Option<&CommandHandler> {
+        self.handlers.get(command).map(|handler| &**handler)
+    }
+}
+
+/// What peers must offer for us to stay connected.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerRequirements {
+    pub min_version: i32,
+    // Only asked of the peers filling our outbound slots, inbound peers may
+    // well be light clients
+    pub services: Services,
+}
+
+impl PeerRequirements {
+    pub fn new() -> PeerRequirements {
+        PeerRequirements {
+            min_version: MIN_PEER_VERSION,
+            services: NODE_NETWORK,
+        }
+    }
+}
+
 struct BitcoinClient {
     version: i32,
     services: Services,
@@ -35,48 +109,155 @@ struct BitcoinClient {
     state: Arc<Mutex<State>>,
     channel: mio::Sender<Message>,
     network_type: NetworkType,
+    // Signet blocks must satisfy it
+    signet_challenge: Option<Vec<u8>>,
+    // Merge mined chains prove their work with an AuxPoW
+    auxpow: Option<AuxPowParams>,
+    handlers: CommandHandlers,
+    // Off when we were told which peer to connect to
+    automatic_connections: bool,
+    requirements: PeerRequirements,
 }
 
 struct State {
     peers: HashMap<mio::Token, Peer>,
     tx_store: HashMap<BitcoinHash, TxMessage>,
     block_store: BlockStore,
+    // Validated headers, blocks are downloaded along its best chain
+    headers: HeaderChain,
+    downloads: BlockDownloader,
+    // Transactions we asked for
     pending_inv: ExpiringCache<BitcoinHash>,
-}
-
-#[derive(PartialEq, Copy, Clone, Debug)]
-enum ConnectionType {
-    Inbound,
-    Outbound,
+    addrman: AddrMan,
+    peers_path: PathBuf,
+    // Addresses changed since they were last saved
+    addresses_dirty: bool,
+    connman: ConnectionManager,
+    banlist: BanList,
+    banlist_path: PathBuf,
+    // Addresses of peers that misbehaved, we don't connect to them again
+    discouraged: HashSet<Ipv6Addr>,
+    // Our last block and when it changed
+    tip: BitcoinHash,
+    tip_changed: SteadyTime,
+    // A tip older than this may mean we're cut off from the network
+    stale_tip_age: Duration,
+    // Keys the network groups that protect inbound peers from eviction
+    group_key: RandomState,
 }
 
 #[derive(Debug)]
 struct Peer {
-    ping_time: time::Tm,
+    // When we sent the last ping, its nonce is 0 once the pong arrived
+    ping_time: SteadyTime,
     ping: i64,
     ping_data: u64,
+    // Lowest round trip time in milliseconds
+    min_ping: Option<i64>,
+    connected_time: SteadyTime,
+    // The header with most work we know the peer has
+    best_known: Option<BitcoinHash>,
+    // Last time it was the first to tell us about a block or transaction
+    last_block_time: Option<SteadyTime>,
+    last_tx_time: Option<SteadyTime>,
+    chain_sync: Option<ChainSync>,
+    // Outbound peers on our chain that aren't evicted for falling behind
+    protected: bool,
     version: Option<VersionMessage>,
     verak_received: bool,
+    // Nonce of the version message we sent, it tells when we connected to
+    // ourselves
+    local_nonce: u64,
+    // Seconds its clock is ahead of ours
+    time_offset: i64,
     connection_type: ConnectionType,
-    waiting_for_blocks: Timeout<bool>,
+    address: IPAddress,
+    waiting_for_headers: Timeout<bool>,
+    // Headers messages in a row that didn't connect to our chain
+    unconnecting_headers: u32,
+    misbehavior: u32,
+}
+
+// An outbound peer whose best header has less work than our tip has until
+// `timeout` to catch up to `work_header`, after that we ask for its headers
+// once more.
+#[derive(Debug)]
+struct ChainSync {
+    timeout: SteadyTime,
+    work_header: BitcoinHash,
+    sent_getheaders: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Misbehavior {
+    InvalidHeader,
+    InvalidBlock,
+    OversizedMessage,
+    // Too many headers messages in a row that didn't connect
+    UnconnectingHeaders,
+    // Blocks or transactions we didn't ask for
+    UnrequestedData,
 }
 
+impl Misbehavior {
+    fn score(&self) -> u32 {
+        match *self {
+            Misbehavior::InvalidHeader => 100,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::OversizedMessage => 20,
+            Misbehavior::UnconnectingHeaders => 20,
+            Misbehavior::UnrequestedData => 20,
+        }
+    }
+}
+
+// Peers are discouraged once their misbehavior adds up to this
+const DISCOURAGEMENT_THRESHOLD: u32 = 100;
+// Headers messages in a row that don't connect before the peer is scored
+const MAX_UNCONNECTING_HEADERS: u32 = 10;
+
 impl State {
-    pub fn new(network_type: NetworkType, blocks_file: File) -> State {
-        State {
+    pub fn new(params: &ChainParams, blocks_file: File, peers_path: PathBuf,
+               banlist_path: PathBuf) -> State {
+        let mut state = State {
             peers: HashMap::new(),
             tx_store: HashMap::new(),
-            block_store: BlockStore::new(blocks_file, network_type),
+            block_store: BlockStore::new(blocks_file, params),
+            headers: HeaderChain::new(params),
+            downloads: BlockDownloader::new(),
             pending_inv: ExpiringCache::new(Duration::minutes(2), Duration::seconds(10)),
+            addrman: AddrMan::open(&peers_path),
+            peers_path: peers_path,
+            addresses_dirty: false,
+            // A slot is left for the feeler
+            connman: ConnectionManager::new(MAX_CONNECTIONS - MAX_FULL_RELAY - MAX_BLOCK_RELAY - 1),
+            banlist: BanList::open(&banlist_path),
+            banlist_path: banlist_path,
+            discouraged: HashSet::new(),
+            tip: params.genesis_hash,
+            tip_changed: SteadyTime::now(),
+            stale_tip_age: Duration::seconds(3 * params.pow_target_spacing),
+            group_key: RandomState::new(),
+        };
+
+        state.load_headers();
+        state
+    }
+
+    // Stored blocks were checked when we got them
+    pub fn load_headers(&mut self) {
+        for height in 1..self.block_store.height() + 1 {
+            let hash = self.block_store.get_hash_at_height(height).unwrap();
+            let header = self.block_store.get_metadata(hash).unwrap().clone();
+            self.headers.insert_trusted(header);
         }
     }
 
-    pub fn is_pending_inv(&mut self, hash: &BitcoinHash) -> bool{
+    pub fn is_pending_inv(&mut self, hash: &BitcoinHash) -> bool {
         self.pending_inv.has(hash)
     }
 
     pub fn add_inv(&mut self, hash: BitcoinHash) {
-        println!("inv for {:?}", hash);
         self.pending_inv.insert(hash);
     }
 
@@ -84,35 +265,448 @@ impl State {
         self.pending_inv.remove(hash);
     }
 
-    pub fn pending_inv_len(&self) -> usize { self.pending_inv.len() }
+    // Returns whether we asked the peer for it
+    pub fn received_block(&mut self, token: mio::Token, hash: &BitcoinHash) -> bool {
+        self.downloads.received(token, hash, SteadyTime::now())
+    }
+
+    pub fn block_not_found(&mut self, token: mio::Token, hash: &BitcoinHash) {
+        self.downloads.not_found(token, hash);
+    }
+
+    pub fn invalid_block(&mut self, token: mio::Token, hash: &BitcoinHash) {
+        self.downloads.invalid(token, hash);
+    }
+
+    pub fn add_downloader(&mut self, token: mio::Token) {
+        self.downloads.add_peer(token);
+    }
+
+    pub fn remove_peer(&mut self, token: mio::Token) {
+        self.peers.remove(&token);
+        self.connman.closed(token);
+        self.downloads.remove_peer(token);
+    }
 
     pub fn height(&self) -> usize { self.block_store.height() }
 
+    pub fn headers_height(&self) -> usize { self.headers.height() }
+
     pub fn block_locators(&self) -> Vec<BitcoinHash> {
-        self.block_store.block_locators()
+        self.headers.block_locators()
+    }
+
+    pub fn has_header(&self, hash: &BitcoinHash) -> bool {
+        self.headers.contains(hash)
+    }
+
+    pub fn accept_header(&mut self, header: &BlockMetadata, auxpow: Option<&AuxPow>)
+            -> Result<BitcoinHash, HeaderError> {
+        self.headers.accept(header, auxpow, Timestamp32::now().secs())
+    }
+
+    // Highest height where the stored best chain and the header best chain agree
+    fn fork_height(&self) -> usize {
+        let mut height = cmp::min(self.block_store.height(), self.headers.height());
+        while self.block_store.get_hash_at_height(height) !=
+              self.headers.get_hash_at_height(height) {
+            height -= 1;
+        }
+        height
+    }
+
+    // Hands out the blocks of the header best chain we still need, returns
+    // the peers that stalled and what to ask each peer for.
+    pub fn schedule_blocks(&mut self) -> (Vec<mio::Token>, Vec<(mio::Token, Vec<BitcoinHash>)>) {
+        let now = SteadyTime::now();
+        let stalled = self.downloads.release_stalled(now);
+
+        let start = self.fork_height() + 1;
+        let end = cmp::min(self.headers.height(), start + BLOCK_DOWNLOAD_WINDOW - 1);
+        let mut missing = vec![];
+
+        for height in start..end + 1 {
+            let hash = *self.headers.get_hash_at_height(height).unwrap();
+            if !self.block_store.has(&hash) && !self.downloads.is_in_flight(&hash) {
+                missing.push((height, hash));
+            }
+        }
+
+        let peers = &self.peers;
+        let requests = self.downloads.schedule(&missing, |token, height| {
+            peers.get(&token).map_or(false, |peer| peer.can_provide(Fetch::Block(height as i64)))
+        }, now);
+
+        (stalled, requests)
+    }
+
+    // Adds stored blocks that were waiting for their parent to the best chain
+    pub fn connect_blocks(&mut self) {
+        for height in self.fork_height() + 1..self.headers.height() + 1 {
+            let hash = *self.headers.get_hash_at_height(height).unwrap();
+            if !self.block_store.has(&hash) {
+                break;
+            }
+            self.block_store.connect(&hash);
+        }
     }
 
-    pub fn add_peer(&mut self, token: mio::Token, version: Option<VersionMessage>) -> ConnectionType {
+    // Height in the stored best chain of the first locator that's in it
+    pub fn find_fork(&self, locators: &[BitcoinHash]) -> Option<usize> {
+        locators.iter()
+            .filter_map(|hash| {
+                self.block_store.get_height(hash)
+                    .filter(|height| self.block_store.get_hash_at_height(*height) == Some(hash))
+            })
+            .next()
+    }
+
+    pub fn get_header(&self, hash: &BitcoinHash) -> Option<BlockMetadata> {
+        self.block_store.get_metadata(hash).cloned()
+    }
+
+    pub fn add_peer(&mut self, token: mio::Token, version: VersionMessage, time_offset: i64) {
         if let Some(peer) = self.peers.get_mut(&token) {
-            peer.version = version;
-            return ConnectionType::Outbound;
+            peer.time_offset = time_offset;
+            peer.version = Some(version);
+        }
+    }
+
+    pub fn sent_version(&mut self, token: mio::Token, nonce: u64) {
+        self.peers.get_mut(&token).map(|peer| peer.local_nonce = nonce);
+    }
+
+    // Whether we sent `nonce` to a peer we connected to
+    pub fn is_local_nonce(&self, nonce: u64) -> bool {
+        self.peers.values().any(|peer| {
+            peer.connection_type.is_outbound() && peer.local_nonce == nonce
+        })
+    }
+
+    // Median clock offset of our outbound peers, inbound ones are easy to
+    // come by for an attacker
+    pub fn median_time_offset(&self) -> i64 {
+        let mut offsets: Vec<_> = self.peers.values()
+            .filter(|peer| peer.connection_type.is_outbound() && peer.version.is_some())
+            .map(|peer| peer.time_offset)
+            .collect();
+        offsets.sort();
+
+        offsets.get(offsets.len() / 2).cloned().unwrap_or(0)
+    }
+
+    pub fn add_outbound_peer(&mut self, token: mio::Token, addr: &SocketAddr,
+                             address: IPAddress) -> ConnectionType {
+        let connection_type = self.connman.connected(token, addr);
+        println!("add_peer token={:?} type={:?}", token, connection_type);
+
+        self.addrman.attempt(&address, Timestamp32::now().secs());
+        self.peers.insert(token, Peer::new(address, connection_type));
+        connection_type
+    }
+
+    pub fn connection_type(&self, token: mio::Token) -> Option<ConnectionType> {
+        self.connman.connection_type(token)
+    }
+
+    pub fn is_connected_to(&self, address: &IPAddress) -> bool {
+        self.peers.values().any(|peer| peer.address == *address)
+    }
+
+    // Banned or discouraged
+    fn is_refused(&self, address: &Ipv6Addr) -> bool {
+        self.banlist.is_banned(address, Timestamp32::now().secs()) ||
+            self.discouraged.contains(address)
+    }
+
+    /// Adds to the misbehavior of a peer, returns whether to disconnect it.
+    /// Peers we were told to connect to are kept.
+    pub fn misbehaving(&mut self, token: mio::Token, misbehavior: Misbehavior) -> bool {
+        let peer = match self.peers.get_mut(&token) {
+            Some(peer) => peer,
+            None => return false,
+        };
+
+        peer.misbehavior += misbehavior.score();
+        println!("Misbehaving token={:?} {:?} score={}", token, misbehavior, peer.misbehavior);
+
+        if peer.misbehavior < DISCOURAGEMENT_THRESHOLD ||
+           peer.connection_type == ConnectionType::Manual {
+            return false;
+        }
+
+        self.discouraged.insert(peer.address.address);
+        true
+    }
+
+    // The peer has the block `hash`, `new` if nobody told us about it before
+    pub fn peer_has_block(&mut self, token: mio::Token, hash: &BitcoinHash, new: bool) {
+        let headers = &self.headers;
+        let peer = match self.peers.get_mut(&token) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        if peer.best_known.map_or(true, |best| headers.has_more_work(hash, &best)) {
+            peer.best_known = Some(*hash);
+        }
+        if new {
+            peer.last_block_time = Some(SteadyTime::now());
+        }
+    }
+
+    // Peers due for a ping and peers that didn't answer the last one in time
+    pub fn check_pings(&self, now: SteadyTime) -> (Vec<mio::Token>, Vec<mio::Token>) {
+        let ping = self.peers.iter()
+            .filter(|&(_, peer)| peer.verak_received && peer.needs_ping(now))
+            .map(|(token, _)| *token)
+            .collect();
+        let timed_out = self.peers.iter()
+            .filter(|&(_, peer)| peer.ping_timed_out(now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        (ping, timed_out)
+    }
+
+    /// Whether our tip didn't change for a while although we aren't waiting
+    /// for blocks. We may be cut off from the network, so we look for one
+    /// more full relay peer until it moves again.
+    pub fn check_stale_tip(&mut self, now: SteadyTime) -> bool {
+        let tip = *self.block_store.get_hash_at_height(self.block_store.height()).unwrap();
+        if tip != self.tip {
+            self.tip = tip;
+            self.tip_changed = now;
+        }
+
+        let stale = now - self.tip_changed > self.stale_tip_age && self.downloads.is_idle();
+        self.connman.set_extra_full_relay(stale);
+        stale
+    }
+
+    // With more full relay peers than slots, the one that told us about a new
+    // block the longest ago goes. Peers we just connected to or that are
+    // sending us blocks get a chance first.
+    pub fn extra_outbound_to_evict(&self, now: SteadyTime) -> Option<mio::Token> {
+        let full_relay: Vec<_> = self.peers.iter()
+            .filter(|&(_, peer)| peer.connection_type == ConnectionType::FullRelay)
+            .collect();
+        if full_relay.len() <= MAX_FULL_RELAY {
+            return None;
         }
 
-        match version {
-            Some(ver) => {
-                println!("add_peer token={:?} type={:?}", token, ConnectionType::Inbound);
-                self.peers.insert(token, Peer::new_inbound(ver));
-                ConnectionType::Inbound
+        full_relay.into_iter()
+            .min_by_key(|&(_, peer)| (peer.last_block_time, cmp::Reverse(peer.connected_time)))
+            .filter(|&(token, peer)| {
+                now - peer.connected_time > Duration::seconds(MIN_CONNECTION_TIME) &&
+                    self.downloads.peer_in_flight(*token) == 0
+            })
+            .map(|(token, _)| *token)
+    }
+
+    /// Outbound peers whose best header stays behind our tip are given some
+    /// time to catch up, then asked for headers. Returns who to ask and who
+    /// to disconnect.
+    pub fn check_chain_sync(&mut self, now: SteadyTime) -> (Vec<mio::Token>, Vec<mio::Token>) {
+        let tip = *self.headers.tip();
+        let headers = &self.headers;
+        let mut protected = self.peers.values().filter(|peer| peer.protected).count();
+        let (mut get_headers, mut disconnect) = (vec![], vec![]);
+
+        for (token, peer) in self.peers.iter_mut() {
+            match peer.connection_type {
+                ConnectionType::FullRelay | ConnectionType::BlockRelay => {},
+                _ => continue,
             }
-            None => {
-                println!("add_peer token={:?} type={:?}", token, ConnectionType::Outbound);
-                self.peers.insert(token, Peer::new_outbound());
-                ConnectionType::Outbound
+            if !peer.verak_received || peer.protected {
+                continue;
+            }
+
+            let has_work = |hash: &BitcoinHash| {
+                peer.best_known.map_or(false, |best| !headers.has_more_work(hash, &best))
+            };
+
+            if has_work(&tip) {
+                peer.chain_sync = None;
+                if protected < MAX_PROTECTED_OUTBOUND &&
+                   peer.connection_type == ConnectionType::FullRelay {
+                    peer.protected = true;
+                    protected += 1;
+                }
+                continue;
+            }
+
+            // The timer starts over whenever it catches up with what our tip was
+            let restart = peer.chain_sync.as_ref().map_or(true, |sync| has_work(&sync.work_header));
+            if restart {
+                peer.chain_sync = Some(ChainSync {
+                    timeout: now + Duration::seconds(CHAIN_SYNC_TIMEOUT),
+                    work_header: tip,
+                    sent_getheaders: false,
+                });
+                continue;
+            }
+
+            let sync = peer.chain_sync.as_mut().unwrap();
+            if now <= sync.timeout {
+                continue;
+            }
+
+            if sync.sent_getheaders {
+                disconnect.push(*token);
+            } else {
+                sync.sent_getheaders = true;
+                sync.timeout = now + Duration::seconds(HEADERS_RESPONSE_TIME);
+                get_headers.push(*token);
+            }
+        }
+
+        (get_headers, disconnect)
+    }
+
+    // Bans `subnet` until `until`, returns the peers in it
+    pub fn ban(&mut self, subnet: SubNet, until: u32) -> Result<Vec<mio::Token>, String> {
+        self.banlist.ban(subnet, Timestamp32::now().secs(), until);
+        try!(self.banlist.save(&self.banlist_path));
+
+        Ok(self.peers.iter()
+           .filter(|&(_, peer)| subnet.contains(&peer.address.address))
+           .map(|(token, _)| *token)
+           .collect())
+    }
+
+    pub fn unban(&mut self, subnet: &SubNet) -> Result<bool, String> {
+        if !self.banlist.unban(subnet) {
+            return Ok(false);
+        }
+
+        try!(self.banlist.save(&self.banlist_path));
+        Ok(true)
+    }
+
+    // Forgets expired bans
+    pub fn sweep_banlist(&mut self) {
+        if self.banlist.sweep(Timestamp32::now().secs()) {
+            if let Err(e) = self.banlist.save(&self.banlist_path) {
+                println!("Couldn't save {}: {}", self.banlist_path.display(), e);
+            }
+        }
+    }
+
+    // A handshake with a peer we connected to finished
+    pub fn outbound_ready(&mut self, token: mio::Token) {
+        let address = match self.peers.get(&token) {
+            Some(peer) if peer.connection_type.is_outbound() => Some(peer.address),
+            _ => None,
+        };
+
+        if let Some(address) = address {
+            let now = Timestamp32::now().secs();
+            self.addrman.good(&address, now);
+            self.addrman.connected(&address, now);
+            self.addresses_dirty = true;
+        }
+    }
+
+    // Addresses a peer sent us, the peer is their source
+    pub fn add_addresses(&mut self, token: mio::Token, addresses: &[(Timestamp32, IPAddress)]) {
+        let source = match self.peers.get(&token) {
+            Some(peer) => peer.address.address,
+            None => return,
+        };
+
+        if self.addrman.add(addresses, &source, Timestamp32::now().secs()) > 0 {
+            self.addresses_dirty = true;
+        }
+    }
+
+    pub fn get_addresses(&self) -> Vec<(Timestamp32, IPAddress)> {
+        self.addrman.get_addr(Timestamp32::now().secs())
+    }
+
+    // The next outbound slot to fill and an address for it from a network
+    // group we aren't connected to yet. Feelers check addresses we never
+    // connected to.
+    pub fn select_outbound(&self) -> Option<(IPAddress, ConnectionType)> {
+        let now = Timestamp32::now().secs();
+        let connection_type = match self.connman.next_outbound(now) {
+            Some(connection_type) => connection_type,
+            None => return None,
+        };
+
+        let new_only = connection_type == ConnectionType::Feeler;
+        (0..SELECT_TRIES).filter_map(|_| self.addrman.select(now, new_only))
+            .find(|address| !self.is_connected_to(address) &&
+                            !self.is_refused(&address.address) &&
+                            self.connman.is_group_free(&address.address))
+            .map(|address| (address, connection_type))
+    }
+
+    pub fn connecting(&mut self, addr: SocketAddr, connection_type: ConnectionType) {
+        self.connman.connecting(addr, connection_type, Timestamp32::now().secs());
+    }
+
+    /// Takes an inbound peer, when we're full another one may be evicted
+    /// for it. Returns whether it's accepted and who was evicted.
+    pub fn accept_inbound(&mut self, token: mio::Token,
+                          addr: &SocketAddr) -> (bool, Option<mio::Token>) {
+        let address = IPAddress::new(NODE_NONE, connman::to_ipv6(addr), addr.port());
+        if self.is_refused(&address.address) {
+            println!("Refusing connection from {}", addr);
+            return (false, None);
+        }
+
+        let mut evicted = None;
+        if self.connman.is_inbound_full() {
+            evicted = self.select_inbound_to_evict();
+            if let Some(evicted) = evicted {
+                self.remove_peer(evicted);
             }
         }
+
+        if !self.connman.accept_inbound(token, addr) {
+            return (false, evicted);
+        }
+
+        println!("add_peer token={:?} type={:?}", token, ConnectionType::Inbound);
+        self.peers.insert(token, Peer::new(address, ConnectionType::Inbound));
+        (true, evicted)
+    }
+
+    fn select_inbound_to_evict(&self) -> Option<mio::Token> {
+        let candidates = self.peers.iter()
+            .filter(|&(_, peer)| peer.connection_type == ConnectionType::Inbound)
+            .map(|(token, peer)| {
+                let mut hasher = self.group_key.build_hasher();
+                addrman::network_group(&peer.address.address).hash(&mut hasher);
+
+                EvictionCandidate {
+                    token: *token,
+                    connected_time: peer.connected_time,
+                    min_ping: peer.min_ping,
+                    last_block_time: peer.last_block_time,
+                    last_tx_time: peer.last_tx_time,
+                    keyed_group: hasher.finish(),
+                }
+            })
+            .collect();
+
+        eviction::select_to_evict(candidates)
+    }
+
+    pub fn connection_failed(&mut self, addr: &SocketAddr) {
+        self.connman.failed(addr);
     }
 
-    pub fn get_peers(&self) -> &HashMap<mio::Token, Peer> { &self.peers }
+    // Saves the addresses if they changed, we don't write them for every message
+    pub fn flush_addresses(&mut self) {
+        if self.addresses_dirty {
+            if let Err(e) = self.addrman.save(&self.peers_path) {
+                println!("Couldn't save {}: {}", self.peers_path.display(), e);
+            }
+            self.addresses_dirty = false;
+        }
+    }
 
     pub fn get_peer(&mut self, token: &mio::Token) -> Option<&mut Peer> {
         self.peers.get_mut(token)
@@ -130,8 +724,8 @@ impl State {
         self.block_store.get_hash_at_height(height)
     }
 
-    pub fn get_block(&mut self, hash: &BitcoinHash) -> Option<BlockMessage> {
-        self.block_store.get(hash)
+    pub fn get_block_data(&mut self, hash: &BitcoinHash) -> Option<Vec<u8>> {
+        self.block_store.get_data(hash)
     }
 
     pub fn block_height(&self, hash: &BitcoinHash) -> Option<usize> {
@@ -142,62 +736,99 @@ impl State {
         self.block_store.has(hash)
     }
 
-    pub fn add_block(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
-        self.block_store.insert(block, hash, data);
+    pub fn add_block(&mut self, block: &BlockRef) {
+        self.block_store.insert(block);
     }
 }
 
 impl Peer {
-    pub fn new_inbound(version: VersionMessage) -> Peer {
+    pub fn new(address: IPAddress, connection_type: ConnectionType) -> Peer {
         Peer {
-            ping_time: time::now(),
+            ping_time: SteadyTime::now(),
             ping: -1,
             ping_data: 0,
-            version: Some(version),
+            min_ping: None,
+            connected_time: SteadyTime::now(),
+            best_known: None,
+            last_block_time: None,
+            last_tx_time: None,
+            chain_sync: None,
+            protected: false,
+            version: None,
             verak_received: false,
-            connection_type: ConnectionType::Inbound,
-            waiting_for_blocks: Timeout::new(),
+            local_nonce: 0,
+            time_offset: 0,
+            connection_type: connection_type,
+            address: address,
+            waiting_for_headers: Timeout::new(),
+            unconnecting_headers: 0,
+            misbehavior: 0,
         }
     }
 
-    pub fn new_outbound() -> Peer {
-        Peer {
-            ping_time: time::now(),
-            ping: -1,
-            ping_data: 0,
-            version: None,
-            verak_received: false,
-            connection_type: ConnectionType::Outbound,
-            waiting_for_blocks: Timeout::new(),
-        }
+    pub fn sent_getheaders(&mut self) {
+        self.waiting_for_headers.set(true, Duration::seconds(15));
+    }
+
+    pub fn got_headers(&mut self) {
+        self.waiting_for_headers.set(false, Duration::seconds(0));
+    }
+
+    pub fn is_waiting_for_headers(&self) -> bool {
+        self.waiting_for_headers.get()
     }
 
-    pub fn sent_getblocks(&mut self) {
-        self.waiting_for_blocks.set(true, Duration::seconds(15));
+    // Headers that don't connect happen when we missed some, returns whether
+    // the peer sent so many in a row that it counts as misbehavior
+    pub fn unconnecting_headers(&mut self) -> bool {
+        self.unconnecting_headers += 1;
+        self.unconnecting_headers % MAX_UNCONNECTING_HEADERS == 0
     }
 
-    pub fn got_inv(&mut self) {
-        self.waiting_for_blocks.set(false, Duration::seconds(0));
+    pub fn connecting_headers(&mut self) {
+        self.unconnecting_headers = 0;
     }
 
-    pub fn is_waiting_for_blocks(&self) -> bool {
-        self.waiting_for_blocks.get()
+    // Based on what the peer advertised in its version message
+    pub fn can_provide(&self, fetch: Fetch) -> bool {
+        self.version.as_ref().map_or(false, |version| {
+            version.services.can_provide(fetch, version.start_height as i64)
+        })
     }
 
-    pub fn ping_time(&self) -> time::Tm { self.ping_time }
+    // Before the handshake is done only commands we don't know about may
+    // come along, they negotiate features
+    pub fn expects(&self, command: &Command) -> bool {
+        match *command {
+            Command::Version => self.version.is_none(),
+            Command::Verack => self.version.is_some() && !self.verak_received,
+            Command::Unknown(_) => self.version.is_some(),
+            _ => self.verak_received,
+        }
+    }
 
     pub fn received_verack(&mut self) {
         self.verak_received = true;
     }
 
     pub fn sent_ping(&mut self, ping_data: u64) {
-        self.ping_time = time::now();
+        self.ping_time = SteadyTime::now();
         self.ping_data = ping_data;
     }
 
+    pub fn needs_ping(&self, now: SteadyTime) -> bool {
+        self.ping_data == 0 && now - self.ping_time >= Duration::seconds(PING_INTERVAL)
+    }
+
+    pub fn ping_timed_out(&self, now: SteadyTime) -> bool {
+        self.ping_data != 0 && now - self.ping_time > Duration::seconds(PING_TIMEOUT)
+    }
+
     pub fn got_pong(&mut self, pong_data: u64) {
         if self.ping_data == pong_data {
-            self.ping = (time::now() - self.ping_time).num_milliseconds();
+            self.ping = (SteadyTime::now() - self.ping_time).num_milliseconds();
+            self.ping_data = 0;
+            self.min_ping = Some(self.min_ping.map_or(self.ping, |min| cmp::min(min, self.ping)));
         } else {
             println!("Invalid ping!");
         }
@@ -205,32 +836,96 @@ impl Peer {
 }
 
 const VERSION: i32 = 70001;
+// Peers older than this can't sync headers
+const MIN_PEER_VERSION: i32 = 31800;
+// Seconds our clock may be off before we warn about it
+const MAX_TIME_OFFSET: i64 = 70 * 60;
+// Seconds a peer's clock may be off before we stop believing it's a clock
+const MAX_PEER_TIME_OFFSET: i64 = 365 * 24 * 60 * 60;
+// Seconds between pings and until the pong must arrive
+const PING_INTERVAL: i64 = 2 * 60;
+const PING_TIMEOUT: i64 = 20 * 60;
+// Seconds an outbound peer behind our tip has to catch up, and to answer
+// getheaders after that
+const CHAIN_SYNC_TIMEOUT: i64 = 20 * 60;
+const HEADERS_RESPONSE_TIME: i64 = 2 * 60;
+// Outbound peers that can't be evicted for falling behind
+const MAX_PROTECTED_OUTBOUND: usize = 4;
+// Seconds before an extra outbound peer can be evicted
+const MIN_CONNECTION_TIME: i64 = 30;
+// Selections until one we aren't connected to comes up
+const SELECT_TRIES: usize = 50;
 type StateMutex<'a> = MutexGuard<'a, State>;
 
+enum HandleError {
+    Decode(DecodeError),
+    Other(String),
+}
+
+impl HandleError {
+    // More items than a message may have
+    fn is_oversized(&self) -> bool {
+        match *self {
+            HandleError::Decode(ref e) => match *e.kind() {
+                DecodeErrorKind::OversizedLength(_) => true,
+                _ => false,
+            },
+            HandleError::Other(_) => false,
+        }
+    }
+}
+
+impl From<DecodeError> for HandleError {
+    fn from(error: DecodeError) -> HandleError { HandleError::Decode(error) }
+}
+
+impl From<String> for HandleError {
+    fn from(error: String) -> HandleError { HandleError::Other(error) }
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandleError::Decode(ref e) => write!(f, "{}", e),
+            HandleError::Other(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
 impl BitcoinClient {
     fn new(state: Arc<Mutex<State>>, channel: Sender<Message>,
-           network_type: NetworkType) -> BitcoinClient {
+           params: &ChainParams, handlers: CommandHandlers,
+           automatic_connections: bool, requirements: PeerRequirements) -> BitcoinClient {
         let client = BitcoinClient {
             version: VERSION,
-            services: Services::new(true),
+            services: NODE_NETWORK,
             user_agent: "/Agi:0.0.1/".to_string(),
             state: state,
             channel: channel,
-            network_type: network_type,
+            network_type: params.network_type,
+            signet_challenge: params.signet_challenge.clone(),
+            auxpow: params.auxpow.clone(),
+            handlers: handlers,
+            automatic_connections: automatic_connections,
+            requirements: requirements,
         };
 
         client
     }
 
     pub fn connect(&self, address: SocketAddr) {
-        self.channel.send(Message::Connect(address)).unwrap();
+        let _ = self.channel.send(Message::Connect(address));
     }
 
     fn send_message(&self, command: Command, token: mio::Token,
                          message: Option<Box<Serialize>>) {
-        let to_send = get_serialized_message(self.network_type,
-                                             command,
-                                             message);
+        let to_send = match get_serialized_message(self.network_type, command, message) {
+            Ok(to_send) => to_send,
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            }
+        };
 
         match self.channel.send(Message::SendMessage(token, to_send)) {
             Ok(_) => {},
@@ -240,111 +935,300 @@ impl BitcoinClient {
         }
     }
 
-    fn get_blocks(&self, state: &mut StateMutex, token: mio::Token) {
-        if state.pending_inv_len() > 100 {
-            return;
-        }
-
-        if state.get_peer(&token).unwrap().is_waiting_for_blocks() {
-            return;
+    fn get_headers(&self, state: &mut StateMutex, token: mio::Token) {
+        match state.get_peer(&token) {
+            Some(peer) if !peer.is_waiting_for_headers() => peer.sent_getheaders(),
+            _ => return,
         }
 
-        state.get_peer(&token).map(|p| p.sent_getblocks());
-
         let message = GetHeadersMessage {
             version: VERSION as u32,
             block_locators: state.block_locators(),
             hash_stop: BitcoinHash::new([0; 32]),
         };
 
-        self.send_message(Command::GetBlocks, token, Some(Box::new(message)));
+        self.send_message(Command::GetHeaders, token, Some(Box::new(message)));
+    }
+
+    // Bodies are only downloaded for the headers with most work
+    fn request_blocks(&self, state: &mut StateMutex) {
+        let (stalled, requests) = state.schedule_blocks();
+
+        for token in stalled {
+            println!("Peer {:?} stalled, asking others for its blocks", token);
+        }
+
+        for (token, hashes) in requests {
+            let inventory = hashes.into_iter()
+                .map(|hash| InventoryVector::new(InventoryVectorType::MSG_BLOCK, hash))
+                .collect();
+
+            self.send_message(Command::GetData, token,
+                              Some(Box::new(InvMessage::new(inventory))));
+        }
     }
 
     fn handle_verack(&self, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
-        state.get_peer(&token).unwrap().received_verack();
+        let connection_type = match state.get_peer(&token) {
+            Some(peer) => {
+                peer.received_verack();
+                peer.connection_type
+            },
+            None => return,
+        };
+        state.outbound_ready(token);
 
-        self.send_message(Command::GetAddr, token, None);
+        if connection_type == ConnectionType::Feeler {
+            // It's online, that's all we wanted to know
+            let _ = self.channel.send(Message::Disconnect(token));
+            return;
+        }
+
+        if connection_type.is_relay() {
+            self.send_message(Command::GetAddr, token, None);
+        }
 
-        self.get_blocks(&mut state, token);
+        // Which blocks it gets depends on what it can provide
+        state.add_downloader(token);
+
+        let next_block = Fetch::Block(state.headers_height() as i64 + 1);
+        if state.get_peer(&token).map_or(false, |peer| peer.can_provide(next_block)) {
+            self.get_headers(&mut state, token);
+        }
         self.ping(&mut state, token);
     }
 
+    fn disconnect(&self, token: mio::Token, reason: &str) {
+        println!("Disconnecting {:?}: {}", token, reason);
+        let _ = self.channel.send(Message::Disconnect(token));
+    }
+
+    fn misbehaving(&self, state: &mut StateMutex, token: mio::Token, misbehavior: Misbehavior) {
+        if state.misbehaving(token, misbehavior) {
+            self.disconnect(token, "misbehaving");
+        }
+    }
+
     fn ping(&self, state: &mut StateMutex, token: mio::Token) {
-        let message = PingMessage::new(rand::random());
-        state.get_peer(&token).unwrap().sent_ping(message.nonce);
+        // A nonce of 0 means no ping is pending
+        let message = PingMessage::new(rand::thread_rng().gen_range(1, u64::max_value()));
+        match state.get_peer(&token) {
+            Some(peer) => peer.sent_ping(message.nonce),
+            None => return,
+        }
         self.send_message(Command::Ping, token, Some(Box::new(message)));
     }
 
-    fn generate_version_message(&self, recipient_ip: IPAddress, start_height: i32) -> VersionMessage {
+    fn generate_version_message(&self, recipient_ip: IPAddress, start_height: i32,
+                                relay: bool, nonce: u64) -> VersionMessage {
         VersionMessage {
             version: self.version,
             services: self.services,
-            timestamp: time::now(),
+            timestamp: Timestamp64::now(),
             addr_recv: recipient_ip,
             addr_from: IPAddress::new(
                 self.services,
                 // TODO: use upnp
                 "0:0:0:0:0:ffff:c0a8:3865".parse().unwrap(),
                 18334),
-            // TODO: figure it out this
-            nonce: rand::random::<u64>(),
+            nonce: nonce,
             user_agent: self.user_agent.clone(),
             start_height: start_height,
-            relay: true,
+            relay: Some(relay),
+        }
+    }
+
+    // Why we don't want a peer that sent us `message`, if we don't
+    fn check_version(&self, state: &StateMutex, message: &VersionMessage,
+                     connection_type: ConnectionType) -> Option<String> {
+        if connection_type == ConnectionType::Inbound && state.is_local_nonce(message.nonce) {
+            return Some(format!("connected to ourselves"));
+        }
+
+        if message.version < self.requirements.min_version {
+            return Some(format!("version {} is below {}", message.version,
+                                self.requirements.min_version));
         }
+
+        let outbound_slot = connection_type == ConnectionType::FullRelay ||
+            connection_type == ConnectionType::BlockRelay;
+        if outbound_slot && !message.services.contains(self.requirements.services) {
+            return Some(format!("services {:?} are missing {:?}", message.services.names(),
+                                self.requirements.services.names()));
+        }
+
+        None
     }
 
     fn handle_version(&self, message: VersionMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
+        let connection_type = match state.get_peer(&token) {
+            Some(peer) => peer.connection_type,
+            None => return,
+        };
+
+        if let Some(reason) = self.check_version(&state, &message, connection_type) {
+            self.disconnect(token, &reason);
+            return;
+        }
+
+        let offset = match time_offset(message.timestamp.secs(), Timestamp64::now().secs()) {
+            Some(offset) => offset,
+            None => {
+                self.disconnect(token, &format!("timestamp {} is absurd",
+                                                message.timestamp.secs()));
+                return;
+            },
+        };
 
-        let version = self.generate_version_message(message.addr_recv, state.height() as i32);
-        let connection_type = state.add_peer(token, Some(message));
+        let addr_recv = message.addr_recv;
+        state.add_peer(token, message, offset);
+
+        let offset = state.median_time_offset();
+        if offset.abs() > MAX_TIME_OFFSET {
+            println!("Warning: peers say our clock is {} seconds off, please check it", offset);
+        }
 
         if connection_type == ConnectionType::Inbound {
+            let nonce = rand::random();
+            let version = self.generate_version_message(addr_recv, state.height() as i32,
+                                                        true, nonce);
+            state.sent_version(token, nonce);
             self.send_message(Command::Version, token, Some(Box::new(version)));
         }
 
         self.send_message(Command::Verack, token, None);
     }
 
-    fn handle_addr(&self, message: AddrMessage, _: mio::Token) {
-        for (_,addr) in message.addr_list {
-            for socket in (addr.address, addr.port).to_socket_addrs().unwrap() {
-                self.channel.send(Message::Connect(socket)).unwrap();
-            }
+    // Fills the free outbound slots with addresses we know
+    fn connect_more(&self, state: &mut StateMutex) {
+        if !self.automatic_connections {
+            return;
+        }
+
+        while let Some((address, connection_type)) = state.select_outbound() {
+            let addr = to_socket_addr(&address);
+            state.connecting(addr, connection_type);
+            self.connect(addr);
+        }
+    }
+
+    fn handle_addr(&self, message: AddrMessage, token: mio::Token) {
+        let mut state = self.state.lock().unwrap();
+        if !state.connection_type(token).map_or(false, |t| t.is_relay()) {
+            return;
         }
+        state.add_addresses(token, &message.addr_list);
+
+        self.connect_more(&mut state);
     }
 
     fn handle_getaddr(&self, token: mio::Token) {
-        let state = self.state.lock().unwrap();
+        let response = AddrMessage::new(self.lock_state().get_addresses());
 
-        let mut peers = vec![];
-        for peer in state.get_peers().values() {
-            if let Some(ref version) = peer.version {
-                peers.push((ShortFormatTm::new(peer.ping_time()), version.addr_from));
+        self.send_message(Command::Addr, token, Some(Box::new(response)));
+    }
+
+    fn handle_headers(&self, headers: Vec<AuxPowHeader>, token: mio::Token)
+            -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.get_peer(&token).map(|p| p.got_headers());
+
+        for header in headers.iter() {
+            let new = !state.has_header(&header.header.hash());
+            match state.accept_header(&header.header, header.auxpow.as_ref()) {
+                Ok(hash) => state.peer_has_block(token, &hash, new),
+                // We may have missed the headers in between, our locator
+                // tells the peer where to start
+                Err(e @ HeaderError::Unconnected(_)) => {
+                    if state.get_peer(&token).map_or(false, |p| p.unconnecting_headers()) {
+                        self.misbehaving(&mut state, token, Misbehavior::UnconnectingHeaders);
+                    }
+                    self.get_headers(&mut state, token);
+                    return Err(format!("Header {:?}: {}", header.header.hash(), e));
+                },
+                Err(e) => {
+                    self.misbehaving(&mut state, token, Misbehavior::InvalidHeader);
+                    return Err(format!("Header {:?}: {}", header.header.hash(), e));
+                },
             }
         }
 
-        let response = AddrMessage::new(peers);
+        state.get_peer(&token).map(|p| p.connecting_headers());
+        println!("headers token={:?} count={} height={}", token, headers.len(),
+                 state.headers_height());
 
-        self.send_message(Command::Addr, token, Some(Box::new(response)));
+        // A full message means the peer has more
+        if headers.len() as u64 == MAX_HEADERS_COUNT {
+            self.get_headers(&mut state, token);
+        }
+
+        self.request_blocks(&mut state);
+        Ok(())
     }
 
-    fn handle_headers(&self, message: HeadersMessage, _: mio::Token) {
-        // TODO: actually do something
-        println!("Headers: {:?}", message.headers.len());
-        panic!();
+    // What we can check before the block is connected
+    fn check_block(&self, block: &BlockRef) -> Result<(), String> {
+        try!(block.check_length());
+        let (merkle_root, mutated) = try!(block.compute_merkle_root());
+        if merkle_root != block.metadata().merkle_root {
+            return Err(format!("Block {:?}: merkle root mismatch", block.hash()));
+        }
+        if mutated {
+            return Err(format!("Block {:?}: duplicate transactions", block.hash()));
+        }
+        if let Some(ref params) = self.auxpow {
+            try!(self.check_auxpow(block, params));
+        }
+        if let Some(ref challenge) = self.signet_challenge {
+            try!(signet::check_block_solution(&try!(block.to_owned()), challenge)
+                 .map_err(|e| format!("Block {:?}: {}", block.hash(), e)));
+        }
+
+        Ok(())
+    }
+
+    fn check_auxpow(&self, block: &BlockRef, params: &AuxPowParams) -> Result<(), String> {
+        let header = block.metadata();
+        try!(auxpow::check_proof_of_work(&header, block.auxpow().as_ref(), params)
+             .map_err(|e| format!("Block {:?}: {}", block.hash(), e)));
+
+        // The height is only known once we have the parent
+        if let Some(height) = self.lock_state().block_height(&header.prev_block) {
+            try!(auxpow::check_height(&header, height + 1, params)
+                 .map_err(|e| format!("Block {:?}: {}", block.hash(), e)));
+        }
+
+        Ok(())
     }
 
-    fn handle_block(&self, message: BlockMessage, token: mio::Token, data: &Cursor<&[u8]>) {
-        let hash = message.hash();
+    fn handle_block(&self, block: BlockRef, token: mio::Token) -> Result<(), String> {
+        let hash = block.hash();
         let mut state = self.state.lock().unwrap();
-        state.received_data(&hash);
-        // We need to skip the header
-        state.add_block(message, &hash, &data.get_ref()[24..]);
+        let requested = state.received_block(token, &hash);
+        let new = !state.has_block(&hash);
+
+        match state.accept_header(&block.metadata(), block.auxpow().as_ref()) {
+            Ok(_) => state.peer_has_block(token, &hash, new),
+            // Blocks we ask for are always on the header chain
+            Err(HeaderError::Unconnected(_)) if !requested => {
+                self.misbehaving(&mut state, token, Misbehavior::UnrequestedData);
+                return Err(format!("Block {:?}: not requested", hash));
+            },
+            // We'll learn about its ancestors from the headers
+            Err(HeaderError::Unconnected(_)) => self.get_headers(&mut state, token),
+            Err(e) => {
+                self.misbehaving(&mut state, token, Misbehavior::InvalidBlock);
+                return Err(format!("Block {:?}: {}", hash, e));
+            },
+        }
 
-        self.get_blocks(&mut state, token);
+        state.add_block(&block);
+        state.connect_blocks();
+
+        self.request_blocks(&mut state);
+        Ok(())
     }
 
     fn handle_getblocks(&self, message: GetHeadersMessage, token: mio::Token) {
@@ -389,71 +1273,157 @@ impl BitcoinClient {
         println!("Filterload {:?}", message);
     }
 
-    fn handle_getheaders(&self, _: GetHeadersMessage, token: mio::Token) {
-        // TODO: actually do something
-        let response = HeadersMessage::new(vec![]);
-        self.send_message(Command::Headers, token, Some(Box::new(response)));
+    fn handle_getheaders(&self, message: GetHeadersMessage, token: mio::Token) {
+        let mut state = self.state.lock().unwrap();
+        let mut hashes = vec![];
+
+        if message.block_locators.is_empty() {
+            // Only the header of hash_stop
+            if state.has_block(&message.hash_stop) {
+                hashes.push(message.hash_stop);
+            }
+        } else {
+            // Without a known locator we start after the genesis block
+            let start = state.find_fork(&message.block_locators).unwrap_or(0) + 1;
+
+            for height in start..start + MAX_HEADERS_COUNT as usize {
+                match state.get_hash_at_height(height) {
+                    Some(hash) => {
+                        hashes.push(*hash);
+                        if *hash == message.hash_stop {
+                            break;
+                        }
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        let mut headers = vec![];
+        for hash in hashes.iter() {
+            let header = state.get_header(hash).unwrap();
+            // Merge mined headers need the AuxPoW from the block
+            let auxpow = match self.auxpow {
+                Some(_) if auxpow::is_auxpow(header.version) => {
+                    let data = state.get_block_data(hash).unwrap();
+                    BlockRef::parse_auxpow(&data).ok().and_then(|block| block.auxpow())
+                },
+                _ => None,
+            };
+
+            headers.push(AuxPowHeader { header: header, auxpow: auxpow });
+        }
+
+        println!("getheaders token={:?} count={}", token, headers.len());
+
+        if self.auxpow.is_some() {
+            let response = AuxPowHeadersMessage {
+                headers: headers.into_iter().map(|header| (header, VarInt::new(0))).collect(),
+            };
+            self.send_message(Command::Headers, token, Some(Box::new(response)));
+        } else {
+            let response = HeadersMessage::new(
+                headers.into_iter().map(|header| (header.header, VarInt::new(0))).collect());
+            self.send_message(Command::Headers, token, Some(Box::new(response)));
+        }
     }
 
     fn handle_tx(&self, message: TxMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
+        if !state.is_pending_inv(&message.hash()) {
+            self.misbehaving(&mut state, token, Misbehavior::UnrequestedData);
+            return;
+        }
+
+        if !state.has_tx(&message.hash()) {
+            state.get_peer(&token).map(|peer| peer.last_tx_time = Some(SteadyTime::now()));
+        }
+
+        state.received_data(&message.hash());
         state.add_tx(message);
 
-        self.get_blocks(&mut state, token);
+        self.request_blocks(&mut state);
     }
 
     fn handle_getdata(&self, message: InvMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
+        let mut not_found = vec![];
 
         for inventory in message.inventory {
             match inventory.type_ {
-                InventoryVectorType::MSG_TX => unimplemented!(),
+                // We don't relay transactions, so we have none to give
+                InventoryVectorType::MSG_TX => not_found.push(inventory),
                 InventoryVectorType::MSG_BLOCK => {
-                    if let Some(block) = state.get_block(&inventory.hash) {
-                        self.send_message(Command::Block, token, Some(Box::new(block)));
+                    // Sent as stored, merge mined blocks keep their AuxPoW
+                    match state.get_block_data(&inventory.hash) {
+                        Some(data) => {
+                            let block = UnknownMessage { command: Command::Block, payload: data };
+                            self.send_message(Command::Block, token, Some(Box::new(block)));
+                        },
+                        None => not_found.push(inventory),
                     }
                 },
                 type_ => println!("Unhandled inv {:?}", type_),
             }
         }
+
+        // So it can ask someone else instead of waiting
+        if not_found.len() > 0 {
+            self.send_message(Command::NotFound, token,
+                              Some(Box::new(InvMessage::new(not_found))));
+        }
     }
 
-    fn handle_notfound(&self, message: InvMessage, _: mio::Token) {
-        println!("Got notfound {:?}", message);
-        panic!();
+    // Blocks it doesn't have are asked from others on the next tick
+    fn handle_notfound(&self, message: InvMessage, token: mio::Token) {
+        let mut state = self.state.lock().unwrap();
+
+        for inventory in message.inventory {
+            match inventory.type_ {
+                InventoryVectorType::MSG_TX => state.received_data(&inventory.hash),
+                InventoryVectorType::MSG_BLOCK => state.block_not_found(token, &inventory.hash),
+                type_ => println!("Unhandled notfound {:?}", type_),
+            }
+        }
     }
 
     fn handle_inv(&self, message: InvMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
 
         let mut new_data = vec![];
+        let mut new_blocks = false;
 
         for inventory in message.inventory {
             match inventory.type_ {
                 InventoryVectorType::MSG_TX => {
-                    if !state.has_tx(&inventory.hash) {
+                    if !state.has_tx(&inventory.hash) &&
+                       !state.is_pending_inv(&inventory.hash) {
                         new_data.push(InventoryVector::new(
                                 InventoryVectorType::MSG_TX,
                                 inventory.hash));
+                        state.add_inv(inventory.hash);
                     }
                 },
                 InventoryVectorType::MSG_BLOCK => {
-                    if !state.has_block(&inventory.hash) &&
-                       !state.is_pending_inv(&inventory.hash) {
-                        new_data.push(InventoryVector::new(
-                                InventoryVectorType::MSG_BLOCK,
-                                inventory.hash));
-                        state.add_inv(inventory.hash);
+                    // Blocks are fetched once we have their header
+                    if state.has_header(&inventory.hash) {
+                        state.peer_has_block(token, &inventory.hash, false);
+                    } else {
+                        new_blocks = true;
                     }
                 },
                 type_ => println!("Unhandled inv {:?}", type_),
             }
         }
 
-        self.send_message(Command::GetData, token,
-                          Some(Box::new(InvMessage::new(new_data))));
+        if new_data.len() > 0 {
+            self.send_message(Command::GetData, token,
+                              Some(Box::new(InvMessage::new(new_data))));
+        }
 
-        state.get_peer(&token).unwrap().got_inv();
+        if new_blocks {
+            self.get_headers(&mut state, token);
+        }
     }
 
     fn handle_pong(&self, message: PingMessage, token: mio::Token) {
@@ -461,22 +1431,45 @@ impl BitcoinClient {
     }
 
     fn handle_reject(&self, message: RejectMessage, token: mio::Token) {
-        println!("Message from {:?} = {:?}", token, message);
-        println!("harakiri :-(");
-        panic!();
+        println!("Reject from {:?} = {:?}", token, message);
     }
 
     fn handle_ping(&self, message: PingMessage, token: mio::Token) {
         self.send_message(Command::Pong, token, Some(Box::new(message)));
     }
 
+    fn handle_unknown(&self, message: UnknownMessage, token: mio::Token) {
+        match self.handlers.get(&message.command) {
+            Some(handler) => {
+                for reply in handler.handle(&message) {
+                    self.send_message(reply.command, token, Some(Box::new(reply)));
+                }
+            },
+            // Newer peers send commands we don't know about, that's fine
+            None => println!("Ignoring unknown command {:?} from {:?}",
+                             message.command.name(), token),
+        }
+    }
+
     fn lock_state<'a>(&'a self) -> StateMutex { self.state.lock().unwrap() }
 
     fn handle_command(&self, header: MessageHeader, token: mio::Token,
-                      message_bytes: &mut Cursor<&[u8]>) -> Result<(), String> {
-        if header.network_type != self.network_type {
-            // This packet is not for the right version :O
-            return Err(format!("Received packet for wrong version: {:?}", header.network_type));
+                      message_bytes: &mut Cursor<&[u8]>) -> Result<(), HandleError> {
+        {
+            let mut state = self.lock_state();
+            let (expected, handshake_done) = match state.get_peer(&token) {
+                Some(peer) => (peer.expects(&header.command), peer.verak_received),
+                // Disconnected already
+                None => return Ok(()),
+            };
+
+            if !expected {
+                if !handshake_done {
+                    self.disconnect(token, "unexpected message during the handshake");
+                }
+                return Err(HandleError::Other(format!("Unexpected {} from {:?}",
+                                                      header.command.name(), token)));
+            }
         }
 
         match header.command {
@@ -516,12 +1509,21 @@ impl BitcoinClient {
                 self.handle_getaddr(token);
             },
             Command::Block => {
-                let message = try!(BlockMessage::deserialize(message_bytes));
-                if message_bytes.get_ref().len() as u64 != message_bytes.position() {
-                    Debug::print_bytes(message_bytes.get_ref());
-                    panic!();
+                // We need to skip the header
+                let data = &message_bytes.get_ref()[message_bytes.position() as usize..];
+                let block = try!(match self.auxpow {
+                    Some(_) => BlockRef::parse_auxpow(data),
+                    None => BlockRef::parse(data),
+                });
+                if let Err(e) = self.check_block(&block) {
+                    let mut state = self.lock_state();
+                    // Its header may still be fine, another peer can send the block
+                    state.invalid_block(token, &block.hash());
+                    self.misbehaving(&mut state, token, Misbehavior::InvalidBlock);
+                    self.request_blocks(&mut state);
+                    return Err(HandleError::Other(e));
                 }
-                self.handle_block(message, token, message_bytes);
+                try!(self.handle_block(block, token));
             },
             Command::GetBlocks => {
                 let message = try!(GetHeadersMessage::deserialize(message_bytes));
@@ -536,8 +1538,15 @@ impl BitcoinClient {
                 self.handle_filterload(message, token);
             },
             Command::Headers => {
-                let message = try!(HeadersMessage::deserialize(message_bytes));
-                self.handle_headers(message, token);
+                let headers = match self.auxpow {
+                    Some(_) => try!(AuxPowHeadersMessage::deserialize(message_bytes)).headers
+                        .into_iter().map(|(header, _)| header).collect(),
+                    None => try!(HeadersMessage::deserialize(message_bytes)).headers
+                        .into_iter()
+                        .map(|(header, _)| AuxPowHeader { header: header, auxpow: None })
+                        .collect(),
+                };
+                try!(self.handle_headers(headers, token));
             },
             Command::Addr => {
                 let message = try!(AddrMessage::deserialize(message_bytes));
@@ -547,8 +1556,10 @@ impl BitcoinClient {
                 let message = try!(RejectMessage::deserialize(message_bytes));
                 self.handle_reject(message, token);
             },
-            Command::Unknown => {
-                return Err(format!("Unknown message. {:?}", message_bytes));
+            command @ Command::Unknown(_) => {
+                let payload = &message_bytes.get_ref()[message_bytes.position() as usize..];
+                self.handle_unknown(UnknownMessage { command: command, payload: payload.to_vec() },
+                                    token);
             },
         };
 
@@ -559,53 +1570,280 @@ impl BitcoinClient {
 impl rpcengine::MessageHandler for BitcoinClient {
     fn handle(&self, token: mio::Token, message: Vec<u8>) {
         let mut cursor = Cursor::new(&message[..]);
-        let handled = MessageHeader::deserialize(&mut cursor)
+        // The engine checked the magic already
+        let handled = MessageHeader::deserialize_for(&mut cursor, self.network_type)
+            .map_err(|e| e.into())
             .and_then(|m| self.handle_command(m, token, &mut cursor));
 
-        if let Err(x) = handled {
-           println!("Error: {:?}", x);
-        };
+        match handled {
+            Ok(()) => {},
+            Err(ref e) if e.is_oversized() => {
+                println!("Error: {}", e);
+                self.misbehaving(&mut self.lock_state(), token, Misbehavior::OversizedMessage);
+            },
+            Err(e) => println!("Error: {}", e),
+        }
     }
 
     fn new_connection(&self, token: mio::Token, addr: SocketAddr) {
         let mut state = self.state.lock().unwrap();
 
-        state.add_peer(token, None);
-
-        let ip = match addr {
-            SocketAddr::V4(ipv4) => ipv4.ip().to_ipv6_mapped(),
-            SocketAddr::V6(ipv6) => *ipv6.ip(),
-        };
+        let ip_address = IPAddress::new(NODE_NETWORK, connman::to_ipv6(&addr), addr.port());
+        let connection_type = state.add_outbound_peer(token, &addr, ip_address);
 
-        let ip_address = IPAddress::new(Services::new(true), ip, addr.port());
-        let version = self.generate_version_message(ip_address, state.height() as i32);
+        // Block relay only peers don't hear about our transactions
+        let nonce = rand::random();
+        let version = self.generate_version_message(ip_address, state.height() as i32,
+                                                    connection_type.is_relay(), nonce);
+        state.sent_version(token, nonce);
 
         self.send_message(Command::Version, token, Some(Box::new(version)));
     }
+
+    fn accept_connection(&self, token: mio::Token, addr: SocketAddr) -> bool {
+        let (accepted, evicted) = self.lock_state().accept_inbound(token, &addr);
+        if let Some(evicted) = evicted {
+            self.disconnect(evicted, "evicted for an inbound peer");
+        }
+        accepted
+    }
+
+    fn frame_error(&self, token: mio::Token, error: &FrameError) {
+        if let FrameError::Oversized(_) = *error {
+            self.misbehaving(&mut self.lock_state(), token, Misbehavior::OversizedMessage);
+        }
+    }
+
+    fn connection_failed(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.connection_failed(&addr);
+
+        self.connect_more(&mut state);
+    }
+
+    fn connection_closed(&self, token: mio::Token) {
+        let mut state = self.state.lock().unwrap();
+        state.remove_peer(token);
+
+        // Its blocks go to the other peers
+        self.request_blocks(&mut state);
+        self.connect_more(&mut state);
+    }
+
+    fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = SteadyTime::now();
+        state.sweep_banlist();
+        state.flush_addresses();
+
+        let (ping, timed_out) = state.check_pings(now);
+        for token in timed_out {
+            self.disconnect(token, "ping timeout");
+        }
+        for token in ping {
+            self.ping(&mut state, token);
+        }
+
+        if state.check_stale_tip(now) {
+            println!("Tip may be stale, looking for another outbound peer");
+        }
+        if let Some(token) = state.extra_outbound_to_evict(now) {
+            self.disconnect(token, "extra outbound peer");
+        }
+
+        let (get_headers, behind) = state.check_chain_sync(now);
+        for token in get_headers {
+            self.get_headers(&mut state, token);
+        }
+        for token in behind {
+            self.disconnect(token, "behind our tip");
+        }
+
+        self.request_blocks(&mut state);
+        self.connect_more(&mut state);
+    }
+}
+
+pub fn start(params: ChainParams, address: SocketAddr, connect_to: Option<SocketAddr>,
+             blocks_file: File, peers_path: PathBuf, banlist_path: PathBuf,
+             import_dir: Option<PathBuf>, requirements: PeerRequirements) {
+    start_with_handlers(params, address, connect_to, blocks_file, peers_path, banlist_path,
+                        import_dir, requirements, CommandHandlers::new());
+}
+
+// Seconds the clock of a peer is ahead of ours, None when it's too far off
+// for the timestamp to be trusted
+fn time_offset(timestamp: i64, now: i64) -> Option<i64> {
+    match timestamp.checked_sub(now) {
+        Some(offset) if offset >= -MAX_PEER_TIME_OFFSET && offset <= MAX_PEER_TIME_OFFSET =>
+            Some(offset),
+        _ => None,
+    }
+}
+
+fn to_socket_addr(address: &IPAddress) -> SocketAddr {
+    match address.address.to_ipv4() {
+        Some(ipv4) if address.address.segments()[5] == 0xffff =>
+            SocketAddr::new(IpAddr::V4(ipv4), address.port),
+        _ => SocketAddr::new(IpAddr::V6(address.address), address.port),
+    }
+}
+
+/// Like `start`, with handlers for commands the client doesn't implement.
+pub fn start_with_handlers(params: ChainParams, address: SocketAddr,
+                           connect_to: Option<SocketAddr>, blocks_file: File,
+                           peers_path: PathBuf, banlist_path: PathBuf,
+                           import_dir: Option<PathBuf>, requirements: PeerRequirements,
+                           handlers: CommandHandlers) {
+    Node::new(params, address, connect_to, blocks_file, peers_path, banlist_path, import_dir,
+              requirements, handlers).run();
+}
+
+/// A node set up to run, `handle` gives access to it from other threads.
+pub struct Node {
+    state: Arc<Mutex<State>>,
+    client: Arc<BitcoinClient>,
+    server: tcp::TcpListener,
+    event_loop: mio::EventLoop<RPCEngine>,
+    network_type: NetworkType,
+    connect_to: Option<SocketAddr>,
+}
+
+impl Node {
+    pub fn new(params: ChainParams, address: SocketAddr, connect_to: Option<SocketAddr>,
+               blocks_file: File, peers_path: PathBuf, banlist_path: PathBuf,
+               import_dir: Option<PathBuf>, requirements: PeerRequirements,
+               handlers: CommandHandlers) -> Node {
+        let server = tcp::TcpListener::bind(&address).unwrap();
+        let mut event_loop = mio::EventLoop::new().unwrap();
+        event_loop.register(&server, rpcengine::SERVER, mio::EventSet::readable(),
+                            mio::PollOpt::edge()).unwrap();
+
+        let state = Arc::new(Mutex::new(State::new(&params, blocks_file, peers_path,
+                                                   banlist_path)));
+
+        if let Some(dir) = import_dir {
+            let mut state = state.lock().unwrap();
+            let mut importer = BlockImporter::new(&mut state.block_store, &params);
+
+            if let Err(e) = importer.import_dir(&dir) {
+                println!("Import stopped: {}", e);
+            }
+
+            let stats = importer.finish();
+            println!("Imported {} blocks, {} already known, {} not connected",
+                     stats.imported, stats.skipped, stats.unconnected);
+
+            state.load_headers();
+        }
+
+        let client = Arc::new(
+                BitcoinClient::new(state.clone(), event_loop.channel(), &params,
+                                   handlers, connect_to.is_none(), requirements));
+
+        println!("running bitcoin server; chain={} port={}", params.name, address.port());
+        event_loop.timeout_ms((), rpcengine::TICK_INTERVAL_MS).unwrap();
+
+        Node {
+            state: state,
+            client: client,
+            server: server,
+            event_loop: event_loop,
+            network_type: params.network_type,
+            connect_to: connect_to,
+        }
+    }
+
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            state: self.state.clone(),
+            channel: self.event_loop.channel(),
+        }
+    }
+
+    /// Connects to peers and serves them until the event loop stops.
+    pub fn run(self) {
+        let Node { state, client, server, mut event_loop, network_type, connect_to } = self;
+        let handler: Arc<rpcengine::MessageHandler> = client.clone();
+
+        let child = thread::spawn(move || {
+            let mut engine = RPCEngine::new(server, handler, network_type);
+            event_loop.run(&mut engine).unwrap();
+        });
+
+        // Without a peer to connect to we use those we saw before
+        match connect_to {
+            Some(address) => client.connect(address),
+            None => client.connect_more(&mut state.lock().unwrap()),
+        }
+
+        let _ = child.join();
+        state.lock().unwrap().flush_addresses();
+    }
 }
 
-pub fn start(address: SocketAddr, connect_to: Option<SocketAddr>, blocks_file: File) {
-    let server = tcp::TcpListener::bind(&address).unwrap();
-    let mut event_loop = mio::EventLoop::new().unwrap();
-    event_loop.register(&server, rpcengine::SERVER, mio::EventSet::readable(),
-                        mio::PollOpt::edge()).unwrap();
+/// Manages the ban list of a running node, changes are saved right away.
+#[derive(Clone)]
+pub struct NodeHandle {
+    state: Arc<Mutex<State>>,
+    channel: Sender<Message>,
+}
 
-    let state = Arc::new(Mutex::new(State::new(NetworkType::TestNet3, blocks_file)));
+impl NodeHandle {
+    /// Bans `subnet` until `until` and disconnects the peers in it.
+    pub fn ban(&self, subnet: SubNet, until: u32) -> Result<(), String> {
+        let peers = try!(self.state.lock().unwrap().ban(subnet, until));
 
-    let client = Arc::new(
-            BitcoinClient::new(state.clone(), event_loop.channel(), NetworkType::TestNet3));
+        for token in peers {
+            println!("Disconnecting {:?}: banned", token);
+            // Fails only once the event loop stopped
+            let _ = self.channel.send(Message::Disconnect(token));
+        }
 
-    let handler: Arc<rpcengine::MessageHandler> = client.clone();
+        Ok(())
+    }
 
-    println!("running bitcoin server; port={}", address.port());
-    let child = thread::spawn(move || {
-        let mut engine = RPCEngine::new(server, handler);
-        event_loop.run(&mut engine).unwrap();
-    });
+    /// Returns false if `subnet` wasn't banned.
+    pub fn unban(&self, subnet: &SubNet) -> Result<bool, String> {
+        self.state.lock().unwrap().unban(subnet)
+    }
 
-    if let Some(address) = connect_to {
-        client.connect(address);
+    /// The bans sorted by when they expire.
+    pub fn banned(&self) -> Vec<(SubNet, BanEntry)> {
+        self.state.lock().unwrap().banlist.entries()
     }
+}
 
-    let _ = child.join();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_offset() {
+        let now = 1700000000;
+        assert_eq!(time_offset(now + 60, now), Some(60));
+        assert_eq!(time_offset(now - MAX_PEER_TIME_OFFSET, now), Some(-MAX_PEER_TIME_OFFSET));
+        assert_eq!(time_offset(now + MAX_PEER_TIME_OFFSET + 1, now), None);
+
+        // Peers can send any 64 bit timestamp
+        assert_eq!(time_offset(i64::max_value(), now), None);
+        assert_eq!(time_offset(i64::min_value(), now), None);
+        assert_eq!(time_offset(i64::min_value(), -1), None);
+        assert_eq!(time_offset(0, i64::min_value()), None);
+    }
+
+    #[test]
+    fn test_unconnecting_headers() {
+        let address = IPAddress::new(NODE_NONE, Ipv6Addr::from([0; 16]), 8333);
+        let mut peer = Peer::new(address, ConnectionType::FullRelay);
+
+        for _ in 1..MAX_UNCONNECTING_HEADERS {
+            assert!(!peer.unconnecting_headers());
+        }
+        assert!(peer.unconnecting_headers());
+
+        // Only those in a row count
+        peer.connecting_headers();
+        assert!(!peer.unconnecting_headers());
+    }
 }
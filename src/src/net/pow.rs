@@ -0,0 +1,230 @@
+// Targets and chain work as 256 bit integers, and the difficulty retarget
+// rules of Bitcoin Core.
+use super::chain_params::ChainParams;
+use super::import::target;
+
+use std::cmp::Ordering;
+
+// BIP94, the first block of a period can't be earlier than this before its parent
+pub const MAX_TIMEWARP: u32 = 600;
+
+/// Unsigned 256 bit integer, least significant limb first.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Uint256([u64; 4]);
+
+impl Ord for Uint256 {
+    fn cmp(&self, other: &Uint256) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for Uint256 {
+    fn partial_cmp(&self, other: &Uint256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Uint256 {
+    pub fn from_u64(value: u64) -> Uint256 { Uint256([value, 0, 0, 0]) }
+
+    /// Target encoded as in the bits of a header, None when it's negative
+    /// or overflows.
+    pub fn from_compact(bits: u32) -> Option<Uint256> {
+        target(bits).map(|bytes| {
+            let mut limbs = [0; 4];
+            for (i, byte) in bytes.iter().enumerate() {
+                limbs[i / 8] |= (*byte as u64) << (8 * (i % 8));
+            }
+            Uint256(limbs)
+        })
+    }
+
+    pub fn to_compact(&self) -> u32 {
+        let mut size = (self.bits() + 7) / 8;
+        let mut compact = if size <= 3 {
+            self.0[0] << (8 * (3 - size))
+        } else {
+            self.shr(8 * (size - 3)).0[0]
+        };
+
+        // The mantissa is signed, keep it positive
+        if compact & 0x00800000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+
+        compact as u32 | (size as u32) << 24
+    }
+
+    fn bits(&self) -> usize {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i + 64 - self.0[i].leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    fn shr(&self, shift: usize) -> Uint256 {
+        let mut result = [0; 4];
+        let (limbs, bits) = (shift / 64, shift % 64);
+        for i in 0..4 {
+            if i + limbs >= 4 {
+                break;
+            }
+            result[i] = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                result[i] |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        Uint256(result)
+    }
+
+    fn shl1(&self) -> Uint256 {
+        let mut result = [0; 4];
+        for i in 0..4 {
+            result[i] = self.0[i] << 1 | if i > 0 { self.0[i - 1] >> 63 } else { 0 };
+        }
+        Uint256(result)
+    }
+
+    fn not(&self) -> Uint256 {
+        Uint256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+
+    // Wraps around on overflow
+    pub fn add(&self, other: &Uint256) -> Uint256 {
+        let mut result = [0; 4];
+        let mut carry = 0;
+        for i in 0..4 {
+            let sum = self.0[i] as u128 + other.0[i] as u128 + carry;
+            result[i] = sum as u64;
+            carry = sum >> 64;
+        }
+        Uint256(result)
+    }
+
+    fn sub(&self, other: &Uint256) -> Uint256 {
+        self.add(&other.not().add(&Uint256::from_u64(1)))
+    }
+
+    fn mul_u64(&self, other: u64) -> Uint256 {
+        let mut result = [0; 4];
+        let mut carry = 0;
+        for i in 0..4 {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            result[i] = product as u64;
+            carry = product >> 64;
+        }
+        Uint256(result)
+    }
+
+    // Long division, the divisor must be below 2^255
+    fn div(&self, divisor: &Uint256) -> Uint256 {
+        let mut quotient = [0; 4];
+        let mut remainder = Uint256::default();
+
+        for i in (0..256).rev() {
+            remainder = remainder.shl1();
+            if self.bit(i) {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= *divisor {
+                remainder = remainder.sub(divisor);
+                quotient[i / 64] |= 1 << (i % 64);
+            }
+        }
+
+        Uint256(quotient)
+    }
+}
+
+/// Expected number of hashes to find a block with `bits`, 2^256 / (target + 1).
+pub fn block_work(bits: u32) -> Uint256 {
+    match Uint256::from_compact(bits) {
+        Some(ref target) if *target != Uint256::default() => {
+            // 2^256 doesn't fit, but (2^256 - target - 1) / (target + 1) + 1 does
+            let divisor = target.add(&Uint256::from_u64(1));
+            target.not().div(&divisor).add(&Uint256::from_u64(1))
+        },
+        _ => Uint256::default(),
+    }
+}
+
+/// Bits of the first block of a period, scaled by how long the last one took.
+pub fn retarget(bits: u32, actual_timespan: i64, params: &ChainParams) -> u32 {
+    let timespan = params.pow_target_timespan;
+    let actual_timespan = if actual_timespan < timespan / 4 {
+        timespan / 4
+    } else if actual_timespan > timespan * 4 {
+        timespan * 4
+    } else {
+        actual_timespan
+    };
+
+    let pow_limit = Uint256::from_compact(params.pow_limit_bits).unwrap();
+    let target = match Uint256::from_compact(bits) {
+        Some(target) => target,
+        None => return params.pow_limit_bits,
+    };
+
+    let new_target = target.mul_u64(actual_timespan as u64)
+        .div(&Uint256::from_u64(timespan as u64));
+
+    if new_target > pow_limit {
+        pow_limit.to_compact()
+    } else {
+        new_target.to_compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::chain_params::ChainParams;
+
+    #[test]
+    fn test_compact() {
+        for bits in [0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x1e0377ae, 0x03123456, 0x02008000,
+                     0x17034219].iter() {
+            assert_eq!(Uint256::from_compact(*bits).unwrap().to_compact(), *bits);
+        }
+
+        // Mantissas with the sign bit set move a byte up
+        assert_eq!(Uint256::from_u64(0x80).to_compact(), 0x02008000);
+        assert_eq!(Uint256::default().to_compact(), 0);
+        assert_eq!(Uint256::from_compact(0x04923456), None);
+    }
+
+    #[test]
+    fn test_block_work() {
+        // Difficulty 1 is about 2^32 hashes
+        assert_eq!(block_work(0x1d00ffff), Uint256::from_u64(0x0100010001));
+        assert_eq!(block_work(0x207fffff), Uint256::from_u64(2));
+        assert_eq!(block_work(0x04923456), Uint256::default());
+
+        let work = block_work(0x1d00ffff);
+        assert!(work.add(&work) > work);
+        assert!(block_work(0x1b0404cb) > work);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = ChainParams::main();
+
+        // Block 32255 to 32256, the first retarget on main
+        assert_eq!(retarget(0x1d00ffff, 1262152739 - 1261130161, &params), 0x1d00d86a);
+        // Much faster and much slower than expected, the change is capped
+        assert_eq!(retarget(0x1c05a3f4, 1279297671 - 1279008237, &params), 0x1c0168fd);
+        assert_eq!(retarget(0x1c387f6f, 1269211443 - 1263163443, &params), 0x1d00e1fd);
+
+        assert_eq!(retarget(0x1c05a3f4, 1, &params), retarget(0x1c05a3f4, 302400, &params));
+
+        // Never easier than the limit
+        assert_eq!(retarget(0x1d00ffff, 10000000, &params), 0x1d00ffff);
+    }
+}
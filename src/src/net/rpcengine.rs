@@ -12,26 +12,52 @@ use std::sync::{Arc, Mutex};
 
 use std::net::SocketAddr;
 
-use serialize::Deserialize;
-use super::messages::MessageHeader;
+use serialize::{DecodeError, MAX_SIZE};
+use super::messages::{MessageHeader, NetworkType};
 
 use std::collections::VecDeque;
 
-use utils::Debug;
 use std::thread;
 
 use std::cmp;
+use std::fmt;
 
 pub const SERVER: mio::Token = mio::Token(0);
+// How often the handler gets a tick
+pub const TICK_INTERVAL_MS: u64 = 10000;
 
 pub trait MessageHandler: Sync + Send {
     fn handle(&self, token: mio::Token, message: Vec<u8>);
     fn new_connection(&self, token: mio::Token, addr: SocketAddr);
+    // An inbound connection, it's closed right away if this returns false
+    fn accept_connection(&self, token: mio::Token, addr: SocketAddr) -> bool;
+    fn connection_failed(&self, addr: SocketAddr);
+    fn connection_closed(&self, token: mio::Token);
+    // The peer sent something that isn't a message, the connection is closed
+    fn frame_error(&self, token: mio::Token, error: &FrameError);
+    fn tick(&self);
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Oversized(usize),
+    Malformed(DecodeError),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::Oversized(length) => write!(f, "message too long, length={}", length),
+            FrameError::Malformed(ref e) => write!(f, "malformed message header: {}", e),
+        }
+    }
 }
 
 pub struct RPCEngine {
     server: TcpListener,
     connections: Slab<Connection>,
+    // Messages for any other network close the connection
+    network_type: NetworkType,
     handler: Arc<MessageHandler>,
     jobs: Arc<Mutex<VecDeque<(mio::Token, Vec<u8>)>>>,
     threads_counter: Arc<Mutex<usize>>,
@@ -58,12 +84,14 @@ impl RPCEngine {
         });
     }
 
-    pub fn new(server: TcpListener, handler: Arc<MessageHandler>) -> RPCEngine {
+    pub fn new(server: TcpListener, handler: Arc<MessageHandler>,
+               network_type: NetworkType) -> RPCEngine {
         // Token 0 is reserver for the server
         let slab = Slab::new_starting_at(mio::Token(1), 1024);
         let engine = RPCEngine {
             server: server,
             connections: slab,
+            network_type: network_type,
             handler: handler,
             jobs: Arc::new(Mutex::new(VecDeque::new())),
             threads_counter: Arc::new(Mutex::new(0)),
@@ -75,8 +103,9 @@ impl RPCEngine {
     fn add_new_peer(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>,
                     socket: TcpStream) -> mio::Token {
         // TODO: handle errors
+        let network_type = self.network_type;
         let token = self.connections
-            .insert_with(|token| Connection::new(socket, token))
+            .insert_with(|token| Connection::new(socket, token, network_type))
             .unwrap();
 
         event_loop.register(
@@ -90,8 +119,11 @@ impl RPCEngine {
 
     fn handle_new_connection(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>) {
         match self.server.accept() {
-            Ok(Some((socket, _))) => {
-                self.add_new_peer(event_loop, socket);
+            Ok(Some((socket, addr))) => {
+                let token = self.add_new_peer(event_loop, socket);
+                if !self.handler.accept_connection(token, addr) {
+                    let _ = self.connections.remove(token);
+                }
             }
             Ok(None) => {
                 println!("the server socket wasn't actually ready");
@@ -114,7 +146,11 @@ impl RPCEngine {
 
         let rpc_vec = self.connections[token].ready(event_loop, events);
         if self.connections[token].is_closed() {
-            let _ = self.connections.remove(token);
+            let connection = self.connections.remove(token).unwrap();
+            if let Some(ref error) = connection.state.error {
+                self.handler.frame_error(token, error);
+            }
+            self.handler.connection_closed(token);
         } else if rpc_vec.len() > 0 {
             let mut jobs = self.jobs.lock().unwrap();
             for rpc in rpc_vec {
@@ -131,10 +167,19 @@ impl RPCEngine {
     }
 
     fn connect(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>, addr: SocketAddr) {
-        if let Ok(socket) = TcpStream::connect(&addr) {
-            let token = self.add_new_peer(event_loop, socket);
+        match TcpStream::connect(&addr) {
+            Ok(socket) => {
+                let token = self.add_new_peer(event_loop, socket);
+
+                self.handler.new_connection(token, addr);
+            },
+            Err(_) => self.handler.connection_failed(addr),
+        }
+    }
 
-            self.handler.new_connection(token, addr);
+    fn disconnect(&mut self, token: mio::Token) {
+        if self.connections.remove(token).is_some() {
+            self.handler.connection_closed(token);
         }
     }
 
@@ -148,6 +193,7 @@ impl RPCEngine {
 pub enum Message {
     Connect(SocketAddr),
     SendMessage(mio::Token, Vec<u8>),
+    Disconnect(mio::Token),
 }
 
 impl mio::Handler for RPCEngine {
@@ -166,8 +212,14 @@ impl mio::Handler for RPCEngine {
         match msg {
             Message::Connect(addr) => self.connect(event_loop, addr),
             Message::SendMessage(token, data) => self.send_message(event_loop, token, data),
+            Message::Disconnect(token) => self.disconnect(token),
         }
     }
+
+    fn timeout(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>, _: ()) {
+        self.handler.tick();
+        event_loop.timeout_ms((), TICK_INTERVAL_MS).unwrap();
+    }
 }
 
 #[derive(Debug)]
@@ -178,11 +230,11 @@ struct Connection {
 }
 
 impl Connection {
-    fn new(socket: TcpStream, token: mio::Token) -> Connection {
+    fn new(socket: TcpStream, token: mio::Token, network_type: NetworkType) -> Connection {
         Connection {
             socket: socket,
             token: token,
-            state: State::new(),
+            state: State::new(network_type),
         }
     }
 
@@ -224,8 +276,9 @@ impl Connection {
                         Ok(x) => {
                             x
                         },
-                        Err(x) => {
-                            println!("Error: {}", x);
+                        Err(e) => {
+                            println!("Error: {}", e);
+                            self.state.error = Some(e);
                             self.state.close();
                             vec![]
                         }
@@ -303,15 +356,20 @@ struct State {
     writing_buf: Cursor<Vec<u8>>,
     writing_queue: VecDeque<Vec<u8>>,
     connection_state: ConnectionState,
+    network_type: NetworkType,
+    // Why we closed the connection, if the peer was at fault
+    error: Option<FrameError>,
 }
 
 impl State {
-    pub fn new() -> State {
+    pub fn new(network_type: NetworkType) -> State {
         State {
             reading_buf: vec![],
             writing_buf: Cursor::new(vec![]),
             writing_queue: VecDeque::new(),
             connection_state: ConnectionState::Active,
+            network_type: network_type,
+            error: None,
         }
     }
 
@@ -342,38 +400,32 @@ impl State {
         }
     }
 
-    fn try_get_rpc(&mut self) -> Result<Vec<u8>, String> {
-        // TODO: handle this assert closing the connection
-        // TODO: handle different networks
+    fn try_get_rpc(&mut self) -> Result<Vec<u8>, FrameError> {
         // The input is too small to contain the header, let's wait
         if self.reading_buf.len() < 24 {
             return Ok(vec![]);
         }
 
-        if let Some(message_len) = self.get_message_length() {
-            // The input doesn't have the full message, let's wait
-            if self.reading_buf.len() < 24 + message_len {
-                return Ok(vec![]);
-            }
-
-            let mut reading_buf = mem::replace(&mut self.reading_buf, vec![]);
-            let remaining = reading_buf.split_off(24 + message_len);
+        let message_len = try!(self.get_message_length().map_err(FrameError::Malformed));
+        if message_len as u64 > MAX_SIZE {
+            return Err(FrameError::Oversized(message_len));
+        }
 
-            self.reading_buf = remaining;
-            Ok(reading_buf)
-        } else {
-            // At some point this will be just an error, but for now
-            // a malformed message is probably just a bug so we need to crash
-            println!("Error: malformed message");
-            Debug::print_bytes(&self.reading_buf);
-            panic!();
+        // The input doesn't have the full message, let's wait
+        if self.reading_buf.len() < 24 + message_len {
+            return Ok(vec![]);
         }
+
+        let mut reading_buf = mem::replace(&mut self.reading_buf, vec![]);
+        let remaining = reading_buf.split_off(24 + message_len);
+
+        self.reading_buf = remaining;
+        Ok(reading_buf)
     }
 
-    fn get_message_length(&self) -> Option<usize> {
+    fn get_message_length(&self) -> Result<usize, DecodeError> {
         let mut cursor = Cursor::new(&self.reading_buf);
-        MessageHeader::deserialize(&mut cursor)
-            .map(|h| Some(h.length as usize)).unwrap_or(None)
+        MessageHeader::deserialize_for(&mut cursor, self.network_type).map(|h| h.length as usize)
     }
 
     pub fn mut_read_buf(&mut self) -> &mut Vec<u8> {
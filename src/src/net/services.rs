@@ -0,0 +1,149 @@
+use std::ops::{BitAnd, BitOr};
+
+/// Service bits advertised in version messages and addr entries. Bits we
+/// don't know about are kept so they can be relayed unchanged.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
+pub struct Services {
+    bits: u64,
+}
+
+pub const NODE_NONE: Services = Services { bits: 0 };
+// Serves the full block chain
+pub const NODE_NETWORK: Services = Services { bits: 1 << 0 };
+// BIP111, handles bloom filtered connections
+pub const NODE_BLOOM: Services = Services { bits: 1 << 2 };
+// BIP144, serves blocks and transactions with witness data
+pub const NODE_WITNESS: Services = Services { bits: 1 << 3 };
+// BIP157, serves compact block filters
+pub const NODE_COMPACT_FILTERS: Services = Services { bits: 1 << 6 };
+// BIP159, only serves the last NETWORK_LIMITED_BLOCKS blocks
+pub const NODE_NETWORK_LIMITED: Services = Services { bits: 1 << 10 };
+// BIP324, supports the v2 encrypted transport
+pub const NODE_P2P_V2: Services = Services { bits: 1 << 11 };
+
+const NAMES: [(Services, &'static str); 6] = [
+    (NODE_NETWORK, "NETWORK"),
+    (NODE_BLOOM, "BLOOM"),
+    (NODE_WITNESS, "WITNESS"),
+    (NODE_COMPACT_FILTERS, "COMPACT_FILTERS"),
+    (NODE_NETWORK_LIMITED, "NETWORK_LIMITED"),
+    (NODE_P2P_V2, "P2P_V2"),
+];
+
+/// BIP159: pruned peers keep at least this many blocks below their tip.
+pub const NETWORK_LIMITED_BLOCKS: i64 = 288;
+
+/// What we want to download from a peer.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Fetch {
+    // Block at the given height
+    Block(i64),
+    // Block at the given height with its witnesses
+    WitnessBlock(i64),
+    Filters,
+}
+
+impl Services {
+    pub fn new(bits: u64) -> Services {
+        Services {
+            bits: bits,
+        }
+    }
+
+    pub fn bits(&self) -> u64 { self.bits }
+
+    pub fn contains(&self, other: Services) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Bits without a known meaning.
+    pub fn unknown(&self) -> Services {
+        let known = NAMES.iter().fold(0, |bits, &(service, _)| bits | service.bits);
+        Services::new(self.bits & !known)
+    }
+
+    /// Names of the known bits as used by Bitcoin Core's RPC.
+    pub fn names(&self) -> Vec<&'static str> {
+        NAMES.iter()
+            .filter(|&&(service, _)| self.contains(service))
+            .map(|&(_, name)| name)
+            .collect()
+    }
+
+    /// Whether a peer advertising these services, with its chain tip at
+    /// `peer_height`, can provide `fetch`.
+    pub fn can_provide(&self, fetch: Fetch, peer_height: i64) -> bool {
+        let has_block = |height: i64| {
+            height <= peer_height &&
+                (self.contains(NODE_NETWORK) ||
+                 (self.contains(NODE_NETWORK_LIMITED) &&
+                  peer_height - height < NETWORK_LIMITED_BLOCKS))
+        };
+
+        match fetch {
+            Fetch::Block(height) => has_block(height),
+            Fetch::WitnessBlock(height) => self.contains(NODE_WITNESS) && has_block(height),
+            Fetch::Filters => self.contains(NODE_COMPACT_FILTERS),
+        }
+    }
+}
+
+impl BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        Services::new(self.bits | other.bits)
+    }
+}
+
+impl BitAnd for Services {
+    type Output = Services;
+
+    fn bitand(self, other: Services) -> Services {
+        Services::new(self.bits & other.bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialize::{Serialize, Deserialize};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_unknown_bits_round_trip() {
+        let services = NODE_NETWORK | NODE_WITNESS | Services::new(1 << 1 | 1 << 40);
+
+        let mut data = vec![];
+        services.serialize(&mut data);
+        assert_eq!(data, vec![0x0b, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(Services::deserialize(&mut Cursor::new(&data[..])), Ok(services));
+
+        assert_eq!(services.unknown(), Services::new(1 << 1 | 1 << 40));
+        assert_eq!(services.names(), vec!["NETWORK", "WITNESS"]);
+        assert!(services.contains(NODE_NETWORK | NODE_WITNESS));
+        assert!(!services.contains(NODE_NETWORK | NODE_BLOOM));
+    }
+
+    #[test]
+    fn test_can_provide() {
+        let full = NODE_NETWORK | NODE_WITNESS;
+        let pruned = NODE_NETWORK_LIMITED | NODE_WITNESS;
+        let filters = NODE_NETWORK | NODE_COMPACT_FILTERS;
+
+        assert!(full.can_provide(Fetch::Block(1), 1000));
+        assert!(!full.can_provide(Fetch::Block(1001), 1000));
+        assert!(full.can_provide(Fetch::WitnessBlock(1), 1000));
+        assert!(!full.can_provide(Fetch::Filters, 1000));
+
+        assert!(!pruned.can_provide(Fetch::Block(1), 1000));
+        assert!(pruned.can_provide(Fetch::Block(713), 1000));
+        assert!(!pruned.can_provide(Fetch::Block(712), 1000));
+        assert!(pruned.can_provide(Fetch::WitnessBlock(1000), 1000));
+
+        assert!(!filters.can_provide(Fetch::WitnessBlock(1), 1000));
+        assert!(filters.can_provide(Fetch::Filters, 1000));
+        assert!(!NODE_NONE.can_provide(Fetch::Block(0), 1000));
+    }
+}
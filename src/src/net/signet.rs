@@ -0,0 +1,579 @@
+// BIP325 block solutions. A signet block is valid when the solution
+// committed in its coinbase spends an output locked by the challenge, the
+// signed transaction commits to the block without the solution itself.
+use script::{Instructions, Parser, ScriptType, SignatureChecker, push_data, witness_program};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::ecdsa::Signature;
+use serialize::{Serialize, Deserialize};
+use utils::CryptoUtils;
+
+use super::messages::{BitcoinHash, BlockMessage, OutPoint, SerializeHash, TxIn, TxMessage,
+                      TxOut, merkle_root};
+
+use std::fmt;
+use std::io::Cursor;
+use std::rc::Rc;
+
+/// Marks the push of the witness commitment output that holds the solution.
+pub const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+
+// OP_RETURN, push of 36 bytes and the BIP141 commitment header
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+const OP_0: u8 = 0x00;
+const OP_RETURN: u8 = 0x6a;
+const OP_CODESEPARATOR: u8 = 0xab;
+
+const SIGHASH_ALL: u32 = 0x01;
+const SIGHASH_NONE: u32 = 0x02;
+const SIGHASH_SINGLE: u32 = 0x03;
+const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SignetError {
+    NoCoinbase,
+    NoWitnessCommitment,
+    // Trailing or truncated data after the header
+    MalformedSolution,
+    InvalidSolution,
+    // P2SH, taproot and future witness versions can't be checked yet
+    UnsupportedChallenge,
+    // The key isn't part of a challenge we know how to sign
+    CannotSign,
+}
+
+impl fmt::Display for SignetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SignetError::NoCoinbase => write!(f, "block has no coinbase"),
+            &SignetError::NoWitnessCommitment => write!(f, "block has no witness commitment"),
+            &SignetError::MalformedSolution => write!(f, "malformed signet solution"),
+            &SignetError::InvalidSolution => write!(f, "signet solution doesn't satisfy the challenge"),
+            &SignetError::UnsupportedChallenge => write!(f, "unsupported signet challenge"),
+            &SignetError::CannotSign => write!(f, "key can't satisfy the signet challenge"),
+        }
+    }
+}
+
+/// Message start of the signet using `challenge`.
+pub fn network_magic(challenge: &[u8]) -> u32 {
+    let mut data = vec![];
+    challenge.to_vec().serialize(&mut data);
+
+    let hash = CryptoUtils::sha256(&CryptoUtils::sha256(&data));
+    hash[..4].iter().rev().fold(0, |magic, &b| magic << 8 | b as u32)
+}
+
+// The last output that looks like a witness commitment
+fn witness_commitment_index(coinbase: &TxMessage) -> Option<usize> {
+    coinbase.tx_out.iter().rposition(|out| {
+        out.pk_script.len() >= 38 && out.pk_script.starts_with(&WITNESS_COMMITMENT_HEADER)
+    })
+}
+
+// Takes the solution out of the first push that starts with the header and
+// has more data, the header itself stays unless `keep_header` is false.
+// Pushes are re-encoded the way Bitcoin Core does.
+fn fetch_and_clear_solution(script: &[u8], keep_header: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut replacement = vec![];
+    let mut solution = None;
+
+    for instruction in Instructions::new(script) {
+        let instruction = match instruction {
+            Ok(instruction) => instruction,
+            Err(_) => break,
+        };
+
+        if !instruction.is_push() || instruction.data.is_empty() {
+            replacement.push(instruction.op_code);
+            continue;
+        }
+
+        let data = instruction.data;
+        if solution.is_none() && data.len() > SIGNET_HEADER.len() &&
+           data.starts_with(&SIGNET_HEADER) {
+            solution = Some(data[SIGNET_HEADER.len()..].to_vec());
+            if keep_header {
+                push_data(&mut replacement, &SIGNET_HEADER);
+            }
+        } else {
+            push_data(&mut replacement, data);
+        }
+    }
+
+    solution.map(|solution| (replacement, solution))
+}
+
+struct SignetTxs {
+    to_sign: TxMessage,
+    script_sig: Vec<u8>,
+    witness: Vec<Vec<u8>>,
+}
+
+// The transaction spending the challenge, with the solution read from the
+// coinbase. The block data it commits to uses the coinbase without it.
+fn signet_txs(block: &BlockMessage, challenge: &[u8]) -> Result<SignetTxs, SignetError> {
+    let mut coinbase = try!(block.txns.first().ok_or(SignetError::NoCoinbase)).clone();
+    let index = try!(witness_commitment_index(&coinbase).ok_or(SignetError::NoWitnessCommitment));
+
+    let solution = match fetch_and_clear_solution(&coinbase.tx_out[index].pk_script, true) {
+        Some((script, solution)) => {
+            coinbase.tx_out[index].pk_script = script;
+            solution
+        },
+        None => vec![],
+    };
+
+    let mut txids = vec![coinbase.hash()];
+    txids.extend(block.txns[1..].iter().map(|tx| tx.hash()));
+
+    let mut block_data = vec![];
+    block.metadata.version.serialize(&mut block_data);
+    block.metadata.prev_block.serialize(&mut block_data);
+    merkle_root(&txids).serialize(&mut block_data);
+    block.metadata.timestamp.serialize(&mut block_data);
+
+    let mut script = vec![OP_0];
+    push_data(&mut script, &block_data);
+
+    let to_spend = TxMessage {
+        version: 0,
+        tx_in: vec![TxIn {
+            previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+            script: script,
+            sequence: 0,
+        }],
+        tx_out: vec![TxOut {
+            value: 0,
+            pk_script: challenge.to_vec(),
+        }],
+        lock_time: 0,
+    };
+
+    let to_sign = TxMessage {
+        version: 0,
+        tx_in: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.hash(), 0),
+            script: vec![],
+            sequence: 0,
+        }],
+        tx_out: vec![TxOut {
+            value: 0,
+            pk_script: vec![OP_RETURN],
+        }],
+        lock_time: 0,
+    };
+
+    let (script_sig, witness) = if solution.is_empty() {
+        (vec![], vec![])
+    } else {
+        let mut cursor = Cursor::new(&solution[..]);
+        let script_sig = try!(Vec::<u8>::deserialize(&mut cursor)
+                              .map_err(|_| SignetError::MalformedSolution));
+        let witness = try!(Vec::<Vec<u8>>::deserialize(&mut cursor)
+                           .map_err(|_| SignetError::MalformedSolution));
+
+        if cursor.position() as usize != solution.len() {
+            return Err(SignetError::MalformedSolution);
+        }
+
+        (script_sig, witness)
+    };
+
+    Ok(SignetTxs {
+        to_sign: to_sign,
+        script_sig: script_sig,
+        witness: witness,
+    })
+}
+
+fn double_sha256(data: &[u8]) -> [u8; 32] {
+    CryptoUtils::sha256(&CryptoUtils::sha256(data))
+}
+
+// Original signature hash of the only input
+fn legacy_sighash(tx: &TxMessage, script_code: &[u8], hash_type: u32) -> [u8; 32] {
+    let mut tx = tx.clone();
+    tx.tx_in[0].script = script_code.to_vec();
+    if hash_type & 0x1f == SIGHASH_NONE {
+        tx.tx_out.clear();
+    }
+
+    let mut data = vec![];
+    tx.serialize(&mut data);
+    hash_type.serialize(&mut data);
+    double_sha256(&data)
+}
+
+// BIP143 signature hash of the only input
+fn witness_v0_sighash(tx: &TxMessage, script_code: &[u8], amount: i64,
+                      hash_type: u32) -> [u8; 32] {
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    let base = hash_type & 0x1f;
+    let input = &tx.tx_in[0];
+
+    let hash_prevouts = if anyone_can_pay {
+        [0; 32]
+    } else {
+        let mut data = vec![];
+        input.previous_output.serialize(&mut data);
+        double_sha256(&data)
+    };
+
+    let hash_sequence = if anyone_can_pay || base == SIGHASH_SINGLE || base == SIGHASH_NONE {
+        [0; 32]
+    } else {
+        let mut data = vec![];
+        input.sequence.serialize(&mut data);
+        double_sha256(&data)
+    };
+
+    let hash_outputs = if base == SIGHASH_NONE || tx.tx_out.is_empty() {
+        [0; 32]
+    } else {
+        // SIGHASH_SINGLE of the first input only covers the first output
+        let outputs = if base == SIGHASH_SINGLE { &tx.tx_out[..1] } else { &tx.tx_out[..] };
+        let mut data = vec![];
+        for output in outputs {
+            output.serialize(&mut data);
+        }
+        double_sha256(&data)
+    };
+
+    let mut data = vec![];
+    tx.version.serialize(&mut data);
+    data.extend(hash_prevouts.iter());
+    data.extend(hash_sequence.iter());
+    input.previous_output.serialize(&mut data);
+    script_code.to_vec().serialize(&mut data);
+    amount.serialize(&mut data);
+    input.sequence.serialize(&mut data);
+    data.extend(hash_outputs.iter());
+    tx.lock_time.serialize(&mut data);
+    hash_type.serialize(&mut data);
+    double_sha256(&data)
+}
+
+// What's left of `script` after the last executed OP_CODESEPARATOR
+fn after_codeseparator(script: &[u8], codeseparator: usize) -> &[u8] {
+    match script.get(codeseparator) {
+        Some(&OP_CODESEPARATOR) => &script[codeseparator + 1..],
+        _ => script,
+    }
+}
+
+// Legacy script codes don't sign the separators
+fn remove_codeseparators(script: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    for instruction in Instructions::new(script) {
+        match instruction {
+            Ok(ref instruction) if instruction.op_code == OP_CODESEPARATOR => {},
+            Ok(ref instruction) if instruction.is_push() => push_data(&mut result, instruction.data),
+            Ok(instruction) => result.push(instruction.op_code),
+            Err(_) => break,
+        }
+    }
+    result
+}
+
+fn signature_checker(tx: TxMessage, script: Vec<u8>, witness: bool) -> SignatureChecker {
+    let secp = Secp256k1::verification_only();
+
+    Rc::new(move |codeseparator: usize, pub_key: &Vec<u8>, sig: &Vec<u8>| {
+        let (hash_type, der) = match sig.split_last() {
+            Some((&hash_type, der)) => (hash_type as u32, der),
+            None => return false,
+        };
+
+        let script_code = after_codeseparator(&script, codeseparator);
+        let message = if witness {
+            witness_v0_sighash(&tx, script_code, 0, hash_type)
+        } else {
+            legacy_sighash(&tx, &remove_codeseparators(script_code), hash_type)
+        };
+
+        match (Signature::from_der(der), PublicKey::from_slice(pub_key)) {
+            (Ok(mut signature), Ok(public_key)) => {
+                // Consensus allows high S values, libsecp256k1 only verifies low ones
+                signature.normalize_s();
+                let message = Message::from_digest(message);
+                secp.verify_ecdsa(&message, &signature, &public_key).is_ok()
+            },
+            _ => false,
+        }
+    })
+}
+
+fn p2pkh_script(key_hash: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9];
+    push_data(&mut script, key_hash);
+    script.extend(vec![0x88, 0xac]);
+    script
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    CryptoUtils::ripemd160(&CryptoUtils::sha256(data))
+}
+
+fn verify_solution(txs: SignetTxs, challenge: &[u8]) -> Result<bool, SignetError> {
+    let SignetTxs { to_sign, script_sig, mut witness } = txs;
+
+    let result = match ScriptType::classify(challenge) {
+        ScriptType::WitnessV0KeyHash => {
+            let program = witness_program(challenge).unwrap().1;
+            if !script_sig.is_empty() || witness.len() != 2 || hash160(&witness[1]) != program {
+                return Ok(false);
+            }
+
+            let script = p2pkh_script(program);
+            let checker = signature_checker(to_sign, script.clone(), true);
+            Parser::execute_witness(witness, script, checker)
+        },
+        ScriptType::WitnessV0ScriptHash => {
+            let program = witness_program(challenge).unwrap().1;
+            let script = match witness.pop() {
+                Some(script) => script,
+                None => return Ok(false),
+            };
+
+            if !script_sig.is_empty() || CryptoUtils::sha256(&script) != program {
+                return Ok(false);
+            }
+
+            let checker = signature_checker(to_sign, script.clone(), true);
+            Parser::execute_witness(witness, script, checker)
+        },
+        ScriptType::ScriptHash |
+        ScriptType::WitnessV1Taproot |
+        ScriptType::WitnessUnknown => return Err(SignetError::UnsupportedChallenge),
+        _ => {
+            if !witness.is_empty() {
+                return Ok(false);
+            }
+
+            let checker = signature_checker(to_sign, challenge.to_vec(), false);
+            Parser::execute_with(script_sig, challenge.to_vec(), checker)
+        },
+    };
+
+    Ok(result.unwrap_or(false))
+}
+
+/// Checks the signet solution of `block` against `challenge`.
+pub fn check_block_solution(block: &BlockMessage, challenge: &[u8]) -> Result<(), SignetError> {
+    let txs = try!(signet_txs(block, challenge));
+
+    if try!(verify_solution(txs, challenge)) {
+        Ok(())
+    } else {
+        Err(SignetError::InvalidSolution)
+    }
+}
+
+/// Signs `block` for a challenge of the form `<key> CHECKSIG`, a 1 of n
+/// `CHECKMULTISIG` or P2WPKH. The coinbase must already have a witness
+/// commitment, the solution is added to it and the merkle root updated. The
+/// proof of work has to be redone afterwards.
+pub fn sign_block(block: &mut BlockMessage, challenge: &[u8],
+                  secret_key: &SecretKey) -> Result<(), SignetError> {
+    let index = {
+        let coinbase = try!(block.txns.first().ok_or(SignetError::NoCoinbase));
+        try!(witness_commitment_index(coinbase).ok_or(SignetError::NoWitnessCommitment))
+    };
+
+    // Drop any previous solution
+    let mut pk_script = block.txns[0].tx_out[index].pk_script.clone();
+    if let Some((script, _)) = fetch_and_clear_solution(&pk_script, false) {
+        pk_script = script;
+    }
+
+    // What the check sees once the solution is cleared
+    let mut unsigned = block.clone();
+    unsigned.txns[0].tx_out[index].pk_script = pk_script.clone();
+    push_data(&mut unsigned.txns[0].tx_out[index].pk_script, &SIGNET_HEADER);
+    let to_sign = try!(signet_txs(&unsigned, challenge)).to_sign;
+
+    let secp = Secp256k1::signing_only();
+    let public_key = PublicKey::from_secret_key(&secp, secret_key);
+    let keys = [public_key.serialize().to_vec(), public_key.serialize_uncompressed().to_vec()];
+    let key_pushed = Instructions::new(challenge).filter_map(|i| i.ok())
+        .any(|i| i.is_push() && keys.iter().any(|key| key[..] == i.data[..]));
+
+    let sign = |message: [u8; 32]| {
+        let message = Message::from_digest(message);
+        let mut sig = secp.sign_ecdsa(&message, secret_key).serialize_der().to_vec();
+        sig.push(SIGHASH_ALL as u8);
+        sig
+    };
+
+    let (script_sig, witness) = match ScriptType::classify(challenge) {
+        ScriptType::PubKey if key_pushed => {
+            let mut script_sig = vec![];
+            push_data(&mut script_sig, &sign(legacy_sighash(&to_sign, challenge, SIGHASH_ALL)));
+            (script_sig, vec![])
+        },
+        // One signature is only enough when the challenge starts with OP_1
+        ScriptType::MultiSig if challenge[0] == 0x51 && key_pushed => {
+            let mut script_sig = vec![OP_0];
+            push_data(&mut script_sig, &sign(legacy_sighash(&to_sign, challenge, SIGHASH_ALL)));
+            (script_sig, vec![])
+        },
+        ScriptType::WitnessV0KeyHash => {
+            let program = witness_program(challenge).unwrap().1;
+            let key = public_key.serialize().to_vec();
+            if hash160(&key) != program {
+                return Err(SignetError::CannotSign);
+            }
+
+            let sighash = witness_v0_sighash(&to_sign, &p2pkh_script(program), 0, SIGHASH_ALL);
+            (vec![], vec![sign(sighash), key])
+        },
+        _ => return Err(SignetError::CannotSign),
+    };
+
+    let mut solution = SIGNET_HEADER.to_vec();
+    script_sig.serialize(&mut solution);
+    witness.serialize(&mut solution);
+    push_data(&mut pk_script, &solution);
+
+    block.txns[0].tx_out[index].pk_script = pk_script;
+    block.metadata.merkle_root = block.compute_merkle_root();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::{BlockMessage, BlockMetadata, Timestamp32};
+
+    use rustc_serialize::hex::FromHex;
+
+    fn secret_key(n: u8) -> SecretKey {
+        SecretKey::from_slice(&[n; 32]).unwrap()
+    }
+
+    fn public_key(key: &SecretKey) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), key)
+    }
+
+    fn block() -> BlockMessage {
+        let mut commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        commitment.extend(vec![0x11; 32]);
+
+        let coinbase = TxMessage {
+            version: 1,
+            tx_in: vec![TxIn {
+                previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                script: vec![0x01, 0x01],
+                sequence: 0xffffffff,
+            }],
+            tx_out: vec![TxOut { value: 5000000000, pk_script: vec![0x51] },
+                         TxOut { value: 0, pk_script: commitment }],
+            lock_time: 0,
+        };
+
+        let mut block = BlockMessage {
+            metadata: BlockMetadata {
+                version: 0x20000000,
+                prev_block: BitcoinHash::new([0x22; 32]),
+                merkle_root: BitcoinHash::new([0; 32]),
+                timestamp: Timestamp32::new(1700000000),
+                bits: 0x1e0377ae,
+                nonce: 0,
+            },
+            txns: vec![coinbase],
+        };
+        block.metadata.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    fn multisig_challenge(keys: &[&SecretKey]) -> Vec<u8> {
+        let mut challenge = vec![0x51];
+        for key in keys {
+            push_data(&mut challenge, &public_key(key).serialize());
+        }
+        challenge.push(0x50 + keys.len() as u8);
+        challenge.push(0xae);
+        challenge
+    }
+
+    #[test]
+    fn test_network_magic() {
+        let challenge = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be43021\
+                         0359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae"
+            .from_hex().unwrap();
+        assert_eq!(network_magic(&challenge), 0x40CF030A);
+    }
+
+    #[test]
+    fn test_sign_and_check_multisig() {
+        let (first, second) = (secret_key(1), secret_key(2));
+        let challenge = multisig_challenge(&[&first, &second]);
+
+        let mut block = block();
+        assert_eq!(check_block_solution(&block, &challenge), Err(SignetError::InvalidSolution));
+
+        sign_block(&mut block, &challenge, &second).unwrap();
+        assert_eq!(block.metadata.merkle_root, block.compute_merkle_root());
+        check_block_solution(&block, &challenge).unwrap();
+
+        // Signing again replaces the solution instead of adding one
+        sign_block(&mut block, &challenge, &first).unwrap();
+        let pk_script = &block.txns[0].tx_out[1].pk_script;
+        assert_eq!(pk_script.windows(4).filter(|w| w == &SIGNET_HEADER).count(), 1);
+        check_block_solution(&block, &challenge).unwrap();
+
+        // The solution commits to the rest of the header
+        let mut other = block.clone();
+        other.metadata.timestamp = Timestamp32::new(1700000001);
+        assert_eq!(check_block_solution(&other, &challenge), Err(SignetError::InvalidSolution));
+
+        // But not to the nonce
+        let mut other = block.clone();
+        other.metadata.nonce = 42;
+        check_block_solution(&other, &challenge).unwrap();
+
+        assert_eq!(sign_block(&mut block, &challenge, &secret_key(3)),
+                   Err(SignetError::CannotSign));
+    }
+
+    #[test]
+    fn test_sign_and_check_p2wpkh() {
+        let key = secret_key(4);
+        let mut challenge = vec![0x00];
+        push_data(&mut challenge, &hash160(&public_key(&key).serialize()));
+
+        let mut block = block();
+        sign_block(&mut block, &challenge, &key).unwrap();
+        check_block_solution(&block, &challenge).unwrap();
+
+        // The signature is only good for its own challenge
+        let other = multisig_challenge(&[&secret_key(5)]);
+        assert_eq!(check_block_solution(&block, &other), Err(SignetError::InvalidSolution));
+    }
+
+    #[test]
+    fn test_malformed_blocks() {
+        let challenge = vec![0x51];
+        let mut block = block();
+
+        // OP_TRUE needs no solution at all
+        check_block_solution(&block, &challenge).unwrap();
+
+        // Nor can a missing one break the interpreter
+        assert_eq!(check_block_solution(&block, &[0x76]), Err(SignetError::InvalidSolution));
+
+        let mut pk_script = block.txns[0].tx_out[1].pk_script.clone();
+        push_data(&mut pk_script, &[0xec, 0xc7, 0xda, 0xa2, 0x00, 0x00, 0xff]);
+        block.txns[0].tx_out[1].pk_script = pk_script;
+        assert_eq!(check_block_solution(&block, &challenge),
+                   Err(SignetError::MalformedSolution));
+
+        block.txns[0].tx_out.pop();
+        assert_eq!(check_block_solution(&block, &challenge),
+                   Err(SignetError::NoWitnessCommitment));
+
+        block.txns.clear();
+        assert_eq!(check_block_solution(&block, &challenge), Err(SignetError::NoCoinbase));
+    }
+}
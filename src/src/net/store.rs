@@ -1,20 +1,21 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use serialize::{Serialize, Deserialize};
-use super::messages::{BlockMetadata, NetworkType, BlockMessage, BitcoinHash,
-                      TxIn, TxOut, OutPoint, TxMessage, ShortFormatTm,
-                      SerializeHash};
+use super::messages::{BlockMetadata, BlockMessage, BitcoinHash, SerializeHash};
+use super::block_ref::BlockRef;
+use super::chain_params::ChainParams;
 
 use std::io::{Seek, SeekFrom};
 
-use time;
 
 pub struct BlockBlobStore {
     store: HashMap<BitcoinHash, (BlockMetadata, usize)>,
     disk_store: File,
     last_index: usize,
+    // Blocks may have an AuxPoW after the header
+    merge_mined: bool,
 }
 
 impl BlockBlobStore {
@@ -26,35 +27,49 @@ impl BlockBlobStore {
         self.store.get(hash).map(|data| &data.0)
     }
 
-    pub fn get_block(&mut self, hash: &BitcoinHash) -> Option<BlockMessage> {
+    pub fn get_data(&mut self, hash: &BitcoinHash) -> Option<Vec<u8>> {
         self.store.get(hash).map(|data| data.1)
             .map(|pos| {
                 self.disk_store.seek(SeekFrom::Start(pos as u64)).unwrap();
 
                 let length: u64        = Deserialize::deserialize(&mut self.disk_store).unwrap();
                 let hash: BitcoinHash  = Deserialize::deserialize(&mut self.disk_store).unwrap();
-                let block: BlockMessage= Deserialize::deserialize(&mut self.disk_store).unwrap();
 
-                let (serialized, real_hash) = block.serialize_hash();
+                let mut data = vec![0; length as usize];
+                self.disk_store.read_exact(&mut data).unwrap();
+
+                assert_eq!(hash, BlockRef::parse(&data).unwrap().hash());
+
+                data
+            })
+    }
 
-                assert_eq!(serialized.len() as u64, length);
-                assert_eq!(hash, real_hash);
+    pub fn get_block(&mut self, hash: &BitcoinHash) -> Option<BlockMessage> {
+        let merge_mined = self.merge_mined;
+        self.get_data(hash)
+            .map(|data| {
+                let block = if merge_mined {
+                    BlockRef::parse_auxpow(&data)
+                } else {
+                    BlockRef::parse(&data)
+                };
 
-                block
+                block.and_then(|block| block.to_owned()).unwrap()
             })
     }
 
-    pub fn insert(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
+    pub fn insert(&mut self, metadata: BlockMetadata, hash: &BitcoinHash, data: &[u8]) {
         if self.store.get(hash).is_none() {
             // Let's save the length and hash to double check data on disk
             (data.len() as u64).serialize(&mut self.disk_store);
             self.disk_store.write_all(hash.inner()).unwrap();
             self.disk_store.write_all(data).unwrap();
 
-            self.store.insert(hash.clone(), (block.into_metadata(), self.last_index));
+            self.store.insert(hash.clone(), (metadata, self.last_index));
 
             self.disk_store.sync_all().unwrap();
-            self.last_index += 0;
+            // Length, hash and block, where the next one starts
+            self.last_index += 8 + 32 + data.len();
         }
     }
 
@@ -77,7 +92,7 @@ impl BlockBlobStore {
         Ok((length, hash, data))
     }
 
-    pub fn new(disk_store_: File) -> BlockBlobStore {
+    pub fn new(disk_store_: File, merge_mined: bool) -> BlockBlobStore {
         let mut disk_store = disk_store_;
 
         let mut store = HashMap::new();
@@ -99,6 +114,7 @@ impl BlockBlobStore {
             store: store,
             disk_store: disk_store,
             last_index: last_index as usize,
+            merge_mined: merge_mined,
         }
     }
 }
@@ -117,6 +133,15 @@ impl BlockStore {
         self.store.get_block(hash)
     }
 
+    pub fn get_metadata(&self, hash: &BitcoinHash) -> Option<&BlockMetadata> {
+        self.store.get(hash)
+    }
+
+    // The block as it was received, with its AuxPoW if it has one
+    pub fn get_data(&mut self, hash: &BitcoinHash) -> Option<Vec<u8>> {
+        self.store.get_data(hash)
+    }
+
     pub fn get_hash_at_height(&self, height: usize) -> Option<&BitcoinHash> {
         self.height_store.get(height)
     }
@@ -127,14 +152,25 @@ impl BlockStore {
 
     pub fn height(&self) -> usize { self.height_store_rev[&self.highest_block] }
 
-    pub fn insert(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
-        self.store.insert(block, hash, data);
+    pub fn insert(&mut self, block: &BlockRef) {
+        let hash = block.hash();
+        self.store.insert(block.metadata(), &hash, block.data());
 
         self.highest_block =
-            Self::insert_chain(hash, &self.store, &mut self.height_store_rev,
+            Self::insert_chain(&hash, &self.store, &mut self.height_store_rev,
                                &mut self.height_store, self.highest_block);
     }
 
+    // Blocks can arrive before their parent, tries again to add a stored
+    // block to the best chain once the parent is there.
+    pub fn connect(&mut self, hash: &BitcoinHash) {
+        if self.store.has(hash) {
+            self.highest_block =
+                Self::insert_chain(hash, &self.store, &mut self.height_store_rev,
+                                   &mut self.height_store, self.highest_block);
+        }
+    }
+
     fn reload_chain(&mut self) {
         for (ref hash, _) in self.store.store.iter() {
             self.highest_block =
@@ -223,33 +259,12 @@ impl BlockStore {
         }
     }
 
-    pub fn new(disk_store: File, network_type: NetworkType) -> BlockStore {
-        let genesis_hash = BitcoinHash::new(match network_type {
-            NetworkType::TestNet3 =>
-                [0x43, 0x49, 0x7F, 0xD7, 0xF8, 0x26, 0x95, 0x71,
-                 0x08, 0xF4, 0xA3, 0x0F, 0xD9, 0xCE, 0xC3, 0xAE,
-                 0xBA, 0x79, 0x97, 0x20, 0x84, 0xE9, 0x0E, 0xAD,
-                 0x01, 0xEA, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00],
-            NetworkType::Main =>
-                [0x6F, 0xE2, 0x8C, 0x0A, 0xB6, 0xF1, 0xB3, 0x72,
-                 0xC1, 0xA6, 0xA2, 0x46, 0xAE, 0x63, 0xF7, 0x4F,
-                 0x93, 0x1E, 0x83, 0x65, 0xE1, 0x5A, 0x08, 0x9C,
-                 0x68, 0xD6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00],
-            NetworkType::TestNet =>  unimplemented!(),
-            NetworkType::NameCoin => unimplemented!(),
-            NetworkType::Unknown =>  unreachable!(),
-        });
-
-        let genesis_block = match network_type {
-            NetworkType::TestNet3 => Self::genesis_block_testnet3(),
-            NetworkType::TestNet =>  unimplemented!(),
-            NetworkType::Main     => unimplemented!(),
-            NetworkType::NameCoin => unimplemented!(),
-            NetworkType::Unknown =>  unreachable!(),
-        };
+    pub fn new(disk_store: File, params: &ChainParams) -> BlockStore {
+        let genesis_hash = params.genesis_hash;
+        let genesis_block = params.genesis_block();
 
         let mut store = BlockStore {
-            store: BlockBlobStore::new(disk_store),
+            store: BlockBlobStore::new(disk_store, params.auxpow.is_some()),
             height_store_rev: HashMap::new(),
             height_store: vec![genesis_hash],
             highest_block: genesis_hash,
@@ -259,74 +274,10 @@ impl BlockStore {
 
         assert_eq!(confirm_hash, genesis_hash);
 
-        store.store.insert(genesis_block, &genesis_hash, &serialized);
+        store.store.insert(genesis_block.into_metadata(), &genesis_hash, &serialized);
         store.height_store_rev.insert(genesis_hash, 0);
         store.reload_chain();
 
         store
     }
-
-    fn genesis_block_testnet3() -> BlockMessage {
-        let metadata = BlockMetadata {
-            version: 1,
-            prev_block: BitcoinHash::new([0; 32]),
-            merkle_root: BitcoinHash::new(
-               [0x3B, 0xA3, 0xED, 0xFD, 0x7A, 0x7B, 0x12, 0xB2,
-                0x7A, 0xC7, 0x2C, 0x3E, 0x67, 0x76, 0x8F, 0x61,
-                0x7F, 0xC8, 0x1B, 0xC3, 0x88, 0x8A, 0x51, 0x32,
-                0x3A, 0x9F, 0xB8, 0xAA, 0x4B, 0x1E, 0x5E, 0x4A]),
-            timestamp:
-                ShortFormatTm::new(time::at_utc(time::Timespec::new(1296688602, 0))),
-            bits: 486604799,
-            nonce: 414098458,
-        };
-
-
-        let out_point = OutPoint {
-            hash: BitcoinHash::new([0; 32]),
-            index: 0,
-        };
-
-        let tx_in = TxIn {
-            previous_output: out_point,
-            script: vec![
-                0x73, 0x6B, 0x6E, 0x61, 0x62, 0x20, 0x72, 0x6F,
-                0x66, 0x20, 0x74, 0x75, 0x6F, 0x6C, 0x69, 0x61,
-                0x62, 0x20, 0x64, 0x6E, 0x6F, 0x63, 0x65, 0x73,
-                0x20, 0x66, 0x6F, 0x20, 0x6B, 0x6E, 0x69, 0x72,
-                0x62, 0x20, 0x6E, 0x6F, 0x20, 0x72, 0x6F, 0x6C,
-                0x6C, 0x65, 0x63, 0x6E, 0x61, 0x68, 0x43, 0x20,
-                0x39, 0x30, 0x30, 0x32, 0x2F, 0x6E, 0x61, 0x4A,
-                0x2F, 0x33, 0x30, 0x20, 0x73, 0x65, 0x6D, 0x69,
-                0x54, 0x20, 0x65, 0x68, 0x54, 0x45, 0x04, 0x01,
-                0x1D, 0x00, 0xFF, 0xFF, 0x04],
-            sequence: 4294967295,
-        };
-
-        let tx_out = TxOut {
-            value: 5000000000,
-            pk_script: vec![
-                0x5F, 0x1D, 0xF1, 0x6B, 0x2B, 0x70, 0x4C, 0x8A,
-                0x57, 0x8D, 0x0B, 0xBA, 0xF7, 0x4D, 0x38, 0x5C,
-                0xDE, 0x12, 0xC1, 0x1E, 0xE5, 0x04, 0x55, 0xF3,
-                0xC4, 0x38, 0xEF, 0x4C, 0x3F, 0xBC, 0xF6, 0x49,
-                0xB6, 0xDE, 0x61, 0x1F, 0xEA, 0xE0, 0x62, 0x79,
-                0xA6, 0x09, 0x39, 0xE0, 0x28, 0xA8, 0xD6, 0x5C,
-                0x10, 0xB7, 0x30, 0x71, 0xA6, 0xF1, 0x67, 0x19,
-                0x27, 0x48, 0x55, 0xFE, 0xB0, 0xFD, 0x8A, 0x67,
-                0x04],
-        };
-
-        let tx = TxMessage {
-            version: 1,
-            tx_in: vec![tx_in],
-            tx_out: vec![tx_out],
-            lock_time: 0,
-        };
-
-        BlockMessage {
-            metadata: metadata,
-            txns: vec![tx],
-        }
-    }
 }
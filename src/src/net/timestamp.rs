@@ -0,0 +1,164 @@
+use serialize::{Serialize, Serializer, Deserialize, Deserializer, DecodeError};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Unsigned 32 bit Unix time used by block headers and addr entries, good
+/// until 2106.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct Timestamp32 {
+    secs: u32,
+}
+
+/// Signed 64 bit Unix time used by version messages.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct Timestamp64 {
+    secs: i64,
+}
+
+// Seconds since the epoch, negative for times before it. 2^63 seconds before
+// wraps to exactly i64::min_value().
+fn system_time_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => (e.duration().as_secs() as i64).wrapping_neg(),
+    }
+}
+
+impl Timestamp32 {
+    pub fn new(secs: u32) -> Timestamp32 {
+        Timestamp32 {
+            secs: secs,
+        }
+    }
+
+    pub fn now() -> Timestamp32 {
+        // Clamped, the clock would have to be past 2106
+        Timestamp32::from_system_time(SystemTime::now())
+            .unwrap_or(Timestamp32::new(u32::max_value()))
+    }
+
+    /// None when the time doesn't fit in 32 bits.
+    pub fn from_system_time(time: SystemTime) -> Option<Timestamp32> {
+        let secs = system_time_secs(time);
+        if secs < 0 || secs > u32::max_value() as i64 {
+            None
+        } else {
+            Some(Timestamp32::new(secs as u32))
+        }
+    }
+
+    pub fn secs(&self) -> u32 { self.secs }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.secs as u64)
+    }
+}
+
+impl Timestamp64 {
+    pub fn new(secs: i64) -> Timestamp64 {
+        Timestamp64 {
+            secs: secs,
+        }
+    }
+
+    pub fn now() -> Timestamp64 {
+        Timestamp64::from_system_time(SystemTime::now())
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Timestamp64 {
+        Timestamp64::new(system_time_secs(time))
+    }
+
+    pub fn secs(&self) -> i64 { self.secs }
+
+    /// None when the platform can't represent the time, peers can send any
+    /// 64 bit value.
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        if self.secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(self.secs as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(self.secs.wrapping_neg() as u64))
+        }
+    }
+}
+
+impl From<Timestamp32> for Timestamp64 {
+    fn from(timestamp: Timestamp32) -> Timestamp64 {
+        Timestamp64::new(timestamp.secs as i64)
+    }
+}
+
+impl Serialize for Timestamp32 {
+    fn serialize(&self, serializer: &mut Serializer) {
+        self.secs.serialize(serializer);
+    }
+
+    fn encoded_len(&self) -> usize { 4 }
+}
+
+impl Deserialize for Timestamp32 {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        u32::deserialize(deserializer).map(Timestamp32::new)
+    }
+}
+
+impl Serialize for Timestamp64 {
+    fn serialize(&self, serializer: &mut Serializer) {
+        self.secs.serialize(serializer);
+    }
+
+    fn encoded_len(&self) -> usize { 8 }
+}
+
+impl Deserialize for Timestamp64 {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        i64::deserialize(deserializer).map(Timestamp64::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialize::{Serialize, Deserialize};
+
+    use std::io::Cursor;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_timestamp32_full_range() {
+        // Past the old 2,000,000,000 limit and the signed 32 bit one
+        for &secs in [0, 2000000001, 0x80000000, u32::max_value()].iter() {
+            let timestamp = Timestamp32::new(secs);
+
+            let mut data = vec![];
+            timestamp.serialize(&mut data);
+            assert_eq!(data.len(), timestamp.encoded_len());
+            assert_eq!(Timestamp32::deserialize(&mut Cursor::new(&data[..])), Ok(timestamp));
+
+            let time = timestamp.to_system_time();
+            assert_eq!(Timestamp32::from_system_time(time), Some(timestamp));
+        }
+
+        assert_eq!(Timestamp32::from_system_time(UNIX_EPOCH + Duration::from_secs(1 << 32)), None);
+    }
+
+    #[test]
+    fn test_timestamp64_never_panics() {
+        for &secs in [i64::min_value(), -1, 0, 2000000001, i64::max_value()].iter() {
+            let timestamp = Timestamp64::new(secs);
+
+            let mut data = vec![];
+            timestamp.serialize(&mut data);
+            assert_eq!(Timestamp64::deserialize(&mut Cursor::new(&data[..])), Ok(timestamp));
+
+            if let Some(time) = timestamp.to_system_time() {
+                assert_eq!(Timestamp64::from_system_time(time), timestamp);
+            }
+        }
+
+        assert_eq!(Timestamp64::new(-1).to_system_time(),
+                   Some(UNIX_EPOCH - Duration::from_secs(1)));
+        assert_eq!(Timestamp64::from(Timestamp32::new(u32::max_value())).secs(),
+                   u32::max_value() as i64);
+    }
+}
@@ -0,0 +1,808 @@
+// Partially Signed Bitcoin Transactions, version 0 (BIP174) and 2 (BIP370).
+//
+// Both versions are held in the same structure: the fields of the unsigned
+// transaction are spread over the global map, the inputs and the outputs as
+// in version 2, and version 0 rebuilds the unsigned transaction from them
+// when serializing.
+mod roles;
+
+use rustc_serialize::base64::{self, FromBase64, ToBase64};
+
+use net::messages::{BitcoinHash, OutPoint, SerializeHash, TxIn, TxMessage, TxOut};
+use serialize::{Serialize, Serializer, Deserialize, DecodeError, KeyValue, KeyValueMap, VarInt};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Cursor;
+
+const MAGIC: &'static [u8] = b"psbt\xff";
+
+const GLOBAL_UNSIGNED_TX: u64 = 0x00;
+const GLOBAL_XPUB: u64 = 0x01;
+const GLOBAL_TX_VERSION: u64 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u64 = 0x03;
+const GLOBAL_INPUT_COUNT: u64 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u64 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u64 = 0x06;
+const GLOBAL_VERSION: u64 = 0xfb;
+
+const IN_NON_WITNESS_UTXO: u64 = 0x00;
+const IN_WITNESS_UTXO: u64 = 0x01;
+const IN_PARTIAL_SIG: u64 = 0x02;
+const IN_SIGHASH_TYPE: u64 = 0x03;
+const IN_REDEEM_SCRIPT: u64 = 0x04;
+const IN_WITNESS_SCRIPT: u64 = 0x05;
+const IN_BIP32_DERIVATION: u64 = 0x06;
+const IN_FINAL_SCRIPTSIG: u64 = 0x07;
+const IN_FINAL_SCRIPTWITNESS: u64 = 0x08;
+const IN_PREVIOUS_TXID: u64 = 0x0e;
+const IN_OUTPUT_INDEX: u64 = 0x0f;
+const IN_SEQUENCE: u64 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u64 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u64 = 0x12;
+
+const OUT_REDEEM_SCRIPT: u64 = 0x00;
+const OUT_WITNESS_SCRIPT: u64 = 0x01;
+const OUT_BIP32_DERIVATION: u64 = 0x02;
+const OUT_AMOUNT: u64 = 0x03;
+const OUT_SCRIPT: u64 = 0x04;
+
+// Lock times below this are block heights, above are timestamps
+const LOCKTIME_THRESHOLD: u32 = 500000000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PsbtError {
+    Decode(DecodeError),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    MissingField(&'static str),
+    // The field doesn't exist in this PSBT version
+    UnexpectedField(&'static str),
+    // Key data on a key that doesn't take any, or malformed key data
+    InvalidKey(&'static str),
+    InvalidValue(&'static str),
+    NonEmptyScriptSig(usize),
+    // Inputs require both a height and a time based lock time
+    LockTimeConflict,
+    // Only PSBTs of the same transaction can be combined
+    TxMismatch,
+    UtxoMismatch(usize),
+    CannotFinalize(usize, &'static str),
+    NotFinalized(usize),
+    // `TxMessage` has no witness, see `Psbt::extract_raw`
+    WitnessNotSupported,
+}
+
+impl fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PsbtError::Decode(ref e) => write!(f, "{}", e),
+            &PsbtError::InvalidMagic => write!(f, "invalid magic bytes"),
+            &PsbtError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            &PsbtError::MissingField(name) => write!(f, "missing {}", name),
+            &PsbtError::UnexpectedField(name) => write!(f, "{} not allowed in this version", name),
+            &PsbtError::InvalidKey(name) => write!(f, "invalid key for {}", name),
+            &PsbtError::InvalidValue(name) => write!(f, "invalid value for {}", name),
+            &PsbtError::NonEmptyScriptSig(i) =>
+                write!(f, "input {} of the unsigned transaction has a scriptSig", i),
+            &PsbtError::LockTimeConflict => write!(f, "conflicting lock time requirements"),
+            &PsbtError::TxMismatch => write!(f, "PSBTs are for different transactions"),
+            &PsbtError::UtxoMismatch(i) => write!(f, "UTXO of input {} doesn't match", i),
+            &PsbtError::CannotFinalize(i, reason) =>
+                write!(f, "can't finalize input {}: {}", i, reason),
+            &PsbtError::NotFinalized(i) => write!(f, "input {} is not finalized", i),
+            &PsbtError::WitnessNotSupported =>
+                write!(f, "transaction has witness data"),
+        }
+    }
+}
+
+impl From<DecodeError> for PsbtError {
+    fn from(error: DecodeError) -> PsbtError {
+        PsbtError::Decode(error)
+    }
+}
+
+impl From<PsbtError> for String {
+    fn from(error: PsbtError) -> String {
+        error.to_string()
+    }
+}
+
+/// BIP32 fingerprint of the master key and derivation path
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeySource {
+    pub fingerprint: [u8; 4],
+    pub path: Vec<u32>,
+}
+
+impl KeySource {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = self.fingerprint.to_vec();
+        for index in self.path.iter() {
+            index.serialize(&mut result);
+        }
+
+        result
+    }
+
+    fn from_bytes(data: &[u8], name: &'static str) -> Result<KeySource, PsbtError> {
+        if data.len() < 4 || data.len() % 4 != 0 {
+            return Err(PsbtError::InvalidValue(name));
+        }
+
+        let mut path = vec![];
+        for index in data[4..].chunks(4) {
+            path.push(try!(decode(index, name)));
+        }
+
+        Ok(KeySource {
+            fingerprint: [data[0], data[1], data[2], data[3]],
+            path: path,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PsbtInput {
+    pub previous_output: OutPoint,
+    // Defaults to 0xffffffff when missing
+    pub sequence: Option<u32>,
+    pub required_time_lock_time: Option<u32>,
+    pub required_height_lock_time: Option<u32>,
+    pub non_witness_utxo: Option<TxMessage>,
+    pub witness_utxo: Option<TxOut>,
+    // Public key to signature
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sighash_type: Option<u32>,
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub final_script_sig: Option<Vec<u8>>,
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+    pub unknown: KeyValueMap,
+}
+
+impl PsbtInput {
+    pub fn new(previous_output: OutPoint, sequence: Option<u32>) -> PsbtInput {
+        PsbtInput {
+            previous_output: previous_output,
+            sequence: sequence,
+            required_time_lock_time: None,
+            required_height_lock_time: None,
+            non_witness_utxo: None,
+            witness_utxo: None,
+            partial_sigs: BTreeMap::new(),
+            sighash_type: None,
+            redeem_script: None,
+            witness_script: None,
+            bip32_derivation: BTreeMap::new(),
+            final_script_sig: None,
+            final_script_witness: None,
+            unknown: KeyValueMap::new(),
+        }
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.final_script_sig.is_some() || self.final_script_witness.is_some()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PsbtOutput {
+    pub amount: i64,
+    pub script: Vec<u8>,
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub unknown: KeyValueMap,
+}
+
+impl PsbtOutput {
+    pub fn new(amount: i64, script: Vec<u8>) -> PsbtOutput {
+        PsbtOutput {
+            amount: amount,
+            script: script,
+            redeem_script: None,
+            witness_script: None,
+            bip32_derivation: BTreeMap::new(),
+            unknown: KeyValueMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Psbt {
+    // 0 or 2
+    pub version: u32,
+    pub tx_version: u32,
+    // Lock time of the unsigned transaction in version 0
+    pub fallback_lock_time: Option<u32>,
+    pub tx_modifiable: Option<u8>,
+    // Serialized extended public key to its source
+    pub xpubs: BTreeMap<Vec<u8>, KeySource>,
+    pub unknown: KeyValueMap,
+    pub inputs: Vec<PsbtInput>,
+    pub outputs: Vec<PsbtOutput>,
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.encoded_len());
+    value.serialize(&mut result);
+    result
+}
+
+// The value must be consumed entirely
+fn decode<T: Deserialize>(data: &[u8], name: &'static str) -> Result<T, PsbtError> {
+    let mut cursor = Cursor::new(data);
+    let value = try!(T::deserialize(&mut cursor).map_err(|_| PsbtError::InvalidValue(name)));
+    if cursor.position() as usize != data.len() {
+        return Err(PsbtError::InvalidValue(name));
+    }
+
+    Ok(value)
+}
+
+fn no_key_data(entry: &KeyValue, name: &'static str) -> Result<(), PsbtError> {
+    if entry.key_data.is_empty() {
+        Ok(())
+    } else {
+        Err(PsbtError::InvalidKey(name))
+    }
+}
+
+fn pub_key(entry: &KeyValue, name: &'static str) -> Result<Vec<u8>, PsbtError> {
+    match entry.key_data.len() {
+        33 | 65 => Ok(entry.key_data.clone()),
+        _ => Err(PsbtError::InvalidKey(name)),
+    }
+}
+
+fn push(map: &mut KeyValueMap, key_type: u64, key_data: Vec<u8>, value: Vec<u8>) {
+    map.insert(KeyValue::new(key_type, key_data, value));
+}
+
+fn push_derivations(map: &mut KeyValueMap, key_type: u64,
+                    derivations: &BTreeMap<Vec<u8>, KeySource>) {
+    for (key, source) in derivations.iter() {
+        push(map, key_type, key.clone(), source.to_bytes());
+    }
+}
+
+fn push_unknown(map: &mut KeyValueMap, unknown: &KeyValueMap) {
+    for entry in unknown.iter() {
+        map.insert(entry.clone());
+    }
+}
+
+impl Psbt {
+    /// Creator role: a PSBT of the given version spending the inputs and
+    /// creating the outputs of `tx`, whose scriptSigs must be empty.
+    pub fn new(tx: TxMessage, version: u32) -> Result<Psbt, PsbtError> {
+        if version != 0 && version != 2 {
+            return Err(PsbtError::UnsupportedVersion(version));
+        }
+
+        for (i, tx_in) in tx.tx_in.iter().enumerate() {
+            if !tx_in.script.is_empty() {
+                return Err(PsbtError::NonEmptyScriptSig(i));
+            }
+        }
+
+        Ok(Psbt {
+            version: version,
+            tx_version: tx.version,
+            fallback_lock_time: Some(tx.lock_time),
+            tx_modifiable: if version == 2 { Some(0) } else { None },
+            xpubs: BTreeMap::new(),
+            unknown: KeyValueMap::new(),
+            inputs: tx.tx_in.into_iter()
+                .map(|i| PsbtInput::new(i.previous_output, Some(i.sequence)))
+                .collect(),
+            outputs: tx.tx_out.into_iter()
+                .map(|o| PsbtOutput::new(o.value, o.pk_script))
+                .collect(),
+        })
+    }
+
+    /// BIP370 lock time determination, version 0 always uses the lock time
+    /// of the unsigned transaction.
+    pub fn lock_time(&self) -> Result<u32, PsbtError> {
+        let constrained = self.inputs.iter()
+            .filter(|i| i.required_time_lock_time.is_some() ||
+                        i.required_height_lock_time.is_some())
+            .collect::<Vec<_>>();
+
+        if constrained.is_empty() {
+            return Ok(self.fallback_lock_time.unwrap_or(0));
+        }
+
+        // Height is preferred when every input allows it
+        if constrained.iter().all(|i| i.required_height_lock_time.is_some()) {
+            return Ok(constrained.iter()
+                      .filter_map(|i| i.required_height_lock_time).max().unwrap());
+        }
+
+        if constrained.iter().all(|i| i.required_time_lock_time.is_some()) {
+            return Ok(constrained.iter()
+                      .filter_map(|i| i.required_time_lock_time).max().unwrap());
+        }
+
+        Err(PsbtError::LockTimeConflict)
+    }
+
+    pub fn unsigned_tx(&self) -> Result<TxMessage, PsbtError> {
+        Ok(TxMessage {
+            version: self.tx_version,
+            tx_in: self.inputs.iter().map(|i| TxIn {
+                previous_output: i.previous_output.clone(),
+                script: vec![],
+                sequence: i.sequence.unwrap_or(0xffffffff),
+            }).collect(),
+            tx_out: self.outputs.iter().map(|o| TxOut {
+                value: o.amount,
+                pk_script: o.script.clone(),
+            }).collect(),
+            lock_time: try!(self.lock_time()),
+        })
+    }
+
+    /// Identifies the transaction regardless of signatures, in version 2
+    /// sequence numbers can still change so they are left out.
+    pub fn unique_id(&self) -> Result<BitcoinHash, PsbtError> {
+        let mut tx = try!(self.unsigned_tx());
+        if self.version == 2 {
+            for tx_in in tx.tx_in.iter_mut() {
+                tx_in.sequence = 0;
+            }
+        }
+
+        Ok(tx.hash())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Psbt, PsbtError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(PsbtError::InvalidMagic);
+        }
+
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(MAGIC.len() as u64);
+
+        let global = try!(KeyValueMap::deserialize(&mut cursor));
+        let (mut psbt, input_count, output_count) = try!(Self::parse_global(&global));
+
+        for _ in 0..input_count {
+            let map = try!(KeyValueMap::deserialize(&mut cursor));
+            let input = try!(Self::parse_input(&map, psbt.version));
+            psbt.inputs.push(input);
+        }
+
+        for _ in 0..output_count {
+            let map = try!(KeyValueMap::deserialize(&mut cursor));
+            let output = try!(Self::parse_output(&map, psbt.version));
+            psbt.outputs.push(output);
+        }
+
+        if psbt.version == 0 {
+            try!(psbt.merge_unsigned_tx(&global));
+        }
+
+        if cursor.position() as usize != data.len() {
+            return Err(PsbtError::InvalidValue("trailing data"));
+        }
+
+        Ok(psbt)
+    }
+
+    pub fn from_base64(data: &str) -> Result<Psbt, PsbtError> {
+        match data.from_base64() {
+            Ok(bytes) => Self::from_bytes(&bytes),
+            Err(_) => Err(PsbtError::InvalidMagic),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn to_base64(&self) -> String {
+        self.to_bytes().to_base64(base64::STANDARD)
+    }
+
+    fn parse_global(map: &KeyValueMap) -> Result<(Psbt, u64, u64), PsbtError> {
+        let version = match map.get(GLOBAL_VERSION, &[]) {
+            Some(value) => try!(decode::<u32>(value, "PSBT_GLOBAL_VERSION")),
+            None => 0,
+        };
+
+        if version != 0 && version != 2 {
+            return Err(PsbtError::UnsupportedVersion(version));
+        }
+
+        let mut psbt = Psbt {
+            version: version,
+            tx_version: 0,
+            fallback_lock_time: None,
+            tx_modifiable: None,
+            xpubs: BTreeMap::new(),
+            unknown: KeyValueMap::new(),
+            inputs: vec![],
+            outputs: vec![],
+        };
+
+        let mut unsigned_tx = None;
+        let mut tx_version = None;
+        let mut input_count = None;
+        let mut output_count = None;
+
+        for entry in map.iter() {
+            let value = &entry.value[..];
+            match entry.key_type {
+                GLOBAL_UNSIGNED_TX => {
+                    let name = "PSBT_GLOBAL_UNSIGNED_TX";
+                    try!(no_key_data(entry, name));
+                    if version != 0 {
+                        return Err(PsbtError::UnexpectedField(name));
+                    }
+                    unsigned_tx = Some(try!(decode::<TxMessage>(value, name)));
+                },
+                GLOBAL_XPUB => {
+                    let name = "PSBT_GLOBAL_XPUB";
+                    if entry.key_data.len() != 78 {
+                        return Err(PsbtError::InvalidKey(name));
+                    }
+                    psbt.xpubs.insert(entry.key_data.clone(),
+                                      try!(KeySource::from_bytes(value, name)));
+                },
+                GLOBAL_TX_VERSION | GLOBAL_FALLBACK_LOCKTIME | GLOBAL_INPUT_COUNT |
+                GLOBAL_OUTPUT_COUNT | GLOBAL_TX_MODIFIABLE if version == 0 => {
+                    return Err(PsbtError::UnexpectedField("PSBT_GLOBAL_TX_VERSION"));
+                },
+                GLOBAL_TX_VERSION => {
+                    try!(no_key_data(entry, "PSBT_GLOBAL_TX_VERSION"));
+                    tx_version = Some(try!(decode::<u32>(value, "PSBT_GLOBAL_TX_VERSION")));
+                },
+                GLOBAL_FALLBACK_LOCKTIME => {
+                    let name = "PSBT_GLOBAL_FALLBACK_LOCKTIME";
+                    try!(no_key_data(entry, name));
+                    psbt.fallback_lock_time = Some(try!(decode(value, name)));
+                },
+                GLOBAL_INPUT_COUNT => {
+                    try!(no_key_data(entry, "PSBT_GLOBAL_INPUT_COUNT"));
+                    let count: VarInt = try!(decode(value, "PSBT_GLOBAL_INPUT_COUNT"));
+                    input_count = Some(count.as_u64());
+                },
+                GLOBAL_OUTPUT_COUNT => {
+                    try!(no_key_data(entry, "PSBT_GLOBAL_OUTPUT_COUNT"));
+                    let count: VarInt = try!(decode(value, "PSBT_GLOBAL_OUTPUT_COUNT"));
+                    output_count = Some(count.as_u64());
+                },
+                GLOBAL_TX_MODIFIABLE => {
+                    let name = "PSBT_GLOBAL_TX_MODIFIABLE";
+                    try!(no_key_data(entry, name));
+                    psbt.tx_modifiable = Some(try!(decode(value, name)));
+                },
+                GLOBAL_VERSION => try!(no_key_data(entry, "PSBT_GLOBAL_VERSION")),
+                _ => { psbt.unknown.insert(entry.clone()); },
+            }
+        }
+
+        if version == 0 {
+            let tx = match unsigned_tx {
+                Some(tx) => tx,
+                None => return Err(PsbtError::MissingField("PSBT_GLOBAL_UNSIGNED_TX")),
+            };
+
+            let counts = (tx.tx_in.len() as u64, tx.tx_out.len() as u64);
+            psbt.tx_version = tx.version;
+            psbt.fallback_lock_time = Some(tx.lock_time);
+            return Ok((psbt, counts.0, counts.1));
+        }
+
+        psbt.tx_version = match tx_version {
+            Some(tx_version) => tx_version,
+            None => return Err(PsbtError::MissingField("PSBT_GLOBAL_TX_VERSION")),
+        };
+
+        match (input_count, output_count) {
+            (Some(inputs), Some(outputs)) => Ok((psbt, inputs, outputs)),
+            (None, _) => Err(PsbtError::MissingField("PSBT_GLOBAL_INPUT_COUNT")),
+            (_, None) => Err(PsbtError::MissingField("PSBT_GLOBAL_OUTPUT_COUNT")),
+        }
+    }
+
+    // In version 0 the previous outputs, sequences and outputs only live in
+    // the unsigned transaction
+    fn merge_unsigned_tx(&mut self, global: &KeyValueMap) -> Result<(), PsbtError> {
+        let name = "PSBT_GLOBAL_UNSIGNED_TX";
+        let tx: TxMessage = try!(decode(global.get(GLOBAL_UNSIGNED_TX, &[]).unwrap(), name));
+
+        for (i, (input, tx_in)) in self.inputs.iter_mut().zip(tx.tx_in.into_iter()).enumerate() {
+            if !tx_in.script.is_empty() {
+                return Err(PsbtError::NonEmptyScriptSig(i));
+            }
+
+            input.previous_output = tx_in.previous_output;
+            input.sequence = Some(tx_in.sequence);
+        }
+
+        for (output, tx_out) in self.outputs.iter_mut().zip(tx.tx_out.into_iter()) {
+            output.amount = tx_out.value;
+            output.script = tx_out.pk_script;
+        }
+
+        Ok(())
+    }
+
+    fn parse_input(map: &KeyValueMap, version: u32) -> Result<PsbtInput, PsbtError> {
+        let mut input = PsbtInput::new(OutPoint::new(BitcoinHash::new([0; 32]), 0), None);
+        let mut previous_txid = None;
+        let mut output_index = None;
+
+        for entry in map.iter() {
+            let value = &entry.value[..];
+            match entry.key_type {
+                IN_NON_WITNESS_UTXO => {
+                    let name = "PSBT_IN_NON_WITNESS_UTXO";
+                    try!(no_key_data(entry, name));
+                    input.non_witness_utxo = Some(try!(decode(value, name)));
+                },
+                IN_WITNESS_UTXO => {
+                    let name = "PSBT_IN_WITNESS_UTXO";
+                    try!(no_key_data(entry, name));
+                    input.witness_utxo = Some(try!(decode(value, name)));
+                },
+                IN_PARTIAL_SIG => {
+                    let key = try!(pub_key(entry, "PSBT_IN_PARTIAL_SIG"));
+                    input.partial_sigs.insert(key, value.to_vec());
+                },
+                IN_SIGHASH_TYPE => {
+                    let name = "PSBT_IN_SIGHASH_TYPE";
+                    try!(no_key_data(entry, name));
+                    input.sighash_type = Some(try!(decode(value, name)));
+                },
+                IN_REDEEM_SCRIPT => {
+                    try!(no_key_data(entry, "PSBT_IN_REDEEM_SCRIPT"));
+                    input.redeem_script = Some(value.to_vec());
+                },
+                IN_WITNESS_SCRIPT => {
+                    try!(no_key_data(entry, "PSBT_IN_WITNESS_SCRIPT"));
+                    input.witness_script = Some(value.to_vec());
+                },
+                IN_BIP32_DERIVATION => {
+                    let name = "PSBT_IN_BIP32_DERIVATION";
+                    let key = try!(pub_key(entry, name));
+                    input.bip32_derivation.insert(key, try!(KeySource::from_bytes(value, name)));
+                },
+                IN_FINAL_SCRIPTSIG => {
+                    try!(no_key_data(entry, "PSBT_IN_FINAL_SCRIPTSIG"));
+                    input.final_script_sig = Some(value.to_vec());
+                },
+                IN_FINAL_SCRIPTWITNESS => {
+                    let name = "PSBT_IN_FINAL_SCRIPTWITNESS";
+                    try!(no_key_data(entry, name));
+                    input.final_script_witness = Some(try!(decode(value, name)));
+                },
+                IN_PREVIOUS_TXID | IN_OUTPUT_INDEX | IN_SEQUENCE | IN_REQUIRED_TIME_LOCKTIME |
+                IN_REQUIRED_HEIGHT_LOCKTIME if version == 0 => {
+                    return Err(PsbtError::UnexpectedField("PSBT_IN_PREVIOUS_TXID"));
+                },
+                IN_PREVIOUS_TXID => {
+                    let name = "PSBT_IN_PREVIOUS_TXID";
+                    try!(no_key_data(entry, name));
+                    previous_txid = Some(try!(decode(value, name)));
+                },
+                IN_OUTPUT_INDEX => {
+                    let name = "PSBT_IN_OUTPUT_INDEX";
+                    try!(no_key_data(entry, name));
+                    output_index = Some(try!(decode(value, name)));
+                },
+                IN_SEQUENCE => {
+                    let name = "PSBT_IN_SEQUENCE";
+                    try!(no_key_data(entry, name));
+                    input.sequence = Some(try!(decode(value, name)));
+                },
+                IN_REQUIRED_TIME_LOCKTIME => {
+                    let name = "PSBT_IN_REQUIRED_TIME_LOCKTIME";
+                    try!(no_key_data(entry, name));
+                    let lock_time: u32 = try!(decode(value, name));
+                    if lock_time < LOCKTIME_THRESHOLD {
+                        return Err(PsbtError::InvalidValue(name));
+                    }
+                    input.required_time_lock_time = Some(lock_time);
+                },
+                IN_REQUIRED_HEIGHT_LOCKTIME => {
+                    let name = "PSBT_IN_REQUIRED_HEIGHT_LOCKTIME";
+                    try!(no_key_data(entry, name));
+                    let lock_time: u32 = try!(decode(value, name));
+                    if lock_time == 0 || lock_time >= LOCKTIME_THRESHOLD {
+                        return Err(PsbtError::InvalidValue(name));
+                    }
+                    input.required_height_lock_time = Some(lock_time);
+                },
+                _ => { input.unknown.insert(entry.clone()); },
+            }
+        }
+
+        if version == 2 {
+            match (previous_txid, output_index) {
+                (Some(hash), Some(index)) => input.previous_output = OutPoint::new(hash, index),
+                (None, _) => return Err(PsbtError::MissingField("PSBT_IN_PREVIOUS_TXID")),
+                (_, None) => return Err(PsbtError::MissingField("PSBT_IN_OUTPUT_INDEX")),
+            }
+        }
+
+        Ok(input)
+    }
+
+    fn parse_output(map: &KeyValueMap, version: u32) -> Result<PsbtOutput, PsbtError> {
+        let mut output = PsbtOutput::new(0, vec![]);
+        let mut amount = None;
+        let mut script = None;
+
+        for entry in map.iter() {
+            let value = &entry.value[..];
+            match entry.key_type {
+                OUT_REDEEM_SCRIPT => {
+                    try!(no_key_data(entry, "PSBT_OUT_REDEEM_SCRIPT"));
+                    output.redeem_script = Some(value.to_vec());
+                },
+                OUT_WITNESS_SCRIPT => {
+                    try!(no_key_data(entry, "PSBT_OUT_WITNESS_SCRIPT"));
+                    output.witness_script = Some(value.to_vec());
+                },
+                OUT_BIP32_DERIVATION => {
+                    let name = "PSBT_OUT_BIP32_DERIVATION";
+                    let key = try!(pub_key(entry, name));
+                    output.bip32_derivation.insert(key, try!(KeySource::from_bytes(value, name)));
+                },
+                OUT_AMOUNT | OUT_SCRIPT if version == 0 => {
+                    return Err(PsbtError::UnexpectedField("PSBT_OUT_AMOUNT"));
+                },
+                OUT_AMOUNT => {
+                    let name = "PSBT_OUT_AMOUNT";
+                    try!(no_key_data(entry, name));
+                    amount = Some(try!(decode(value, name)));
+                },
+                OUT_SCRIPT => {
+                    try!(no_key_data(entry, "PSBT_OUT_SCRIPT"));
+                    script = Some(value.to_vec());
+                },
+                _ => { output.unknown.insert(entry.clone()); },
+            }
+        }
+
+        if version == 2 {
+            match (amount, script) {
+                (Some(amount), Some(script)) => {
+                    output.amount = amount;
+                    output.script = script;
+                },
+                (None, _) => return Err(PsbtError::MissingField("PSBT_OUT_AMOUNT")),
+                (_, None) => return Err(PsbtError::MissingField("PSBT_OUT_SCRIPT")),
+            }
+        }
+
+        Ok(output)
+    }
+
+    fn global_map(&self) -> KeyValueMap {
+        let mut map = KeyValueMap::new();
+
+        if self.version == 0 {
+            // Version 0 PSBTs always have a valid lock time
+            let tx = self.unsigned_tx().unwrap();
+            push(&mut map, GLOBAL_UNSIGNED_TX, vec![], encode(&tx));
+        }
+
+        for (xpub, source) in self.xpubs.iter() {
+            push(&mut map, GLOBAL_XPUB, xpub.clone(), source.to_bytes());
+        }
+
+        if self.version == 2 {
+            push(&mut map, GLOBAL_TX_VERSION, vec![], encode(&self.tx_version));
+            if let Some(lock_time) = self.fallback_lock_time {
+                push(&mut map, GLOBAL_FALLBACK_LOCKTIME, vec![], encode(&lock_time));
+            }
+            push(&mut map, GLOBAL_INPUT_COUNT, vec![],
+                 encode(&VarInt::new(self.inputs.len() as u64)));
+            push(&mut map, GLOBAL_OUTPUT_COUNT, vec![],
+                 encode(&VarInt::new(self.outputs.len() as u64)));
+            if let Some(flags) = self.tx_modifiable {
+                push(&mut map, GLOBAL_TX_MODIFIABLE, vec![], encode(&flags));
+            }
+            push(&mut map, GLOBAL_VERSION, vec![], encode(&self.version));
+        }
+
+        push_unknown(&mut map, &self.unknown);
+        map
+    }
+
+    fn input_map(&self, input: &PsbtInput) -> KeyValueMap {
+        let mut map = KeyValueMap::new();
+
+        if let Some(ref tx) = input.non_witness_utxo {
+            push(&mut map, IN_NON_WITNESS_UTXO, vec![], encode(tx));
+        }
+        if let Some(ref tx_out) = input.witness_utxo {
+            push(&mut map, IN_WITNESS_UTXO, vec![], encode(tx_out));
+        }
+        for (key, sig) in input.partial_sigs.iter() {
+            push(&mut map, IN_PARTIAL_SIG, key.clone(), sig.clone());
+        }
+        if let Some(sighash) = input.sighash_type {
+            push(&mut map, IN_SIGHASH_TYPE, vec![], encode(&sighash));
+        }
+        if let Some(ref script) = input.redeem_script {
+            push(&mut map, IN_REDEEM_SCRIPT, vec![], script.clone());
+        }
+        if let Some(ref script) = input.witness_script {
+            push(&mut map, IN_WITNESS_SCRIPT, vec![], script.clone());
+        }
+        push_derivations(&mut map, IN_BIP32_DERIVATION, &input.bip32_derivation);
+        if let Some(ref script) = input.final_script_sig {
+            push(&mut map, IN_FINAL_SCRIPTSIG, vec![], script.clone());
+        }
+        if let Some(ref witness) = input.final_script_witness {
+            push(&mut map, IN_FINAL_SCRIPTWITNESS, vec![], encode(witness));
+        }
+
+        if self.version == 2 {
+            push(&mut map, IN_PREVIOUS_TXID, vec![], encode(&input.previous_output.hash));
+            push(&mut map, IN_OUTPUT_INDEX, vec![], encode(&input.previous_output.index));
+            if let Some(sequence) = input.sequence {
+                push(&mut map, IN_SEQUENCE, vec![], encode(&sequence));
+            }
+            if let Some(lock_time) = input.required_time_lock_time {
+                push(&mut map, IN_REQUIRED_TIME_LOCKTIME, vec![], encode(&lock_time));
+            }
+            if let Some(lock_time) = input.required_height_lock_time {
+                push(&mut map, IN_REQUIRED_HEIGHT_LOCKTIME, vec![], encode(&lock_time));
+            }
+        }
+
+        push_unknown(&mut map, &input.unknown);
+        map
+    }
+
+    fn output_map(&self, output: &PsbtOutput) -> KeyValueMap {
+        let mut map = KeyValueMap::new();
+
+        if let Some(ref script) = output.redeem_script {
+            push(&mut map, OUT_REDEEM_SCRIPT, vec![], script.clone());
+        }
+        if let Some(ref script) = output.witness_script {
+            push(&mut map, OUT_WITNESS_SCRIPT, vec![], script.clone());
+        }
+        push_derivations(&mut map, OUT_BIP32_DERIVATION, &output.bip32_derivation);
+
+        if self.version == 2 {
+            push(&mut map, OUT_AMOUNT, vec![], encode(&output.amount));
+            push(&mut map, OUT_SCRIPT, vec![], output.script.clone());
+        }
+
+        push_unknown(&mut map, &output.unknown);
+        map
+    }
+}
+
+impl Serialize for Psbt {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.push_bytes(MAGIC);
+        self.global_map().serialize(serializer);
+
+        for input in self.inputs.iter() {
+            self.input_map(input).serialize(serializer);
+        }
+
+        for output in self.outputs.iter() {
+            self.output_map(output).serialize(serializer);
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        MAGIC.len() + self.global_map().encoded_len() +
+            self.inputs.iter().map(|i| self.input_map(i).encoded_len()).sum::<usize>() +
+            self.outputs.iter().map(|o| self.output_map(o).encoded_len()).sum::<usize>()
+    }
+}
@@ -0,0 +1,292 @@
+// Combiner, finalizer and extractor roles. The creator is `Psbt::new` and
+// the updater and signer work on the public fields directly.
+use super::{Psbt, PsbtError, PsbtInput};
+
+use net::messages::{SerializeHash, TxOut, TxMessage};
+use script::{Instructions, ScriptType, witness_program, push_data};
+use serialize::{Serialize, KeyValueMap};
+use utils::CryptoUtils;
+
+use std::collections::BTreeMap;
+
+const OP_1: u8 = 0x51;
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    CryptoUtils::ripemd160(&CryptoUtils::sha256(data))
+}
+
+fn merge_map<K: Ord, V>(ours: &mut BTreeMap<K, V>, theirs: BTreeMap<K, V>) {
+    for (key, value) in theirs.into_iter() {
+        ours.entry(key).or_insert(value);
+    }
+}
+
+fn merge_unknown(ours: &mut KeyValueMap, theirs: KeyValueMap) {
+    for entry in theirs.iter() {
+        ours.insert(entry.clone());
+    }
+}
+
+impl PsbtInput {
+    // Output being spent, checked against the previous transaction when known
+    fn spent_output(&self, index: usize) -> Result<TxOut, PsbtError> {
+        if let Some(ref tx) = self.non_witness_utxo {
+            if tx.hash() != self.previous_output.hash {
+                return Err(PsbtError::UtxoMismatch(index));
+            }
+
+            return match tx.tx_out.get(self.previous_output.index as usize) {
+                Some(tx_out) => Ok(tx_out.clone()),
+                None => Err(PsbtError::UtxoMismatch(index)),
+            };
+        }
+
+        match self.witness_utxo {
+            Some(ref tx_out) => Ok(tx_out.clone()),
+            None => Err(PsbtError::CannotFinalize(index, "missing UTXO")),
+        }
+    }
+
+    // Stack items satisfying `script` with the partial signatures
+    fn satisfy(&self, script: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+        let instructions = match Instructions::new(script).collect::<Result<Vec<_>, _>>() {
+            Ok(instructions) => instructions,
+            Err(_) => return Err("invalid script"),
+        };
+
+        match ScriptType::classify(script) {
+            ScriptType::PubKey => match self.partial_sigs.get(instructions[0].data) {
+                Some(sig) => Ok(vec![sig.clone()]),
+                None => Err("missing signature"),
+            },
+            ScriptType::PubKeyHash => {
+                match self.partial_sigs.iter().find(|&(key, _)| hash160(key) == script[3..23]) {
+                    Some((key, sig)) => Ok(vec![sig.clone(), key.clone()]),
+                    None => Err("missing signature"),
+                }
+            },
+            ScriptType::MultiSig => {
+                let required = (instructions[0].op_code - OP_1 + 1) as usize;
+                let keys = &instructions[1..instructions.len() - 2];
+
+                // CHECKMULTISIG pops one element too many
+                let mut stack = vec![vec![]];
+                stack.extend(keys.iter()
+                             .filter_map(|key| self.partial_sigs.get(key.data))
+                             .take(required)
+                             .cloned());
+
+                if stack.len() <= required {
+                    return Err("not enough signatures");
+                }
+                Ok(stack)
+            },
+            _ => Err("unsupported script"),
+        }
+    }
+
+    fn finalize(&mut self, index: usize) -> Result<(), PsbtError> {
+        let spent = try!(self.spent_output(index));
+        let mut script_sig = vec![];
+        let mut script = spent.pk_script.clone();
+
+        if ScriptType::classify(&script) == ScriptType::ScriptHash {
+            let redeem_script = match self.redeem_script {
+                Some(ref redeem_script) => redeem_script.clone(),
+                None => return Err(PsbtError::CannotFinalize(index, "missing redeem script")),
+            };
+
+            if hash160(&redeem_script) != script[2..22] {
+                return Err(PsbtError::CannotFinalize(index, "redeem script mismatch"));
+            }
+
+            push_data(&mut script_sig, &redeem_script);
+            script = redeem_script;
+        }
+
+        let witness = match witness_program(&script) {
+            Some((0, program)) if program.len() == 20 => {
+                let stack = try!(self.satisfy(&p2pkh_script(program))
+                                 .map_err(|e| PsbtError::CannotFinalize(index, e)));
+                Some(stack)
+            },
+            Some((0, program)) if program.len() == 32 => {
+                let witness_script = match self.witness_script {
+                    Some(ref witness_script) => witness_script.clone(),
+                    None => return Err(PsbtError::CannotFinalize(index, "missing witness script")),
+                };
+
+                if CryptoUtils::sha256(&witness_script) != program {
+                    return Err(PsbtError::CannotFinalize(index, "witness script mismatch"));
+                }
+
+                let mut stack = try!(self.satisfy(&witness_script)
+                                     .map_err(|e| PsbtError::CannotFinalize(index, e)));
+                stack.push(witness_script);
+                Some(stack)
+            },
+            Some(_) => return Err(PsbtError::CannotFinalize(index, "unsupported witness version")),
+            None => None,
+        };
+
+        if witness.is_none() {
+            let stack = try!(self.satisfy(&script).map_err(|e| PsbtError::CannotFinalize(index, e)));
+            let mut result = vec![];
+            for item in stack.iter() {
+                push_data(&mut result, item);
+            }
+
+            // The redeem script goes last
+            result.extend(script_sig);
+            script_sig = result;
+        }
+
+        self.final_script_sig = if script_sig.is_empty() { None } else { Some(script_sig) };
+        self.final_script_witness = witness;
+
+        // Only the UTXOs and unknown fields stay once the input is final
+        self.partial_sigs.clear();
+        self.sighash_type = None;
+        self.redeem_script = None;
+        self.witness_script = None;
+        self.bip32_derivation.clear();
+
+        Ok(())
+    }
+
+    fn combine(&mut self, other: PsbtInput) {
+        if self.non_witness_utxo.is_none() {
+            self.non_witness_utxo = other.non_witness_utxo;
+        }
+        if self.witness_utxo.is_none() {
+            self.witness_utxo = other.witness_utxo;
+        }
+        if self.sighash_type.is_none() {
+            self.sighash_type = other.sighash_type;
+        }
+        if self.redeem_script.is_none() {
+            self.redeem_script = other.redeem_script;
+        }
+        if self.witness_script.is_none() {
+            self.witness_script = other.witness_script;
+        }
+        if self.final_script_sig.is_none() {
+            self.final_script_sig = other.final_script_sig;
+        }
+        if self.final_script_witness.is_none() {
+            self.final_script_witness = other.final_script_witness;
+        }
+        if self.required_time_lock_time.is_none() {
+            self.required_time_lock_time = other.required_time_lock_time;
+        }
+        if self.required_height_lock_time.is_none() {
+            self.required_height_lock_time = other.required_height_lock_time;
+        }
+
+        merge_map(&mut self.partial_sigs, other.partial_sigs);
+        merge_map(&mut self.bip32_derivation, other.bip32_derivation);
+        merge_unknown(&mut self.unknown, other.unknown);
+    }
+}
+
+// P2WPKH is satisfied like the equivalent P2PKH script
+fn p2pkh_script(key_hash: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(key_hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+impl Psbt {
+    /// Combiner role: merges the fields of another PSBT of the same
+    /// transaction, existing values win.
+    pub fn combine(&mut self, other: Psbt) -> Result<(), PsbtError> {
+        if self.version != other.version || try!(self.unique_id()) != try!(other.unique_id()) {
+            return Err(PsbtError::TxMismatch);
+        }
+
+        merge_map(&mut self.xpubs, other.xpubs);
+        merge_unknown(&mut self.unknown, other.unknown);
+
+        for (input, theirs) in self.inputs.iter_mut().zip(other.inputs.into_iter()) {
+            input.combine(theirs);
+        }
+
+        for (output, theirs) in self.outputs.iter_mut().zip(other.outputs.into_iter()) {
+            if output.redeem_script.is_none() {
+                output.redeem_script = theirs.redeem_script;
+            }
+            if output.witness_script.is_none() {
+                output.witness_script = theirs.witness_script;
+            }
+            merge_map(&mut output.bip32_derivation, theirs.bip32_derivation);
+            merge_unknown(&mut output.unknown, theirs.unknown);
+        }
+
+        Ok(())
+    }
+
+    /// Finalizer role for P2PK, P2PKH, multisig, P2WPKH and P2WSH, bare or
+    /// wrapped in P2SH. Already final inputs are left alone.
+    pub fn finalize(&mut self) -> Result<(), PsbtError> {
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            if !input.is_finalized() {
+                try!(input.finalize(i));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn signed_tx(&self) -> Result<TxMessage, PsbtError> {
+        let mut tx = try!(self.unsigned_tx());
+        for (i, (input, tx_in)) in self.inputs.iter().zip(tx.tx_in.iter_mut()).enumerate() {
+            if !input.is_finalized() {
+                return Err(PsbtError::NotFinalized(i));
+            }
+
+            tx_in.script = input.final_script_sig.clone().unwrap_or(vec![]);
+        }
+
+        Ok(tx)
+    }
+
+    fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|i| i.final_script_witness.as_ref().map_or(false, |w| !w.is_empty()))
+    }
+
+    /// Extractor role. `TxMessage` can't hold witnesses, transactions
+    /// spending segwit outputs have to use `extract_raw`.
+    pub fn extract(&self) -> Result<TxMessage, PsbtError> {
+        if self.has_witness() {
+            return Err(PsbtError::WitnessNotSupported);
+        }
+
+        self.signed_tx()
+    }
+
+    /// Network serialization of the final transaction, in the BIP144
+    /// format when any input has a witness.
+    pub fn extract_raw(&self) -> Result<Vec<u8>, PsbtError> {
+        let tx = try!(self.signed_tx());
+        let mut result = vec![];
+
+        if !self.has_witness() {
+            tx.serialize(&mut result);
+            return Ok(result);
+        }
+
+        tx.version.serialize(&mut result);
+        // Marker and flag
+        result.push(0x00);
+        result.push(0x01);
+        tx.tx_in.serialize(&mut result);
+        tx.tx_out.serialize(&mut result);
+        for input in self.inputs.iter() {
+            input.final_script_witness.clone().unwrap_or(vec![]).serialize(&mut result);
+        }
+        tx.lock_time.serialize(&mut result);
+
+        Ok(result)
+    }
+}
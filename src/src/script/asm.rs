@@ -0,0 +1,256 @@
+use rustc_serialize::hex::ToHex;
+
+use utils::IntUtils;
+
+use super::op_codes::OpCode;
+
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_RETURN: u8 = 0x6a;
+
+const MAX_SCRIPT_SIZE: usize = 10000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Instruction<'a> {
+    pub op_code: u8,
+    // Only set for push operations
+    pub data: &'a [u8],
+}
+
+impl<'a> Instruction<'a> {
+    pub fn is_push(&self) -> bool {
+        self.op_code <= OP_PUSHDATA4
+    }
+}
+
+// Walks a script one op code at a time, stops after the first truncated push
+pub struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+    done: bool,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(script: &'a [u8]) -> Instructions<'a> {
+        Instructions {
+            script: script,
+            position: 0,
+            done: false,
+        }
+    }
+
+    // Offset of the next instruction
+    pub fn position(&self) -> usize { self.position }
+
+    fn read_length(&mut self, bytes: usize) -> Result<usize, String> {
+        if self.position + bytes > self.script.len() {
+            return Err(format!("Truncated push length at {}", self.position));
+        }
+
+        let mut length = vec![];
+        length.extend(&self.script[self.position..self.position + bytes]);
+        self.position += bytes;
+
+        Ok(IntUtils::to_u64(&length) as usize)
+    }
+
+    fn next_instruction(&mut self) -> Result<Instruction<'a>, String> {
+        let op_code = self.script[self.position];
+        self.position += 1;
+
+        let length = match op_code {
+            0x00...0x4b => op_code as usize,
+            OP_PUSHDATA1 => try!(self.read_length(1)),
+            OP_PUSHDATA2 => try!(self.read_length(2)),
+            OP_PUSHDATA4 => try!(self.read_length(4)),
+            _ => return Ok(Instruction { op_code: op_code, data: &[] }),
+        };
+
+        if length > self.script.len() - self.position {
+            return Err(format!("Truncated push of {} bytes at {}", length, self.position));
+        }
+
+        let data = &self.script[self.position..self.position + length];
+        self.position += length;
+
+        Ok(Instruction { op_code: op_code, data: data })
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.position >= self.script.len() {
+            return None;
+        }
+
+        let result = self.next_instruction();
+        self.done = result.is_err();
+
+        Some(result)
+    }
+}
+
+// Same names used by Bitcoin Core
+fn op_name(op_code: u8) -> String {
+    let name = match op_code {
+        0x00 => return "0".to_owned(),
+        0x4f => return "-1".to_owned(),
+        0x51...0x60 => return (op_code - 0x50).to_string(),
+        0x65 => "VERIF",
+        0x66 => "VERNOTIF",
+        0x7e => "CAT",
+        0x7f => "SUBSTR",
+        0x80 => "LEFT",
+        0x81 => "RIGHT",
+        0x83 => "INVERT",
+        0x84 => "AND",
+        0x85 => "OR",
+        0x86 => "XOR",
+        0x8d => "2MUL",
+        0x8e => "2DIV",
+        0x95 => "MUL",
+        0x96 => "DIV",
+        0x97 => "MOD",
+        0x98 => "LSHIFT",
+        0x99 => "RSHIFT",
+        0xb2 => "CHECKSEQUENCEVERIFY",
+        0xba => "CHECKSIGADD",
+        0xbb...0xfe => "UNKNOWN",
+        0xff => "INVALIDOPCODE",
+        _ => match OpCode::from_byte(op_code) {
+            Some(op) => op.to_str(),
+            None => "UNKNOWN",
+        },
+    };
+
+    format!("OP_{}", name)
+}
+
+// BIP66 strict DER encoding, followed by the sighash type
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+
+    true
+}
+
+fn sighash_name(sighash: u8) -> Option<&'static str> {
+    match sighash {
+        0x01 => Some("ALL"),
+        0x02 => Some("NONE"),
+        0x03 => Some("SINGLE"),
+        0x81 => Some("ALL|ANYONECANPAY"),
+        0x82 => Some("NONE|ANYONECANPAY"),
+        0x83 => Some("SINGLE|ANYONECANPAY"),
+        _ => None,
+    }
+}
+
+fn push_to_asm(data: &[u8], attempt_sighash_decode: bool) -> String {
+    if data.len() <= 4 {
+        let mut number = vec![];
+        number.extend(data);
+        return IntUtils::to_i32(&number).to_string();
+    }
+
+    if attempt_sighash_decode && is_valid_signature_encoding(data) {
+        if let Some(name) = sighash_name(data[data.len() - 1]) {
+            return format!("{}[{}]", data[..data.len() - 1].to_hex(), name);
+        }
+    }
+
+    data.to_hex()
+}
+
+// Human readable form of a script, in the format of Bitcoin Core's
+// `decoderawtransaction`. Signatures in scriptSigs can be shown with their
+// sighash type by setting `attempt_sighash_decode`.
+pub fn to_asm(script: &[u8], attempt_sighash_decode: bool) -> String {
+    let unspendable = script.len() > MAX_SCRIPT_SIZE ||
+                      (script.len() > 0 && script[0] == OP_RETURN);
+    let decode = attempt_sighash_decode && !unspendable;
+
+    let mut result = vec![];
+    for instruction in Instructions::new(script) {
+        match instruction {
+            Ok(ref instruction) if instruction.is_push() =>
+                result.push(push_to_asm(instruction.data, decode)),
+            Ok(instruction) => result.push(op_name(instruction.op_code)),
+            Err(_) => {
+                result.push("[error]".to_owned());
+                break;
+            }
+        }
+    }
+
+    result.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    #[test]
+    fn test_p2pkh_asm() {
+        let script = "76a914a10a4da7d425923f7296b4b9b6dc4fe2564a3ba688ac".from_hex().unwrap();
+        assert_eq!(to_asm(&script, false),
+                   "OP_DUP OP_HASH160 a10a4da7d425923f7296b4b9b6dc4fe2564a3ba6 \
+                    OP_EQUALVERIFY OP_CHECKSIG");
+    }
+
+    #[test]
+    fn test_small_pushes_asm() {
+        let script = "0001ff02e8030051604f6a".from_hex().unwrap();
+        assert_eq!(to_asm(&script, false), "0 -127 1000 0 1 16 -1 OP_RETURN");
+    }
+
+    #[test]
+    fn test_truncated_push_asm() {
+        let script = "51050102".from_hex().unwrap();
+        assert_eq!(to_asm(&script, false), "1 [error]");
+    }
+
+    #[test]
+    fn test_signature_asm() {
+        let script = "483045022100a16379ef6976f74c697beca71c79008f64a547fd856fe89c2ee08082ed4ba56002205c5f58ed92ad00c04395fa2bef655a99e2602e87212c1ba6499610a2ffc1f30a01".from_hex().unwrap();
+
+        assert_eq!(to_asm(&script, true),
+                   "3045022100a16379ef6976f74c697beca71c79008f64a547fd856fe89c2ee08082ed4ba56002205c5f58ed92ad00c04395fa2bef655a99e2602e87212c1ba6499610a2ffc1f30a[ALL]");
+        assert_eq!(to_asm(&script, false), script[1..].to_hex());
+    }
+}
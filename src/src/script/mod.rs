@@ -1,16 +1,27 @@
 mod op_codes;
 mod human_parser;
+mod asm;
+mod standard;
+mod names;
 
 use self::op_codes::OpCode;
 
+use std::rc::Rc;
+
+pub use self::asm::{to_asm, Instruction, Instructions};
+pub use self::standard::{ScriptType, witness_program, push_data};
+pub use self::names::{NameOp, NameScript};
+
+// fn(codeseparator: usize, pub_key_str: Vec<u8>, sig_str: Vec<u8) -> bool
+pub type SignatureChecker = Rc<Fn(usize, &Vec<u8>, &Vec<u8>) -> bool>;
+
 pub struct Context {
     script: BitcoinScript,
     stack: Vec<Vec<u8>>,
     valid: bool,
     altstack: Vec<Vec<u8>>,
     codeseparator: usize,
-    // fn(codeseparator: usize, pub_key_str: Vec<u8>, sig_str: Vec<u8) -> bool
-    checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool,
+    checksig: SignatureChecker,
     // Whether or not the last OP_IF, OP_ELSE or OP_NOTIF has been executed
     conditional_executed: Vec<bool>,
 }
@@ -85,6 +96,11 @@ impl BitcoinScript {
 impl Context {
     pub fn new(script: Vec<u8>, stack: Vec<Vec<u8>>,
                checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool) -> Context {
+        Context::with_checker(script, stack, Rc::new(checksig))
+    }
+
+    pub fn with_checker(script: Vec<u8>, stack: Vec<Vec<u8>>,
+                        checksig: SignatureChecker) -> Context {
         Context {
             script: BitcoinScript::new(script),
             stack: stack,
@@ -113,11 +129,17 @@ impl Parser {
     pub fn execute(sig_script: Vec<u8>, script_pub_key: Vec<u8>,
                    checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool)
     -> Result<bool, String> {
+        Self::execute_with(sig_script, script_pub_key, Rc::new(checksig))
+    }
+
+    /// Like `execute`, the checker can capture what's being signed.
+    pub fn execute_with(sig_script: Vec<u8>, script_pub_key: Vec<u8>,
+                        checksig: SignatureChecker) -> Result<bool, String> {
         // OP_CHECKSIG is not allowed when executing sigScript
         // TODO: ideally we should just invalidate the context
         let sig_script_context = try!(Self::execute_base(vec![],
                                                         sig_script,
-                                                        Parser::no_checksig_allowed));
+                                                        Rc::new(Parser::no_checksig_allowed)));
 
         if !sig_script_context.valid {
             return Ok(false);
@@ -130,11 +152,21 @@ impl Parser {
            op_codes::is_true(&script_pub_key_context.stack.last()))
     }
 
+    /// Runs a witness script on the rest of the witness stack, BIP141
+    /// requires exactly one true element to be left.
+    pub fn execute_witness(stack: Vec<Vec<u8>>, witness_script: Vec<u8>,
+                           checksig: SignatureChecker) -> Result<bool, String> {
+        let context = try!(Self::execute_base(stack, witness_script, checksig));
+
+        Ok(context.valid && context.stack.len() == 1 &&
+           op_codes::is_true(&context.stack.last()))
+    }
+
     fn execute_base(input_stack: Vec<Vec<u8>>,
                     script: Vec<u8>,
-                    checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool)
+                    checksig: SignatureChecker)
     -> Result<Context, String> {
-        let mut context = Context::new(script.clone(), input_stack, checksig);
+        let mut context = Context::with_checker(script.clone(), input_stack, checksig);
 
         if context.script.script.len() == 0 {
             return Ok(context);
@@ -0,0 +1,145 @@
+// Namecoin name operations. The operation and its arguments are prepended
+// to a regular script and dropped before it runs:
+//
+//   NAME_NEW <hash> 2DROP <address>
+//   NAME_FIRSTUPDATE <name> <rand> <value> 2DROP 2DROP <address>
+//   NAME_UPDATE <name> <value> 2DROP DROP <address>
+use super::asm::Instructions;
+
+const OP_NAME_NEW: u8 = 0x51;
+const OP_NAME_FIRSTUPDATE: u8 = 0x52;
+const OP_NAME_UPDATE: u8 = 0x53;
+const OP_NOP: u8 = 0x61;
+const OP_DROP: u8 = 0x75;
+const OP_2DROP: u8 = 0x6d;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NameOp<'a> {
+    // Commits to the hash of the rand value and the name being registered
+    New { hash: &'a [u8] },
+    FirstUpdate { name: &'a [u8], rand: &'a [u8], value: &'a [u8] },
+    Update { name: &'a [u8], value: &'a [u8] },
+}
+
+impl<'a> NameOp<'a> {
+    // Names used by Namecoin Core
+    pub fn name(&self) -> &'static str {
+        match self {
+            &NameOp::New { .. }         => "name_new",
+            &NameOp::FirstUpdate { .. } => "name_firstupdate",
+            &NameOp::Update { .. }      => "name_update",
+        }
+    }
+
+    pub fn name_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            &NameOp::New { .. } => None,
+            &NameOp::FirstUpdate { name, .. } | &NameOp::Update { name, .. } => Some(name),
+        }
+    }
+}
+
+/// A script with a name operation in front of the address it pays to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NameScript<'a> {
+    pub op: NameOp<'a>,
+    pub address: &'a [u8],
+}
+
+fn is_drop(op_code: u8) -> bool {
+    op_code == OP_DROP || op_code == OP_2DROP || op_code == OP_NOP
+}
+
+impl<'a> NameScript<'a> {
+    /// Splits a name script, None for anything else.
+    pub fn parse(script: &'a [u8]) -> Option<NameScript<'a>> {
+        let mut instructions = Instructions::new(script);
+
+        let name_op = match instructions.next() {
+            Some(Ok(instruction)) => instruction.op_code,
+            _ => return None,
+        };
+
+        // Pushed arguments up to the first drop
+        let mut args = vec![];
+        loop {
+            match instructions.next() {
+                Some(Ok(ref instruction)) if is_drop(instruction.op_code) => break,
+                Some(Ok(ref instruction)) if instruction.is_push() => args.push(instruction.data),
+                _ => return None,
+            }
+        }
+
+        // Then any number of drops, the address starts after them
+        let mut address = instructions.position();
+        loop {
+            match instructions.next() {
+                Some(Ok(ref instruction)) if is_drop(instruction.op_code) => {
+                    address = instructions.position();
+                },
+                _ => break,
+            }
+        }
+
+        let op = match (name_op, args.len()) {
+            (OP_NAME_NEW, 1) => NameOp::New { hash: args[0] },
+            (OP_NAME_FIRSTUPDATE, 3) =>
+                NameOp::FirstUpdate { name: args[0], rand: args[1], value: args[2] },
+            (OP_NAME_UPDATE, 2) => NameOp::Update { name: args[0], value: args[1] },
+            _ => return None,
+        };
+
+        Some(NameScript {
+            op: op,
+            address: &script[address..],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use script::ScriptType;
+    use rustc_serialize::hex::FromHex;
+
+    const ADDRESS: &'static str = "76a914a10a4da7d425923f7296b4b9b6dc4fe2564a3ba688ac";
+
+    #[test]
+    fn test_name_new() {
+        let script = format!("5114{}6d{}", "11".repeat(20), ADDRESS).from_hex().unwrap();
+        let name_script = NameScript::parse(&script).unwrap();
+
+        assert_eq!(name_script.op, NameOp::New { hash: &[0x11; 20] });
+        assert_eq!(name_script.op.name(), "name_new");
+        assert_eq!(name_script.op.name_bytes(), None);
+        assert_eq!(name_script.address, &ADDRESS.from_hex().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_name_firstupdate_and_update() {
+        // d/bitcoin with a rand value and {"ip":"1.2.3.4"}
+        let script = format!("5209642f626974636f696e08{}107b226970223a22312e322e332e34227d6d6d{}",
+                             "22".repeat(8), ADDRESS).from_hex().unwrap();
+        let name_script = NameScript::parse(&script).unwrap();
+
+        assert_eq!(name_script.op, NameOp::FirstUpdate {
+            name: b"d/bitcoin",
+            rand: &[0x22; 8],
+            value: b"{\"ip\":\"1.2.3.4\"}",
+        });
+        assert_eq!(name_script.op.name_bytes(), Some(&b"d/bitcoin"[..]));
+        assert_eq!(ScriptType::classify(name_script.address), ScriptType::PubKeyHash);
+
+        let script = format!("5309642f626974636f696e00756d{}", ADDRESS).from_hex().unwrap();
+        assert_eq!(NameScript::parse(&script).unwrap().op,
+                   NameOp::Update { name: b"d/bitcoin", value: b"" });
+    }
+
+    #[test]
+    fn test_not_name_scripts() {
+        for script in [ADDRESS, "", "51", "516d", "530101010101016d75",
+                       "54010101016d75", "5101ff6a"].iter() {
+            assert_eq!(NameScript::parse(&script.from_hex().unwrap()), None);
+        }
+    }
+}
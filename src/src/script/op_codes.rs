@@ -16,7 +16,9 @@ fn op_dup(context: Context) -> Context {
 }
 
 fn op_ifdup(context: Context) -> Context {
-    assert!(context.stack.len() > 0);
+    if context.stack.len() == 0 {
+        return op_mark_invalid(context);
+    }
 
     if is_true(&context.stack.last()) {
         return op_dup(context);
@@ -34,7 +36,9 @@ where F: Fn(&mut Vec<Vec<u8>>) {
 }
 
 fn op_depth(context: Context) -> Context {
-    assert!(context.stack.len() <= 0x7f);
+    if context.stack.len() > 0x7f {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let size = IntUtils::to_vec_u8(st.len() as i64);
@@ -47,7 +51,9 @@ fn op_drop(context: Context) -> Context {
 }
 
 fn op_nip(context: Context) -> Context {
-    assert!(context.stack.len() >= 1);
+    if context.stack.len() < 1 {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let el = st.pop().unwrap();
@@ -57,7 +63,9 @@ fn op_nip(context: Context) -> Context {
 }
 
 fn pick(context: Context, depth: usize) -> Context {
-    assert!(context.stack.len() >= depth + 1);
+    if context.stack.len() < depth + 1 {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let el = st.get(st.len() - depth - 1).unwrap().clone();
@@ -66,7 +74,9 @@ fn pick(context: Context, depth: usize) -> Context {
 }
 
 fn op_toaltstack(context: Context) -> Context {
-    assert!(context.stack.len() > 0);
+    if context.stack.len() == 0 {
+        return op_mark_invalid(context);
+    }
 
     let mut new_context = context;
     let el = new_context.stack.pop().unwrap();
@@ -76,7 +86,9 @@ fn op_toaltstack(context: Context) -> Context {
 }
 
 fn op_fromaltstack(context: Context) -> Context {
-    assert!(context.altstack.len() > 0);
+    if context.altstack.len() == 0 {
+        return op_mark_invalid(context);
+    }
 
     let mut new_context = context;
     let el = new_context.altstack.pop().unwrap();
@@ -90,17 +102,18 @@ fn op_over(context: Context) -> Context {
 }
 
 fn op_pick(context: Context) -> Context {
-    assert!(context.stack.len() > 0);
-
     let mut new_context = context;
-    let el = new_context.stack.pop().unwrap();
-    let size = IntUtils::to_i32(&el);
 
-    pick(new_context, size as usize)
+    match pop_number(&mut new_context) {
+        Some(size) if size >= 0 => pick(new_context, size as usize),
+        _ => op_mark_invalid(new_context),
+    }
 }
 
 fn roll(context: Context, size: u8) -> Context {
-    assert!(size == 0x00 || context.stack.len() > size as usize - 1);
+    if context.stack.len() < size as usize + 1 {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let pos = st.len() - 1 - size as usize;
@@ -110,14 +123,12 @@ fn roll(context: Context, size: u8) -> Context {
 }
 
 fn op_roll(context: Context) -> Context {
-    assert!(context.stack.len() > 0);
-
     let mut new_context = context;
-    let size = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(size <= 0xff);
-    assert!(size >= 0x00);
 
-    roll(new_context, size as u8)
+    match pop_number(&mut new_context) {
+        Some(size) if size >= 0x00 && size <= 0xff => roll(new_context, size as u8),
+        _ => op_mark_invalid(new_context),
+    }
 }
 
 fn op_rot(context: Context)  -> Context { roll(context, 2) }
@@ -151,14 +162,26 @@ fn op_2swap(context: Context) -> Context {
     roll(roll(context, 3), 3)
 }
 
+// Numbers on the stack are at most 4 bytes long, anything else fails the
+// script like a missing element
+fn pop_number(context: &mut Context) -> Option<i32> {
+    match context.stack.pop() {
+        Some(ref el) if el.len() <= 4 => Some(IntUtils::to_i32(el)),
+        _ => None,
+    }
+}
+
 fn unary_op<F>(context: Context, op: F) -> Context
 where F: Fn(i32) -> i64 {
-    assert!(context.stack.len() > 0);
+    let mut new_context = context;
 
-    stack_op(context, |st| {
-        let input = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input) as i64));
-    })
+    match pop_number(&mut new_context) {
+        Some(input) => {
+            new_context.stack.push(IntUtils::to_vec_u8(op(input) as i64));
+            new_context
+        },
+        None => op_mark_invalid(new_context),
+    }
 }
 
 fn op_1add(context: Context)   -> Context { unary_op(context, |a| a as i64 + 1 ) }
@@ -175,13 +198,17 @@ fn op_0notequal(context: Context) -> Context {
 
 fn binary_op<F>(context: Context, op: F) -> Context
 where F: Fn(i32, i32) -> i64 {
-    assert!(context.stack.len() >= 2);
+    let mut new_context = context;
+    let input1 = pop_number(&mut new_context);
+    let input2 = pop_number(&mut new_context);
 
-    stack_op(context, |st| {
-        let input1 = IntUtils::to_i32(&st.pop().unwrap());
-        let input2 = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input2, input1)));
-    })
+    match (input1, input2) {
+        (Some(input1), Some(input2)) => {
+            new_context.stack.push(IntUtils::to_vec_u8(op(input2, input1)));
+            new_context
+        },
+        _ => op_mark_invalid(new_context),
+    }
 }
 
 fn bool_binary_op<F>(context: Context, op: F) -> Context
@@ -238,14 +265,18 @@ fn op_max(context: Context) -> Context {
 
 fn ternary_op<F>(context: Context, op: F) -> Context
 where F: Fn(i32, i32, i32) -> i32 {
-    assert!(context.stack.len() >= 3);
+    let mut new_context = context;
+    let input1 = pop_number(&mut new_context);
+    let input2 = pop_number(&mut new_context);
+    let input3 = pop_number(&mut new_context);
 
-    stack_op(context, |st| {
-        let input1 = IntUtils::to_i32(&st.pop().unwrap());
-        let input2 = IntUtils::to_i32(&st.pop().unwrap());
-        let input3 = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input3, input2, input1) as i64));
-    })
+    match (input1, input2, input3) {
+        (Some(input1), Some(input2), Some(input3)) => {
+            new_context.stack.push(IntUtils::to_vec_u8(op(input3, input2, input1) as i64));
+            new_context
+        },
+        _ => op_mark_invalid(new_context),
+    }
 }
 
 fn bool_ternary_op<F>(context: Context, op: F) -> Context
@@ -257,32 +288,33 @@ fn op_within(context: Context) -> Context {
     bool_ternary_op(context, |x, min, max| x >= min && x < max)
 }
 
+fn hash_op<F>(context: Context, hash: F) -> Context
+where F: Fn(&Vec<u8>) -> Vec<u8> {
+    let mut new_context = context;
+
+    match new_context.stack.pop() {
+        Some(last) => {
+            new_context.stack.push(hash(&last));
+            new_context
+        },
+        None => op_mark_invalid(new_context),
+    }
+}
+
 fn op_sha256(context: Context) -> Context {
-    stack_op(context, |st| {
-        let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha256(&last).to_vec());
-    })
+    hash_op(context, |last| CryptoUtils::sha256(last).to_vec())
 }
 
 fn op_sha1(context: Context) -> Context {
-    stack_op(context, |st| {
-        let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha1(&last).to_vec());
-    })
+    hash_op(context, |last| CryptoUtils::sha1(last).to_vec())
 }
 
 fn op_hash256(context: Context) -> Context {
-    stack_op(context, |st| {
-        let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha256(&CryptoUtils::sha256(&last)).to_vec());
-    })
+    hash_op(context, |last| CryptoUtils::sha256(&CryptoUtils::sha256(last)).to_vec())
 }
 
 fn op_ripemd160(context: Context) -> Context {
-    stack_op(context, |st| {
-        let last = st.pop().unwrap();
-        st.push(CryptoUtils::ripemd160(&last).to_vec());
-    })
+    hash_op(context, |last| CryptoUtils::ripemd160(last).to_vec())
 }
 
 fn op_codeseparator(context: Context) -> Context {
@@ -302,10 +334,12 @@ fn get_boolean(data: bool) -> Vec<u8> {
 }
 
 fn op_checksig(context: Context) -> Context {
-    assert!(context.stack.len() >= 2);
+    if context.stack.len() < 2 {
+        return op_mark_invalid(context);
+    }
 
     let codeseparator = context.codeseparator;
-    let checksig = context.checksig;
+    let checksig = context.checksig.clone();
     let mut new_context = context;
 
     let pub_key_str = new_context.stack.pop().unwrap();
@@ -323,16 +357,14 @@ fn op_checksigverify(context: Context) -> Context {
 }
 
 fn op_checkmultisig(context: Context) -> Context {
-    assert!(context.stack.len() > 1);
-
     let codeseparator = context.codeseparator;
-    let checksig = context.checksig;
+    let checksig = context.checksig.clone();
     let mut new_context = context;
 
-    let pub_keys_number = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(pub_keys_number >= 0);
-    assert!(pub_keys_number <= 20);
-    assert!(new_context.stack.len() > pub_keys_number as usize);
+    let pub_keys_number = match pop_number(&mut new_context) {
+        Some(n) if n >= 0 && n <= 20 && new_context.stack.len() > n as usize => n,
+        _ => return op_mark_invalid(new_context),
+    };
 
     let mut pub_keys = vec![];
     for _ in 0..pub_keys_number {
@@ -340,10 +372,10 @@ fn op_checkmultisig(context: Context) -> Context {
     }
     pub_keys.reverse();
 
-    let sig_strs_number = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(sig_strs_number >= 0);
-    assert!(sig_strs_number <= pub_keys_number);
-    assert!(new_context.stack.len() > sig_strs_number as usize);
+    let sig_strs_number = match pop_number(&mut new_context) {
+        Some(n) if n >= 0 && n <= pub_keys_number && new_context.stack.len() > n as usize => n,
+        _ => return op_mark_invalid(new_context),
+    };
 
     let mut sig_strs = vec![];
     for _ in 0..sig_strs_number {
@@ -378,11 +410,7 @@ fn op_checkmultisigverify(context: Context) -> Context {
 }
 
 fn op_hash160(context: Context) -> Context {
-    stack_op(context, |st| {
-        let last = st.pop().unwrap();
-        st.push(CryptoUtils::ripemd160(&CryptoUtils::sha256(&last))
-                .to_vec());
-    })
+    hash_op(context, |last| CryptoUtils::ripemd160(&CryptoUtils::sha256(last)).to_vec())
 }
 
 fn op_equalverify(context: Context) -> Context {
@@ -390,7 +418,9 @@ fn op_equalverify(context: Context) -> Context {
 }
 
 fn op_equal(context: Context) -> Context {
-    assert!(context.stack.len() >= 2);
+    if context.stack.len() < 2 {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let x = st.pop().unwrap();
@@ -508,7 +538,10 @@ fn op_nop(context: Context) -> Context { context }
 
 fn op_if(context: Context) -> Context {
     let mut new_context = context;
-    let last = new_context.stack.pop().unwrap();
+    let last = match new_context.stack.pop() {
+        Some(last) => last,
+        None => return op_mark_invalid(new_context),
+    };
 
     new_context.script.next();
 
@@ -605,7 +638,11 @@ fn get_next_op(script: &BitcoinScript) -> usize {
 
 fn op_else(context: Context) -> Context {
     let mut new_context = context;
-    let conditional_executed = new_context.conditional_executed.pop().unwrap();
+    let conditional_executed = match new_context.conditional_executed.pop() {
+        Some(executed) => executed,
+        // OP_ELSE without OP_IF
+        None => return op_mark_invalid(new_context),
+    };
     new_context.script.next();
     new_context.conditional_executed.push(!conditional_executed);
 
@@ -618,7 +655,10 @@ fn op_else(context: Context) -> Context {
 
 fn op_notif(context: Context) -> Context {
     let mut new_context = context;
-    let last = new_context.stack.pop().unwrap();
+    let last = match new_context.stack.pop() {
+        Some(last) => last,
+        None => return op_mark_invalid(new_context),
+    };
 
     new_context.script.next();
 
@@ -678,7 +718,9 @@ fn op_mark_invalid(context: Context) -> Context {
 }
 
 fn op_size(context: Context) -> Context {
-    assert!(context.stack.len() > 0);
+    if context.stack.len() == 0 {
+        return op_mark_invalid(context);
+    }
 
     stack_op(context, |st| {
         let size = IntUtils::to_vec_u8(st.last().unwrap().len() as i64);
@@ -735,6 +777,12 @@ macro_rules! op_codes {
                 }
             }
 
+            pub fn to_str(&self) -> &'static str {
+                match self {
+                    $(&OpCode::$element => $tostring),*
+                }
+            }
+
             pub fn from_str(data: &str) -> Option<OpCode> {
                 match data {
                     $($tostring => Some(OpCode::$element)),*,
@@ -1019,10 +1067,13 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_op_dup_panic() {
+    fn test_op_dup_empty() {
         let context = get_context(vec![]);
-        OpCode::Dup.execute(context);
+        let output = OpCode::Dup.execute(context);
+        let mut expected = get_context(vec![]);
+        expected.valid = false;
+
+        assert_eq!(expected, output);
     }
 
     #[test]
@@ -1055,10 +1106,13 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_op_equalverify_panic() {
+    fn test_op_equalverify_empty() {
         let context = get_context(vec![]);
-        OpCode::EqualVerify.execute(context);
+        let output = OpCode::EqualVerify.execute(context);
+        let mut expected = get_context(vec![]);
+        expected.valid = false;
+
+        assert_eq!(expected, output);
     }
 
     #[test]
@@ -1231,9 +1285,12 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_op_ifdup_panic() {
-        OpCode::IfDup.execute(get_context(vec![]));
+    fn test_op_ifdup_empty() {
+        let output = OpCode::IfDup.execute(get_context(vec![]));
+        let mut expected = get_context(vec![]);
+        expected.valid = false;
+
+        assert_eq!(output, expected);
     }
 
     fn test_stack_base(op: OpCode, stack: Vec<Vec<u8>>, expected: Vec<Vec<u8>>) {
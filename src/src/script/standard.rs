@@ -0,0 +1,185 @@
+use super::asm::{Instruction, Instructions};
+
+use serialize::Serialize;
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+const OP_DUP: u8 = 0x76;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_HASH160: u8 = 0xa9;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ScriptType {
+    NonStandard,
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    MultiSig,
+    NullData,
+    WitnessV0KeyHash,
+    WitnessV0ScriptHash,
+    WitnessV1Taproot,
+    WitnessUnknown,
+}
+
+impl ScriptType {
+    // Names used by Bitcoin Core in `scriptPubKey.type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            &ScriptType::NonStandard         => "nonstandard",
+            &ScriptType::PubKey              => "pubkey",
+            &ScriptType::PubKeyHash          => "pubkeyhash",
+            &ScriptType::ScriptHash          => "scripthash",
+            &ScriptType::MultiSig            => "multisig",
+            &ScriptType::NullData            => "nulldata",
+            &ScriptType::WitnessV0KeyHash    => "witness_v0_keyhash",
+            &ScriptType::WitnessV0ScriptHash => "witness_v0_scripthash",
+            &ScriptType::WitnessV1Taproot    => "witness_v1_taproot",
+            &ScriptType::WitnessUnknown      => "witness_unknown",
+        }
+    }
+
+    pub fn classify(script: &[u8]) -> ScriptType {
+        if script.len() == 25 && script[0] == OP_DUP && script[1] == OP_HASH160 &&
+           script[2] == 20 && script[23] == OP_EQUALVERIFY && script[24] == OP_CHECKSIG {
+            return ScriptType::PubKeyHash;
+        }
+
+        if script.len() == 23 && script[0] == OP_HASH160 && script[1] == 20 &&
+           script[22] == OP_EQUAL {
+            return ScriptType::ScriptHash;
+        }
+
+        if let Some((version, program)) = witness_program(script) {
+            return match (version, program.len()) {
+                (0, 20) => ScriptType::WitnessV0KeyHash,
+                (0, 32) => ScriptType::WitnessV0ScriptHash,
+                (0, _)  => ScriptType::NonStandard,
+                (1, 32) => ScriptType::WitnessV1Taproot,
+                _       => ScriptType::WitnessUnknown,
+            };
+        }
+
+        let instructions = match Instructions::new(script).collect::<Result<Vec<_>, _>>() {
+            Ok(instructions) => instructions,
+            Err(_) => return ScriptType::NonStandard,
+        };
+
+        if script.len() > 0 && script[0] == OP_RETURN &&
+           instructions[1..].iter().all(|i| i.op_code <= OP_16) {
+            return ScriptType::NullData;
+        }
+
+        if instructions.len() == 2 && is_pub_key(&instructions[0]) &&
+           instructions[1].op_code == OP_CHECKSIG {
+            return ScriptType::PubKey;
+        }
+
+        if is_multisig(&instructions) {
+            return ScriptType::MultiSig;
+        }
+
+        ScriptType::NonStandard
+    }
+}
+
+// BIP141: a version byte followed by a single push of 2 to 40 bytes
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 {
+        return None;
+    }
+
+    let version = match script[0] {
+        OP_0 => 0,
+        OP_1...OP_16 => script[0] - OP_1 + 1,
+        _ => return None,
+    };
+
+    if script[1] as usize + 2 != script.len() {
+        return None;
+    }
+
+    Some((version, &script[2..]))
+}
+
+fn is_pub_key(instruction: &Instruction) -> bool {
+    instruction.is_push() && (instruction.data.len() == 33 || instruction.data.len() == 65)
+}
+
+fn small_int(instruction: &Instruction) -> Option<usize> {
+    match instruction.op_code {
+        OP_1...OP_16 => Some((instruction.op_code - OP_1 + 1) as usize),
+        _ => None,
+    }
+}
+
+fn is_multisig(instructions: &[Instruction]) -> bool {
+    if instructions.len() < 4 || instructions[instructions.len() - 1].op_code != OP_CHECKMULTISIG {
+        return false;
+    }
+
+    let keys = &instructions[1..instructions.len() - 2];
+    match (small_int(&instructions[0]), small_int(&instructions[instructions.len() - 2])) {
+        (Some(m), Some(n)) => m <= n && n == keys.len() && keys.iter().all(is_pub_key),
+        _ => false,
+    }
+}
+
+// Smallest push of `data` onto the stack
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        0 => script.push(OP_0),
+        len @ 1...0x4b => script.push(len as u8),
+        len @ 0x4c...0xff => {
+            script.push(OP_PUSHDATA1);
+            script.push(len as u8);
+        },
+        len @ 0x100...0xffff => {
+            script.push(OP_PUSHDATA2);
+            (len as u16).serialize(script);
+        },
+        len => {
+            script.push(OP_PUSHDATA4);
+            (len as u32).serialize(script);
+        },
+    }
+
+    script.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    fn classify(script: &str) -> ScriptType {
+        ScriptType::classify(&script.from_hex().unwrap())
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("76a914a10a4da7d425923f7296b4b9b6dc4fe2564a3ba688ac"),
+                   ScriptType::PubKeyHash);
+        assert_eq!(classify("a914a10a4da7d425923f7296b4b9b6dc4fe2564a3ba687"),
+                   ScriptType::ScriptHash);
+        assert_eq!(classify("0014a10a4da7d425923f7296b4b9b6dc4fe2564a3ba6"),
+                   ScriptType::WitnessV0KeyHash);
+        assert_eq!(classify("5120a10a4da7d425923f7296b4b9b6dc4fe2564a3ba6a10a4da7d425923f7296b4b9"),
+                   ScriptType::WitnessV1Taproot);
+        assert_eq!(classify("6a0379657300"), ScriptType::NullData);
+        assert_eq!(classify("6a76"), ScriptType::NonStandard);
+        assert_eq!(classify("2102ca2a810ab17249b6033a038de563983881b4069270183f3c0aba945653e44216ac"),
+                   ScriptType::PubKey);
+        assert_eq!(classify("522102ca2a810ab17249b6033a038de563983881b4069270183f3c0aba945653e442162103f480f1b648d0d5167804ad4d586e0e757cc33fde0e133fd036e45d60d2db59e12103c18131d8de99d45fb72a774cab0ccc258cd2abd9605610da20b9a232c88a3cb653ae"),
+                   ScriptType::MultiSig);
+        assert_eq!(classify("51"), ScriptType::NonStandard);
+    }
+}
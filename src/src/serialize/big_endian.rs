@@ -0,0 +1,26 @@
+use super::{BigEndian, Serializer, Deserializer, DecodeError};
+
+macro_rules! big_endian {
+    ($ty: ty, $bytes: expr) => {
+        impl BigEndian for $ty {
+            fn serialize_be(&self, serializer: &mut Serializer) {
+                let data = serializer.to_bytes(*self as u64);
+                for i in 0..$bytes {
+                    serializer.push(data[$bytes - 1 - i]);
+                }
+            }
+
+            fn deserialize_be(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+                let mut data = [0; $bytes];
+                try!(deserializer.read_ex(&mut data));
+                data.reverse();
+
+                Ok(deserializer.to_u_slice(&data) as $ty)
+            }
+        }
+    }
+}
+
+big_endian!(u16, 2);
+big_endian!(u32, 4);
+big_endian!(u64, 8);
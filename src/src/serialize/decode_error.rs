@@ -0,0 +1,87 @@
+use std::fmt;
+
+use super::{DecodeError, DecodeErrorKind, PathSegment};
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind, offset: u64) -> DecodeError {
+        DecodeError {
+            kind: kind,
+            offset: offset,
+            type_name: None,
+            path: vec![],
+        }
+    }
+
+    pub fn kind(&self) -> &DecodeErrorKind { &self.kind }
+
+    /// Position of the failing read, relative to the start of the deserializer.
+    pub fn offset(&self) -> u64 { self.offset }
+
+    /// Records that the error happened while decoding `field` of `type_name`.
+    /// Errors bubble up from the innermost field so every level prepends itself.
+    pub fn in_struct(mut self, type_name: &'static str, field: &'static str) -> DecodeError {
+        self.path.insert(0, PathSegment::Field(field));
+        self.type_name = Some(type_name);
+        self
+    }
+
+    pub fn in_field(mut self, field: &'static str) -> DecodeError {
+        self.path.insert(0, PathSegment::Field(field));
+        self
+    }
+
+    pub fn at_index(mut self, index: usize) -> DecodeError {
+        self.path.insert(0, PathSegment::Index(index));
+        self
+    }
+
+    /// Field path of the error, e.g. `TxMessage.tx_in[3].script`
+    pub fn path(&self) -> String {
+        let mut path = self.type_name.unwrap_or("").to_string();
+
+        for segment in self.path.iter() {
+            match segment {
+                &PathSegment::Field(field) => {
+                    if path.len() > 0 {
+                        path.push('.');
+                    }
+                    path.push_str(field);
+                },
+                &PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+
+        path
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DecodeErrorKind::UnexpectedEof          => write!(f, "unexpected end of data"),
+            &DecodeErrorKind::OversizedLength(len)   => write!(f, "oversized length {}", len),
+            &DecodeErrorKind::InvalidEnumValue(val)  => write!(f, "invalid enum value {}", val),
+            &DecodeErrorKind::NonCanonicalVarInt     => write!(f, "non-canonical VarInt"),
+            &DecodeErrorKind::InvalidUtf8            => write!(f, "invalid UTF-8"),
+            &DecodeErrorKind::DuplicateKey           => write!(f, "duplicate key"),
+            &DecodeErrorKind::Io(ref e)              => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path();
+        if path.len() > 0 {
+            write!(f, "{} at offset {} in `{}`", self.kind, self.offset, path)
+        } else {
+            write!(f, "{} at offset {}", self.kind, self.offset)
+        }
+    }
+}
+
+impl From<DecodeError> for String {
+    fn from(error: DecodeError) -> String {
+        format!("{}", error)
+    }
+}
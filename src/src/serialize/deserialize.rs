@@ -1,74 +1,65 @@
-use time;
+use std::cmp;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::mem;
 
-use std::io::Read;
+use super::{Deserialize, Deserializer, VarInt, DecodeError, DecodeErrorKind, MAX_SIZE,
+            MAX_VECTOR_ALLOCATE};
 
-use super::{Deserialize, Deserializer, VarInt};
+impl Deserialize for i16 {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        deserializer.to_i(2).map(|r| r as i16)
+    }
+}
 
 impl Deserialize for i32 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_i(4).map(|r| r as i32)
     }
 }
 
 impl Deserialize for i64 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_i(8)
     }
 }
 
 impl Deserialize for u8 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_u(1).map(|r| r as u8)
     }
 }
 
 impl Deserialize for u16 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_u(2).map(|r| r as u16)
     }
 }
 
 impl Deserialize for u32 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_u(4).map(|r| r as u32)
     }
 }
 
 impl Deserialize for u64 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         deserializer.to_u(8).map(|r| r as u64)
     }
 }
 
-impl Deserialize for time::Tm {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let sec = try!(deserializer.to_i(8));
-        // Somewhere around 2033 this will break
-        // unfortunately time::Tm crashes with an invalid time :-(
-        // so we need to do some validation.
-        // TODO: switch to a better library
-        if sec < 0 || sec > 2000000000 {
-            panic!();
-            // Err(format!("Invalid time sec={}", sec))
-        } else {
-            Ok(time::at_utc(time::Timespec::new(sec, 0)))
-        }
-    }
-}
-
 impl Deserialize for bool {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         let data = try!(deserializer.to_u_fixed(1));
         Ok(data != 0)
     }
 }
 
 impl Deserialize for String {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
         let length = try!(VarInt::deserialize(deserializer)).as_u64() as usize;
 
         if length > 1024 {
-            return Err(format!("String is too long, length={}", length));
+            return Err(deserializer.error(DecodeErrorKind::OversizedLength(length as u64)));
         }
 
         let mut bytes = [0; 1024];
@@ -77,57 +68,87 @@ impl Deserialize for String {
         let mut bytes_vector = vec![];
         bytes_vector.extend(bytes[0..length].into_iter());
 
-        String::from_utf8(bytes_vector).map_err(|e| format!("Error: {:?}", e))
+        match String::from_utf8(bytes_vector) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(deserializer.error(DecodeErrorKind::InvalidUtf8)),
+        }
     }
 }
 
-impl<U: Deserialize> Deserialize for Vec<U> {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let length = try!(VarInt::deserialize(deserializer)).as_u64() as usize;
+/// Deserializes a length-prefixed vector refusing more than `max_count` elements.
+pub fn deserialize_vec<U: Deserialize>(deserializer: &mut Deserializer, max_count: u64)
+    -> Result<Vec<U>, DecodeError> {
+    let offset = deserializer.position();
+    let length = try!(VarInt::deserialize(deserializer)).as_u64();
 
-        let mut result = vec![];
-        for _ in 0..length {
-            result.push(try!(U::deserialize(deserializer)));
+    if length > max_count || length > MAX_SIZE {
+        return Err(DecodeError::new(DecodeErrorKind::OversizedLength(length), offset));
+    }
+
+    let length = length as usize;
+    let chunk = cmp::max(1, MAX_VECTOR_ALLOCATE / cmp::max(1, mem::size_of::<U>()));
+
+    let mut result = vec![];
+    for i in 0..length {
+        if result.len() == result.capacity() {
+            result.reserve(cmp::min(length - i, chunk));
         }
 
-        Ok(result)
+        result.push(try!(U::deserialize(deserializer).map_err(|e| e.at_index(i))));
+    }
+
+    Ok(result)
+}
+
+/// Deserializes a trailing field that older peers may not send, running out
+/// of data right before it is not an error.
+pub fn deserialize_optional<U: Deserialize>(deserializer: &mut Deserializer)
+    -> Result<Option<U>, DecodeError> {
+    let offset = deserializer.position();
+
+    match U::deserialize(deserializer) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.kind() == &DecodeErrorKind::UnexpectedEof && e.offset() == offset =>
+            Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl<U: Deserialize> Deserialize for Vec<U> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        deserialize_vec(deserializer, MAX_SIZE)
     }
 }
 
 impl<U:Deserialize, K: Deserialize> Deserialize for (U, K) {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let first  = try!(U::deserialize(deserializer));
-        let second = try!(K::deserialize(deserializer));
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let first  = try!(U::deserialize(deserializer).map_err(|e| e.in_field("0")));
+        let second = try!(K::deserialize(deserializer).map_err(|e| e.in_field("1")));
 
         Ok((first, second))
     }
 }
 
-// TODO: figure out a way to generalize this
-// probably related to https://github.com/rust-lang/rfcs/issues/1038
-impl<U: Deserialize + Default + Copy> Deserialize for [U; 4] {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let mut result = [U::default(); 4];
-        for i in 0..4 {
-            result[i] = try!(U::deserialize(deserializer));
+impl<U: Deserialize + Default + Copy, const N: usize> Deserialize for [U; N] {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let mut result = [U::default(); N];
+        for i in 0..N {
+            result[i] = try!(U::deserialize(deserializer).map_err(|e| e.at_index(i)));
         }
 
         Ok(result)
     }
 }
 
-impl<U: Deserialize + Default + Copy> Deserialize for [U; 32] {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let mut result = [U::default(); 32];
-        for i in 0..32 {
-            result[i] = try!(U::deserialize(deserializer));
-        }
+impl<T: Read + Seek> Deserializer for T {
+    fn position(&mut self) -> u64 {
+        self.seek(SeekFrom::Current(0)).unwrap_or(0)
+    }
 
-        Ok(result)
+    fn error(&mut self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind, self.position())
     }
-}
 
-impl<T: Read> Deserializer for T {
     fn to_u_slice(&self, data: &[u8]) -> u64 {
         let mut result = 0;
         let mut multiplier: u64 = 1;
@@ -140,29 +161,30 @@ impl<T: Read> Deserializer for T {
         result
     }
 
-    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), String> {
-        self.read_exact(out).map_err(|e| format!("Error: {:?}", e))
-    }
+    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), DecodeError> {
+        let offset = self.position();
 
-    fn to_i(&mut self, size: usize) -> Result<i64, String> {
-        assert!(size == 1 || size == 2 || size == 4 || size == 8);
+        self.read_exact(out).map_err(|e| {
+            let kind = match e.kind() {
+                ErrorKind::UnexpectedEof => DecodeErrorKind::UnexpectedEof,
+                _ => DecodeErrorKind::Io(format!("{:?}", e)),
+            };
 
-        let mut data = [0; 8];
-        try!(self.read_ex(&mut data[0..size]));
+            DecodeError::new(kind, offset)
+        })
+    }
 
-        let sign     = data[size-1] & 0x80;
-        data[size-1] = data[size-1] & 0x7F;
+    fn to_i(&mut self, size: usize) -> Result<i64, DecodeError> {
+        assert!(size == 1 || size == 2 || size == 4 || size == 8);
 
-        let unsigned = self.to_u_slice(&data[0..size]) as i64;
+        let unsigned = try!(self.to_u_fixed(size));
 
-        if sign > 0 {
-            Ok(unsigned * -1)
-        } else {
-            Ok(unsigned)
-        }
+        // Sign-extend the two's complement value to 64 bits
+        let shift = 64 - 8 * size;
+        Ok(((unsigned << shift) as i64) >> shift)
     }
 
-    fn to_u_fixed(&mut self, size: usize) -> Result<u64, String> {
+    fn to_u_fixed(&mut self, size: usize) -> Result<u64, DecodeError> {
         assert!(size == 1 || size == 2 || size == 4 || size == 8);
 
         let mut data = [0; 8];
@@ -171,7 +193,7 @@ impl<T: Read> Deserializer for T {
         Ok(self.to_u_slice(&data[0..size]))
     }
 
-    fn to_u(&mut self, size: usize) -> Result<u64, String> {
+    fn to_u(&mut self, size: usize) -> Result<u64, DecodeError> {
         self.to_u_fixed(size)
     }
 }
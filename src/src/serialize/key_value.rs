@@ -0,0 +1,109 @@
+use std::io::Cursor;
+
+use super::{KeyValue, KeyValueMap, VarInt, Serialize, Serializer, Deserialize, Deserializer,
+            DecodeError, DecodeErrorKind};
+
+impl KeyValue {
+    pub fn new(key_type: u64, key_data: Vec<u8>, value: Vec<u8>) -> KeyValue {
+        KeyValue {
+            key_type: key_type,
+            key_data: key_data,
+            value: value,
+        }
+    }
+
+    fn key_len(&self) -> usize {
+        VarInt::new(self.key_type).encoded_len() + self.key_data.len()
+    }
+}
+
+impl KeyValueMap {
+    pub fn new() -> KeyValueMap {
+        KeyValueMap {
+            entries: vec![],
+        }
+    }
+
+    /// Adds an entry, returns false and leaves the map untouched if the key
+    /// is already present.
+    pub fn insert(&mut self, entry: KeyValue) -> bool {
+        if self.get(entry.key_type, &entry.key_data).is_some() {
+            return false;
+        }
+
+        self.entries.push(entry);
+        true
+    }
+
+    pub fn get(&self, key_type: u64, key_data: &[u8]) -> Option<&[u8]> {
+        self.entries.iter()
+            .find(|e| e.key_type == key_type && e.key_data == key_data)
+            .map(|e| &e.value[..])
+    }
+
+    pub fn iter<'a>(&'a self) -> ::std::slice::Iter<'a, KeyValue> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Serialize for KeyValueMap {
+    fn serialize(&self, serializer: &mut Serializer) {
+        for entry in self.entries.iter() {
+            VarInt::new(entry.key_len() as u64).serialize(serializer);
+            VarInt::new(entry.key_type).serialize(serializer);
+            serializer.push_bytes(&entry.key_data);
+            entry.value.serialize(serializer);
+        }
+
+        // A key of length zero terminates the map
+        serializer.push(0x00);
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.entries.iter().fold(1, |len, entry| {
+            len + VarInt::new(entry.key_len() as u64).encoded_len() + entry.key_len() +
+                entry.value.encoded_len()
+        })
+    }
+}
+
+impl Deserialize for KeyValueMap {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let mut map = KeyValueMap::new();
+
+        loop {
+            let offset = deserializer.position();
+            let key: Vec<u8> = try!(Deserialize::deserialize(deserializer)
+                                        .map_err(|e| e.at_index(map.len())));
+            if key.is_empty() {
+                break;
+            }
+
+            // The key type is a VarInt at the start of the key, offsets inside
+            // the key are relative to the map
+            let mut key_reader = Cursor::new(&key[..]);
+            let key_type = try!(VarInt::deserialize(&mut key_reader).map_err(|e| {
+                DecodeError::new(e.kind().clone(), offset).at_index(map.len())
+            }));
+            let key_data = key[key_reader.position() as usize..].to_vec();
+
+            let value = try!(Deserialize::deserialize(deserializer)
+                                 .map_err(|e| e.at_index(map.len())));
+
+            if !map.insert(KeyValue::new(key_type.as_u64(), key_data, value)) {
+                return Err(DecodeError::new(DecodeErrorKind::DuplicateKey, offset)
+                               .at_index(map.len()));
+            }
+        }
+
+        Ok(map)
+    }
+}
@@ -1,14 +1,34 @@
 mod serialize;
 mod deserialize;
 mod var_int;
+mod decode_error;
+mod big_endian;
+mod key_value;
+
+pub use self::deserialize::{deserialize_vec, deserialize_optional};
+
+/// Largest payload accepted in a single protocol message, every length
+/// prefix read from the network is bounded by this as well.
+pub const MAX_SIZE: u64 = 0x02000000;
+
+// Vectors are allocated at most this many bytes at a time, so a forged length
+// can't make us reserve memory for data that was never sent.
+const MAX_VECTOR_ALLOCATE: usize = 5000000;
 
 pub trait Serialize {
     fn serialize(&self, serializer: &mut Serializer);
-    fn size() -> usize where Self: Sized;
+    /// Exact number of bytes `serialize` writes, computed without allocating.
+    fn encoded_len(&self) -> usize;
 }
 
 pub trait Deserialize: Sized {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String>;
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError>;
+}
+
+/// Integers that can also be encoded in network byte order.
+pub trait BigEndian: Sized {
+    fn serialize_be(&self, serializer: &mut Serializer);
+    fn deserialize_be(deserializer: &mut Deserializer) -> Result<Self, DecodeError>;
 }
 
 pub trait Serializer {
@@ -21,10 +41,12 @@ pub trait Serializer {
 }
 
 pub trait Deserializer {
-    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), String>;
-    fn to_i(&mut self, size: usize) -> Result<i64, String>;
-    fn to_u_fixed(&mut self, size: usize) -> Result<u64, String>;
-    fn to_u(&mut self, size: usize) -> Result<u64, String>;
+    fn position(&mut self) -> u64;
+    fn error(&mut self, kind: DecodeErrorKind) -> DecodeError;
+    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), DecodeError>;
+    fn to_i(&mut self, size: usize) -> Result<i64, DecodeError>;
+    fn to_u_fixed(&mut self, size: usize) -> Result<u64, DecodeError>;
+    fn to_u(&mut self, size: usize) -> Result<u64, DecodeError>;
     fn to_u_slice(&self, data: &[u8]) -> u64;
 }
 
@@ -33,3 +55,42 @@ pub struct VarInt {
     data: u64,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum DecodeErrorKind {
+    UnexpectedEof,
+    OversizedLength(u64),
+    InvalidEnumValue(u64),
+    NonCanonicalVarInt,
+    InvalidUtf8,
+    DuplicateKey,
+    Io(String),
+}
+
+/// Entry of a key-value map as used by PSBT. The key is encoded as a length
+/// prefixed VarInt type followed by the key data, the value is length prefixed.
+#[derive(PartialEq, Debug, Clone)]
+pub struct KeyValue {
+    pub key_type: u64,
+    pub key_data: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Key-value pairs terminated by a zero length key, keys are unique.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct KeyValueMap {
+    entries: Vec<KeyValue>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PathSegment {
+    Field(&'static str),
+    Index(usize),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct DecodeError {
+    kind: DecodeErrorKind,
+    offset: u64,
+    type_name: Option<&'static str>,
+    path: Vec<PathSegment>,
+}
@@ -1,6 +1,4 @@
-use time;
 use std::io::Write;
-use std::usize;
 
 use super::{Serialize, Serializer, VarInt};
 
@@ -34,15 +32,10 @@ impl<T: Write> Serializer for T {
     }
 
     fn i_to_fixed(&mut self, x: i64, bytes: usize) {
-        let u: u64 = x.abs() as u64;
-        let mut data: [u8; 8] = self.to_bytes(u);
-        let sign = if u == x as u64 { 0x00 } else { 0x80 };
-
-        data[bytes-1] = data[bytes-1] | sign;
-
-        for i in 0..bytes {
-            self.push(data[i]);
-        }
+        // Signed integers are little-endian two's complement, so truncating
+        // the 64-bit representation gives the right bytes for any width.
+        let data = self.to_bytes(x as u64);
+        self.push_bytes(&data[0..bytes]);
     }
 }
 
@@ -51,7 +44,7 @@ impl Serialize for bool {
         serializer.push(if *self { 1 } else { 0 });
     }
 
-    fn size() -> usize { 1 }
+    fn encoded_len(&self) -> usize { 1 }
 }
 
 impl Serialize for i16 {
@@ -59,7 +52,7 @@ impl Serialize for i16 {
         serializer.i_to_fixed(*self as i64, 2);
     }
 
-    fn size() -> usize { 2 }
+    fn encoded_len(&self) -> usize { 2 }
 }
 
 impl Serialize for i32 {
@@ -67,7 +60,7 @@ impl Serialize for i32 {
         serializer.i_to_fixed(*self as i64, 4);
     }
 
-    fn size() -> usize { 4 }
+    fn encoded_len(&self) -> usize { 4 }
 }
 
 impl Serialize for i64 {
@@ -75,7 +68,7 @@ impl Serialize for i64 {
         serializer.i_to_fixed(*self, 8);
     }
 
-    fn size() -> usize { 8 }
+    fn encoded_len(&self) -> usize { 8 }
 }
 
 impl Serialize for u8 {
@@ -83,7 +76,7 @@ impl Serialize for u8 {
         serializer.push(*self);
     }
 
-    fn size() -> usize { 1 }
+    fn encoded_len(&self) -> usize { 1 }
 }
 
 impl Serialize for u16 {
@@ -91,7 +84,7 @@ impl Serialize for u16 {
         serializer.u_to_fixed(*self as u64, 2);
     }
 
-    fn size() -> usize { 2 }
+    fn encoded_len(&self) -> usize { 2 }
 }
 
 impl Serialize for u32 {
@@ -99,7 +92,7 @@ impl Serialize for u32 {
         serializer.serialize_u(*self as u64, 4);
     }
 
-    fn size() -> usize { 4 }
+    fn encoded_len(&self) -> usize { 4 }
 }
 
 impl Serialize for u64 {
@@ -107,15 +100,7 @@ impl Serialize for u64 {
         serializer.serialize_u(*self, 8);
     }
 
-    fn size() -> usize { 8 }
-}
-
-impl Serialize for time::Tm {
-    fn serialize(&self, serializer: &mut Serializer) {
-        serializer.i_to_fixed(self.to_timespec().sec, 8);
-    }
-
-    fn size() -> usize { 8 }
+    fn encoded_len(&self) -> usize { 8 }
 }
 
 impl Serialize for String {
@@ -125,7 +110,9 @@ impl Serialize for String {
         serializer.push_bytes(&self.as_bytes());
     }
 
-    fn size() -> usize { usize::MAX }
+    fn encoded_len(&self) -> usize {
+        VarInt::new(self.len() as u64).encoded_len() + self.len()
+    }
 }
 
 impl<U: Serialize> Serialize for Vec<U> {
@@ -138,7 +125,9 @@ impl<U: Serialize> Serialize for Vec<U> {
         }
     }
 
-    fn size() -> usize { usize::MAX }
+    fn encoded_len(&self) -> usize {
+        VarInt::new(self.len() as u64).encoded_len() + self[..].encoded_len()
+    }
 }
 
 impl <U: Serialize, V: Serialize> Serialize for (U,V) {
@@ -147,7 +136,7 @@ impl <U: Serialize, V: Serialize> Serialize for (U,V) {
         self.1.serialize(serializer);
     }
 
-    fn size() -> usize { U::size() + V::size() }
+    fn encoded_len(&self) -> usize { self.0.encoded_len() + self.1.encoded_len() }
 }
 
 impl <U: Serialize> Serialize for [U] {
@@ -157,27 +146,19 @@ impl <U: Serialize> Serialize for [U] {
         }
     }
 
-    fn size() -> usize { usize::MAX }
-}
-
-impl <U: Serialize> Serialize for [U; 4] {
-    fn serialize(&self, serializer: &mut Serializer) {
-        for x in self {
-            x.serialize(serializer);
-        }
+    fn encoded_len(&self) -> usize {
+        self.iter().fold(0, |len, x| len + x.encoded_len())
     }
-
-    fn size() -> usize { U::size() * 4 }
 }
 
-impl <U: Serialize> Serialize for [U; 32] {
+impl <U: Serialize, const N: usize> Serialize for [U; N] {
     fn serialize(&self, serializer: &mut Serializer) {
         for x in self {
             x.serialize(serializer);
         }
     }
 
-    fn size() -> usize { U::size() * 32 }
+    fn encoded_len(&self) -> usize { self[..].encoded_len() }
 }
 
 impl <'a, U: Serialize> Serialize for &'a U {
@@ -185,5 +166,5 @@ impl <'a, U: Serialize> Serialize for &'a U {
         (*self).serialize(serializer);
     }
 
-    fn size() -> usize { U::size() }
+    fn encoded_len(&self) -> usize { (*self).encoded_len() }
 }
@@ -1,4 +1,5 @@
-use super::{VarInt, Serialize, Serializer, Deserialize, Deserializer};
+use super::{VarInt, Serialize, Serializer, Deserialize, Deserializer, DecodeError,
+            DecodeErrorKind};
 
 impl VarInt {
     pub fn new(data: u64) -> VarInt {
@@ -35,11 +36,20 @@ impl Serialize for VarInt {
         };
     }
 
-    fn size() -> usize { 8 }
+    fn encoded_len(&self) -> usize {
+        match self.data {
+            0x00000...0x0000000fc => 1,
+            0x000fd...0x00000ffff => 3,
+            0x10000...0x0ffffffff => 5,
+            _                     => 9,
+        }
+    }
 }
 
 impl Deserialize for VarInt {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, DecodeError> {
+        let offset = deserializer.position();
+
         let mut data = [0; 1];
         try!(deserializer.read_ex(&mut data));
 
@@ -47,14 +57,20 @@ impl Deserialize for VarInt {
             return Ok(VarInt::new(data[0] as u64));
         }
 
-        let bytes = match data[0] {
-            0xfd => 2,
-            0xfe => 4,
-            0xff => 8,
-            _    => 0,
+        let (bytes, min) = match data[0] {
+            0xfd => (2, 0xfd),
+            0xfe => (4, 0x10000),
+            _    => (8, 0x100000000),
         };
 
-        Ok(VarInt::new(try!(deserializer.to_u_fixed(bytes))))
+        let value = try!(deserializer.to_u_fixed(bytes));
+
+        // Every value has exactly one valid encoding, the shortest one
+        if value < min {
+            return Err(DecodeError::new(DecodeErrorKind::NonCanonicalVarInt, offset));
+        }
+
+        Ok(VarInt::new(value))
     }
 }
 
@@ -0,0 +1,85 @@
+use serialize::{Serialize, Deserialize, DecodeErrorKind};
+
+use std::io::Cursor;
+
+#[derive(Debug, PartialEq, BitcoinEncode, BitcoinDecode)]
+#[repr(u8)]
+enum Color {
+    Red = 1,
+    Green = 2,
+    Blue = 0x10,
+}
+
+#[derive(Debug, PartialEq, BitcoinEncode, BitcoinDecode)]
+struct Wrapper(u16, [u8; 3]);
+
+#[derive(Debug, PartialEq, BitcoinEncode, BitcoinDecode)]
+#[bitcoin(serialize_path = "::serialize")]
+struct Custom {
+    color: Color,
+    #[bitcoin(big_endian)]
+    port: u16,
+    wrapper: Wrapper,
+    #[bitcoin(max_count = 2)]
+    items: Vec<u32>,
+    #[bitcoin(optional)]
+    extra: Option<u8>,
+}
+
+fn serialize<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut buffer = vec![];
+    value.serialize(&mut buffer);
+    buffer
+}
+
+fn deserialize<T: Deserialize>(data: &[u8]) -> T {
+    T::deserialize(&mut Cursor::new(data)).unwrap()
+}
+
+#[test]
+fn test_derive_round_trip() {
+    let custom = Custom {
+        color: Color::Blue,
+        port: 8333,
+        wrapper: Wrapper(0x0102, [7, 8, 9]),
+        items: vec![1, 2],
+        extra: Some(0xAA),
+    };
+
+    let expected = vec![0x10,
+                        0x20, 0x8D,
+                        0x02, 0x01, 0x07, 0x08, 0x09,
+                        0x02, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+                        0xAA];
+
+    assert_eq!(serialize(&custom), expected);
+    assert_eq!(deserialize::<Custom>(&expected), custom);
+}
+
+#[test]
+fn test_derive_optional_missing() {
+    let data = vec![0x01, 0x20, 0x8D, 0x02, 0x01, 0x07, 0x08, 0x09, 0x00];
+
+    let custom: Custom = deserialize(&data);
+    assert_eq!(custom.color, Color::Red);
+    assert_eq!(custom.extra, None);
+    assert_eq!(serialize(&custom), data);
+}
+
+#[test]
+fn test_derive_errors() {
+    let data = vec![0x03];
+    let error = Custom::deserialize(&mut Cursor::new(&data[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::InvalidEnumValue(3));
+    assert_eq!(error.path(), "Custom.color");
+
+    let data = vec![0x02, 0x20, 0x8D, 0x02, 0x01, 0x07, 0x08, 0x09, 0x03];
+    let error = Custom::deserialize(&mut Cursor::new(&data[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::OversizedLength(3));
+    assert_eq!(error.path(), "Custom.items");
+
+    let data = vec![0x02, 0x20, 0x8D, 0x02, 0x01, 0x07];
+    let error = Custom::deserialize(&mut Cursor::new(&data[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::UnexpectedEof);
+    assert_eq!(error.path(), "Custom.wrapper.1[1]");
+}
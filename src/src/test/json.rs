@@ -0,0 +1,138 @@
+use net::json::FromJson;
+use net::messages::*;
+
+use std::io::{Cursor, Read};
+use std::fs::File;
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::{Json, ToJson};
+
+use serialize::Deserialize;
+
+const MAINNET_GENESIS_BLOCK: &'static str =
+    "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd\
+     7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c\
+     0101000000010000000000000000000000000000000000000000000000000000000000000000ffff\
+     ffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c\
+     6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73\
+     ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a6\
+     7962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f\
+     ac00000000";
+
+fn genesis_block() -> BlockMessage {
+    let bytes = MAINNET_GENESIS_BLOCK.from_hex().unwrap();
+    BlockMessage::deserialize(&mut Cursor::new(&bytes[..])).unwrap()
+}
+
+fn find<'a>(json: &'a Json, path: &[&str]) -> &'a Json {
+    json.find_path(path).unwrap()
+}
+
+#[test]
+fn test_genesis_block_json() {
+    let json = genesis_block().to_json();
+
+    assert_eq!(find(&json, &["hash"]).as_string(),
+               Some("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"));
+    assert_eq!(find(&json, &["merkleroot"]).as_string(),
+               Some("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"));
+    assert_eq!(find(&json, &["bits"]).as_string(), Some("1d00ffff"));
+    assert_eq!(find(&json, &["versionHex"]).as_string(), Some("00000001"));
+    assert_eq!(find(&json, &["difficulty"]).as_f64(), Some(1.0));
+    assert_eq!(find(&json, &["time"]).as_i64(), Some(1231006505));
+    assert_eq!(find(&json, &["nonce"]).as_u64(), Some(2083236893));
+    assert_eq!(find(&json, &["size"]).as_u64(), Some(285));
+    assert_eq!(find(&json, &["weight"]).as_u64(), Some(1140));
+    assert_eq!(find(&json, &["nTx"]).as_u64(), Some(1));
+    assert!(json.find("previousblockhash").is_none());
+
+    let tx = &find(&json, &["tx"]).as_array().unwrap()[0];
+    assert_eq!(find(tx, &["txid"]).as_string(),
+               Some("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"));
+    assert_eq!(find(tx, &["vsize"]).as_u64(), Some(204));
+
+    let vin = &find(tx, &["vin"]).as_array().unwrap()[0];
+    assert!(find(vin, &["coinbase"]).as_string().unwrap().starts_with("04ffff001d0104"));
+    assert_eq!(find(vin, &["sequence"]).as_u64(), Some(4294967295));
+
+    let vout = &find(tx, &["vout"]).as_array().unwrap()[0];
+    assert_eq!(find(vout, &["value"]).as_f64(), Some(50.0));
+    assert_eq!(find(vout, &["n"]).as_u64(), Some(0));
+    assert_eq!(find(vout, &["scriptPubKey", "type"]).as_string(), Some("pubkey"));
+    assert_eq!(find(vout, &["scriptPubKey", "asm"]).as_string(),
+               Some("04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f\
+                     4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f OP_CHECKSIG"));
+}
+
+#[test]
+fn test_block_json_round_trip() {
+    let block = genesis_block();
+    let json = Json::from_str(&block.to_json().to_string()).unwrap();
+    assert_eq!(BlockMessage::from_json(&json).unwrap(), block);
+
+    let mut block_data = vec![];
+    File::open("src/test/block.dat").unwrap().read_to_end(&mut block_data).unwrap();
+    let block = BlockMessage::deserialize(&mut Cursor::new(&block_data[..])).unwrap();
+
+    let json = Json::from_str(&block.to_json().to_string()).unwrap();
+    assert_eq!(BlockMessage::from_json(&json).unwrap(), block);
+}
+
+#[test]
+fn test_tx_json() {
+    let tx = "0100000002abf3a7e5bb08d828d9facb5f43e89437c8db8eb37e47ef590abe1040b8074cc3000000006a47304402201adc73cb90a42440a83f590e7a5309b611924c603c195da956ddbee1a024599e02205bd1b89ab89d8496c6ee8ae89bd98d725541137e9e044bc87a6f2d0cb53248e901210371196e03bfa6fdff8a4f2d9d4ba705ddbf40b062d2c0113253129d3230045f3bffffffffabf3a7e5bb08d828d9facb5f43e89437c8db8eb37e47ef590abe1040b8074cc3010000006b483045022100a16379ef6976f74c697beca71c79008f64a547fd856fe89c2ee08082ed4ba56002205c5f58ed92ad00c04395fa2bef655a99e2602e87212c1ba6499610a2ffc1f30a0121038966fb63c2c52b9d6c948029cf0d1e125944d5129e913565dcd6adf71355a0a4ffffffff03a0860100000000001976a914231709007241b6f638859d47384fe60f0f6a26ef88acc18d0ed2050000001976a9148c38e68d20d575f421f044a5995e1e18070b290f88ac0000000000000000056a0379657300000000".from_hex().unwrap();
+    let tx = TxMessage::deserialize(&mut Cursor::new(&tx[..])).unwrap();
+    let json = tx.to_json();
+
+    let vin = &find(&json, &["vin"]).as_array().unwrap()[0];
+    assert_eq!(find(vin, &["txid"]).as_string(),
+               Some("c34c07b84010be0a59ef477eb38edbc83794e8435fcbfad928d808bbe5a7f3ab"));
+    assert_eq!(find(vin, &["vout"]).as_u64(), Some(0));
+    assert!(find(vin, &["scriptSig", "asm"]).as_string().unwrap().contains("[ALL] 0371196e"));
+
+    let vout = find(&json, &["vout"]).as_array().unwrap();
+    assert_eq!(find(&vout[0], &["value"]).as_f64(), Some(0.001));
+    assert_eq!(find(&vout[0], &["scriptPubKey", "type"]).as_string(), Some("pubkeyhash"));
+    assert_eq!(find(&vout[1], &["value"]).as_f64(), Some(249.99005633));
+    assert_eq!(find(&vout[2], &["value"]).as_f64(), Some(0.0));
+    assert_eq!(find(&vout[2], &["scriptPubKey", "asm"]).as_string(),
+               Some("OP_RETURN 7562617"));
+    assert_eq!(find(&vout[2], &["scriptPubKey", "type"]).as_string(), Some("nulldata"));
+
+    let json = Json::from_str(&json.to_string()).unwrap();
+    assert_eq!(TxMessage::from_json(&json).unwrap(), tx);
+}
+
+#[test]
+fn test_message_json_round_trip() {
+    let bytes = "62ea0000010000000000000011b2d05000000000010000000000000000000000000000000000\
+                 ffff000000000000010000000000000000000000000000000000ffff0000000000003b2eb35d\
+                 8ce617650f2f5361746f7368693a302e372e322fc03e030001".from_hex().unwrap();
+    let version = VersionMessage::deserialize(&mut Cursor::new(&bytes[..])).unwrap();
+    let json = Json::from_str(&version.to_json().to_string()).unwrap();
+
+    assert_eq!(find(&json, &["user_agent"]).as_string(), Some("/Satoshi:0.7.2/"));
+    assert_eq!(find(&json, &["addr_recv", "address"]).as_string(), Some("::ffff:0.0.0.0"));
+    assert_eq!(find(&json, &["relay"]).as_boolean(), Some(true));
+    assert_eq!(VersionMessage::from_json(&json).unwrap(), version);
+
+    let hash = BitcoinHash::new([0x11; 32]);
+    let inv = InvMessage::new(vec![InventoryVector::new(InventoryVectorType::MSG_BLOCK, hash)]);
+    let json = Json::from_str(&inv.to_json().to_string()).unwrap();
+    assert_eq!(json.to_string(),
+               "{\"inventory\":[{\"hash\":\"1111111111111111111111111111111111111111111111111111111111111111\",\"type\":\"MSG_BLOCK\"}]}");
+    assert_eq!(InvMessage::from_json(&json).unwrap(), inv);
+}
+
+#[test]
+fn test_invalid_json() {
+    let json = Json::from_str("{\"value\": 21000001, \"scriptPubKey\": {\"hex\": \"\"}}").unwrap();
+    assert!(TxOut::from_json(&json).is_err());
+
+    let json = Json::from_str("{\"nonce\": -1}").unwrap();
+    assert_eq!(PingMessage::from_json(&json).unwrap_err(), "nonce: Expected u64, found `-1`");
+
+    let json = Json::from_str("{\"inventory\": [{\"type\": \"MSG_TX\"}]}").unwrap();
+    assert_eq!(InvMessage::from_json(&json).unwrap_err(),
+               "inventory: [0]: Missing field `hash`");
+}
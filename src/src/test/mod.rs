@@ -1 +1,5 @@
 mod net;
+mod serialize;
+mod derive;
+mod json;
+mod psbt;
@@ -1,17 +1,33 @@
 use net::*;
 use net::messages::*;
+use net::p2pclient::CommandHandlers;
 
 use utils::Debug;
 
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::fs::File;
+use std::net::Ipv6Addr;
 
-use serialize::{Serialize, Deserialize};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use serialize::{Serialize, Deserialize, DecodeErrorKind, MAX_SIZE};
 
 #[test]
 fn test_block() {
     let mut block_data = File::open("src/test/block.dat").unwrap();
-    BlockMessage::deserialize(&mut block_data).unwrap();
+    let block = BlockMessage::deserialize(&mut block_data).unwrap();
+
+    let mut result = vec![];
+    block.serialize(&mut result);
+    assert_eq!(block.encoded_len(), result.len());
+    assert_eq!(block.weight(), result.len() * 4);
+    assert_eq!(block.vsize(), result.len());
+
+    for tx in block.txns.iter() {
+        let mut result = vec![];
+        tx.serialize(&mut result);
+        assert_eq!(tx.encoded_len(), result.len());
+    }
 }
 
 #[test]
@@ -45,10 +61,10 @@ fn test_version_message() {
     let message = VersionMessage::deserialize(&mut deserializer).unwrap();
 
     assert_eq!(message.version, 60002);
-    assert_eq!(message.services, Services::new(true));
+    assert_eq!(message.services, NODE_NETWORK);
     assert_eq!(message.user_agent, "/Satoshi:0.7.2/");
     assert_eq!(message.start_height, 212672);
-    assert_eq!(message.relay, false);
+    assert_eq!(message.relay, Some(false));
 
     let mut result_buffer = Cursor::new(vec![]);
     message.serialize(&mut result_buffer);
@@ -104,9 +120,244 @@ fn test_complete_message() {
 
     let message = VersionMessage::deserialize(&mut deserializer).unwrap();
 
-    let serialized = get_serialized_message(NetworkType::Main, Command::Version, Some(Box::new(message)));
+    let serialized = get_serialized_message(NetworkType::Main, Command::Version, Some(Box::new(message))).unwrap();
     Debug::print_bytes(&serialized);
     Debug::print_bytes(&buffer);
 
     assert_eq!(buffer, serialized);
 }
+
+fn assert_round_trip<T: Serialize + Deserialize>(hex: &str) -> T {
+    let bytes = hex.from_hex().unwrap();
+
+    let mut deserializer = Cursor::new(&bytes[..]);
+    let message = T::deserialize(&mut deserializer).unwrap();
+    assert_eq!(deserializer.position() as usize, bytes.len());
+
+    let mut result = vec![];
+    message.serialize(&mut result);
+    assert_eq!(result, bytes);
+    assert_eq!(message.encoded_len(), bytes.len());
+
+    message
+}
+
+const TESTNET3_GENESIS_HEADER: &'static str =
+    "01000000000000000000000000000000000000000000000000000000000000000000000\
+     03ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae549\
+     4dffff001d1aa4ae18";
+
+#[test]
+fn test_message_header_vector() {
+    let header: MessageHeader =
+        assert_round_trip("0b11090776657261636b000000000000000000005df6e0e2");
+
+    assert_eq!(header.network_type, NetworkType::TestNet3);
+    assert_eq!(header.command, Command::Verack);
+    assert_eq!(header.length, 0);
+}
+
+#[test]
+fn test_unknown_command() {
+    let header: MessageHeader =
+        assert_round_trip("0b11090773656e646865616465727300000000005df6e0e2");
+
+    assert_eq!(header.command, Command::from_name("sendheaders").unwrap());
+    assert_eq!(header.command.name(), "sendheaders");
+    assert!(!header.command.is_known());
+
+    let header: MessageHeader =
+        assert_round_trip("0b11090766696c7465726c6f61640000000000005df6e0e2");
+    assert_eq!(header.command, Command::FilterLoad);
+
+    // Bytes after the NUL must be kept even if they make no sense
+    let header: MessageHeader =
+        assert_round_trip("0b11090776657261636b000000000001000000005df6e0e2");
+    assert!(!header.command.is_known());
+    assert_eq!(header.command.name(), "verack");
+
+    assert_eq!(Command::from_name("verack"), Some(Command::Verack));
+    assert_eq!(Command::from_name("averylongcommand"), None);
+    assert_eq!(Command::from_name(""), None);
+
+    let message = UnknownMessage {
+        command: Command::from_name("sendcmpct").unwrap(),
+        payload: vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    };
+    let serialized = get_serialized_message(NetworkType::TestNet3, message.command,
+                                            Some(Box::new(message.clone()))).unwrap();
+    assert_eq!(&serialized[24..], &message.payload[..]);
+}
+
+#[test]
+fn test_command_handlers() {
+    let mut handlers = CommandHandlers::new();
+    let echo = |message: &UnknownMessage| vec![message.clone()];
+
+    assert!(handlers.register("ping", Box::new(echo)).is_err());
+    assert!(handlers.register("", Box::new(echo)).is_err());
+    handlers.register("sendcmpct", Box::new(echo)).unwrap();
+
+    let message = UnknownMessage {
+        command: Command::from_name("sendcmpct").unwrap(),
+        payload: vec![1, 2, 3],
+    };
+    let handler = handlers.get(&message.command).unwrap();
+    assert_eq!(handler.handle(&message), vec![message.clone()]);
+    assert!(handlers.get(&Command::from_name("wtxidrelay").unwrap()).is_none());
+}
+
+#[test]
+fn test_ping_message_vector() {
+    let ping: PingMessage = assert_round_trip("efcdab8967452301");
+    assert_eq!(ping.nonce, 0x0123456789ABCDEF);
+}
+
+#[test]
+fn test_filterload_message_vector() {
+    let filter: FilterLoadMessage = assert_round_trip("02b50f0b0000000000000000");
+    assert_eq!(filter.filter, vec![0xB5, 0x0F]);
+    assert_eq!(filter.n_hash_funcs, 11);
+}
+
+#[test]
+fn test_addr_message_vector() {
+    let addr: AddrMessage = assert_round_trip(
+        "01e215104d010000000000000000000000000000000000ffff0a000001208d");
+
+    assert_eq!(addr.addr_list.len(), 1);
+    assert_eq!(addr.addr_list[0].0.secs(), 0x4D1015E2);
+    assert_eq!(addr.addr_list[0].1.address, "::ffff:10.0.0.1".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(addr.addr_list[0].1.port, 8333);
+}
+
+#[test]
+fn test_reject_message_vector() {
+    let reject: RejectMessage =
+        assert_round_trip("747800000000000000000000100762616420747874");
+
+    assert_eq!(reject.message, Command::Tx);
+    assert_eq!(reject.ccode, 0x10);
+    assert_eq!(reject.reason, "bad txt");
+}
+
+#[test]
+fn test_getheaders_message_vector() {
+    let message: GetHeadersMessage = assert_round_trip(
+        "7111010001\
+         1111111111111111111111111111111111111111111111111111111111111111\
+         0000000000000000000000000000000000000000000000000000000000000000");
+
+    assert_eq!(message.version, 70001);
+    assert_eq!(message.block_locators, vec![BitcoinHash::new([0x11; 32])]);
+    assert_eq!(message.hash_stop, BitcoinHash::new([0; 32]));
+}
+
+#[test]
+fn test_inv_message_vector() {
+    let inv: InvMessage = assert_round_trip(
+        "0102000000\
+         43497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea330900000000");
+
+    assert_eq!(inv.inventory.len(), 1);
+    assert_eq!(inv.inventory[0].type_, InventoryVectorType::MSG_BLOCK);
+}
+
+#[test]
+fn test_tx_out_negative_value() {
+    let tx_out: TxOut = assert_round_trip("ffffffffffffffff00");
+    assert_eq!(tx_out.value, -1);
+
+    let tx_out: TxOut = assert_round_trip("00f2052a010000000151");
+    assert_eq!(tx_out.value, 5000000000);
+    assert_eq!(tx_out.pk_script, vec![0x51]);
+}
+
+#[test]
+fn test_tx_in_vector() {
+    let tx_in: TxIn = assert_round_trip(
+        "1111111111111111111111111111111111111111111111111111111111111111\
+         0100000001acfeffffff");
+
+    assert_eq!(tx_in.previous_output.index, 1);
+    assert_eq!(tx_in.script, vec![0xAC]);
+    assert_eq!(tx_in.sequence, 0xFFFFFFFE);
+}
+
+#[test]
+fn test_block_metadata_vector() {
+    let metadata: BlockMetadata = assert_round_trip(TESTNET3_GENESIS_HEADER);
+    assert_eq!(metadata.version, 1);
+    assert_eq!(metadata.bits, 486604799);
+    assert_eq!(metadata.nonce, 414098458);
+    assert_eq!(format!("{:?}", metadata.hash()),
+               "000000000933EA01AD0EE984209779BAAEC3CED90FA3F408719526F8D77F4943");
+
+    // Negative versions must use two's complement
+    let negative = format!("ffffffff{}", &TESTNET3_GENESIS_HEADER[8..]);
+    let metadata: BlockMetadata = assert_round_trip(&negative);
+    assert_eq!(metadata.version, -1);
+}
+
+#[test]
+fn test_headers_message_vector() {
+    let headers: HeadersMessage =
+        assert_round_trip(&format!("01{}00", TESTNET3_GENESIS_HEADER));
+
+    assert_eq!(headers.headers.len(), 1);
+    assert_eq!(headers.headers[0].0.nonce, 414098458);
+}
+
+#[test]
+fn test_block_message_vector() {
+    let mut block_data = vec![];
+    File::open("src/test/block.dat").unwrap().read_to_end(&mut block_data).unwrap();
+
+    let mut deserializer = Cursor::new(&block_data[..]);
+    let block = BlockMessage::deserialize(&mut deserializer).unwrap();
+
+    let mut result = vec![];
+    block.serialize(&mut result);
+    assert_eq!(&result[..], &block_data[..deserializer.position() as usize]);
+}
+
+#[test]
+fn test_negative_version_message() {
+    let mut buffer = vec![0xFF, 0xFF, 0xFF, 0xFF];
+    buffer.extend(vec![0; 8 + 8 + 26 + 26 + 8 + 1 + 4 + 1]);
+
+    let message: VersionMessage = assert_round_trip(&buffer.to_hex());
+    assert_eq!(message.version, -1);
+}
+
+#[test]
+fn test_message_max_counts() {
+    // 50001 inventory entries
+    let inv = "fd51c3".from_hex().unwrap();
+    let error = InvMessage::deserialize(&mut Cursor::new(&inv[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::OversizedLength(50001));
+    assert_eq!(error.path(), "InvMessage.inventory");
+
+    // 2001 headers
+    let headers = "fdd107".from_hex().unwrap();
+    let error = HeadersMessage::deserialize(&mut Cursor::new(&headers[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::OversizedLength(2001));
+
+    // 1001 addresses
+    let addr = "fde903".from_hex().unwrap();
+    let error = AddrMessage::deserialize(&mut Cursor::new(&addr[..])).unwrap_err();
+    assert_eq!(error.kind(), &DecodeErrorKind::OversizedLength(1001));
+}
+
+#[test]
+fn test_oversized_message() {
+    let message = FilterLoadMessage {
+        filter: vec![0; MAX_SIZE as usize + 1],
+        n_hash_funcs: 0,
+        n_tweak: 0,
+        n_flags: 0,
+    };
+
+    assert!(get_serialized_message(NetworkType::Main, Command::FilterLoad,
+                                   Some(Box::new(message))).is_err());
+}
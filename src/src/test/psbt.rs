@@ -0,0 +1,232 @@
+use net::messages::*;
+use psbt::{KeySource, Psbt, PsbtError};
+use serialize::{DecodeErrorKind, Serialize};
+use utils::CryptoUtils;
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+fn hash160(data: &[u8]) -> Vec<u8> {
+    CryptoUtils::ripemd160(&CryptoUtils::sha256(data)).to_vec()
+}
+
+fn pub_key(n: u8) -> Vec<u8> {
+    let mut key = vec![0x02];
+    key.extend(vec![n; 32]);
+    key
+}
+
+fn signature(n: u8) -> Vec<u8> {
+    let mut sig = vec![0x30; 70];
+    sig[1] = n;
+    sig.push(0x01);
+    sig
+}
+
+fn p2pkh(key: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend(hash160(key));
+    script.extend(vec![0x88, 0xac]);
+    script
+}
+
+fn spending_tx(previous_output: OutPoint) -> TxMessage {
+    TxMessage {
+        version: 2,
+        tx_in: vec![TxIn {
+            previous_output: previous_output,
+            script: vec![],
+            sequence: 0xfffffffe,
+        }],
+        tx_out: vec![TxOut {
+            value: 99990000,
+            pk_script: p2pkh(&pub_key(9)),
+        }],
+        lock_time: 650000,
+    }
+}
+
+fn updated_psbt(version: u32) -> Psbt {
+    let tx = spending_tx(OutPoint::new(BitcoinHash::new([7; 32]), 1));
+    let mut psbt = Psbt::new(tx, version).unwrap();
+
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value: 100000000,
+        pk_script: vec![0x00, 0x14].into_iter().chain(hash160(&pub_key(1))).collect(),
+    });
+    psbt.inputs[0].partial_sigs.insert(pub_key(1), signature(1));
+    psbt.inputs[0].sighash_type = Some(1);
+    psbt.inputs[0].bip32_derivation.insert(pub_key(1), KeySource {
+        fingerprint: [0xd9, 0x0c, 0x6a, 0x4f],
+        path: vec![0x80000054, 0x80000000, 0x80000000, 0, 1],
+    });
+    psbt.outputs[0].bip32_derivation.insert(pub_key(9), KeySource {
+        fingerprint: [0xd9, 0x0c, 0x6a, 0x4f],
+        path: vec![0x80000054, 0x80000000, 0x80000000, 1, 0],
+    });
+
+    psbt
+}
+
+#[test]
+fn test_round_trip_v0() {
+    let psbt = updated_psbt(0);
+    let bytes = psbt.to_bytes();
+
+    assert_eq!(bytes.len(), psbt.encoded_len());
+    // Magic followed by the unsigned transaction key
+    assert!(bytes.to_hex().starts_with("70736274ff010055"));
+
+    assert_eq!(Psbt::from_bytes(&bytes), Ok(psbt.clone()));
+    assert_eq!(Psbt::from_base64(&psbt.to_base64()), Ok(psbt.clone()));
+    assert_eq!(psbt.unsigned_tx().unwrap().lock_time, 650000);
+}
+
+#[test]
+fn test_round_trip_v2() {
+    let mut psbt = updated_psbt(2);
+    psbt.inputs[0].required_height_lock_time = Some(700000);
+
+    let bytes = psbt.to_bytes();
+    assert_eq!(bytes.len(), psbt.encoded_len());
+    assert_eq!(Psbt::from_bytes(&bytes), Ok(psbt.clone()));
+
+    // The input requirement overrides the fallback
+    assert_eq!(psbt.lock_time(), Ok(700000));
+
+    psbt.inputs[0].required_height_lock_time = None;
+    psbt.inputs[0].required_time_lock_time = Some(1600000000);
+    assert_eq!(psbt.lock_time(), Ok(1600000000));
+
+    // Version 2 ids leave the sequence numbers out
+    let v0 = updated_psbt(0);
+    assert!(v0.unique_id().unwrap() != psbt.unique_id().unwrap());
+}
+
+#[test]
+fn test_invalid_psbts() {
+    assert_eq!(Psbt::from_bytes(b"psbu\xff\x00"), Err(PsbtError::InvalidMagic));
+    assert_eq!(Psbt::from_bytes(&"70736274ff00".from_hex().unwrap()),
+               Err(PsbtError::MissingField("PSBT_GLOBAL_UNSIGNED_TX")));
+
+    // PSBT_GLOBAL_TX_VERSION twice
+    match Psbt::from_bytes(&"70736274ff0102040200000001020402000000".from_hex().unwrap()) {
+        Err(PsbtError::Decode(e)) => assert_eq!(e.kind(), &DecodeErrorKind::DuplicateKey),
+        result => panic!("unexpected {:?}", result),
+    }
+
+    // PSBT_GLOBAL_TX_VERSION in a version 0 PSBT
+    let bytes = updated_psbt(0).to_bytes();
+    let mut v0 = "70736274ff010204".from_hex().unwrap();
+    v0.extend(vec![2, 0, 0, 0]);
+    v0.extend(bytes[5..].iter());
+    assert_eq!(Psbt::from_bytes(&v0), Err(PsbtError::UnexpectedField("PSBT_GLOBAL_TX_VERSION")));
+
+    let mut tx = spending_tx(OutPoint::new(BitcoinHash::new([7; 32]), 1));
+    tx.tx_in[0].script = vec![0x51];
+    assert_eq!(Psbt::new(tx, 0), Err(PsbtError::NonEmptyScriptSig(0)));
+}
+
+#[test]
+fn test_combine() {
+    let mut first = updated_psbt(0);
+    let mut second = updated_psbt(0);
+    first.inputs[0].partial_sigs.clear();
+    second.inputs[0].partial_sigs.insert(pub_key(2), signature(2));
+
+    first.combine(second).unwrap();
+    assert_eq!(first.inputs[0].partial_sigs.len(), 2);
+    assert_eq!(first.inputs[0].partial_sigs.get(&pub_key(2)), Some(&signature(2)));
+
+    let other = Psbt::new(spending_tx(OutPoint::new(BitcoinHash::new([8; 32]), 1)), 0).unwrap();
+    assert_eq!(first.combine(other), Err(PsbtError::TxMismatch));
+
+    // Sequence numbers aren't part of the version 2 identity
+    let mut first = updated_psbt(2);
+    let mut second = updated_psbt(2);
+    second.inputs[0].sequence = Some(0);
+    second.inputs[0].partial_sigs.insert(pub_key(2), signature(2));
+    first.combine(second).unwrap();
+    assert_eq!(first.inputs[0].sequence, Some(0xfffffffe));
+    assert_eq!(first.inputs[0].partial_sigs.len(), 2);
+}
+
+#[test]
+fn test_finalize_p2pkh() {
+    let previous = TxMessage {
+        version: 1,
+        tx_in: vec![],
+        tx_out: vec![TxOut { value: 5000, pk_script: vec![] },
+                     TxOut { value: 100000000, pk_script: p2pkh(&pub_key(3)) }],
+        lock_time: 0,
+    };
+
+    let mut psbt = Psbt::new(spending_tx(OutPoint::new(previous.hash(), 1)), 0).unwrap();
+    assert_eq!(psbt.extract(), Err(PsbtError::NotFinalized(0)));
+
+    psbt.inputs[0].non_witness_utxo = Some(previous.clone());
+    assert_eq!(psbt.finalize(), Err(PsbtError::CannotFinalize(0, "missing signature")));
+
+    psbt.inputs[0].partial_sigs.insert(pub_key(3), signature(3));
+    psbt.finalize().unwrap();
+    assert!(psbt.inputs[0].partial_sigs.is_empty());
+
+    let mut script_sig = vec![71];
+    script_sig.extend(signature(3));
+    script_sig.push(33);
+    script_sig.extend(pub_key(3));
+
+    let tx = psbt.extract().unwrap();
+    assert_eq!(tx.tx_in[0].script, script_sig);
+    assert_eq!(psbt.extract_raw().unwrap(), {
+        let mut raw = vec![];
+        tx.serialize(&mut raw);
+        raw
+    });
+
+    // The previous transaction must be the one being spent
+    let mut psbt = Psbt::new(spending_tx(OutPoint::new(BitcoinHash::new([7; 32]), 1)), 0).unwrap();
+    psbt.inputs[0].non_witness_utxo = Some(previous);
+    assert_eq!(psbt.finalize(), Err(PsbtError::UtxoMismatch(0)));
+}
+
+#[test]
+fn test_finalize_p2sh_p2wsh_multisig() {
+    // 2 of 3 multisig
+    let mut witness_script = vec![0x52];
+    for n in 4..7 {
+        witness_script.push(33);
+        witness_script.extend(pub_key(n));
+    }
+    witness_script.extend(vec![0x53, 0xae]);
+
+    let mut redeem_script = vec![0x00, 0x20];
+    redeem_script.extend(CryptoUtils::sha256(&witness_script).iter());
+
+    let mut script_pub_key = vec![0xa9, 0x14];
+    script_pub_key.extend(hash160(&redeem_script));
+    script_pub_key.push(0x87);
+
+    let mut psbt = Psbt::new(spending_tx(OutPoint::new(BitcoinHash::new([7; 32]), 0)), 2).unwrap();
+    psbt.inputs[0].witness_utxo = Some(TxOut { value: 100000000, pk_script: script_pub_key });
+    psbt.inputs[0].redeem_script = Some(redeem_script.clone());
+    psbt.inputs[0].witness_script = Some(witness_script.clone());
+    psbt.inputs[0].partial_sigs.insert(pub_key(6), signature(6));
+    assert_eq!(psbt.finalize(), Err(PsbtError::CannotFinalize(0, "not enough signatures")));
+
+    psbt.inputs[0].partial_sigs.insert(pub_key(4), signature(4));
+    psbt.finalize().unwrap();
+
+    let mut script_sig = vec![34];
+    script_sig.extend(redeem_script);
+    assert_eq!(psbt.inputs[0].final_script_sig, Some(script_sig));
+    assert_eq!(psbt.inputs[0].final_script_witness,
+               Some(vec![vec![], signature(4), signature(6), witness_script]));
+
+    // Finalized inputs survive serialization
+    assert_eq!(Psbt::from_bytes(&psbt.to_bytes()), Ok(psbt.clone()));
+
+    assert_eq!(psbt.extract(), Err(PsbtError::WitnessNotSupported));
+    let raw = psbt.extract_raw().unwrap();
+    assert_eq!(&raw[..6], &[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    assert_eq!(&raw[raw.len() - 4..], &[0x10, 0xeb, 0x09, 0x00]);
+}
@@ -0,0 +1,106 @@
+use serialize::{Serialize, Deserialize, DecodeErrorKind, VarInt, MAX_SIZE};
+
+use std::io::Cursor;
+
+use rand;
+
+fn serialize<T: Serialize>(value: T) -> Vec<u8> {
+    let mut buffer = vec![];
+    value.serialize(&mut buffer);
+    buffer
+}
+
+fn deserialize<T: Deserialize>(data: &[u8]) -> T {
+    let mut cursor = Cursor::new(data);
+    let value = T::deserialize(&mut cursor).unwrap();
+    assert_eq!(cursor.position() as usize, data.len());
+    value
+}
+
+#[test]
+fn test_signed_known_vectors() {
+    assert_eq!(serialize(-1i16), vec![0xFF, 0xFF]);
+    assert_eq!(serialize(-2i32), vec![0xFE, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(serialize(i32::min_value()), vec![0x00, 0x00, 0x00, 0x80]);
+    assert_eq!(serialize(i32::max_value()), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    assert_eq!(serialize(-5000000000i64),
+               vec![0x00, 0x0E, 0xFA, 0xD5, 0xFE, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(serialize(i64::min_value()),
+               vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]);
+
+    assert_eq!(deserialize::<i16>(&[0x00, 0x80]), i16::min_value());
+    assert_eq!(deserialize::<i32>(&[0xFF, 0xFF, 0xFF, 0xFF]), -1);
+    assert_eq!(deserialize::<i32>(&[0x62, 0xEA, 0x00, 0x00]), 60002);
+    assert_eq!(deserialize::<i64>(&[0x00, 0x0E, 0xFA, 0xD5, 0xFE, 0xFF, 0xFF, 0xFF]),
+               -5000000000);
+}
+
+#[test]
+fn test_signed_round_trip() {
+    for _ in 0..10000 {
+        let x16: i16 = rand::random();
+        let x32: i32 = rand::random();
+        let x64: i64 = rand::random();
+
+        assert_eq!(serialize(x16), x16.to_le_bytes().to_vec());
+        assert_eq!(serialize(x32), x32.to_le_bytes().to_vec());
+        assert_eq!(serialize(x64), x64.to_le_bytes().to_vec());
+
+        assert_eq!(deserialize::<i16>(&serialize(x16)), x16);
+        assert_eq!(deserialize::<i32>(&serialize(x32)), x32);
+        assert_eq!(deserialize::<i64>(&serialize(x64)), x64);
+    }
+}
+
+#[test]
+fn test_unsigned_round_trip() {
+    for _ in 0..10000 {
+        let x16: u16 = rand::random();
+        let x32: u32 = rand::random();
+        let x64: u64 = rand::random();
+
+        assert_eq!(serialize(x16), x16.to_le_bytes().to_vec());
+        assert_eq!(serialize(x32), x32.to_le_bytes().to_vec());
+        assert_eq!(serialize(x64), x64.to_le_bytes().to_vec());
+
+        assert_eq!(deserialize::<u16>(&serialize(x16)), x16);
+        assert_eq!(deserialize::<u32>(&serialize(x32)), x32);
+        assert_eq!(deserialize::<u64>(&serialize(x64)), x64);
+    }
+}
+
+fn decode_error<T: Deserialize>(data: &[u8]) -> DecodeErrorKind {
+    let mut cursor = Cursor::new(data);
+    T::deserialize(&mut cursor).err().unwrap().kind().clone()
+}
+
+#[test]
+fn test_var_int_canonical() {
+    assert_eq!(deserialize::<VarInt>(&[0xFC]).as_u64(), 0xFC);
+    assert_eq!(deserialize::<VarInt>(&[0xFD, 0xFD, 0x00]).as_u64(), 0xFD);
+    assert_eq!(deserialize::<VarInt>(&[0xFE, 0x00, 0x00, 0x01, 0x00]).as_u64(), 0x10000);
+    assert_eq!(deserialize::<VarInt>(&[0xFF, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00])
+               .as_u64(), 0x100000000);
+
+    assert_eq!(decode_error::<VarInt>(&[0xFD, 0xFC, 0x00]),
+               DecodeErrorKind::NonCanonicalVarInt);
+    assert_eq!(decode_error::<VarInt>(&[0xFE, 0xFF, 0xFF, 0x00, 0x00]),
+               DecodeErrorKind::NonCanonicalVarInt);
+    assert_eq!(decode_error::<VarInt>(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00]),
+               DecodeErrorKind::NonCanonicalVarInt);
+}
+
+#[test]
+fn test_vec_oversized_length() {
+    // 0x02000001 elements is above the protocol message cap
+    assert_eq!(decode_error::<Vec<u8>>(&[0xFE, 0x01, 0x00, 0x00, 0x02]),
+               DecodeErrorKind::OversizedLength(MAX_SIZE + 1));
+}
+
+#[test]
+fn test_vec_forged_length() {
+    // A length just below the cap with almost no data behind it must fail
+    // on the missing data rather than trying to allocate everything upfront
+    assert_eq!(decode_error::<Vec<u64>>(&[0xFE, 0x00, 0x00, 0x00, 0x02, 0x01]),
+               DecodeErrorKind::UnexpectedEof);
+}
@@ -6,11 +6,39 @@ use crypto::ripemd160;
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use net::banlist::{SubNet, DEFAULT_BAN_TIME};
+use net::chain_params::ChainParams;
+use net::p2pclient::PeerRequirements;
+use net::Services;
+use secp256k1::SecretKey;
+
+use rustc_serialize::hex::FromHex;
 
 pub struct Config {
+    pub chain: ChainParams,
     pub port: u16,
     pub blocks_file: File,
+    // Addresses of peers we know about
+    pub peers_file: PathBuf,
+    // Addresses we refuse to talk to
+    pub banlist_file: PathBuf,
+    // Edit the ban list and exit instead of running a node
+    pub ban: Vec<SubNet>,
+    pub unban: Vec<SubNet>,
+    pub ban_time: u32,
+    pub list_banned: bool,
     pub connect_to: Option<SocketAddr>,
+    pub peer_requirements: PeerRequirements,
+    // Bitcoin Core `blocks` directory to bootstrap from
+    pub import_dir: Option<PathBuf>,
+    // Signs this block for the signet and exits instead of running a node
+    pub sign_block: Option<Vec<u8>>,
+    pub signet_key: Option<SecretKey>,
+    // Mines this many blocks on regtest and exits
+    pub generate: Option<usize>,
+    pub coinbase_script: Vec<u8>,
 }
 
 impl Config {
@@ -31,22 +59,75 @@ impl Config {
         args.next();
 
         let mut config = Config {
-            port: 18333,
+            chain: ChainParams::testnet3(),
+            port: 0,
             blocks_file: try!(Self::get_store("block.dat")),
+            peers_file: PathBuf::from("peers.dat"),
+            banlist_file: PathBuf::from("banlist.dat"),
+            ban: vec![],
+            unban: vec![],
+            ban_time: DEFAULT_BAN_TIME,
+            list_banned: false,
             connect_to: None,
+            peer_requirements: PeerRequirements::new(),
+            import_dir: None,
+            sign_block: None,
+            signet_key: None,
+            generate: None,
+            // OP_TRUE, anyone can spend the generated coins
+            coinbase_script: vec![0x51],
         };
 
+        // Defaults to the port of the chain, whichever comes first
+        let mut port = None;
+        // Resolved once all the arguments are read, they can come in any order
+        let mut chain = None;
+        let mut signet_challenge = None;
+
         loop {
             match args.next() {
+                // The only flag without a value
+                Some(ref arg) if arg == "--list-banned" => config.list_banned = true,
                 Some(arg) => {
                     let next = args.next();
                     match arg.as_ref() {
                         "-c" | "--connect" =>
                             config.connect_to = Some(try!(Self::parse_address(next))),
+                        "--min-version" =>
+                            config.peer_requirements.min_version =
+                                try!(Self::parse_min_version(next)),
+                        "--required-services" =>
+                            config.peer_requirements.services =
+                                try!(Self::parse_services(next)),
+                        "--chain" =>
+                            chain = Some(try!(Self::parse_chain(next))),
+                        "--signet-challenge" =>
+                            signet_challenge =
+                                Some(try!(Self::parse_hex(next, "signet challenge"))),
+                        "--sign-block" =>
+                            config.sign_block = Some(try!(Self::parse_hex(next, "block"))),
+                        "--signet-key" =>
+                            config.signet_key = Some(try!(Self::parse_secret_key(next))),
+                        "--generate" =>
+                            config.generate = Some(try!(Self::parse_count(next))),
+                        "--generate-to" =>
+                            config.coinbase_script = try!(Self::parse_hex(next, "script")),
                         "-p" | "--port" =>
-                            config.port = try!(Self::parse_port(next)),
+                            port = Some(try!(Self::parse_port(next))),
                         "-f" | "--block-file" =>
                             config.blocks_file = try!(Self::parse_block_file(next)),
+                        "--peers-file" =>
+                            config.peers_file = try!(Self::parse_peers_file(next)),
+                        "--banlist-file" =>
+                            config.banlist_file = try!(Self::parse_banlist_file(next)),
+                        "--ban" =>
+                            config.ban.push(try!(Self::parse_subnet(next))),
+                        "--unban" =>
+                            config.unban.push(try!(Self::parse_subnet(next))),
+                        "--ban-time" =>
+                            config.ban_time = try!(Self::parse_ban_time(next)),
+                        "-i" | "--import-blocks" =>
+                            config.import_dir = Some(try!(Self::parse_import_dir(next))),
                         _ => try!(Self::parse_error(arg)),
                     }
                 }
@@ -54,6 +135,9 @@ impl Config {
             };
         }
 
+        config.chain = try!(Self::resolve_chain(chain, signet_challenge));
+        config.port = port.unwrap_or(config.chain.default_port);
+
         Ok(config)
     }
 
@@ -65,6 +149,22 @@ impl Config {
         }
     }
 
+    fn parse_min_version(arg: Option<String>) -> Result<i32, String> {
+        match arg {
+            Some(ref version) => version.parse()
+                .map_err(|e| format!("Unrecognized version `{}`, message: {:?}", version, e)),
+            None => Err(format!("Missing version.")),
+        }
+    }
+
+    fn parse_services(arg: Option<String>) -> Result<Services, String> {
+        match arg {
+            Some(ref bits) => bits.parse().map(Services::new)
+                .map_err(|e| format!("Unrecognized services `{}`, message: {:?}", bits, e)),
+            None => Err(format!("Missing services.")),
+        }
+    }
+
     fn parse_block_file(arg: Option<String>) -> Result<File, String> {
         match arg {
             Some(ref path) => Self::get_store(path),
@@ -72,6 +172,83 @@ impl Config {
         }
     }
 
+    fn parse_import_dir(arg: Option<String>) -> Result<PathBuf, String> {
+        match arg {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Err(format!("Missing blocks directory.")),
+        }
+    }
+
+    fn parse_peers_file(arg: Option<String>) -> Result<PathBuf, String> {
+        match arg {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Err(format!("Missing peers file.")),
+        }
+    }
+
+    fn parse_banlist_file(arg: Option<String>) -> Result<PathBuf, String> {
+        match arg {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Err(format!("Missing ban list file.")),
+        }
+    }
+
+    fn parse_subnet(arg: Option<String>) -> Result<SubNet, String> {
+        match arg {
+            Some(ref subnet) => SubNet::parse(subnet),
+            None => Err(format!("Missing subnet.")),
+        }
+    }
+
+    fn parse_ban_time(arg: Option<String>) -> Result<u32, String> {
+        match arg {
+            Some(ref secs) => secs.parse()
+                .map_err(|e| format!("Unrecognized ban time `{}`, message: {:?}", secs, e)),
+            None => Err(format!("Missing ban time.")),
+        }
+    }
+
+    fn parse_chain(arg: Option<String>) -> Result<ChainParams, String> {
+        match arg {
+            Some(ref name) => ChainParams::from_name(name),
+            None => Err(format!("Missing chain.")),
+        }
+    }
+
+    // A challenge makes the chain a custom signet, only `--chain signet` goes
+    // with it
+    fn resolve_chain(chain: Option<ChainParams>,
+                     challenge: Option<Vec<u8>>) -> Result<ChainParams, String> {
+        match (chain, challenge) {
+            (Some(ref chain), Some(_)) if chain.name != "signet" =>
+                Err(format!("A signet challenge can't be used with chain `{}`", chain.name)),
+            (_, Some(challenge)) => Ok(ChainParams::custom_signet(challenge)),
+            (Some(chain), None) => Ok(chain),
+            (None, None) => Ok(ChainParams::testnet3()),
+        }
+    }
+
+    fn parse_hex(arg: Option<String>, name: &str) -> Result<Vec<u8>, String> {
+        match arg {
+            Some(ref hex) => hex.from_hex()
+                .map_err(|e| format!("Unrecognized {} `{}`, message: {:?}", name, hex, e)),
+            None => Err(format!("Missing {}.", name)),
+        }
+    }
+
+    fn parse_secret_key(arg: Option<String>) -> Result<SecretKey, String> {
+        let key = try!(Self::parse_hex(arg, "signet key"));
+        SecretKey::from_slice(&key).map_err(|e| format!("Unrecognized signet key: {}", e))
+    }
+
+    fn parse_count(arg: Option<String>) -> Result<usize, String> {
+        match arg {
+            Some(ref count) => count.parse()
+                .map_err(|e| format!("Unrecognized block count `{}`, message: {:?}", count, e)),
+            None => Err(format!("Missing block count.")),
+        }
+    }
+
     fn parse_port(arg: Option<String>) -> Result<u16, String> {
         match arg {
             Some(ref port) => port.parse()
@@ -268,4 +445,19 @@ mod tests {
         test_hash(&CryptoUtils::ripemd160, "dGVzdA==", "XlL+5H5rBwVl90NyRozcaZ3okQc=");
         test_hash(&CryptoUtils::ripemd160, "dGVzdF8y", "rwwVga+QLGzlz74RtoOwUT/L6Bw=");
     }
+
+    #[test]
+    fn test_resolve_chain() {
+        let custom = ChainParams::custom_signet(vec![0x51]);
+        let resolve = |chain: Option<&str>, challenge: Option<Vec<u8>>| {
+            Config::resolve_chain(chain.map(|name| ChainParams::from_name(name).unwrap()),
+                                  challenge)
+        };
+
+        assert_eq!(resolve(None, None), Ok(ChainParams::testnet3()));
+        assert_eq!(resolve(Some("regtest"), None), Ok(ChainParams::regtest()));
+        assert_eq!(resolve(None, Some(vec![0x51])), Ok(custom.clone()));
+        assert_eq!(resolve(Some("signet"), Some(vec![0x51])), Ok(custom));
+        assert!(resolve(Some("main"), Some(vec![0x51])).is_err());
+    }
 }